@@ -6,7 +6,13 @@ pub fn parse_args() -> getopts::Matches {
     let args: Vec<String> = env::args().collect();
     let mut opts = Options::new();
     opts.optopt("", "debug-post", "", "");
+    opts.optopt("", "debug-post-type", "", "");
     opts.optopt("", "chat-id", "", "");
+    opts.optflag("", "dry-run", "");
+    opts.optflag("", "migrate", "");
+    opts.optflag("", "migration-status", "");
+    opts.optflag("", "vacuum", "");
+    opts.optflag("", "selftest", "");
     match opts.parse(&args[1..]) {
         Ok(m) => m,
         Err(f) => {
@@ -1,12 +1,22 @@
-use crate::{handle_post::handle_video_link, *};
-use anyhow::Result;
+use crate::{
+    handle_post::{handle_new_post, handle_video_link},
+    *,
+};
+use anyhow::{Context, Result};
+use chrono::NaiveTime;
+use chrono_tz::Tz;
 use lazy_static::lazy_static;
 use regex::Regex;
 use secrecy::ExposeSecret;
-use std::{env, sync::Arc};
+use std::{
+    env,
+    net::{Ipv4Addr, SocketAddr},
+    sync::Arc,
+};
 use teloxide::{
     dispatching::DefaultKey,
-    types::{FileId, MessageId},
+    types::{MaybeInaccessibleMessage, MessageId, ReplyParameters, User},
+    update_listeners::webhooks,
     utils::command::{BotCommands, ParseError},
 };
 use url::Url;
@@ -23,17 +33,75 @@ pub enum Command {
     Help,
     #[command(
         description = "subscribe to subreddit's top posts",
-        parse_with = parse_subscribe_message
+        parse_with = parse_subscribe_message,
+        alias = "s"
     )]
     Sub(SubscriptionArgs),
-    #[command(description = "unsubscribe from subreddit's top posts")]
+    #[command(description = "unsubscribe from subreddit's top posts", alias = "u")]
     Unsub(String),
-    #[command(description = "list subreddit subscriptions")]
+    #[command(description = "list subreddit subscriptions", alias = "ls")]
     ListSubs,
-    #[command(description = "get top posts", parse_with = parse_subscribe_message)]
+    #[command(
+        description = "show the settings that apply to a subscription and where they come from"
+    )]
+    EffectiveConfig(String),
+    #[command(
+        description = "get top posts",
+        parse_with = parse_subscribe_message,
+        alias = "g"
+    )]
     Get(SubscriptionArgs),
+    #[command(description = "show bot and yt-dlp versions")]
+    Version,
     #[command(description = "register channel to which the bot is supposed to post")]
     RegisterChannel(i64),
+    #[command(description = "show the registered repost channel")]
+    ShowChannel,
+    #[command(description = "unregister the repost channel")]
+    UnregisterChannel,
+    #[command(
+        description = "set quiet hours during which posts are held, e.g. 23:00-07:00, or off"
+    )]
+    Quiet(String),
+    #[command(
+        description = "show or hide the repost buttons below posts in this chat, given as on, off or default"
+    )]
+    RepostButtons(String),
+    #[command(
+        description = "set the time zone of quiet hours and digests in this chat, e.g. Europe/Berlin, or default"
+    )]
+    Timezone(String),
+    #[command(description = "never send a post, given as a link or an id, in this chat")]
+    Mute(String),
+    #[command(description = "unmute a post muted with /mute")]
+    Unmute(String),
+    #[command(
+        description = "show what's stored about a post, given as a link or an id, in this chat"
+    )]
+    Inspect(String),
+    #[command(description = "send a sample message to test reposting to the registered channel")]
+    TestRepost,
+    #[command(
+        description = "send the last posts of a subreddit again, e.g. /resend pics 3",
+        parse_with = "split"
+    )]
+    Resend { subreddit: String, count: u32 },
+    #[command(
+        description = "send a digest of the top posts of all time, e.g. /topall pics 10",
+        parse_with = "split"
+    )]
+    TopAll { subreddit: String, count: u32 },
+    #[command(
+        description = "forget the posts of a subreddit seen in this chat, so they are sent again"
+    )]
+    ResetSeen(String),
+    #[command(
+        description = "move the subscriptions and seen posts of a chat to another, e.g. /movechat -100123 -100456",
+        parse_with = "split"
+    )]
+    MoveChat { from: i64, to: i64 },
+    #[command(description = "stop or resume sending new posts to all chats, given as on or off")]
+    Maintenance(String),
     #[command(description = "repost to the registered channel", parse_with = "split")]
     RepostToChannel {
         message_id: i32,
@@ -41,25 +109,46 @@ pub enum Command {
     },
 }
 
+impl Command {
+    /// Commands that only admins can use, as they affect more than the subscriptions of the chat or
+    /// can flood it with posts sent again.
+    fn requires_admin(&self) -> bool {
+        matches!(
+            self,
+            Command::RegisterChannel(_)
+                | Command::UnregisterChannel
+                | Command::ResetSeen(_)
+                | Command::MoveChat { .. }
+                | Command::Maintenance(_)
+        )
+    }
+}
+
+/// Creates the Telegram client, using the Bot API server in `TELEGRAM_BOT_API_URL` if set.
+pub fn create_bot(config: &config::Config) -> Bot {
+    let client = teloxide::net::default_reqwest_settings()
+        .timeout(Duration::from_secs(600))
+        .build()
+        .expect("Client creation failed");
+    let tg = Bot::with_client(config.telegram_bot_token.expose_secret(), client);
+    match env::var_os(TELEGRAM_BOT_API_URL_ENV) {
+        Some(url) => tg.set_api_url(
+            Url::parse(url.to_str().expect("Unicode string expected"))
+                .expect("Bot api must be a url"),
+        ),
+        None => tg,
+    }
+}
+
 pub struct MyBot {
     pub dispatcher: Dispatcher<Arc<Bot>, anyhow::Error, DefaultKey>,
     pub tg: Arc<Bot>,
+    config: Arc<config::Config>,
 }
 
 impl MyBot {
-    pub async fn new(config: Arc<config::Config>) -> Result<Self> {
-        let client = teloxide::net::default_reqwest_settings()
-            .timeout(Duration::from_secs(600))
-            .build()
-            .expect("Client creation failed");
-        let mut tg = Bot::with_client(config.telegram_bot_token.expose_secret(), client);
-        if let Some(url) = env::var_os(TELEGRAM_BOT_API_URL_ENV) {
-            tg = tg.set_api_url(
-                Url::parse(url.to_str().expect("Unicode string expected"))
-                    .expect("Bot api must be a url"),
-            );
-        }
-
+    pub async fn new(config: Arc<config::Config>, maintenance: Arc<Maintenance>) -> Result<Self> {
+        let tg = create_bot(&config);
         tg.set_my_commands(Command::bot_commands()).await?;
 
         let tg = Arc::new(tg);
@@ -69,7 +158,9 @@ impl MyBot {
                 Update::filter_message().branch(
                     dptree::filter(|msg: Message, config: Arc<config::Config>| {
                         msg.from
-                            .map(|user| config.authorized_user_ids.contains(&user.id.0))
+                            .map(|user| {
+                                config.is_authorized_user(user.id.0, user.username.as_deref())
+                            })
                             .unwrap_or_default()
                     })
                     .branch(
@@ -83,14 +174,14 @@ impl MyBot {
             .branch(
                 Update::filter_callback_query().branch(
                     dptree::filter(|msg: CallbackQuery, config: Arc<config::Config>| {
-                        config.authorized_user_ids.contains(&msg.from.id.0)
+                        config.is_authorized_user(msg.from.id.0, msg.from.username.as_deref())
                     })
                     .endpoint(callback_handler),
                 ),
             );
 
         let dispatcher = Dispatcher::builder(tg.clone(), handler)
-            .dependencies(dptree::deps![config.clone()])
+            .dependencies(dptree::deps![config.clone(), maintenance])
             .default_handler(|upd| async move {
                 warn!("unhandled update: {upd:?}");
             })
@@ -99,21 +190,53 @@ impl MyBot {
             ))
             .build();
 
-        let my_bot = MyBot { dispatcher, tg };
+        let my_bot = MyBot {
+            dispatcher,
+            tg,
+            config,
+        };
         Ok(my_bot)
     }
 
-    pub fn spawn(
+    /// Starts dispatching updates, received by polling or through a webhook depending on
+    /// `telegram_mode`. In webhook mode the webhook is registered with Telegram before returning.
+    pub async fn spawn(
         mut self,
-    ) -> (
+    ) -> Result<(
         tokio::task::JoinHandle<()>,
         teloxide::dispatching::ShutdownToken,
-    ) {
+    )> {
         let shutdown_token = self.dispatcher.shutdown_token();
-        (
-            tokio::spawn(async move { self.dispatcher.dispatch().await }),
-            shutdown_token,
-        )
+        let handle = match self.config.telegram_mode {
+            config::TelegramMode::Polling => {
+                tokio::spawn(async move { self.dispatcher.dispatch().await })
+            }
+            config::TelegramMode::Webhook => {
+                let url = self
+                    .config
+                    .webhook_url
+                    .as_deref()
+                    .context("webhook_url must be set when telegram_mode is webhook")?;
+                let url = Url::parse(url).with_context(|| format!("invalid webhook_url {url}"))?;
+                let address = SocketAddr::from((Ipv4Addr::UNSPECIFIED, self.config.webhook_port));
+                info!("receiving updates through webhook {url} on {address}");
+                let listener =
+                    webhooks::axum(self.tg.clone(), webhooks::Options::new(address, url))
+                        .await
+                        .context("failed to set up webhook")?;
+                tokio::spawn(async move {
+                    self.dispatcher
+                        .dispatch_with_listener(
+                            listener,
+                            LoggingErrorHandler::with_custom_text(
+                                "an error from the webhook listener",
+                            ),
+                        )
+                        .await
+                })
+            }
+        };
+        Ok((handle, shutdown_token))
     }
 }
 
@@ -132,19 +255,29 @@ pub async fn handle_no_command(
         let text = message.text().context("No text in message")?;
 
         let db = db::Database::open(config)?;
+        let thread_id = message.thread_id.filter(|_| message.is_topic_message);
         // Check if the text matches the YouTube regex
         if RE_YOUTUBE.is_match(text) {
             let link = Url::parse(text)?;
-            handle_video_link(&db, tg, message.chat.id.0, &link).await?;
+            handle_video_link(&db, tg, message.chat.id.0, thread_id, &link, config).await?;
         } else {
-            let id = RE_REDDIT
-                .captures(text)
-                .context("Couldn't match reddit post url")?
-                .get(1)
-                .context("Couldn't find reddit post id")?
-                .as_str();
-            let post = reddit::get_link(id).await?;
-            process_post(&db, message.chat.id.0, &post, config, tg).await?;
+            let id = if reddit::is_share_link(text) {
+                reddit::resolve_share_link(text).await?
+            } else {
+                RE_REDDIT
+                    .captures(text)
+                    .context("Couldn't match reddit post url")?
+                    .get(1)
+                    .context("Couldn't find reddit post id")?
+                    .as_str()
+                    .to_string()
+            };
+            let post = reddit::get_link(&id).await?;
+            let options = DeliveryOptions {
+                topic: thread_id.map(|thread_id| thread_id.0 .0),
+                ..Default::default()
+            };
+            process_post(&db, message.chat.id.0, &post, config, tg, &options).await?;
         }
 
         Ok(())
@@ -163,60 +296,154 @@ pub async fn handle_command(
     tg: Arc<Bot>,
     command: Command,
     config: Arc<config::Config>,
+    maintenance: Arc<Maintenance>,
 ) -> Result<()> {
     async fn handle(
         message: &Message,
         tg: &Bot,
         command: Command,
         config: Arc<config::Config>,
+        maintenance: &Maintenance,
     ) -> Result<()> {
+        let is_admin = message
+            .from
+            .as_ref()
+            .is_some_and(|user| config.is_admin(user.id.0));
+        if command.requires_admin() && !is_admin {
+            tg.send_message(message.chat.id, "You are not permitted to use this command")
+                .await?;
+            return Ok(());
+        }
+
         let db = db::Database::open(&config)?;
+        if let Command::Sub(args) = &command {
+            let chat_id = message.chat.id.0;
+            let limit_error = subscription_limit_error(
+                &db,
+                &config,
+                chat_id,
+                args.kind,
+                &args.subreddit,
+                is_admin,
+            )?;
+            if let Some(reply) = limit_error {
+                tg.send_message(message.chat.id, reply).await?;
+                return Ok(());
+            }
+        }
         match command {
             Command::Help => {
                 tg.send_message(message.chat.id, Command::descriptions().to_string())
                     .await?;
             }
-            Command::Sub(mut args) => {
+            Command::Sub(mut args) if args.kind == SubscriptionKind::User => {
                 let chat_id = message.chat.id.0;
-                let subreddit_about = reddit::get_subreddit_about(&args.subreddit).await;
-                match subreddit_about {
-                    Ok(data) => {
-                        args.subreddit = data.display_name;
+                let clamp_note = clamp_limit(&mut args, &config);
+                let username = &args.subreddit;
+                match reddit::get_user_top_posts(username, 1, &TopPostsTimePeriod::All).await {
+                    Ok(_) => {
                         db.subscribe(chat_id, &args)?;
                         info!("subscribed in chat id {chat_id} with {args:#?};");
-                        tg.send_message(
-                            ChatId(chat_id),
-                            format!("Subscribed to r/{}", args.subreddit),
-                        )
-                        .await?;
+                        let reply = [Some(format!("Subscribed to u/{username}")), clamp_note]
+                            .into_iter()
+                            .flatten()
+                            .join("\n");
+                        tg.send_message(ChatId(chat_id), reply).await?;
                     }
-                    Err(reddit::SubredditAboutError::NoSuchSubreddit) => {
-                        tg.send_message(ChatId(chat_id), "No such subreddit")
+                    Err(
+                        err @ (reddit::TopPostsError::Forbidden | reddit::TopPostsError::NotFound),
+                    ) => {
+                        tg.send_message(ChatId(chat_id), format!("Could not subscribe: {err}"))
                             .await?;
                     }
                     Err(err) => {
-                        Err(err).context("Couldn't download about.json for subreddit")?;
+                        Err(err).context("Couldn't get posts for user")?;
                     }
                 }
             }
+            Command::Sub(args) => {
+                let chat_id = message.chat.id.0;
+                match subscribe_subreddit(&db, &config, chat_id, &args).await {
+                    Ok(reply) => {
+                        tg.send_message(ChatId(chat_id), reply).await?;
+                    }
+                    Err(err)
+                        if matches!(
+                            err.downcast_ref(),
+                            Some(reddit::SubredditAboutError::NoSuchSubreddit)
+                        ) =>
+                    {
+                        let suggestions = suggest_subreddits(&args.subreddit, &config).await;
+                        if suggestions.is_empty() {
+                            tg.send_message(ChatId(chat_id), "No such subreddit")
+                                .await?;
+                        } else {
+                            // Replying to the command lets the buttons subscribe with its other
+                            // arguments
+                            tg.send_message(
+                                ChatId(chat_id),
+                                "No such subreddit. Did you mean one of these?",
+                            )
+                            .reply_parameters(ReplyParameters::new(message.id))
+                            .reply_markup(messages::format_subreddit_suggestion_buttons(
+                                &suggestions,
+                            ))
+                            .await?;
+                        }
+                    }
+                    Err(err) => return Err(err),
+                }
+            }
             Command::Unsub(subreddit) => {
                 let chat_id = message.chat.id.0;
-                let subreddit = subreddit.replace("r/", "");
-                let reply = match db.unsubscribe(chat_id, &subreddit) {
-                    Ok(sub) => format!("Unsubscribed from r/{sub}"),
-                    Err(_) => format!("Error: Not subscribed to r/{subreddit}"),
+                let (kind, subreddit) = parse_subscription_name(&subreddit);
+                let prefix = kind.prefix();
+                let reply = match db.unsubscribe(chat_id, kind, &subreddit) {
+                    Ok(sub) => format!("Unsubscribed from {prefix}{sub}"),
+                    Err(_) => format!("Error: Not subscribed to {prefix}{subreddit}"),
                 };
                 tg.send_message(ChatId(chat_id), reply).await?;
             }
             Command::ListSubs => {
                 let subs = db.get_subscriptions_for_chat(message.chat.id.0)?;
-                let reply = messages::format_subscription_list(&subs);
+                let reply = messages::format_subscription_list(&subs, chrono::Utc::now());
+                tg.send_message(message.chat.id, reply).await?;
+            }
+            Command::EffectiveConfig(subreddit) => {
+                let (kind, subreddit) = parse_subscription_name(subreddit.trim());
+                let name = format!("{}{subreddit}", kind.prefix());
+                let sub = db
+                    .get_subscriptions_for_chat(message.chat.id.0)?
+                    .into_iter()
+                    .find(|sub| sub.kind == kind && sub.subreddit.eq_ignore_ascii_case(&subreddit));
+                let reply = match sub {
+                    Some(sub) => messages::format_effective_config(
+                        &name,
+                        &config::resolve_effective(&sub, &config),
+                    ),
+                    None => format!("Error: Not subscribed to {name}"),
+                };
+                tg.send_message(message.chat.id, reply).await?;
+            }
+            Command::Version => {
+                let ytdlp_version = tokio::task::block_in_place(ytdlp::version)
+                    .map_err(|err| warn!("could not get yt-dlp version: {err:?}"))
+                    .ok();
+                let reply = messages::format_version(ytdlp_version.as_deref());
                 tg.send_message(message.chat.id, reply).await?;
             }
             Command::Get(args) => {
                 handle_get_command(db, args, config, message, tg).await?;
             }
             Command::RegisterChannel(channel_id) => {
+                if let Err(reason) = check_repost_channel(tg, ChatId(channel_id)).await {
+                    tg.send_message(
+                        message.chat.id,
+                        format!("Could not register channel {channel_id}: {reason}"),
+                    )
+                    .await?;
+                    return Ok(());
+                }
                 db.set_repost_channel(message.chat.id.0, channel_id)?;
                 tg.send_message(
                     message.chat.id,
@@ -224,6 +451,196 @@ pub async fn handle_command(
                 )
                 .await?;
             }
+            Command::ShowChannel => {
+                let reply = match db.get_repost_channel(message.chat.id.0)? {
+                    Some(channel_id) => {
+                        let title = tg
+                            .get_chat(ChatId(channel_id))
+                            .await
+                            .ok()
+                            .and_then(|chat| chat.title().map(ToOwned::to_owned));
+                        match title {
+                            Some(title) => format!("Repost channel: {title} ({channel_id})"),
+                            None => format!("Repost channel: {channel_id}"),
+                        }
+                    }
+                    None => "Repost channel not registered".to_string(),
+                };
+                tg.send_message(message.chat.id, reply).await?;
+            }
+            Command::Quiet(input) => {
+                let chat_id = message.chat.id.0;
+                let input = input.trim();
+                let reply = if input.is_empty() {
+                    match db.get_quiet_hours(chat_id)? {
+                        Some(quiet_hours) => messages::format_quiet_hours(&quiet_hours),
+                        None => "Quiet hours are not set".to_string(),
+                    }
+                } else if input.eq_ignore_ascii_case("off") {
+                    db.set_quiet_hours(chat_id, None)?;
+                    "Quiet hours disabled".to_string()
+                } else {
+                    match parse_quiet_hours(input) {
+                        Ok(quiet_hours) => {
+                            db.set_quiet_hours(chat_id, Some(quiet_hours))?;
+                            messages::format_quiet_hours(&quiet_hours)
+                        }
+                        Err(err) => err,
+                    }
+                };
+                tg.send_message(message.chat.id, reply).await?;
+            }
+            Command::Timezone(input) => {
+                let chat_id = message.chat.id.0;
+                let input = input.trim();
+                let reply = if input.is_empty() {
+                    match db.get_timezone(chat_id)? {
+                        Some(timezone) => format!("Time zone is {timezone}"),
+                        None => format!("Time zone is {} (config)", config.tz()),
+                    }
+                } else if input.eq_ignore_ascii_case("default") {
+                    db.set_timezone(chat_id, None)?;
+                    format!("Time zone follows the config, {}", config.tz())
+                } else {
+                    match parse_timezone(input) {
+                        Ok(timezone) => {
+                            db.set_timezone(chat_id, Some(timezone))?;
+                            format!("Time zone set to {timezone}")
+                        }
+                        Err(err) => err,
+                    }
+                };
+                tg.send_message(message.chat.id, reply).await?;
+            }
+            Command::RepostButtons(input) => {
+                let chat_id = message.chat.id.0;
+                let input = input.trim().to_lowercase();
+                let reply = match input.as_str() {
+                    "" => {
+                        let show = db.get_show_repost_buttons(chat_id)?;
+                        let source = if show.is_some() { "chat" } else { "config" };
+                        let show = show.unwrap_or(config.show_repost_buttons);
+                        let state = if show { "shown" } else { "hidden" };
+                        format!("Repost buttons are {state} ({source})")
+                    }
+                    "on" => {
+                        db.set_show_repost_buttons(chat_id, Some(true))?;
+                        "Repost buttons are shown below new posts".to_string()
+                    }
+                    "off" => {
+                        db.set_show_repost_buttons(chat_id, Some(false))?;
+                        "Repost buttons are hidden below new posts".to_string()
+                    }
+                    "default" => {
+                        db.set_show_repost_buttons(chat_id, None)?;
+                        "Repost buttons follow the config".to_string()
+                    }
+                    _ => "Expected on, off or default".to_string(),
+                };
+                tg.send_message(message.chat.id, reply).await?;
+            }
+            Command::Mute(input) => {
+                let reply = match parse_post_id(&input) {
+                    Some(post_id) => {
+                        db.mute_post(message.chat.id.0, post_id)?;
+                        format!("Muted post {post_id}")
+                    }
+                    None => "Give a link to a post or a post id".to_string(),
+                };
+                tg.send_message(message.chat.id, reply).await?;
+            }
+            Command::Unmute(input) => {
+                let reply = match parse_post_id(&input) {
+                    Some(post_id) if db.unmute_post(message.chat.id.0, post_id)? => {
+                        format!("Unmuted post {post_id}")
+                    }
+                    Some(post_id) => format!("Post {post_id} is not muted"),
+                    None => "Give a link to a post or a post id".to_string(),
+                };
+                tg.send_message(message.chat.id, reply).await?;
+            }
+            Command::Inspect(input) => {
+                let reply = match parse_post_id(&input) {
+                    Some(post_id) => {
+                        let info = db.get_post_debug_info(message.chat.id.0, post_id)?;
+                        messages::format_post_debug_info(post_id, &info)
+                    }
+                    None => "Give a link to a post or a post id".to_string(),
+                };
+                tg.send_message(message.chat.id, reply).await?;
+            }
+            Command::UnregisterChannel => {
+                db.clear_repost_channel(message.chat.id.0)?;
+                tg.send_message(message.chat.id, "Repost channel unregistered")
+                    .await?;
+            }
+            Command::Resend { subreddit, count } => {
+                handle_resend_command(db, &config, message, tg, &subreddit, count).await?;
+            }
+            Command::TopAll { subreddit, count } => {
+                handle_top_all_command(&config, &db, message, tg, &subreddit, count).await?;
+            }
+            Command::ResetSeen(subreddit) => {
+                let (_, subreddit) = parse_subscription_name(subreddit.trim());
+                let reply = if subreddit.is_empty() {
+                    "Give a subreddit, e.g. /resetseen pics".to_string()
+                } else {
+                    let cleared = db.clear_seen_for_subreddit(message.chat.id.0, &subreddit)?;
+                    info!(
+                        "cleared {cleared} seen posts of r/{subreddit} in chat id {}",
+                        message.chat.id
+                    );
+                    format!(
+                        "Forgot {cleared} seen post(s) of r/{subreddit}. Recent posts will be sent \
+                         again on the next check."
+                    )
+                };
+                tg.send_message(message.chat.id, reply).await?;
+            }
+            Command::MoveChat { from, to } => {
+                let reply = match db.move_chat(from, to) {
+                    Ok(moved) => {
+                        info!("moved chat {from} to {to}: {moved:?}");
+                        messages::format_moved_chat(from, to, &moved)
+                    }
+                    Err(err) => format!("Could not move chat {from} to {to}: {err}"),
+                };
+                tg.send_message(message.chat.id, reply).await?;
+            }
+            Command::TestRepost => {
+                if db.get_repost_channel(message.chat.id.0)?.is_none() {
+                    tg.send_message(message.chat.id, "Repost channel not registered")
+                        .await?;
+                    return Ok(());
+                }
+                tg.send_message(
+                    message.chat.id,
+                    "Test post. Press a button to repost it to the registered channel.",
+                )
+                .reply_markup(messages::format_test_repost_buttons())
+                .await?;
+            }
+            Command::Maintenance(input) => {
+                let input = input.trim();
+                let reply = if input.is_empty() && maintenance.is_on() {
+                    "Maintenance mode is on"
+                } else if input.is_empty() {
+                    "Maintenance mode is off"
+                } else if input.eq_ignore_ascii_case("on") {
+                    if !maintenance.set(true) {
+                        warn!("entering maintenance mode, new posts will not be sent");
+                    }
+                    "Maintenance mode on, new posts will not be sent until it's turned off"
+                } else if input.eq_ignore_ascii_case("off") {
+                    if maintenance.set(false) {
+                        warn!("leaving maintenance mode, resuming sending new posts");
+                    }
+                    "Maintenance mode off"
+                } else {
+                    "Give on or off"
+                };
+                tg.send_message(message.chat.id, reply).await?;
+            }
             Command::RepostToChannel {
                 description,
                 message_id,
@@ -239,7 +656,7 @@ pub async fn handle_command(
         Ok(())
     }
 
-    if let Err(err) = handle(&message, &tg, command, config).await {
+    if let Err(err) = handle(&message, &tg, command, config, &maintenance).await {
         error!("failed to handle message: {err:?}");
         tg.send_message(message.chat.id, "Something went wrong")
             .await?;
@@ -267,34 +684,53 @@ async fn handle_repost(
     };
     tg.copy_message(ChatId(repost_channel_id), chat_id, MessageId(message_id))
         .caption(caption)
+        .parse_mode(teloxide::types::ParseMode::Html)
         .send()
         .await?;
     Ok(())
 }
 
+/// Reposts the sample message of /testrepost and tells the chat whether it worked.
+async fn handle_test_repost(
+    db: db::Database,
+    config: &config::Config,
+    tg: &Bot,
+    chat_id: ChatId,
+    message_id: MessageId,
+    copy_caption: bool,
+) -> Result<()> {
+    if db.get_repost_channel(chat_id.0)?.is_none() {
+        tg.send_message(chat_id, "Repost channel not registered")
+            .await?;
+        return Ok(());
+    }
+    let caption = messages::format_repost_caption(
+        copy_caption.then_some("Test repost"),
+        config.repost_caption_suffix.as_deref(),
+    );
+    let reply = match handle_repost(db, chat_id, tg, message_id.0, caption).await {
+        Ok(()) => "Test repost succeeded".to_string(),
+        Err(err) => {
+            warn!("test repost failed in chat {chat_id}: {err:?}");
+            format!("Test repost failed: {err}")
+        }
+    };
+    tg.send_message(chat_id, reply).await?;
+    Ok(())
+}
+
 async fn handle_repost_gallery(
     db: db::Database,
+    config: &config::Config,
     chat_id: ChatId,
     tg: &Bot,
-    gallery_file_ids: Vec<FileId>,
+    gallery_files: Vec<TelegramFile>,
     post_caption: Option<String>,
 ) -> Result<()> {
-    let mut media_group = vec![];
-    let mut first = true;
-
-    for file_id in gallery_file_ids {
-        let mut input_media_photo = InputMediaPhoto::new(InputFile::file_id(file_id));
-        // The first InputMediaPhoto in the vector needs to contain the caption and parse_mode;
-        if first {
-            if let Some(caption) = &post_caption {
-                input_media_photo = input_media_photo.caption(caption);
-            }
-            input_media_photo = input_media_photo.parse_mode(teloxide::types::ParseMode::Html);
-            first = false;
-        }
-
-        media_group.push(InputMedia::Photo(input_media_photo))
-    }
+    let files = gallery_files
+        .into_iter()
+        .map(|file| (InputFile::file_id(file.file_id), file.kind, file.dimensions))
+        .collect::<Vec<_>>();
 
     let Some(repost_channel_id) = db.get_repost_channel(chat_id.0)? else {
         tg.send_message(chat_id, "Repost channel not registered".to_string())
@@ -302,45 +738,106 @@ async fn handle_repost_gallery(
         return Ok(());
     };
 
-    tg.send_media_group(ChatId(repost_channel_id), media_group)
-        .await?;
+    let repost_channel_id = ChatId(repost_channel_id);
+    match config.gallery_mode {
+        config::GalleryMode::MediaGroup => {
+            let media_group = handle_post::build_media_group(files, post_caption.as_deref(), false);
+            tg.send_media_group(repost_channel_id, media_group).await?;
+        }
+        config::GalleryMode::ReplyChain => {
+            handle_post::send_reply_chain(
+                config,
+                tg,
+                repost_channel_id,
+                None,
+                files,
+                post_caption.as_deref(),
+                false,
+            )
+            .await?;
+        }
+    }
     Ok(())
 }
 
 async fn handle_get_command(
     db: db::Database,
-    args: SubscriptionArgs,
+    mut args: SubscriptionArgs,
     config: Arc<config::Config>,
     message: &Message,
     tg: &Bot,
 ) -> Result<(), anyhow::Error> {
+    // Posts are sent to the topic the command was sent in, unless another one was given
+    if args.topic.is_none() && message.is_topic_message {
+        args.topic = message.thread_id.map(|thread_id| thread_id.0 .0);
+    }
     let subreddit = &args.subreddit;
     let limit = args
         .limit
         .or(config.default_limit)
         .unwrap_or(config::DEFAULT_LIMIT);
+    let limit = if limit > config.max_get_limit {
+        tg.send_message(message.chat.id, format_limit_clamped(limit, &config))
+            .await?;
+        config.max_get_limit
+    } else {
+        limit
+    };
     let time = args
         .time
         .or(config.default_time)
         .unwrap_or(config::DEFAULT_TIME_PERIOD);
-    let filter = args.filter.or(config.default_filter);
+    let filter = args
+        .filter
+        .clone()
+        .or_else(|| config.default_filter.map(|filter| vec![filter]));
     let chat_id = message.chat.id.0;
-    let posts = reddit::get_subreddit_top_posts(subreddit, limit, &time)
-        .await
-        .context("failed to get posts")?
+    let posts = match reddit::get_top_posts(args.kind, subreddit, limit, &time).await {
+        Ok(posts) => posts,
+        Err(
+            err @ (reddit::TopPostsError::Forbidden
+            | reddit::TopPostsError::NotFound
+            | reddit::TopPostsError::RateLimited),
+        ) => {
+            tg.send_message(message.chat.id, format!("Could not get posts: {err}"))
+                .await?;
+            return Ok(());
+        }
+        Err(err) => return Err(err).context("failed to get posts"),
+    };
+    let posts = posts
         .into_iter()
-        .filter(|p| {
-            if filter.is_some() {
-                filter.as_ref() == Some(&p.post_type)
-            } else {
-                true
-            }
+        .filter(|p| p.matches_filter(filter.as_deref()))
+        .filter(|p| !args.media_only || p.is_media())
+        .filter(|p| match &args.domains {
+            Some(domains) => p.is_from_domain(domains),
+            None => true,
+        })
+        .filter(|p| match &args.flairs {
+            Some(flairs) => p.has_flair(flairs, config.flair_match),
+            None => true,
+        })
+        .filter(|p| match args.min_comments {
+            Some(min_comments) => p.num_comments >= min_comments.into(),
+            None => true,
         })
         .collect::<Vec<_>>();
-    debug!("got {} post(s) for subreddit /r/{}", posts.len(), subreddit);
-    if !posts.is_empty() {
+    debug!(
+        "got {} post(s) for {}{}",
+        posts.len(),
+        args.kind.prefix(),
+        subreddit
+    );
+    if !posts.is_empty() && args.digest {
+        let name = format!("{}{}", args.kind.prefix(), subreddit);
+        let thread_id = DeliveryOptions::from(&args).thread_id();
+        handle_post::send_post_digest(&config, &db, tg, chat_id, thread_id, &name, &posts).await?;
+    } else if !posts.is_empty() && args.group_media {
+        let options = DeliveryOptions::from(&args);
+        handle_post::process_posts_grouped(&db, chat_id, &posts, &config, tg, &options).await?;
+    } else if !posts.is_empty() {
         for post in posts {
-            process_post(&db, chat_id, &post, &config, tg).await?;
+            process_post(&db, chat_id, &post, &config, tg, &(&args).into()).await?;
         }
     } else {
         tg.send_message(message.chat.id, "No posts found").await?;
@@ -348,24 +845,376 @@ async fn handle_get_command(
     Ok(())
 }
 
+/// Sends the top posts of all time as a digest. Unlike /get, the posts are not marked as seen, so
+/// subscriptions still send them if they reach the top again.
+async fn handle_top_all_command(
+    config: &config::Config,
+    db: &db::Database,
+    message: &Message,
+    tg: &Bot,
+    name: &str,
+    count: u32,
+) -> Result<()> {
+    let (kind, subreddit) = parse_subscription_name(name);
+    let count = if count > config.max_get_limit {
+        tg.send_message(message.chat.id, format_limit_clamped(count, config))
+            .await?;
+        config.max_get_limit
+    } else {
+        count
+    };
+    let time = reddit::TopPostsTimePeriod::All;
+    let posts = match reddit::get_top_posts(kind, &subreddit, count, &time).await {
+        Ok(posts) => posts,
+        Err(
+            err @ (reddit::TopPostsError::Forbidden
+            | reddit::TopPostsError::NotFound
+            | reddit::TopPostsError::RateLimited),
+        ) => {
+            tg.send_message(message.chat.id, format!("Could not get posts: {err}"))
+                .await?;
+            return Ok(());
+        }
+        Err(err) => return Err(err).context("failed to get posts"),
+    };
+    if posts.is_empty() {
+        tg.send_message(message.chat.id, "No posts found").await?;
+        return Ok(());
+    }
+    let name = format!("{}{subreddit}", kind.prefix());
+    let thread_id = message.thread_id.filter(|_| message.is_topic_message);
+    handle_post::send_post_digest(config, db, tg, message.chat.id.0, thread_id, &name, &posts).await
+}
+
+/// Sends recently seen posts of a subreddit again, fetching them anew to get fresh media urls. The
+/// seen state of the posts is not changed.
+async fn handle_resend_command(
+    db: db::Database,
+    config: &config::Config,
+    message: &Message,
+    tg: &Bot,
+    subreddit: &str,
+    count: u32,
+) -> Result<()> {
+    let chat_id = message.chat.id.0;
+    let (kind, subreddit) = parse_subscription_name(subreddit);
+    if kind == SubscriptionKind::User {
+        // Posts are recorded under the subreddit they were posted to, not the user
+        tg.send_message(
+            message.chat.id,
+            "Resending is only supported for subreddits",
+        )
+        .await?;
+        return Ok(());
+    }
+    let count = if count > config.max_get_limit {
+        tg.send_message(message.chat.id, format_limit_clamped(count, config))
+            .await?;
+        config.max_get_limit
+    } else {
+        count
+    };
+
+    let post_ids = db.get_recent_post_ids(chat_id, &subreddit, count)?;
+    if post_ids.is_empty() {
+        tg.send_message(message.chat.id, format!("No posts seen from r/{subreddit}"))
+            .await?;
+        return Ok(());
+    }
+
+    let options = db
+        .get_subscriptions_for_chat(chat_id)?
+        .iter()
+        .find(|sub| {
+            sub.kind == SubscriptionKind::Subreddit
+                && sub.subreddit.eq_ignore_ascii_case(&subreddit)
+        })
+        .map(DeliveryOptions::from)
+        .unwrap_or_default();
+    let options = DeliveryOptions {
+        show_repost_buttons: db.get_show_repost_buttons(chat_id)?,
+        ..options
+    };
+    // Oldest first, so that the posts end up in the order they were originally sent
+    for post_id in post_ids.iter().rev() {
+        match reddit::get_link(post_id).await {
+            Ok(post) => {
+                if let Err(err) = handle_new_post(config, tg, chat_id, &post, &options, None).await
+                {
+                    error!("failed to resend post {post_id}: {err:?}");
+                }
+            }
+            // Deleted posts no longer resolve, but a link to them may still be useful
+            Err(err) => {
+                let Some(permalink) = db.get_post_permalink(chat_id, post_id)? else {
+                    warn!("could not get post {post_id} to resend, skipping it: {err:?}");
+                    continue;
+                };
+                warn!("could not get post {post_id} to resend, sending a link to it: {err:?}");
+                let url =
+                    reddit::format_url_from_path(&permalink, config.links_base_url.as_deref());
+                tg.send_message(message.chat.id, format!("Could not get post {url}"))
+                    .await?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Checks that the bot is present in the channel and is allowed to post to it. Returns the reason
+/// to show to the user if it's not.
+async fn check_repost_channel(tg: &Bot, channel_id: ChatId) -> Result<(), String> {
+    let me = tg.get_me().await.map_err(|err| err.to_string())?;
+    let member = tg.get_chat_member(channel_id, me.id).await.map_err(|err| {
+        warn!("could not get bot membership in channel {channel_id}: {err:?}");
+        "bot is not a member of that channel".to_string()
+    })?;
+
+    if !member.kind.is_privileged() {
+        return Err("bot is not an admin of that channel".to_string());
+    }
+    if !member.kind.can_post_messages() {
+        return Err("bot is not allowed to post messages in that channel".to_string());
+    }
+
+    Ok(())
+}
+
+/// Reply to show instead of subscribing if the chat already has the maximum number of
+/// subscriptions. Changing an existing subscription is always allowed, and admins are not limited.
+fn subscription_limit_error(
+    db: &db::Database,
+    config: &config::Config,
+    chat_id: i64,
+    kind: SubscriptionKind,
+    name: &str,
+    is_admin: bool,
+) -> Result<Option<String>> {
+    let Some(max) = config.max_subscriptions_per_chat.filter(|_| !is_admin) else {
+        return Ok(None);
+    };
+    let is_existing = db
+        .get_subscriptions_for_chat(chat_id)?
+        .iter()
+        .any(|sub| sub.kind == kind && sub.subreddit.eq_ignore_ascii_case(name));
+    if is_existing || db.count_subscriptions(chat_id)? < max {
+        return Ok(None);
+    }
+    Ok(Some(format!(
+        "Could not subscribe: this chat already has the maximum of {max} subscriptions"
+    )))
+}
+
+/// Finds existing subreddits with names similar to one that doesn't exist. Failing to search only
+/// means there are no suggestions.
+async fn suggest_subreddits(
+    name: &str,
+    config: &config::Config,
+) -> Vec<reddit::SubredditSearchResult> {
+    if config.subreddit_suggestions == 0 {
+        return vec![];
+    }
+    match reddit::search_subreddits(name, config.subreddit_suggestions).await {
+        Ok(results) => results,
+        Err(err) => {
+            warn!("failed to search subreddits for {name}: {err:?}");
+            vec![]
+        }
+    }
+}
+
+/// Parses the arguments of the /sub message a subreddit suggestion replied to, e.g.
+/// `/sub pcis limit=2`.
+fn parse_sub_message_args(text: &str) -> Option<SubscriptionArgs> {
+    let (command, rest) = text.split_once(char::is_whitespace)?;
+    if !command.starts_with('/') {
+        return None;
+    }
+    parse_subscribe_message(rest.to_string())
+        .ok()
+        .map(|(args,)| args)
+}
+
+/// Subscribes to a subreddit suggested when /sub was given one that doesn't exist, with the other
+/// arguments of the original /sub message.
+async fn handle_subscribe_callback(
+    db: &db::Database,
+    config: &config::Config,
+    tg: &Bot,
+    user: &User,
+    msg: &MaybeInaccessibleMessage,
+    subreddit: String,
+) -> Result<()> {
+    let chat_id = msg.chat().id;
+    let original_args = msg
+        .regular_message()
+        .and_then(|msg| msg.reply_to_message())
+        .and_then(|msg| msg.text())
+        .and_then(parse_sub_message_args);
+    let args = SubscriptionArgs {
+        kind: SubscriptionKind::Subreddit,
+        subreddit,
+        ..original_args.unwrap_or_default()
+    };
+
+    let is_admin = config.is_admin(user.id.0);
+    if let Some(reply) =
+        subscription_limit_error(db, config, chat_id.0, args.kind, &args.subreddit, is_admin)?
+    {
+        tg.send_message(chat_id, reply).await?;
+        return Ok(());
+    }
+    let reply = subscribe_subreddit(db, config, chat_id.0, &args).await?;
+    // Editing the suggestions also removes their buttons
+    tg.edit_message_text(chat_id, msg.id(), reply).await?;
+    Ok(())
+}
+
+/// Subscribes the chat to a subreddit, with its name spelled the way Reddit does. Returns the reply
+/// to show. Errors with `SubredditAboutError::NoSuchSubreddit` if the subreddit doesn't exist.
+async fn subscribe_subreddit(
+    db: &db::Database,
+    config: &config::Config,
+    chat_id: i64,
+    args: &SubscriptionArgs,
+) -> Result<String> {
+    let mut args = args.clone();
+    let clamp_note = clamp_limit(&mut args, config);
+    let about = reddit::get_subreddit_about(&args.subreddit)
+        .await
+        .context("Couldn't download about.json for subreddit")?;
+    args.subreddit = about.display_name;
+    db.subscribe(chat_id, &args)?;
+    info!("subscribed in chat id {chat_id} with {args:#?};");
+    let reply = [
+        Some(format!("Subscribed to r/{}", args.subreddit)),
+        clamp_note,
+    ]
+    .into_iter()
+    .flatten()
+    .join("\n");
+    Ok(reply)
+}
+
+fn format_limit_clamped(limit: u32, config: &config::Config) -> String {
+    format!(
+        "Limit {limit} is over the maximum of {max}, using {max} instead",
+        max = config.max_get_limit
+    )
+}
+
+/// Clamps the limit of a subscription to `max_get_limit`. Returns a note to show to the user if
+/// the limit was clamped.
+fn clamp_limit(args: &mut SubscriptionArgs, config: &config::Config) -> Option<String> {
+    let limit = args.limit.filter(|limit| *limit > config.max_get_limit)?;
+    args.limit = Some(config.max_get_limit);
+    Some(format_limit_clamped(limit, config))
+}
+
+/// Parses a post id from a link to a post, e.g. `https://reddit.com/r/rust/comments/v6nu75/...`,
+/// or a plain post id. Anything not shaped like Reddit's base-36 ids is rejected.
+fn parse_post_id(input: &str) -> Option<&str> {
+    lazy_static! {
+        static ref POST_LINK_RE: Regex =
+            Regex::new(r"^https?://[^\s/]+/(?:\S*/)?comments/([a-z0-9]{5,10})(?:[/?#]\S*)?$")
+                .unwrap();
+        static ref POST_ID_RE: Regex = Regex::new(r"^(?:t3_)?([a-z0-9]{5,10})$").unwrap();
+    }
+
+    let input = input.trim();
+    POST_LINK_RE
+        .captures(input)
+        .or_else(|| POST_ID_RE.captures(input))
+        .and_then(|caps| caps.get(1))
+        .map(|m| m.as_str())
+}
+
+/// Parses quiet hours given as `HH:MM-HH:MM`.
+fn parse_quiet_hours(input: &str) -> Result<QuietHours, String> {
+    let parse_time = |time: &str| {
+        NaiveTime::parse_from_str(time.trim(), "%H:%M")
+            .map_err(|_| format!("Invalid time {:?}, expected HH:MM", time.trim()))
+    };
+    let (start, end) = input
+        .split_once('-')
+        .ok_or_else(|| "Expected quiet hours as HH:MM-HH:MM, e.g. 23:00-07:00".to_string())?;
+    let quiet_hours = QuietHours {
+        start: parse_time(start)?,
+        end: parse_time(end)?,
+    };
+    if quiet_hours.start == quiet_hours.end {
+        return Err("Quiet hours must not start and end at the same time".to_string());
+    }
+
+    Ok(quiet_hours)
+}
+
+/// Parses an IANA time zone name, e.g. `Europe/Berlin`.
+fn parse_timezone(input: &str) -> Result<Tz, String> {
+    input.trim().parse().map_err(|_| {
+        format!("Invalid time zone {input:?}, expected a name such as Europe/Berlin or America/New_York")
+    })
+}
+
+/// Parses a subreddit (`rust`, `r/rust`) or a user (`u/spez`, `user/spez`) from a subscription
+/// name.
+fn parse_subscription_name(name: &str) -> (SubscriptionKind, String) {
+    match name.trim_start_matches('/').split_once('/') {
+        Some(("u" | "user", username)) => (
+            SubscriptionKind::User,
+            username.trim_end_matches('/').to_string(),
+        ),
+        _ => (
+            SubscriptionKind::Subreddit,
+            name.replace("/r/", "").replace("r/", ""),
+        ),
+    }
+}
+
 fn parse_subscribe_message(input: String) -> Result<(SubscriptionArgs,), ParseError> {
     lazy_static! {
         static ref SUBREDDIT_RE: Regex = Regex::new(r"^[^\s]+").unwrap();
         static ref LIMIT_RE: Regex = Regex::new(r"\blimit=(\d+)\b").unwrap();
         static ref TIME_RE: Regex = Regex::new(r"\btime=(\w+)\b").unwrap();
-        static ref FILTER_RE: Regex = Regex::new(r"\bfilter=(\w+)\b").unwrap();
+        static ref COMMENTS_RE: Regex = Regex::new(r"\bcomments=(\d+)\b").unwrap();
+        static ref MIN_COMMENTS_RE: Regex = Regex::new(r"\bmin_comments=(\d+)\b").unwrap();
+        static ref COMMENT_SORT_RE: Regex = Regex::new(r"\bcomment_sort=(\w+)\b").unwrap();
+        static ref CAPTION_RE: Regex = Regex::new(r"\bcaption=(\w+)\b").unwrap();
+        static ref LINK_PREVIEW_RE: Regex = Regex::new(r"\blink_preview=(\w+)\b").unwrap();
+        static ref TOPIC_RE: Regex = Regex::new(r"\btopic=(\d+)\b").unwrap();
+        static ref SKIP_STICKIED_RE: Regex = Regex::new(r"\bskip_stickied=(\w+)\b").unwrap();
+        static ref FILTER_RE: Regex = Regex::new(r"\bfilter=([\w,]+)").unwrap();
+        static ref DOMAIN_RE: Regex = Regex::new(r"\bdomain=([\w.,-]+)").unwrap();
+        static ref TEMPLATE_RE: Regex = Regex::new(r#"\btemplate="([^"]*)""#).unwrap();
+        static ref DIGEST_RE: Regex = Regex::new(r"(?:^|\s)digest(?:\s|$)").unwrap();
+        static ref SELFTEXT_RE: Regex = Regex::new(r"(?:^|\s)selftext(?:\s|$)").unwrap();
+        static ref ONLY_NEWER_THAN_SUB_RE: Regex =
+            Regex::new(r"(?:^|\s)only_newer_than_sub(?:\s|$)").unwrap();
+        static ref GROUP_MEDIA_RE: Regex = Regex::new(r"(?:^|\s)group_media(?:\s|$)").unwrap();
+        static ref PHOTO_AS_DOCUMENT_RE: Regex =
+            Regex::new(r"(?:^|\s)photo_as_document(?:\s|$)").unwrap();
+        static ref ATTACH_SOURCE_RE: Regex = Regex::new(r"(?:^|\s)attach_source(?:\s|$)").unwrap();
+        static ref MEDIA_ONLY_RE: Regex = Regex::new(r"(?:^|\s)media_only(?:\s|$)").unwrap();
+        static ref EXTENDED_INFO_RE: Regex = Regex::new(r"(?:^|\s)extended_info(?:\s|$)").unwrap();
+        static ref FLAIR_RE: Regex = Regex::new(r#"\bflair=(?:"([^"]*)"|([^\s"]+))"#).unwrap();
     }
 
     let subreddit_match = SUBREDDIT_RE
         .find(&input)
         .ok_or_else(|| ParseError::Custom("No subreddit given".into()))?;
-    let subreddit = subreddit_match
-        .as_str()
-        .to_string()
-        .replace("/r/", "")
-        .replace("r/", "");
+    let (kind, subreddit) = parse_subscription_name(subreddit_match.as_str());
     let rest = &input[(subreddit_match.end())..];
 
+    // Template is free-form text, so it's taken out before matching the rest of the options
+    let template = TEMPLATE_RE
+        .captures(rest)
+        .and_then(|caps| caps.get(1))
+        .map(|m| m.as_str().replace("\\n", "\n"));
+    if let Some(template) = &template {
+        messages::validate_template(template).map_err(|e| ParseError::Custom(e.into()))?;
+    }
+    let rest = &TEMPLATE_RE.replace(rest, "");
+
     let limit: Option<u32> = LIMIT_RE
         .captures(rest)
         .and_then(|caps| caps.get(1))
@@ -382,22 +1231,138 @@ fn parse_subscribe_message(input: String) -> Result<(SubscriptionArgs,), ParseEr
             None => Ok(None),
         })?;
 
-    let filter = Ok(FILTER_RE.captures(rest))
+    let filter = FILTER_RE
+        .captures(rest)
+        .and_then(|caps| caps.get(1))
+        .map(|m| {
+            m.as_str()
+                .split(',')
+                .filter(|t| !t.is_empty())
+                .map(|t| t.parse::<PostType>())
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|e| ParseError::IncorrectFormat(e.into()))
+        })
+        .transpose()?
+        .map(|types| types.into_iter().unique().collect::<Vec<_>>())
+        .filter(|types| !types.is_empty());
+
+    let domains = DOMAIN_RE
+        .captures(rest)
+        .and_then(|caps| caps.get(1))
+        .map(|m| {
+            m.as_str()
+                .split(',')
+                .filter(|d| !d.is_empty())
+                .map(str::to_lowercase)
+                .collect::<Vec<_>>()
+        })
+        .filter(|d| !d.is_empty());
+
+    // Flairs can contain spaces, in which case they need to be quoted
+    let flairs = FLAIR_RE
+        .captures(rest)
+        .and_then(|caps| caps.get(1).or_else(|| caps.get(2)))
+        .map(|m| {
+            m.as_str()
+                .split(',')
+                .map(|f| f.trim().to_lowercase())
+                .filter(|f| !f.is_empty())
+                .collect::<Vec<_>>()
+        })
+        .filter(|f| !f.is_empty());
+
+    let comments: Option<u32> = COMMENTS_RE
+        .captures(rest)
+        .and_then(|caps| caps.get(1))
+        .and_then(|m| m.as_str().parse().ok());
+
+    let min_comments: Option<u32> = MIN_COMMENTS_RE
+        .captures(rest)
+        .and_then(|caps| caps.get(1))
+        .and_then(|m| m.as_str().parse().ok());
+
+    let comment_sort = Ok(COMMENT_SORT_RE.captures(rest))
+        .map(|o| o.and_then(|caps| caps.get(1)))
+        .and_then(|o| match o {
+            Some(m) => m
+                .as_str()
+                .parse::<reddit::CommentSort>()
+                .map(Some)
+                .map_err(|e| ParseError::IncorrectFormat(e.into())),
+            None => Ok(None),
+        })?;
+
+    let caption = Ok(CAPTION_RE.captures(rest))
+        .map(|o| o.and_then(|caps| caps.get(1)))
+        .and_then(|o| match o {
+            Some(m) => m
+                .as_str()
+                .parse::<CaptionMode>()
+                .map(Some)
+                .map_err(|e| ParseError::IncorrectFormat(e.into())),
+            None => Ok(None),
+        })?;
+
+    let link_preview = Ok(LINK_PREVIEW_RE.captures(rest))
         .map(|o| o.and_then(|caps| caps.get(1)))
         .and_then(|o| match o {
             Some(m) => m
                 .as_str()
-                .parse::<PostType>()
+                .parse::<LinkPreview>()
                 .map(Some)
                 .map_err(|e| ParseError::IncorrectFormat(e.into())),
             None => Ok(None),
         })?;
 
+    let topic: Option<i32> = TOPIC_RE
+        .captures(rest)
+        .and_then(|caps| caps.get(1))
+        .and_then(|m| m.as_str().parse().ok());
+
+    let skip_stickied = Ok(SKIP_STICKIED_RE.captures(rest))
+        .map(|o| o.and_then(|caps| caps.get(1)))
+        .and_then(|o| match o {
+            Some(m) => m
+                .as_str()
+                .parse::<bool>()
+                .map(Some)
+                .map_err(|e| ParseError::IncorrectFormat(e.into())),
+            None => Ok(None),
+        })?;
+
+    let digest = DIGEST_RE.is_match(rest);
+    let selftext = SELFTEXT_RE.is_match(rest);
+    let only_newer_than_sub = ONLY_NEWER_THAN_SUB_RE.is_match(rest);
+    let group_media = GROUP_MEDIA_RE.is_match(rest);
+    let photo_as_document = PHOTO_AS_DOCUMENT_RE.is_match(rest);
+    let attach_source = ATTACH_SOURCE_RE.is_match(rest);
+    let media_only = MEDIA_ONLY_RE.is_match(rest);
+    let extended_info = EXTENDED_INFO_RE.is_match(rest);
+
     let args = SubscriptionArgs {
+        kind,
         subreddit,
         limit,
         time,
         filter,
+        domains,
+        flairs,
+        template,
+        digest,
+        selftext,
+        photo_as_document,
+        caption,
+        comments,
+        comment_sort,
+        topic,
+        skip_stickied,
+        only_newer_than_sub,
+        min_comments,
+        link_preview,
+        attach_source,
+        media_only,
+        extended_info,
+        group_media,
     };
 
     Ok((args,))
@@ -412,12 +1377,22 @@ async fn callback_handler(
 
     let msg = q.message.expect("Message must exist");
     let data = q.data.expect("Data expected");
-    let data: ButtonCallbackData = serde_json::from_str(&data)?;
-    let caption = if data.copy_caption {
+    if let Ok(data) = serde_json::from_str::<SubscribeCallbackData>(&data) {
+        return handle_subscribe_callback(&db, &config, &tg, &q.from, &msg, data.subreddit).await;
+    }
+    let data = ButtonCallbackData::decode(&data)?;
+    if data.post_id == TEST_REPOST_POST_ID {
+        return handle_test_repost(db, &config, &tg, msg.chat().id, msg.id(), data.copy_caption)
+            .await;
+    }
+    let title = if data.copy_caption {
         Some(db.get_post_title(msg.chat().id.0, &data.post_id)?)
     } else {
         None
     };
+    // Only the copy in the repost channel gets the suffix
+    let caption =
+        messages::format_repost_caption(title.as_deref(), config.repost_caption_suffix.as_deref());
     let msg_id = if let Some(reply_id) = msg
         .regular_message()
         .and_then(|x| x.reply_to_message())
@@ -428,8 +1403,8 @@ async fn callback_handler(
         msg.id()
     };
     if data.is_gallery {
-        let tg_file_ids = db.get_telegram_files_for_post(&data.post_id, msg.chat().id.0)?;
-        handle_repost_gallery(db, msg.chat().id, &tg, tg_file_ids, caption)
+        let tg_files = db.get_telegram_files_for_post(&data.post_id, msg.chat().id.0)?;
+        handle_repost_gallery(db, &config, msg.chat().id, &tg, tg_files, caption)
             .await
             .context("Failed handling gallery repost")?;
     } else {
@@ -445,6 +1420,84 @@ async fn callback_handler(
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_command_aliases() {
+        let Ok(Command::Sub(args)) = Command::parse("/s pics limit=2", "bot") else {
+            panic!("/s should parse as /sub");
+        };
+        assert_eq!(args.subreddit, "pics");
+        assert_eq!(args.limit, Some(2));
+        assert!(matches!(
+            Command::parse("/u pics", "bot"),
+            Ok(Command::Unsub(subreddit)) if subreddit == "pics"
+        ));
+        assert!(matches!(
+            Command::parse("/ls", "bot"),
+            Ok(Command::ListSubs)
+        ));
+        assert!(matches!(
+            Command::parse("/g pics", "bot"),
+            Ok(Command::Get(_))
+        ));
+        assert!(Command::descriptions().to_string().contains("/sub, /s"));
+    }
+
+    #[test]
+    fn test_subscription_limit_error() {
+        let config = config::Config {
+            max_subscriptions_per_chat: Some(1),
+            ..Default::default()
+        };
+        let mut db = db::Database::open(&config).unwrap();
+        db.migrate().unwrap();
+        let limit_error = |name, is_admin| {
+            subscription_limit_error(&db, &config, 1, SubscriptionKind::Subreddit, name, is_admin)
+                .unwrap()
+                .is_some()
+        };
+        assert!(!limit_error("pics", false));
+        db.subscribe(
+            1,
+            &SubscriptionArgs {
+                subreddit: "pics".into(),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(db.count_subscriptions(1).unwrap(), 1);
+        assert!(limit_error("aww", false));
+        assert!(!limit_error("aww", true));
+        // Changing an existing subscription doesn't add one
+        assert!(!limit_error("Pics", false));
+        // A user with the same name is a different subscription
+        assert!(
+            subscription_limit_error(&db, &config, 1, SubscriptionKind::User, "pics", false)
+                .unwrap()
+                .is_some()
+        );
+    }
+
+    #[test]
+    fn test_parse_move_chat_command() {
+        assert!(matches!(
+            Command::parse("/movechat -100123 -100456", "bot"),
+            Ok(Command::MoveChat {
+                from: -100123,
+                to: -100456
+            })
+        ));
+        assert!(Command::parse("/movechat -100123", "bot").is_err());
+    }
+
+    #[test]
+    fn test_parse_top_all_command() {
+        assert!(matches!(
+            Command::parse("/topall pics 10", "bot"),
+            Ok(Command::TopAll { subreddit, count: 10 }) if subreddit == "pics"
+        ));
+        assert!(Command::parse("/topall pics", "bot").is_err());
+    }
+
     #[test]
     fn test_parse_subscribe_message_only_subreddit() {
         let args = parse_subscribe_message("AnimalsBeingJerks".to_string()).unwrap();
@@ -452,9 +1505,7 @@ mod tests {
             args.0,
             SubscriptionArgs {
                 subreddit: "AnimalsBeingJerks".to_string(),
-                limit: None,
-                time: None,
-                filter: None,
+                ..Default::default()
             },
         )
     }
@@ -466,9 +1517,7 @@ mod tests {
             args.0,
             SubscriptionArgs {
                 subreddit: "AnimalsBeingJerks".to_string(),
-                limit: None,
-                time: None,
-                filter: None,
+                ..Default::default()
             },
         );
 
@@ -477,9 +1526,7 @@ mod tests {
             args.0,
             SubscriptionArgs {
                 subreddit: "AnimalsBeingJerks".to_string(),
-                limit: None,
-                time: None,
-                filter: None,
+                ..Default::default()
             },
         )
     }
@@ -495,8 +1542,291 @@ mod tests {
                 subreddit: "AnimalsBeingJerks".to_string(),
                 limit: Some(5),
                 time: Some(TopPostsTimePeriod::Week),
-                filter: Some(PostType::Video),
+                filter: Some(vec![PostType::Video]),
+                ..Default::default()
             },
         )
     }
+
+    #[test]
+    fn test_parse_subscribe_message_user() {
+        for input in ["u/spez", "/u/spez", "user/spez", "/user/spez/"] {
+            let args = parse_subscribe_message(format!("{input} limit=5")).unwrap();
+            assert_eq!(args.0.kind, SubscriptionKind::User);
+            assert_eq!(args.0.subreddit, "spez");
+            assert_eq!(args.0.limit, Some(5));
+        }
+
+        let args = parse_subscribe_message("r/user".to_string()).unwrap();
+        assert_eq!(args.0.kind, SubscriptionKind::Subreddit);
+        assert_eq!(args.0.subreddit, "user");
+    }
+
+    #[test]
+    fn test_parse_subscribe_message_flairs() {
+        let args = parse_subscribe_message("worldnews flair=News,Analysis".to_string()).unwrap();
+        assert_eq!(
+            args.0.flairs,
+            Some(vec!["news".to_string(), "analysis".to_string()])
+        );
+
+        let args = parse_subscribe_message(
+            r#"worldnews flair="Breaking News, Analysis" limit=2"#.to_string(),
+        )
+        .unwrap();
+        assert_eq!(
+            args.0.flairs,
+            Some(vec!["breaking news".to_string(), "analysis".to_string()])
+        );
+        assert_eq!(args.0.limit, Some(2));
+    }
+
+    #[test]
+    fn test_parse_subscribe_message_digest() {
+        let args = parse_subscribe_message("rust digest limit=10".to_string()).unwrap();
+        assert!(args.0.digest);
+        assert_eq!(args.0.limit, Some(10));
+
+        let args = parse_subscribe_message("rust domain=digest.com".to_string()).unwrap();
+        assert!(!args.0.digest);
+
+        let args = parse_subscribe_message("rust selftext digest".to_string()).unwrap();
+        assert!(args.0.selftext);
+        assert!(!args.0.photo_as_document);
+        assert!(args.0.digest);
+
+        let args = parse_subscribe_message("pics photo_as_document".to_string()).unwrap();
+        assert!(args.0.photo_as_document);
+    }
+
+    #[test]
+    fn test_clamp_limit() {
+        let config = config::Config {
+            max_get_limit: 10,
+            ..Default::default()
+        };
+        let mut args = parse_subscribe_message("rust limit=100".to_string())
+            .unwrap()
+            .0;
+        assert!(clamp_limit(&mut args, &config).is_some());
+        assert_eq!(args.limit, Some(10));
+
+        let mut args = parse_subscribe_message("rust limit=5".to_string())
+            .unwrap()
+            .0;
+        assert_eq!(clamp_limit(&mut args, &config), None);
+        assert_eq!(args.limit, Some(5));
+    }
+
+    #[test]
+    fn test_parse_post_id() {
+        assert_eq!(
+            parse_post_id("https://www.reddit.com/r/rust/comments/v6nu75/title/"),
+            Some("v6nu75")
+        );
+        assert_eq!(parse_post_id(" v6nu75 "), Some("v6nu75"));
+        assert_eq!(parse_post_id("t3_v6nu75"), Some("v6nu75"));
+        assert_eq!(
+            parse_post_id("https://old.reddit.com/comments/v6nu75"),
+            Some("v6nu75")
+        );
+        assert_eq!(parse_post_id("https://example.com/"), None);
+        assert_eq!(parse_post_id(""), None);
+        assert_eq!(parse_post_id("abc"), None);
+        assert_eq!(parse_post_id("not_a_post"), None);
+        assert_eq!(parse_post_id("Hello!"), None);
+        assert_eq!(parse_post_id("see comments/v6nu75"), None);
+    }
+
+    #[test]
+    fn test_parse_quiet_hours() {
+        assert_eq!(
+            parse_quiet_hours("23:00-07:30"),
+            Ok(QuietHours {
+                start: NaiveTime::from_hms_opt(23, 0, 0).unwrap(),
+                end: NaiveTime::from_hms_opt(7, 30, 0).unwrap(),
+            })
+        );
+        assert!(parse_quiet_hours(" 9:00 - 17:00 ").is_ok());
+        assert!(parse_quiet_hours("23:00").is_err());
+        assert!(parse_quiet_hours("25:00-07:00").is_err());
+        assert!(parse_quiet_hours("07:00-07:00").is_err());
+    }
+
+    #[test]
+    fn test_parse_timezone() {
+        assert_eq!(parse_timezone("Europe/Berlin"), Ok(Tz::Europe__Berlin));
+        assert_eq!(parse_timezone(" Asia/Kolkata "), Ok(Tz::Asia__Kolkata));
+        assert!(parse_timezone("+02:00").is_err());
+        assert!(parse_timezone("Mars/Olympus_Mons").is_err());
+    }
+
+    #[test]
+    fn test_parse_subscribe_message_comments() {
+        let args =
+            parse_subscribe_message("AskReddit comments=3 comment_sort=best".to_string()).unwrap();
+        assert_eq!(args.0.comments, Some(3));
+        assert_eq!(args.0.comment_sort, Some(reddit::CommentSort::Best));
+
+        assert!(parse_subscribe_message("AskReddit comment_sort=hot".to_string()).is_err());
+    }
+
+    #[test]
+    fn test_parse_subscribe_message_group_media() {
+        let args = parse_subscribe_message("pics limit=5".to_string()).unwrap();
+        assert!(!args.0.group_media);
+        let args = parse_subscribe_message("pics limit=5 group_media".to_string()).unwrap();
+        assert!(args.0.group_media);
+    }
+
+    #[test]
+    fn test_parse_subscribe_message_skip_stickied() {
+        let args = parse_subscribe_message("pics".to_string()).unwrap();
+        assert_eq!(args.0.skip_stickied, None);
+        let args = parse_subscribe_message("pics skip_stickied=false".to_string()).unwrap();
+        assert_eq!(args.0.skip_stickied, Some(false));
+        assert!(parse_subscribe_message("pics skip_stickied=maybe".to_string()).is_err());
+    }
+
+    #[test]
+    fn test_parse_subscribe_message_only_newer_than_sub() {
+        let args = parse_subscribe_message("pics".to_string()).unwrap();
+        assert!(!args.0.only_newer_than_sub);
+        let args = parse_subscribe_message("pics only_newer_than_sub limit=3".to_string()).unwrap();
+        assert!(args.0.only_newer_than_sub);
+        assert_eq!(args.0.limit, Some(3));
+    }
+
+    #[test]
+    fn test_parse_subscribe_message_filter() {
+        let args = parse_subscribe_message("pics filter=gallery".to_string()).unwrap();
+        assert_eq!(args.0.filter, Some(vec![PostType::Gallery]));
+        let args = parse_subscribe_message("pics filter=image".to_string()).unwrap();
+        assert_eq!(args.0.filter, Some(vec![PostType::Image]));
+        let args = parse_subscribe_message("pics filter=video,gallery,video".to_string()).unwrap();
+        assert_eq!(
+            args.0.filter,
+            Some(vec![PostType::Video, PostType::Gallery])
+        );
+        assert!(parse_subscribe_message("pics filter=images".to_string()).is_err());
+        assert!(parse_subscribe_message("pics filter=video,images".to_string()).is_err());
+    }
+
+    #[test]
+    fn test_parse_subscribe_message_min_comments() {
+        let args =
+            parse_subscribe_message("AskReddit comments=3 min_comments=50".to_string()).unwrap();
+        assert_eq!(args.0.comments, Some(3));
+        assert_eq!(args.0.min_comments, Some(50));
+        let args = parse_subscribe_message("AskReddit comments=3".to_string()).unwrap();
+        assert_eq!(args.0.min_comments, None);
+    }
+
+    #[test]
+    fn test_parse_subscribe_message_caption() {
+        let args = parse_subscribe_message("pics caption=title".to_string()).unwrap();
+        assert_eq!(args.0.caption, Some(CaptionMode::Title));
+        let args = parse_subscribe_message("pics caption=none".to_string()).unwrap();
+        assert_eq!(args.0.caption, Some(CaptionMode::None));
+
+        assert!(parse_subscribe_message("pics caption=short".to_string()).is_err());
+    }
+
+    #[test]
+    fn test_parse_sub_message_args() {
+        let args = parse_sub_message_args("/sub pcis limit=2 digest").unwrap();
+        assert_eq!(args.subreddit, "pcis");
+        assert_eq!(args.limit, Some(2));
+        assert!(args.digest);
+        assert!(parse_sub_message_args("/s@tgreddit_bot pcis").is_some());
+        assert!(parse_sub_message_args("/sub").is_none());
+        assert!(parse_sub_message_args("pcis limit=2").is_none());
+    }
+
+    #[test]
+    fn test_parse_subscribe_message_attach_source() {
+        let args = parse_subscribe_message("videos attach_source".to_string()).unwrap();
+        assert!(args.0.attach_source);
+        let args = parse_subscribe_message("videos limit=2".to_string()).unwrap();
+        assert!(!args.0.attach_source);
+    }
+
+    #[test]
+    fn test_parse_subscribe_message_media_only() {
+        let args = parse_subscribe_message("pics media_only limit=2".to_string()).unwrap();
+        assert!(args.0.media_only);
+        let args = parse_subscribe_message("pics limit=2".to_string()).unwrap();
+        assert!(!args.0.media_only);
+    }
+
+    #[test]
+    fn test_parse_subscribe_message_extended_info() {
+        let args = parse_subscribe_message("pics extended_info limit=2".to_string()).unwrap();
+        assert!(args.0.extended_info);
+        let args = parse_subscribe_message("pics limit=2".to_string()).unwrap();
+        assert!(!args.0.extended_info);
+    }
+
+    #[test]
+    fn test_parse_subscribe_message_link_preview() {
+        let args = parse_subscribe_message("pics link_preview=small".to_string()).unwrap();
+        assert_eq!(args.0.link_preview, Some(LinkPreview::Small));
+        let args = parse_subscribe_message("pics link_preview=disabled".to_string()).unwrap();
+        assert_eq!(args.0.link_preview, Some(LinkPreview::Disabled));
+        let args = parse_subscribe_message("pics".to_string()).unwrap();
+        assert_eq!(args.0.link_preview, None);
+        assert!(parse_subscribe_message("pics link_preview=huge".to_string()).is_err());
+    }
+
+    #[test]
+    fn test_parse_subscribe_message_topic() {
+        let args = parse_subscribe_message("pics topic=42 limit=5".to_string()).unwrap();
+        assert_eq!(args.0.topic, Some(42));
+        assert_eq!(args.0.limit, Some(5));
+    }
+
+    #[test]
+    fn test_parse_subscribe_message_domains() {
+        let args = parse_subscribe_message("programming domain=GitHub.com,gitlab.com".to_string())
+            .unwrap();
+        assert_eq!(
+            args.0.domains,
+            Some(vec!["github.com".to_string(), "gitlab.com".to_string()])
+        );
+    }
+
+    #[test]
+    fn test_parse_subscribe_message_time_periods() {
+        for (arg, time) in [
+            ("hour", TopPostsTimePeriod::Hour),
+            ("day", TopPostsTimePeriod::Day),
+            ("week", TopPostsTimePeriod::Week),
+            ("month", TopPostsTimePeriod::Month),
+            ("year", TopPostsTimePeriod::Year),
+            ("all", TopPostsTimePeriod::All),
+        ] {
+            let args = parse_subscribe_message(format!("AnimalsBeingJerks time={arg}")).unwrap();
+            assert_eq!(args.0.time, Some(time));
+            assert_eq!(time.to_string(), arg);
+        }
+
+        assert!(parse_subscribe_message("AnimalsBeingJerks time=All".to_string()).is_err());
+    }
+
+    #[test]
+    fn test_parse_subscribe_message_template() {
+        let args = parse_subscribe_message(
+            r#"AnimalsBeingJerks template="{title}\n▲{score} limit=5" limit=3"#.to_string(),
+        )
+        .unwrap();
+        assert_eq!(
+            args.0.template,
+            Some("{title}\n▲{score} limit=5".to_string())
+        );
+        assert_eq!(args.0.limit, Some(3));
+
+        assert!(
+            parse_subscribe_message(r#"AnimalsBeingJerks template="{foo}""#.to_string()).is_err()
+        );
+    }
 }
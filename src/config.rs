@@ -1,23 +1,41 @@
-use log::error;
+use chrono_tz::Tz;
+use itertools::Itertools;
+use log::{error, info};
 use secrecy::SecretString;
 use serde::Deserialize;
-use std::{env, path::PathBuf};
+use std::{collections::BTreeSet, env, path::PathBuf, sync::Mutex};
+use strum_macros::Display;
 
 use crate::{
-    reddit::{PostType, TopPostsTimePeriod},
+    messages,
+    reddit::{CommentSort, PostType, TopPostsTimePeriod},
+    types::{CaptionMode, LinkPreview, Subscription},
     PKG_NAME,
 };
 
 const CONFIG_PATH_ENV: &str = "CONFIG_PATH";
 pub const DEFAULT_LIMIT: u32 = 1;
 pub const DEFAULT_TIME_PERIOD: TopPostsTimePeriod = TopPostsTimePeriod::Day;
+const DEFAULT_VIDEO_MAX_HEIGHT: u32 = 1080;
 
 #[derive(Deserialize, Debug, Default)]
 pub struct Config {
     pub authorized_user_ids: Vec<u64>,
+    #[serde(default)]
+    pub authorized_usernames: Vec<String>,
+    /// Ids of users authorized by `authorized_usernames`, resolved when they first use the bot
+    #[serde(skip)]
+    pub resolved_user_ids: Mutex<BTreeSet<u64>>,
+    #[serde(default)]
+    pub admin_user_ids: Vec<u64>,
     #[serde(default = "default_db_path")]
     pub db_path: PathBuf,
     pub telegram_bot_token: SecretString,
+    #[serde(default)]
+    pub telegram_mode: TelegramMode,
+    pub webhook_url: Option<String>,
+    #[serde(default = "default_webhook_port")]
+    pub webhook_port: u16,
     pub check_interval_secs: u64,
     #[serde(default = "default_skip_initial_send")]
     pub skip_initial_send: bool,
@@ -25,13 +43,358 @@ pub struct Config {
     pub default_limit: Option<u32>,
     pub default_time: Option<TopPostsTimePeriod>,
     pub default_filter: Option<PostType>,
+    pub default_template: Option<String>,
+    #[serde(default)]
+    pub dry_run: bool,
+    #[serde(default = "default_max_subscription_failures")]
+    pub max_subscription_failures: u32,
+    pub error_report_chat_id: Option<i64>,
+    pub startup_notify_chat_id: Option<i64>,
+    #[serde(default = "default_error_report_interval_secs")]
+    pub error_report_interval_secs: u64,
+    #[serde(default = "default_telegram_max_concurrent_sends")]
+    pub telegram_max_concurrent_sends: usize,
+    #[serde(default = "default_telegram_min_send_interval_ms")]
+    pub telegram_min_send_interval_ms: u64,
+    pub download_dir: Option<PathBuf>,
+    pub allowed_media_hosts: Option<Vec<String>>,
+    #[serde(default)]
+    pub blocked_media_hosts: Vec<String>,
+    pub video_cache_dir: Option<PathBuf>,
+    #[serde(default = "default_video_cache_max_bytes")]
+    pub video_cache_max_bytes: u64,
+    #[serde(default)]
+    pub convert_gifs: bool,
+    #[serde(default = "default_gif_max_bitrate_kbps")]
+    pub gif_max_bitrate_kbps: u32,
+    #[serde(default = "default_gif_max_width")]
+    pub gif_max_width: u32,
+    #[serde(default)]
+    pub transcode_oversize: bool,
+    #[serde(default = "default_show_repost_buttons")]
+    pub show_repost_buttons: bool,
+    #[serde(default)]
+    pub require_ytdlp: bool,
+    pub video_format: Option<String>,
+    pub video_max_height: Option<u32>,
+    #[serde(default)]
+    pub embed_subtitles: bool,
+    #[serde(default)]
+    pub subtitle_langs: Vec<String>,
+    #[serde(default)]
+    pub flair_match: FlairMatch,
+    #[serde(default = "default_skip_stickied")]
+    pub skip_stickied: bool,
+    #[serde(default = "default_respect_spoilers")]
+    pub respect_spoilers: bool,
+    pub sensitive_marker: Option<String>,
+    #[serde(default)]
+    pub sensitive_subreddits: Vec<String>,
+    pub timezone: Option<String>,
+    #[serde(default = "default_max_get_limit")]
+    pub max_get_limit: u32,
+    pub max_subscriptions_per_chat: Option<u32>,
+    #[serde(default = "default_subreddit_suggestions")]
+    pub subreddit_suggestions: u32,
+    #[serde(default)]
+    pub include_selftext: bool,
+    #[serde(default)]
+    pub photo_as_document: bool,
+    #[serde(default = "default_download_types")]
+    pub download_types: Vec<PostType>,
+    pub proxy_url: Option<String>,
+    pub post_delivered_webhook_url: Option<String>,
+    pub reddit_user_agent: Option<String>,
+    pub reddit_cookie: Option<SecretString>,
+    pub geo_filter: Option<String>,
+    #[serde(default)]
+    pub gallery_mode: GalleryMode,
+    #[serde(default)]
+    pub gallery_item_captions: bool,
+    #[serde(default)]
+    pub link_preview: LinkPreview,
+    #[serde(default)]
+    pub dedup_media_urls: bool,
+    #[serde(default)]
+    pub dedup_titles: bool,
+    #[serde(default = "default_dedup_titles_window_hours")]
+    pub dedup_titles_window_hours: u64,
+    #[serde(default = "default_dedup_titles_similarity")]
+    pub dedup_titles_similarity: f64,
+    #[serde(default)]
+    pub score_badges: bool,
+    #[serde(default)]
+    pub post_link_button: bool,
+    pub repost_caption_suffix: Option<String>,
+    #[serde(default = "default_score_badge_tiers")]
+    pub score_badge_tiers: Vec<ScoreBadgeTier>,
+}
+
+impl Config {
+    /// Whether the user can use the bot at all. Admins are always authorized.
+    pub fn is_authorized(&self, user_id: u64) -> bool {
+        self.authorized_user_ids.contains(&user_id)
+            || self.admin_user_ids.contains(&user_id)
+            || self
+                .resolved_user_ids
+                .lock()
+                .expect("No poison")
+                .contains(&user_id)
+    }
+
+    /// Like `is_authorized`, but also authorizes the user if their username is in
+    /// `authorized_usernames`, remembering their id from then on.
+    pub fn is_authorized_user(&self, user_id: u64, username: Option<&str>) -> bool {
+        if self.is_authorized(user_id) {
+            return true;
+        }
+        let Some(username) = username else {
+            return false;
+        };
+        let is_authorized_username = self.authorized_usernames.iter().any(|authorized| {
+            authorized
+                .trim_start_matches('@')
+                .eq_ignore_ascii_case(username)
+        });
+        if is_authorized_username {
+            info!("authorized user @{username} has id {user_id}");
+            self.resolved_user_ids
+                .lock()
+                .expect("No poison")
+                .insert(user_id);
+        }
+        is_authorized_username
+    }
+
+    /// Whether the user can use admin commands. Without any admins configured, every user in
+    /// `authorized_user_ids` is an admin. Users authorized by username never are, as usernames can
+    /// be taken over.
+    pub fn is_admin(&self, user_id: u64) -> bool {
+        if self.admin_user_ids.is_empty() {
+            self.authorized_user_ids.contains(&user_id)
+        } else {
+            self.admin_user_ids.contains(&user_id)
+        }
+    }
+
+    /// Whether media may be downloaded from the host of the url. Hosts are allowed if they match
+    /// `allowed_media_hosts`, or if it's not set, and don't match `blocked_media_hosts`.
+    pub fn is_media_host_allowed(&self, url: &str) -> bool {
+        is_media_host_allowed(
+            self.allowed_media_hosts.as_deref(),
+            &self.blocked_media_hosts,
+            url,
+        )
+    }
+
+    /// yt-dlp format selector for videos. `video_format` is used as is, otherwise the best video
+    /// no higher than `video_max_height` is picked.
+    pub fn video_format(&self) -> String {
+        match &self.video_format {
+            Some(format) => format.clone(),
+            None => format!(
+                "bv[height<={}]+ba/best",
+                self.video_max_height.unwrap_or(DEFAULT_VIDEO_MAX_HEIGHT)
+            ),
+        }
+    }
+
+    /// Time zone used for quiet hours and digests. Defaults to UTC.
+    pub fn tz(&self) -> Tz {
+        self.timezone
+            .as_deref()
+            .and_then(|timezone| timezone.parse().ok())
+            .unwrap_or(Tz::UTC)
+    }
+}
+
+/// See `Config::is_media_host_allowed`.
+pub fn is_media_host_allowed(allowed: Option<&[String]>, blocked: &[String], url: &str) -> bool {
+    let Some(host) = url::Url::parse(url)
+        .ok()
+        .and_then(|url| url.host_str().map(|host| host.to_lowercase()))
+    else {
+        return false;
+    };
+    let matches = |patterns: &[String]| {
+        patterns
+            .iter()
+            .any(|pattern| host_matches(&host, &pattern.to_lowercase()))
+    };
+    allowed.is_none_or(&matches) && !matches(blocked)
+}
+
+/// Matches a host to a pattern that is either a host, or a host prefixed with `*.` that also
+/// matches all of its subdomains.
+fn host_matches(host: &str, pattern: &str) -> bool {
+    match pattern.strip_prefix("*.") {
+        Some(domain) => {
+            host == domain
+                || host
+                    .strip_suffix(domain)
+                    .is_some_and(|subdomain| subdomain.ends_with('.'))
+        }
+        None => host == pattern,
+    }
+}
+
+/// How updates are received from Telegram.
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum TelegramMode {
+    /// By repeatedly asking Telegram for new updates.
+    #[default]
+    Polling,
+    /// By Telegram making requests to `webhook_url`, served on `webhook_port`.
+    Webhook,
+}
+
+/// How the items of a gallery are sent.
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum GalleryMode {
+    /// As a single album. Telegram may reorder or split mixed photos and videos.
+    #[default]
+    MediaGroup,
+    /// As separate messages, each replying to the previous one, which preserves the order.
+    ReplyChain,
+}
+
+/// How the flairs given to a subscription are compared to the flair of a post. Comparison is
+/// always case-insensitive.
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum FlairMatch {
+    #[default]
+    Exact,
+    Substring,
+}
+
+/// Badge shown in front of the title of posts with a score above the threshold.
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ScoreBadgeTier {
+    pub above: i64,
+    pub badge: String,
+}
+
+/// Where the value of a subscription setting comes from, in order of precedence.
+#[derive(Display, Debug, Clone, Copy, PartialEq, Eq)]
+#[strum(serialize_all = "lowercase")]
+pub enum SettingSource {
+    Subscription,
+    Config,
+    Default,
+}
+
+/// Value of a setting that applies to a subscription.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EffectiveSetting {
+    pub name: &'static str,
+    pub value: String,
+    pub source: SettingSource,
+}
+
+fn resolve<T: ToString>(
+    name: &'static str,
+    sub: Option<T>,
+    config: Option<T>,
+    default: T,
+) -> EffectiveSetting {
+    let (value, source) = match (sub, config) {
+        (Some(value), _) => (value, SettingSource::Subscription),
+        (None, Some(value)) => (value, SettingSource::Config),
+        (None, None) => (default, SettingSource::Default),
+    };
+    EffectiveSetting {
+        name,
+        value: value.to_string(),
+        source,
+    }
+}
+
+/// Resolves the settings of a subscription by falling back to the config and then to the built-in
+/// defaults for those not set on the subscription.
+pub fn resolve_effective(sub: &Subscription, config: &Config) -> Vec<EffectiveSetting> {
+    // Flags can only be turned on, so an unset flag falls back to the config
+    let flag = |name, sub: bool, config: bool| {
+        resolve(name, sub.then_some(true), config.then_some(true), false)
+    };
+    vec![
+        resolve("limit", sub.limit, config.default_limit, DEFAULT_LIMIT),
+        resolve("time", sub.time, config.default_time, DEFAULT_TIME_PERIOD),
+        resolve(
+            "filter",
+            sub.filter.as_ref().map(|filter| filter.iter().join(",")),
+            config.default_filter.map(|filter| filter.to_string()),
+            "all".to_string(),
+        ),
+        resolve(
+            "template",
+            sub.template.clone(),
+            config.default_template.clone(),
+            "none".to_string(),
+        ),
+        resolve("caption", sub.caption, None, CaptionMode::default()),
+        resolve("comments", sub.comments, None, 0),
+        resolve(
+            "comment_sort",
+            sub.comment_sort,
+            None,
+            CommentSort::default(),
+        ),
+        resolve("min_comments", sub.min_comments, None, 0),
+        resolve(
+            "link_preview",
+            sub.link_preview,
+            Some(config.link_preview),
+            LinkPreview::default(),
+        ),
+        resolve(
+            "skip_stickied",
+            sub.skip_stickied,
+            Some(config.skip_stickied),
+            true,
+        ),
+        flag("digest", sub.digest, false),
+        flag("selftext", sub.selftext, config.include_selftext),
+        flag(
+            "photo_as_document",
+            sub.photo_as_document,
+            config.photo_as_document,
+        ),
+        flag("only_newer_than_sub", sub.only_newer_than_sub, false),
+        flag("attach_source", sub.attach_source, false),
+        flag("media_only", sub.media_only, false),
+        flag("extended_info", sub.extended_info, false),
+    ]
 }
 
 pub fn read_config() -> Config {
     env::var(CONFIG_PATH_ENV)
         .map_err(|_| format!("{CONFIG_PATH_ENV} environment variable not set"))
         .and_then(|config_path| std::fs::read_to_string(config_path).map_err(|e| e.to_string()))
-        .and_then(|str| toml::from_str(&str).map_err(|e| e.to_string()))
+        .and_then(|str| toml::from_str::<Config>(&str).map_err(|e| e.to_string()))
+        .and_then(|config| {
+            if let Some(template) = &config.default_template {
+                messages::validate_template(template)?;
+            }
+            if config
+                .video_format
+                .as_deref()
+                .is_some_and(|format| format.trim().is_empty())
+            {
+                return Err("video_format must not be empty".to_string());
+            }
+            if !(config.dedup_titles_similarity > 0.0 && config.dedup_titles_similarity <= 1.0) {
+                return Err("dedup_titles_similarity must be above 0 and at most 1".to_string());
+            }
+            if let Some(timezone) = &config.timezone {
+                timezone
+                    .parse::<Tz>()
+                    .map_err(|e| format!("invalid timezone {timezone:?}: {e}"))?;
+            }
+            Ok(config)
+        })
         .unwrap_or_else(|err| {
             error!("failed to read config: {err}");
             std::process::exit(1);
@@ -46,3 +409,187 @@ fn default_db_path() -> PathBuf {
 fn default_skip_initial_send() -> bool {
     true
 }
+
+fn default_max_subscription_failures() -> u32 {
+    5
+}
+
+fn default_show_repost_buttons() -> bool {
+    true
+}
+
+fn default_error_report_interval_secs() -> u64 {
+    3600
+}
+
+fn default_dedup_titles_window_hours() -> u64 {
+    24
+}
+
+fn default_dedup_titles_similarity() -> f64 {
+    0.8
+}
+
+fn default_telegram_max_concurrent_sends() -> usize {
+    2
+}
+
+fn default_telegram_min_send_interval_ms() -> u64 {
+    500
+}
+
+fn default_video_cache_max_bytes() -> u64 {
+    1024 * 1024 * 1024
+}
+
+fn default_gif_max_bitrate_kbps() -> u32 {
+    1000
+}
+
+fn default_gif_max_width() -> u32 {
+    720
+}
+
+fn default_score_badge_tiers() -> Vec<ScoreBadgeTier> {
+    vec![
+        ScoreBadgeTier {
+            above: 5000,
+            badge: "🔥".to_string(),
+        },
+        ScoreBadgeTier {
+            above: 1000,
+            badge: "⭐".to_string(),
+        },
+    ]
+}
+
+fn default_max_get_limit() -> u32 {
+    10
+}
+
+fn default_subreddit_suggestions() -> u32 {
+    3
+}
+
+fn default_skip_stickied() -> bool {
+    true
+}
+
+fn default_respect_spoilers() -> bool {
+    true
+}
+
+fn default_download_types() -> Vec<PostType> {
+    vec![
+        PostType::Image,
+        PostType::Video,
+        PostType::Gallery,
+        PostType::Audio,
+    ]
+}
+
+fn default_webhook_port() -> u16 {
+    8443
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_admin_user_ids() {
+        let mut config = Config {
+            authorized_user_ids: vec![1, 2],
+            ..Default::default()
+        };
+        assert!(config.is_admin(1));
+        assert!(!config.is_admin(3));
+
+        config.admin_user_ids = vec![3];
+        assert!(config.is_authorized(1));
+        assert!(config.is_authorized(3));
+        assert!(!config.is_authorized(4));
+        assert!(!config.is_admin(1));
+        assert!(config.is_admin(3));
+    }
+
+    #[test]
+    fn test_resolve_effective() {
+        let sub = Subscription {
+            subreddit: "pics".into(),
+            limit: Some(5),
+            selftext: true,
+            ..Default::default()
+        };
+        let config = Config {
+            default_limit: Some(3),
+            default_time: Some(TopPostsTimePeriod::Week),
+            photo_as_document: true,
+            ..Default::default()
+        };
+        let settings = resolve_effective(&sub, &config);
+        let setting = |name| {
+            let setting = settings.iter().find(|s| s.name == name).unwrap();
+            (setting.value.as_str(), setting.source)
+        };
+        assert_eq!(setting("limit"), ("5", SettingSource::Subscription));
+        assert_eq!(setting("time"), ("week", SettingSource::Config));
+        assert_eq!(setting("filter"), ("all", SettingSource::Default));
+        assert_eq!(setting("selftext"), ("true", SettingSource::Subscription));
+        assert_eq!(
+            setting("photo_as_document"),
+            ("true", SettingSource::Config)
+        );
+        assert_eq!(setting("digest"), ("false", SettingSource::Default));
+    }
+
+    #[test]
+    fn test_video_format() {
+        let mut config = Config::default();
+        assert_eq!(config.video_format(), "bv[height<=1080]+ba/best");
+        config.video_max_height = Some(720);
+        assert_eq!(config.video_format(), "bv[height<=720]+ba/best");
+        config.video_format = Some("ba".into());
+        assert_eq!(config.video_format(), "ba");
+    }
+
+    #[test]
+    fn test_authorized_usernames() {
+        let config = Config {
+            authorized_user_ids: vec![1],
+            authorized_usernames: vec!["@Alice".into()],
+            ..Default::default()
+        };
+        assert!(config.is_authorized_user(1, None));
+        assert!(!config.is_authorized(2));
+        assert!(!config.is_authorized_user(2, Some("bob")));
+        assert!(config.is_authorized_user(2, Some("alice")));
+        // The id is remembered, even if the username changes
+        assert!(config.is_authorized(2));
+        assert!(config.is_authorized_user(2, Some("alice_renamed")));
+        // Without admins configured, only users authorized by id are admins
+        assert!(config.is_admin(1));
+        assert!(!config.is_admin(2));
+    }
+
+    #[test]
+    fn test_is_media_host_allowed() {
+        let mut config = Config::default();
+        assert!(config.is_media_host_allowed("https://i.imgur.com/a.jpg"));
+        assert!(!config.is_media_host_allowed("not a url"));
+
+        config.allowed_media_hosts = Some(vec!["*.redd.it".into(), "i.imgur.com".into()]);
+        assert!(config.is_media_host_allowed("https://i.redd.it/a.jpg"));
+        assert!(config.is_media_host_allowed("https://v.REDD.it/a"));
+        assert!(config.is_media_host_allowed("https://redd.it/a"));
+        assert!(config.is_media_host_allowed("https://i.imgur.com/a.jpg"));
+        assert!(!config.is_media_host_allowed("https://imgur.com/a.jpg"));
+        assert!(!config.is_media_host_allowed("https://notredd.it/a.jpg"));
+        assert!(!config.is_media_host_allowed("http://localhost/a.jpg"));
+
+        config.allowed_media_hosts = None;
+        config.blocked_media_hosts = vec!["*.example.com".into()];
+        assert!(!config.is_media_host_allowed("https://cdn.example.com/a.jpg"));
+        assert!(config.is_media_host_allowed("https://i.redd.it/a.jpg"));
+    }
+}
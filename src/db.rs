@@ -1,17 +1,98 @@
 use crate::{config::*, reddit::*, types::*};
 use anyhow::{Context, Result};
+use chrono_tz::Tz;
+use itertools::Itertools;
+use lazy_static::lazy_static;
 use rusqlite::{named_params, Connection, Row};
 use rusqlite::{
     types::{FromSql, FromSqlError, FromSqlResult, ToSql, ToSqlOutput, Value, ValueRef},
     OptionalExtension,
 };
 use rusqlite_migration::{Migrations, M};
-use std::path::Path;
+use std::collections::HashMap;
+use std::ops::{Deref, DerefMut};
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use std::string::ToString;
+use std::time::Duration;
 use std::{convert::TryFrom, sync::Mutex};
 use teloxide::types::{FileId, FileUniqueId};
 
+/// How many unused connections are kept open per database file
+const MAX_IDLE_CONNECTIONS: usize = 4;
+/// How long to wait for another connection to release its lock before failing with "database is
+/// locked"
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+lazy_static! {
+    /// Connections returned by dropped `Database`s, reused by the next `Database::open` of the same
+    /// file instead of opening a new connection every time.
+    static ref IDLE_CONNECTIONS: Mutex<HashMap<PathBuf, Vec<Connection>>> =
+        Mutex::new(HashMap::new());
+}
+
+fn configure_connection(conn: &Connection) -> Result<()> {
+    conn.pragma_update(None, "foreign_keys", "ON")?;
+    // Readers don't block the writer and vice versa, which matters with several connections open
+    // at once
+    conn.pragma_update_and_check(None, "journal_mode", "WAL", |_| Ok(()))?;
+    conn.busy_timeout(BUSY_TIMEOUT)?;
+    Ok(())
+}
+
+fn take_idle_connection(db_path: &Path) -> Option<Connection> {
+    IDLE_CONNECTIONS
+        .lock()
+        .expect("No poison")
+        .get_mut(db_path)
+        .and_then(Vec::pop)
+}
+
+fn release_connection(db_path: &Path, conn: Connection) {
+    // In-memory databases are private to their connection, so reusing one would be surprising
+    if conn.path().is_none_or(str::is_empty) {
+        return;
+    }
+    let mut idle = IDLE_CONNECTIONS.lock().expect("No poison");
+    let conns = idle.entry(db_path.to_owned()).or_default();
+    if conns.len() < MAX_IDLE_CONNECTIONS {
+        conns.push(conn);
+    }
+}
+
+/// Connection that goes back to the pool of idle connections when dropped.
+#[derive(Debug)]
+pub struct PooledConnection {
+    conn: Option<Connection>,
+    db_path: PathBuf,
+}
+
+impl Deref for PooledConnection {
+    type Target = Connection;
+
+    fn deref(&self) -> &Connection {
+        self.conn
+            .as_ref()
+            .expect("Connection is only taken on drop")
+    }
+}
+
+impl DerefMut for PooledConnection {
+    fn deref_mut(&mut self) -> &mut Connection {
+        self.conn
+            .as_mut()
+            .expect("Connection is only taken on drop")
+    }
+}
+
+impl Drop for PooledConnection {
+    fn drop(&mut self) {
+        if let Some(conn) = self.conn.take() {
+            release_connection(&self.db_path, conn);
+        }
+    }
+}
+
 const MIGRATIONS: &[&str] = &[
     "
     create table post(
@@ -134,19 +215,204 @@ const MIGRATIONS: &[&str] = &[
     "
     ALTER TABLE telegram_file_new RENAME TO telegram_file;
     ",
+    "
+    alter table subscription add column failure_count integer not null default 0;
+    ",
+    "
+    alter table subscription add column paused integer not null default 0;
+    ",
+    "
+    alter table subscription add column domains text;
+    ",
+    "
+    alter table subscription add column template text;
+    ",
+    "
+    alter table telegram_file add column media_type text;
+    ",
+    "
+    alter table subscription add column kind text not null default 'subreddit';
+    ",
+    "
+    alter table subscription add column flairs text;
+    ",
+    "
+    alter table subscription add column digest integer not null default 0;
+    ",
+    "
+    alter table chat add column quiet_start text;
+    ",
+    "
+    alter table chat add column quiet_end text;
+    ",
+    "
+    alter table subscription add column selftext integer not null default 0;
+    ",
+    "
+    alter table subscription add column comments integer;
+    ",
+    "
+    alter table subscription add column comment_sort text;
+    ",
+    "
+    alter table subscription add column primed integer not null default 0;
+    ",
+    "
+    alter table subscription add column topic integer;
+    ",
+    "
+    alter table subscription add column photo_as_document integer not null default 0;
+    ",
+    "
+    alter table subscription add column caption text;
+    ",
+    "
+    alter table subscription add column skip_stickied integer;
+    ",
+    "
+    alter table subscription add column only_newer_than_sub integer not null default 0;
+    ",
+    "
+    alter table post add column permalink text;
+    ",
+    "
+    alter table subscription add column min_comments integer;
+    ",
+    "
+    create table muted_post(
+        post_id     text not null,
+        chat_id     integer not null,
+        muted_at    text not null,
+        primary key (post_id, chat_id)
+    ) strict;
+    ",
+    "
+    create table seen_media(
+        chat_id     integer not null,
+        url         text not null,
+        post_id     text not null,
+        seen_at     text not null,
+        primary key (chat_id, url)
+    ) strict;
+    ",
+    "
+    alter table subscription add column link_preview text;
+    ",
+    "
+    alter table telegram_file add column width integer;
+    alter table telegram_file add column height integer;
+    ",
+    "
+    alter table subscription add column attach_source integer not null default 0;
+    ",
+    "
+    alter table chat add column show_repost_buttons integer;
+    ",
+    "
+    alter table subscription add column media_only integer not null default 0;
+    ",
+    "
+    alter table chat add column timezone text;
+    ",
+    "
+    alter table subscription add column extended_info integer not null default 0;
+    ",
+    "
+    create table subscription_new(
+        chat_id             integer not null,
+        kind                text not null default 'subreddit',
+        subreddit           text not null,
+        created_at          text not null,
+        post_limit          integer,
+        time                text,
+        filter              text,
+        failure_count       integer not null default 0,
+        paused              integer not null default 0,
+        domains             text,
+        template            text,
+        flairs              text,
+        digest              integer not null default 0,
+        selftext            integer not null default 0,
+        comments            integer,
+        comment_sort        text,
+        primed              integer not null default 0,
+        topic               integer,
+        photo_as_document   integer not null default 0,
+        caption             text,
+        skip_stickied       integer,
+        only_newer_than_sub integer not null default 0,
+        min_comments        integer,
+        link_preview        text,
+        attach_source       integer not null default 0,
+        media_only          integer not null default 0,
+        extended_info       integer not null default 0,
+        primary key (kind, subreddit, chat_id),
+        foreign key (chat_id) references chat(chat_id)
+    );
+    insert into subscription_new (chat_id, kind, subreddit, created_at, post_limit, time, filter, failure_count, paused, domains, template, flairs, digest, selftext, comments, comment_sort, primed, topic, photo_as_document, caption, skip_stickied, only_newer_than_sub, min_comments, link_preview, attach_source, media_only, extended_info)
+    select chat_id, kind, subreddit, created_at, post_limit, time, filter, failure_count, paused, domains, template, flairs, digest, selftext, comments, comment_sort, primed, topic, photo_as_document, caption, skip_stickied, only_newer_than_sub, min_comments, link_preview, attach_source, media_only, extended_info
+    from subscription;
+    drop table subscription;
+    alter table subscription_new rename to subscription;
+    ",
+    "
+    alter table post add column pending_kind text;
+    alter table post add column pending_subscription text;
+    ",
 ];
 
+/// What `Database::move_chat` moved.
+#[derive(Debug, PartialEq, Eq)]
+pub struct MovedChat {
+    pub subscriptions: usize,
+    /// Subscriptions the target chat already had, which were kept as they were
+    pub conflicting_subscriptions: Vec<String>,
+}
+
+/// A post held during quiet hours, see `Database::get_pending_posts`.
+#[derive(Debug, PartialEq, Eq)]
+pub struct PendingPost {
+    pub post_id: String,
+    /// Kind and name of the subscription the post was held for. Posts held before this was
+    /// recorded have none.
+    pub subscription: Option<(SubscriptionKind, String)>,
+}
+
+/// What's stored about a post in a chat, see `Database::get_post_debug_info`.
+#[derive(Debug, Default, PartialEq)]
+pub struct PostDebugInfo {
+    /// Whether the post is recorded at all, which it is once seen or while held during quiet
+    /// hours
+    pub recorded: bool,
+    /// Not set while the post is held during quiet hours
+    pub seen_at: Option<chrono::DateTime<chrono::Utc>>,
+    pub subreddit: Option<String>,
+    pub title: Option<String>,
+    pub telegram_files: usize,
+    pub muted: bool,
+}
+
 #[derive(Debug)]
 pub struct Database {
-    pub conn: Mutex<Connection>,
+    pub conn: Mutex<PooledConnection>,
 }
 
 impl Database {
     pub fn open(config: &Config) -> Result<Self> {
-        let conn = Self::get_conn(&config.db_path).context("error connecting to database")?;
-        conn.pragma_update(None, "foreign_keys", "ON")?;
+        let conn = match take_idle_connection(&config.db_path) {
+            Some(conn) => conn,
+            None => {
+                let conn =
+                    Self::get_conn(&config.db_path).context("error connecting to database")?;
+                configure_connection(&conn)?;
+                conn
+            }
+        };
         Ok(Database {
-            conn: Mutex::new(conn),
+            conn: Mutex::new(PooledConnection {
+                conn: Some(conn),
+                db_path: config.db_path.clone(),
+            }),
         })
     }
 
@@ -162,9 +428,35 @@ impl Database {
         Connection::open(db_path)
     }
 
+    fn migrations() -> Migrations<'static> {
+        Migrations::new(MIGRATIONS.iter().map(|e| M::up(e)).collect())
+    }
+
     pub fn migrate(&mut self) -> Result<(), rusqlite_migration::Error> {
-        let migrations = MIGRATIONS.iter().map(|e| M::up(e)).collect();
-        Migrations::new(migrations).to_latest(&mut self.conn.lock().expect("No poison"))
+        Self::migrations().to_latest(&mut self.conn.lock().expect("No poison"))
+    }
+
+    /// Returns the schema version of the database and the latest version known to the bot.
+    pub fn migration_status(&self) -> Result<(usize, usize)> {
+        let current = Self::migrations().current_version(&self.conn.lock().expect("No poison"))?;
+        Ok(((&current).into(), MIGRATIONS.len()))
+    }
+
+    /// Rebuilds the database file to reclaim the free pages left behind by deletes and updates
+    /// statistics for the query planner. Returns the size in bytes before and after.
+    ///
+    /// VACUUM fails when a transaction is open or another connection is writing, so this should
+    /// only be called before the bot starts using the database.
+    pub fn vacuum(&self) -> Result<(u64, u64)> {
+        let conn = self.conn.lock().expect("No poison");
+        let size = || -> Result<u64> {
+            let page_count: u64 = conn.pragma_query_value(None, "page_count", |row| row.get(0))?;
+            let page_size: u64 = conn.pragma_query_value(None, "page_size", |row| row.get(0))?;
+            Ok(page_count * page_size)
+        };
+        let before = size()?;
+        conn.execute_batch("vacuum; pragma optimize;")?;
+        Ok((before, size()?))
     }
 
     pub fn record_post<T: Recordable>(
@@ -177,8 +469,8 @@ impl Database {
         let conn = self.conn.lock().expect("No poison");
         let mut stmt = conn.prepare(
             "
-            insert or ignore into post (post_id, chat_id, subreddit, seen_at, post_title)
-            values (:post_id, :chat_id, :subreddit, :seen_at, :post_title)
+            insert or ignore into post (post_id, chat_id, subreddit, seen_at, post_title, permalink)
+            values (:post_id, :chat_id, :subreddit, :seen_at, :post_title, :permalink)
             ",
         )?;
         stmt.execute(named_params! {
@@ -187,6 +479,7 @@ impl Database {
             ":subreddit": &post.subreddit(),
             ":seen_at": seen_at,
             ":post_title": &post.title(),
+            ":permalink": post.permalink(),
         })?;
 
         // Then, update the seen_at field for the row with the given post_id and chat_id, only if seen_at is null
@@ -215,6 +508,31 @@ impl Database {
         self.record_post(chat_id, post, current_time)
     }
 
+    /// Path of the post on reddit, if the post was recorded with one.
+    pub fn get_post_permalink(&self, chat_id: i64, post_id: &str) -> Result<Option<String>> {
+        let conn = &self.conn.lock().expect("No poison");
+        let mut stmt = conn.prepare(
+            "
+            select permalink
+            from post
+            where post_id = :post_id and chat_id = :chat_id
+            ",
+        )?;
+
+        let permalink = stmt
+            .query_row(
+                named_params! {
+                    ":post_id": post_id,
+                    ":chat_id": chat_id,
+                },
+                |row| row.get("permalink"),
+            )
+            .optional()
+            .context("could not retrieve post permalink")?;
+
+        Ok(permalink.flatten())
+    }
+
     pub fn get_post_title(&self, chat_id: i64, post_id: &str) -> Result<String> {
         let conn = &self.conn.lock().expect("No poison");
         let mut stmt = conn.prepare(
@@ -238,6 +556,67 @@ impl Database {
         Ok(post_title)
     }
 
+    pub fn get_post_debug_info(&self, chat_id: i64, post_id: &str) -> Result<PostDebugInfo> {
+        let conn = &self.conn.lock().expect("No poison");
+        let params = named_params! {
+            ":post_id": post_id,
+            ":chat_id": chat_id,
+        };
+        let post = conn
+            .query_row(
+                "
+                select subreddit, post_title, seen_at
+                from post
+                where post_id = :post_id and chat_id = :chat_id
+                ",
+                params,
+                |row| {
+                    Ok((
+                        row.get("subreddit")?,
+                        row.get("post_title")?,
+                        row.get("seen_at")?,
+                    ))
+                },
+            )
+            .optional()
+            .context("could not get post")?;
+        let telegram_files: usize = conn
+            .query_row(
+                "
+                select count(*)
+                from telegram_file
+                where post_id = :post_id and chat_id = :chat_id
+                ",
+                params,
+                |row| row.get(0),
+            )
+            .context("could not count telegram files")?;
+        let muted = conn
+            .query_row(
+                "
+                select exists(
+                    select 1 from muted_post where post_id = :post_id and chat_id = :chat_id
+                )
+                ",
+                params,
+                |row| row.get(0),
+            )
+            .context("could not get if post is muted")?;
+
+        let (subreddit, title, seen_at) = match post {
+            Some((subreddit, title, seen_at)) => (Some(subreddit), Some(title), seen_at),
+            None => (None, None, None),
+        };
+        Ok(PostDebugInfo {
+            recorded: subreddit.is_some(),
+            seen_at,
+            subreddit,
+            title,
+            telegram_files,
+            muted,
+        })
+    }
+
     pub fn is_post_seen<T: Recordable>(&self, chat_id: i64, post: &T) -> Result<bool> {
         let conn = &self.conn.lock().expect("No poison");
         let mut stmt = conn.prepare(
@@ -288,28 +667,51 @@ impl Database {
         let conn = &self.conn.lock().expect("No poison");
         let mut stmt = conn.prepare(
             "
-            insert or replace into subscription (chat_id, subreddit, post_limit, time, filter, created_at)
-            values (:chat_id, :subreddit, :limit, :time, :filter, :created_at)
+            insert or replace into subscription (chat_id, kind, subreddit, post_limit, time, filter, domains, flairs, template, digest, selftext, photo_as_document, caption, comments, comment_sort, topic, skip_stickied, only_newer_than_sub, min_comments, link_preview, attach_source, media_only, extended_info, created_at)
+            values (:chat_id, :kind, :subreddit, :limit, :time, :filter, :domains, :flairs, :template, :digest, :selftext, :photo_as_document, :caption, :comments, :comment_sort, :topic, :skip_stickied, :only_newer_than_sub, :min_comments, :link_preview, :attach_source, :media_only, :extended_info, :created_at)
             ",
         )?;
         stmt.execute(named_params! {
             ":chat_id": chat_id,
+            ":kind": args.kind,
             ":subreddit": args.subreddit,
             ":limit": args.limit,
             ":time": args.time,
-            ":filter": args.filter,
+            ":filter": args.filter.as_ref().map(|f| f.iter().join(",")),
+            ":domains": args.domains.as_ref().map(|d| d.join(",")),
+            ":flairs": args.flairs.as_ref().map(|f| f.join(",")),
+            ":template": args.template,
+            ":digest": args.digest,
+            ":selftext": args.selftext,
+            ":photo_as_document": args.photo_as_document,
+            ":caption": args.caption,
+            ":comments": args.comments,
+            ":comment_sort": args.comment_sort,
+            ":topic": args.topic,
+            ":skip_stickied": args.skip_stickied,
+            ":only_newer_than_sub": args.only_newer_than_sub,
+            ":min_comments": args.min_comments,
+            ":link_preview": args.link_preview,
+            ":attach_source": args.attach_source,
+            ":media_only": args.media_only,
+            ":extended_info": args.extended_info,
             ":created_at": chrono::Utc::now()
         })
         .context("could not add subscription")?;
         Ok(())
     }
 
-    pub fn unsubscribe(&self, chat_id: i64, subreddit: &str) -> Result<String> {
+    pub fn unsubscribe(
+        &self,
+        chat_id: i64,
+        kind: SubscriptionKind,
+        subreddit: &str,
+    ) -> Result<String> {
         let conn = &self.conn.lock().expect("No poison");
         let mut stmt = conn.prepare(
             "
             delete from subscription
-            where chat_id = :chat_id and subreddit LIKE :subreddit
+            where chat_id = :chat_id and kind = :kind and subreddit LIKE :subreddit
             returning subreddit
             ",
         )?;
@@ -317,6 +719,7 @@ impl Database {
             .query_row(
                 named_params! {
                     ":chat_id": chat_id,
+                    ":kind": kind,
                     ":subreddit": subreddit,
                 },
                 |row| row.get("subreddit"),
@@ -326,11 +729,21 @@ impl Database {
         Ok(deleted_subreddit)
     }
 
+    pub fn count_subscriptions(&self, chat_id: i64) -> Result<u32> {
+        let conn = &self.conn.lock().expect("No poison");
+        conn.query_row(
+            "select count(*) from subscription where chat_id = :chat_id",
+            named_params! { ":chat_id": chat_id },
+            |row| row.get(0),
+        )
+        .context("could not count subscriptions")
+    }
+
     pub fn get_subscriptions_for_chat(&self, chat_id: i64) -> Result<Vec<Subscription>> {
         let conn = &self.conn.lock().expect("No poison");
         let mut stmt = conn.prepare(
             "
-            select chat_id, subreddit, post_limit, time, filter, created_at
+            select chat_id, kind, subreddit, post_limit, time, filter, domains, flairs, template, digest, selftext, photo_as_document, caption, comments, comment_sort, topic, skip_stickied, only_newer_than_sub, min_comments, link_preview, attach_source, media_only, extended_info, paused, primed, created_at
             from subscription
             where chat_id = ?
             ",
@@ -347,7 +760,7 @@ impl Database {
         let conn = &self.conn.lock().expect("No poison");
         let mut stmt = conn.prepare(
             "
-            select chat_id, subreddit, post_limit, time, filter, created_at
+            select chat_id, kind, subreddit, post_limit, time, filter, domains, flairs, template, digest, selftext, photo_as_document, caption, comments, comment_sort, topic, skip_stickied, only_newer_than_sub, min_comments, link_preview, attach_source, media_only, extended_info, paused, primed, created_at
             from subscription
             ",
         )?;
@@ -359,6 +772,105 @@ impl Database {
         Ok(subs)
     }
 
+    /// Increments the consecutive failure count of a subscription and returns the new count.
+    pub fn record_subscription_failure(
+        &self,
+        chat_id: i64,
+        kind: SubscriptionKind,
+        subreddit: &str,
+    ) -> Result<u32> {
+        let conn = &self.conn.lock().expect("No poison");
+        let mut stmt = conn.prepare(
+            "
+            update subscription
+            set failure_count = failure_count + 1
+            where chat_id = :chat_id and kind = :kind and subreddit = :subreddit
+            returning failure_count
+            ",
+        )?;
+        stmt.query_row(
+            named_params! {
+                ":chat_id": chat_id,
+                ":kind": kind,
+                ":subreddit": subreddit,
+            },
+            |row| row.get("failure_count"),
+        )
+        .context("could not record subscription failure")
+    }
+
+    pub fn reset_subscription_failures(
+        &self,
+        chat_id: i64,
+        kind: SubscriptionKind,
+        subreddit: &str,
+    ) -> Result<()> {
+        let conn = &self.conn.lock().expect("No poison");
+        let mut stmt = conn.prepare(
+            "
+            update subscription
+            set failure_count = 0
+            where chat_id = :chat_id and kind = :kind and subreddit = :subreddit
+              and failure_count != 0
+            ",
+        )?;
+        stmt.execute(named_params! {
+            ":chat_id": chat_id,
+            ":kind": kind,
+            ":subreddit": subreddit,
+        })
+        .context("could not reset subscription failures")
+        .map(|_| ())
+    }
+
+    pub fn pause_subscription(
+        &self,
+        chat_id: i64,
+        kind: SubscriptionKind,
+        subreddit: &str,
+    ) -> Result<()> {
+        let conn = &self.conn.lock().expect("No poison");
+        let mut stmt = conn.prepare(
+            "
+            update subscription
+            set paused = 1
+            where chat_id = :chat_id and kind = :kind and subreddit = :subreddit
+            ",
+        )?;
+        stmt.execute(named_params! {
+            ":chat_id": chat_id,
+            ":kind": kind,
+            ":subreddit": subreddit,
+        })
+        .context("could not pause subscription")
+        .map(|_| ())
+    }
+
+    /// Records that the first posts of the subscription were marked seen and the chat was notified
+    /// about it if needed.
+    pub fn mark_subscription_primed(
+        &self,
+        chat_id: i64,
+        kind: SubscriptionKind,
+        subreddit: &str,
+    ) -> Result<()> {
+        let conn = &self.conn.lock().expect("No poison");
+        let mut stmt = conn.prepare(
+            "
+            update subscription
+            set primed = 1
+            where chat_id = :chat_id and kind = :kind and subreddit = :subreddit
+            ",
+        )?;
+        stmt.execute(named_params! {
+            ":chat_id": chat_id,
+            ":kind": kind,
+            ":subreddit": subreddit,
+        })
+        .context("could not mark subscription primed")
+        .map(|_| ())
+    }
+
     pub fn ensure_chat_exists(&self, chat_id: i64) -> Result<()> {
         let conn = &self.conn.lock().expect("No poison");
         let chat_exists: bool = conn.query_row(
@@ -412,6 +924,24 @@ impl Database {
         Ok(())
     }
 
+    pub fn clear_repost_channel(&self, chat_id: i64) -> Result<()> {
+        let conn = &self.conn.lock().expect("No poison");
+        let mut stmt = conn.prepare(
+            "
+            update chat
+            set repost_channel_id = null
+            where chat_id = :chat_id;
+            ",
+        )?;
+
+        stmt.execute(named_params! {
+            ":chat_id": chat_id,
+        })
+        .context("could not clear repost channel")?;
+
+        Ok(())
+    }
+
     pub fn get_repost_channel(&self, chat_id: i64) -> Result<Option<i64>> {
         let conn = &self.conn.lock().expect("No poison");
         let mut stmt = conn.prepare(
@@ -422,7 +952,7 @@ impl Database {
             ",
         )?;
 
-        let repost_channel_id: Option<i64> = stmt
+        let repost_channel_id: Option<Option<i64>> = stmt
             .query_row(
                 named_params! {
                     ":chat_id": chat_id,
@@ -432,39 +962,641 @@ impl Database {
             .optional()
             .context("could not get repost channel")?;
 
-        Ok(repost_channel_id)
+        Ok(repost_channel_id.flatten())
     }
 
-    pub fn add_telegram_file(
-        &self,
-        post_id: &str,
-        chat_id: i64,
-        telegram_file_id: &FileId,
-        telegram_unique_file_id: &FileUniqueId,
-    ) -> Result<()> {
+    /// `None` leaves it to config.
+    pub fn set_show_repost_buttons(&self, chat_id: i64, show: Option<bool>) -> Result<()> {
+        self.ensure_chat_exists(chat_id)?;
         let conn = &self.conn.lock().expect("No poison");
         let mut stmt = conn.prepare(
             "
-            insert or ignore into telegram_file (post_id, chat_id, telegram_file_id, telegram_file_unique_id)
-            values (:post_id, :chat_id, :telegram_file_id, :telegram_file_unique_id)
+            update chat
+            set show_repost_buttons = :show_repost_buttons
+            where chat_id = :chat_id;
             ",
         )?;
+
         stmt.execute(named_params! {
-            ":post_id": post_id,
             ":chat_id": chat_id,
-            ":telegram_file_id": telegram_file_id.0,
-            ":telegram_file_unique_id": telegram_unique_file_id.0,
+            ":show_repost_buttons": show,
         })
-        .context("could not add telegram file")
-        .map(|_| ())
+        .context("could not set show repost buttons")?;
+
+        Ok(())
     }
 
-    pub fn get_telegram_files_for_post(&self, post_id: &str, chat_id: i64) -> Result<Vec<FileId>> {
+    pub fn get_show_repost_buttons(&self, chat_id: i64) -> Result<Option<bool>> {
         let conn = &self.conn.lock().expect("No poison");
         let mut stmt = conn.prepare(
             "
-            select telegram_file_id
-            from telegram_file
+            select show_repost_buttons
+            from chat
+            where chat_id = :chat_id;
+            ",
+        )?;
+
+        let show: Option<Option<bool>> = stmt
+            .query_row(
+                named_params! {
+                    ":chat_id": chat_id,
+                },
+                |row| row.get("show_repost_buttons"),
+            )
+            .optional()
+            .context("could not get show repost buttons")?;
+
+        Ok(show.flatten())
+    }
+
+    pub fn set_quiet_hours(&self, chat_id: i64, quiet_hours: Option<QuietHours>) -> Result<()> {
+        self.ensure_chat_exists(chat_id)?;
+        let conn = &self.conn.lock().expect("No poison");
+        let mut stmt = conn.prepare(
+            "
+            update chat
+            set quiet_start = :quiet_start, quiet_end = :quiet_end
+            where chat_id = :chat_id;
+            ",
+        )?;
+
+        stmt.execute(named_params! {
+            ":chat_id": chat_id,
+            ":quiet_start": quiet_hours.map(|q| q.start),
+            ":quiet_end": quiet_hours.map(|q| q.end),
+        })
+        .context("could not set quiet hours")?;
+
+        Ok(())
+    }
+
+    /// `None` leaves it to config.
+    pub fn set_timezone(&self, chat_id: i64, timezone: Option<Tz>) -> Result<()> {
+        self.ensure_chat_exists(chat_id)?;
+        let conn = &self.conn.lock().expect("No poison");
+        let mut stmt = conn.prepare(
+            "
+            update chat
+            set timezone = :timezone
+            where chat_id = :chat_id;
+            ",
+        )?;
+
+        stmt.execute(named_params! {
+            ":chat_id": chat_id,
+            ":timezone": timezone.map(|timezone| timezone.name()),
+        })
+        .context("could not set timezone")?;
+
+        Ok(())
+    }
+
+    pub fn get_timezone(&self, chat_id: i64) -> Result<Option<Tz>> {
+        let conn = &self.conn.lock().expect("No poison");
+        let mut stmt = conn.prepare(
+            "
+            select timezone
+            from chat
+            where chat_id = :chat_id;
+            ",
+        )?;
+
+        let timezone: Option<Option<String>> = stmt
+            .query_row(
+                named_params! {
+                    ":chat_id": chat_id,
+                },
+                |row| row.get("timezone"),
+            )
+            .optional()
+            .context("could not get timezone")?;
+
+        timezone
+            .flatten()
+            .map(|timezone| {
+                timezone
+                    .parse()
+                    .with_context(|| format!("invalid timezone {timezone:?}"))
+            })
+            .transpose()
+    }
+
+    /// The time zone set for the chat, or else the one from config.
+    pub fn get_chat_timezone(&self, chat_id: i64, config: &Config) -> Result<Tz> {
+        Ok(self.get_timezone(chat_id)?.unwrap_or_else(|| config.tz()))
+    }
+
+    pub fn get_quiet_hours(&self, chat_id: i64) -> Result<Option<QuietHours>> {
+        let conn = &self.conn.lock().expect("No poison");
+        let mut stmt = conn.prepare(
+            "
+            select quiet_start, quiet_end
+            from chat
+            where chat_id = :chat_id;
+            ",
+        )?;
+
+        let quiet_hours = stmt
+            .query_row(
+                named_params! {
+                    ":chat_id": chat_id,
+                },
+                |row| {
+                    Ok(row
+                        .get::<_, Option<_>>("quiet_start")?
+                        .zip(row.get::<_, Option<_>>("quiet_end")?)
+                        .map(|(start, end)| QuietHours { start, end }))
+                },
+            )
+            .optional()
+            .context("could not get quiet hours")?;
+
+        Ok(quiet_hours.flatten())
+    }
+
+    /// Records a post held during quiet hours for the given subscription, to be delivered when
+    /// quiet hours end.
+    pub fn hold_post<T: Recordable>(
+        &self,
+        chat_id: i64,
+        post: &T,
+        kind: SubscriptionKind,
+        subscription: &str,
+    ) -> Result<()> {
+        self.record_post(chat_id, post, None)?;
+        let conn = &self.conn.lock().expect("No poison");
+        conn.execute(
+            "
+            update post
+            set pending_kind = :kind, pending_subscription = :subscription
+            where post_id = :post_id and chat_id = :chat_id and seen_at is null
+            ",
+            named_params! {
+                ":post_id": post.id(),
+                ":chat_id": chat_id,
+                ":kind": kind,
+                ":subscription": subscription,
+            },
+        )
+        .context("could not hold post")
+        .map(|_| ())
+    }
+
+    /// Gets posts that were held during quiet hours but not delivered yet.
+    pub fn get_pending_posts(&self, chat_id: i64) -> Result<Vec<PendingPost>> {
+        let conn = &self.conn.lock().expect("No poison");
+        let mut stmt = conn.prepare(
+            "
+            select post_id, pending_kind, pending_subscription
+            from post
+            where chat_id = :chat_id and seen_at is null
+            ",
+        )?;
+
+        let posts = stmt
+            .query_map(
+                named_params! {
+                    ":chat_id": chat_id,
+                },
+                |row| {
+                    let kind: Option<SubscriptionKind> = row.get("pending_kind")?;
+                    let subscription: Option<String> = row.get("pending_subscription")?;
+                    Ok(PendingPost {
+                        post_id: row.get("post_id")?,
+                        subscription: kind.zip(subscription),
+                    })
+                },
+            )?
+            .collect::<Result<Vec<_>, _>>()
+            .context("could not get pending posts")?;
+
+        Ok(posts)
+    }
+
+    /// Ids of the most recently seen posts of a subreddit in the chat, newest first.
+    pub fn get_recent_post_ids(
+        &self,
+        chat_id: i64,
+        subreddit: &str,
+        count: u32,
+    ) -> Result<Vec<String>> {
+        let conn = &self.conn.lock().expect("No poison");
+        let mut stmt = conn.prepare(
+            "
+            select post_id
+            from post
+            where chat_id = :chat_id
+              and subreddit = :subreddit collate nocase
+              and seen_at is not null
+            order by seen_at desc
+            limit :count
+            ",
+        )?;
+
+        let post_ids = stmt
+            .query_map(
+                named_params! {
+                    ":chat_id": chat_id,
+                    ":subreddit": subreddit,
+                    ":count": count,
+                },
+                |row| row.get("post_id"),
+            )?
+            .collect::<Result<Vec<String>, _>>()
+            .context("could not get recent posts")?;
+
+        Ok(post_ids)
+    }
+
+    /// Titles of the posts of the subreddit seen in the chat since the given time, including posts
+    /// that are held and not seen yet.
+    pub fn recent_titles_for_subreddit(
+        &self,
+        chat_id: i64,
+        subreddit: &str,
+        since: chrono::DateTime<chrono::Utc>,
+    ) -> Result<Vec<String>> {
+        let conn = &self.conn.lock().expect("No poison");
+        let mut stmt = conn.prepare(
+            "
+            select post_title
+            from post
+            where chat_id = :chat_id
+              and subreddit = :subreddit collate nocase
+              and (seen_at >= :since or seen_at is null)
+            ",
+        )?;
+
+        let titles = stmt
+            .query_map(
+                named_params! {
+                    ":chat_id": chat_id,
+                    ":subreddit": subreddit,
+                    ":since": since,
+                },
+                |row| row.get("post_title"),
+            )?
+            .collect::<Result<Vec<String>, _>>()
+            .context("could not get recent titles")?;
+
+        Ok(titles)
+    }
+
+    /// Marks a pending post seen without delivering it.
+    pub fn mark_pending_post_seen(&self, chat_id: i64, post_id: &str) -> Result<()> {
+        let conn = &self.conn.lock().expect("No poison");
+        let mut stmt = conn.prepare(
+            "
+            update post
+            set seen_at = :seen_at
+            where post_id = :post_id and chat_id = :chat_id and seen_at is null
+            ",
+        )?;
+        stmt.execute(named_params! {
+            ":seen_at": chrono::Utc::now(),
+            ":post_id": post_id,
+            ":chat_id": chat_id,
+        })
+        .context("could not mark pending post seen")
+        .map(|_| ())
+    }
+
+    /// Forgets the posts of a subreddit seen in the chat, along with their sent files and media,
+    /// so that they can be sent again. Returns the number of posts forgotten.
+    pub fn clear_seen_for_subreddit(&self, chat_id: i64, subreddit: &str) -> Result<usize> {
+        let mut conn = self.conn.lock().expect("No poison");
+        let tx = conn.transaction()?;
+        let params = named_params! {
+            ":chat_id": chat_id,
+            ":subreddit": subreddit,
+        };
+        // Files and media reference the posts, so they are deleted first
+        tx.execute(
+            "
+            delete from telegram_file
+            where chat_id = :chat_id
+              and post_id in (
+                select post_id from post
+                where chat_id = :chat_id and subreddit = :subreddit collate nocase
+              )
+            ",
+            params,
+        )?;
+        tx.execute(
+            "
+            delete from seen_media
+            where chat_id = :chat_id
+              and post_id in (
+                select post_id from post
+                where chat_id = :chat_id and subreddit = :subreddit collate nocase
+              )
+            ",
+            params,
+        )?;
+        let deleted = tx.execute(
+            "
+            delete from post
+            where chat_id = :chat_id and subreddit = :subreddit collate nocase
+            ",
+            params,
+        )?;
+        tx.commit().context("could not clear seen posts")?;
+        Ok(deleted)
+    }
+
+    /// Moves the subscriptions, seen posts and settings of a chat to another chat in one
+    /// transaction. Where both chats have something, the target chat's is kept: its
+    /// subscriptions, seen posts and settings are not overwritten by those of the source chat.
+    pub fn move_chat(&self, from: i64, to: i64) -> Result<MovedChat> {
+        anyhow::ensure!(from != to, "can't move a chat to itself");
+        let mut conn = self.conn.lock().expect("No poison");
+        let tx = conn.transaction()?;
+        // Posts and their files reference each other by chat, so they're checked once both have
+        // been moved
+        tx.pragma_update(None, "defer_foreign_keys", "ON")?;
+        let params = named_params! { ":from": from, ":to": to };
+
+        tx.execute(
+            "insert or ignore into chat (chat_id) values (:to)",
+            named_params! { ":to": to },
+        )?;
+        tx.execute(
+            "
+            update chat
+            set repost_channel_id = coalesce(
+                    repost_channel_id,
+                    (select repost_channel_id from chat where chat_id = :from)
+                ),
+                show_repost_buttons = coalesce(
+                    show_repost_buttons,
+                    (select show_repost_buttons from chat where chat_id = :from)
+                ),
+                timezone = coalesce(
+                    timezone,
+                    (select timezone from chat where chat_id = :from)
+                )
+            where chat_id = :to
+            ",
+            params,
+        )?;
+        tx.execute(
+            "
+            update chat
+            set (quiet_start, quiet_end) = (
+                select quiet_start, quiet_end from chat where chat_id = :from
+            )
+            where chat_id = :to and quiet_start is null
+            ",
+            params,
+        )?;
+
+        let conflicting_subscriptions = tx
+            .prepare(
+                "
+                delete from subscription
+                where chat_id = :from
+                  and (kind, lower(subreddit)) in (
+                    select kind, lower(subreddit) from subscription where chat_id = :to
+                  )
+                returning kind, subreddit
+                ",
+            )?
+            .query_map(params, |row| {
+                let kind: SubscriptionKind = row.get("kind")?;
+                let subreddit: String = row.get("subreddit")?;
+                Ok(format!("{}{subreddit}", kind.prefix()))
+            })?
+            .collect::<Result<Vec<String>, _>>()?;
+        let subscriptions = tx.execute(
+            "update subscription set chat_id = :to where chat_id = :from",
+            params,
+        )?;
+
+        tx.execute(
+            "
+            delete from telegram_file
+            where chat_id = :from
+              and post_id in (select post_id from post where chat_id = :to)
+            ",
+            params,
+        )?;
+        tx.execute(
+            "update telegram_file set chat_id = :to where chat_id = :from",
+            params,
+        )?;
+        tx.execute(
+            "
+            delete from post
+            where chat_id = :from
+              and post_id in (select post_id from post where chat_id = :to)
+            ",
+            params,
+        )?;
+        tx.execute(
+            "update post set chat_id = :to where chat_id = :from",
+            params,
+        )?;
+        for table in ["muted_post", "seen_media"] {
+            tx.execute(
+                &format!("update or ignore {table} set chat_id = :to where chat_id = :from"),
+                params,
+            )?;
+            tx.execute(
+                &format!("delete from {table} where chat_id = :from"),
+                named_params! { ":from": from },
+            )?;
+        }
+        tx.execute(
+            "delete from chat where chat_id = :from",
+            named_params! { ":from": from },
+        )?;
+
+        tx.commit().context("could not move chat")?;
+        Ok(MovedChat {
+            subscriptions,
+            conflicting_subscriptions,
+        })
+    }
+
+    pub fn mute_post(&self, chat_id: i64, post_id: &str) -> Result<()> {
+        let conn = &self.conn.lock().expect("No poison");
+        let mut stmt = conn.prepare(
+            "
+            insert or ignore into muted_post (post_id, chat_id, muted_at)
+            values (:post_id, :chat_id, :muted_at)
+            ",
+        )?;
+        stmt.execute(named_params! {
+            ":post_id": post_id,
+            ":chat_id": chat_id,
+            ":muted_at": chrono::Utc::now(),
+        })
+        .context("could not mute post")
+        .map(|_| ())
+    }
+
+    /// Unmutes a post and returns whether it was muted.
+    pub fn unmute_post(&self, chat_id: i64, post_id: &str) -> Result<bool> {
+        let conn = &self.conn.lock().expect("No poison");
+        let mut stmt = conn.prepare(
+            "
+            delete from muted_post
+            where post_id = :post_id and chat_id = :chat_id
+            ",
+        )?;
+        let deleted = stmt
+            .execute(named_params! {
+                ":post_id": post_id,
+                ":chat_id": chat_id,
+            })
+            .context("could not unmute post")?;
+        Ok(deleted > 0)
+    }
+
+    /// Whether the post, or the post it is a crosspost of, is muted in the chat.
+    pub fn is_post_muted(&self, chat_id: i64, post: &Post) -> Result<bool> {
+        let conn = &self.conn.lock().expect("No poison");
+        let mut stmt = conn.prepare(
+            "
+            select exists(
+                select 1
+                  from muted_post
+                 where chat_id = :chat_id and post_id in (:post_id, :parent_id)
+            );
+            ",
+        )?;
+
+        stmt.query_row(
+            named_params! {
+                ":chat_id": chat_id,
+                ":post_id": post.id,
+                ":parent_id": post.crosspost_parent_id(),
+            },
+            |row| row.get(0),
+        )
+        .map_err(anyhow::Error::from)
+    }
+
+    /// Records the canonical media url of a post as seen in the chat, keeping the first post seen
+    /// with it.
+    pub fn record_media_seen(&self, chat_id: i64, url: &str, post_id: &str) -> Result<()> {
+        let conn = &self.conn.lock().expect("No poison");
+        let mut stmt = conn.prepare(
+            "
+            insert or ignore into seen_media (chat_id, url, post_id, seen_at)
+            values (:chat_id, :url, :post_id, :seen_at)
+            ",
+        )?;
+        stmt.execute(named_params! {
+            ":chat_id": chat_id,
+            ":url": url,
+            ":post_id": post_id,
+            ":seen_at": chrono::Utc::now(),
+        })
+        .context("could not record media seen")
+        .map(|_| ())
+    }
+
+    pub fn is_media_seen(&self, chat_id: i64, url: &str) -> Result<bool> {
+        let conn = &self.conn.lock().expect("No poison");
+        let mut stmt = conn.prepare(
+            "
+            select exists(
+                select 1
+                  from seen_media
+                 where chat_id = :chat_id and url = :url
+            );
+            ",
+        )?;
+
+        stmt.query_row(
+            named_params! {
+                ":chat_id": chat_id,
+                ":url": url,
+            },
+            |row| row.get(0),
+        )
+        .map_err(anyhow::Error::from)
+    }
+
+    pub fn add_telegram_file(
+        &self,
+        post_id: &str,
+        chat_id: i64,
+        telegram_file_id: &FileId,
+        telegram_unique_file_id: &FileUniqueId,
+        kind: MediaKind,
+        dimensions: Option<Dimensions>,
+    ) -> Result<()> {
+        let conn = &self.conn.lock().expect("No poison");
+        let mut stmt = conn.prepare(
+            "
+            insert or ignore into telegram_file (post_id, chat_id, telegram_file_id, telegram_file_unique_id, media_type, width, height)
+            values (:post_id, :chat_id, :telegram_file_id, :telegram_file_unique_id, :media_type, :width, :height)
+            ",
+        )?;
+        stmt.execute(named_params! {
+            ":post_id": post_id,
+            ":chat_id": chat_id,
+            ":telegram_file_id": telegram_file_id.0,
+            ":telegram_file_unique_id": telegram_unique_file_id.0,
+            ":media_type": kind,
+            ":width": dimensions.map(|dimensions| dimensions.width),
+            ":height": dimensions.map(|dimensions| dimensions.height),
+        })
+        .context("could not add telegram file")
+        .map(|_| ())
+    }
+
+    /// Returns files uploaded for the post in whichever chat it was first uploaded to. Telegram
+    /// file ids are specific to the bot, not the chat, so these can be sent to any chat.
+    ///
+    /// Files recorded before media type was tracked can't be reused, in which case nothing is
+    /// returned.
+    pub fn get_telegram_files_for_post_from_any_chat(
+        &self,
+        post_id: &str,
+    ) -> Result<Vec<TelegramFile>> {
+        let conn = &self.conn.lock().expect("No poison");
+        let mut stmt = conn.prepare(
+            "
+            select telegram_file_id, media_type, width, height
+            from telegram_file
+            where post_id = :post_id
+              and chat_id = (select chat_id from telegram_file where post_id = :post_id order by id limit 1)
+            order by id
+            ",
+        )?;
+
+        let files = stmt
+            .query_map(named_params! { ":post_id": post_id }, |row| {
+                let file_id: String = row.get("telegram_file_id")?;
+                let kind: Option<MediaKind> = row.get("media_type")?;
+                let dimensions = file_dimensions(row)?;
+                Ok(kind.map(|kind| TelegramFile {
+                    file_id: file_id.into(),
+                    kind,
+                    dimensions,
+                }))
+            })
+            .context("could not retrieve telegram files")?
+            .collect::<Result<Option<Vec<_>>, _>>()?;
+
+        Ok(files.unwrap_or_default())
+    }
+
+    /// Gets the files sent for a post in the chat, in the order they were sent. Files recorded
+    /// before the media type was stored are galleries of photos.
+    pub fn get_telegram_files_for_post(
+        &self,
+        post_id: &str,
+        chat_id: i64,
+    ) -> Result<Vec<TelegramFile>> {
+        let conn = &self.conn.lock().expect("No poison");
+        let mut stmt = conn.prepare(
+            "
+            select telegram_file_id, media_type, width, height
+            from telegram_file
             where post_id = :post_id and chat_id = :chat_id
             order by telegram_file.id
             ",
@@ -476,30 +1608,115 @@ impl Database {
                     ":post_id": post_id,
                     ":chat_id": chat_id,
                 },
-                |row| row.get("telegram_file_id"),
+                |row| {
+                    let file_id: String = row.get("telegram_file_id")?;
+                    let kind: Option<MediaKind> = row.get("media_type")?;
+                    Ok(TelegramFile {
+                        file_id: file_id.into(),
+                        kind: kind.unwrap_or(MediaKind::Photo),
+                        dimensions: file_dimensions(row)?,
+                    })
+                },
             )
             .context("could not retrieve telegram files")?;
 
-        let telegram_files: Result<Vec<String>, _> = rows.collect();
-        Ok(telegram_files?.into_iter().map(|x| x.into()).collect())
+        Ok(rows.collect::<Result<Vec<_>, _>>()?)
+    }
+}
+
+/// Dimensions of a file recorded in the telegram_file table, if both were recorded.
+fn file_dimensions(row: &Row) -> rusqlite::Result<Option<Dimensions>> {
+    let width: Option<u32> = row.get("width")?;
+    let height: Option<u32> = row.get("height")?;
+    Ok(width
+        .zip(height)
+        .map(|(width, height)| Dimensions { width, height }))
+}
+
+pub trait Recordable {
+    fn id(&self) -> &str;
+    fn title(&self) -> &str;
+    fn subreddit(&self) -> &str;
+    /// Path of the post on reddit, if it's a reddit post
+    fn permalink(&self) -> Option<&str> {
+        None
+    }
+}
+
+impl ToSql for TopPostsTimePeriod {
+    fn to_sql(&self) -> Result<rusqlite::types::ToSqlOutput<'_>, rusqlite::Error> {
+        Ok(ToSqlOutput::Owned(Value::Text(self.to_string())))
+    }
+}
+
+impl ToSql for PostType {
+    fn to_sql(&self) -> Result<rusqlite::types::ToSqlOutput<'_>, rusqlite::Error> {
+        Ok(ToSqlOutput::Owned(Value::Text(self.to_string())))
+    }
+}
+
+impl ToSql for CommentSort {
+    fn to_sql(&self) -> Result<rusqlite::types::ToSqlOutput<'_>, rusqlite::Error> {
+        Ok(ToSqlOutput::Owned(Value::Text(self.to_string())))
+    }
+}
+
+impl FromSql for CommentSort {
+    fn column_result(value: ValueRef) -> FromSqlResult<CommentSort> {
+        let str = String::column_result(value)?;
+        CommentSort::from_str(&str).map_err(|e| FromSqlError::Other(From::from(e)))
+    }
+}
+
+impl ToSql for MediaKind {
+    fn to_sql(&self) -> Result<rusqlite::types::ToSqlOutput<'_>, rusqlite::Error> {
+        Ok(ToSqlOutput::Owned(Value::Text(self.to_string())))
+    }
+}
+
+impl ToSql for SubscriptionKind {
+    fn to_sql(&self) -> Result<rusqlite::types::ToSqlOutput<'_>, rusqlite::Error> {
+        Ok(ToSqlOutput::Owned(Value::Text(self.to_string())))
+    }
+}
+
+impl FromSql for SubscriptionKind {
+    fn column_result(value: ValueRef) -> FromSqlResult<SubscriptionKind> {
+        let str = String::column_result(value)?;
+        SubscriptionKind::from_str(&str).map_err(|e| FromSqlError::Other(From::from(e)))
+    }
+}
+
+impl ToSql for CaptionMode {
+    fn to_sql(&self) -> Result<rusqlite::types::ToSqlOutput<'_>, rusqlite::Error> {
+        Ok(ToSqlOutput::Owned(Value::Text(self.to_string())))
     }
 }
 
-pub trait Recordable {
-    fn id(&self) -> &str;
-    fn title(&self) -> &str;
-    fn subreddit(&self) -> &str;
+impl FromSql for CaptionMode {
+    fn column_result(value: ValueRef) -> FromSqlResult<CaptionMode> {
+        let str = String::column_result(value)?;
+        CaptionMode::from_str(&str).map_err(|e| FromSqlError::Other(From::from(e)))
+    }
 }
 
-impl ToSql for TopPostsTimePeriod {
-    fn to_sql(&self) -> Result<rusqlite::types::ToSqlOutput, rusqlite::Error> {
+impl ToSql for LinkPreview {
+    fn to_sql(&self) -> Result<rusqlite::types::ToSqlOutput<'_>, rusqlite::Error> {
         Ok(ToSqlOutput::Owned(Value::Text(self.to_string())))
     }
 }
 
-impl ToSql for PostType {
-    fn to_sql(&self) -> Result<rusqlite::types::ToSqlOutput, rusqlite::Error> {
-        Ok(ToSqlOutput::Owned(Value::Text(self.to_string())))
+impl FromSql for LinkPreview {
+    fn column_result(value: ValueRef) -> FromSqlResult<LinkPreview> {
+        let str = String::column_result(value)?;
+        LinkPreview::from_str(&str).map_err(|e| FromSqlError::Other(From::from(e)))
+    }
+}
+
+impl FromSql for MediaKind {
+    fn column_result(value: ValueRef) -> FromSqlResult<MediaKind> {
+        let str = String::column_result(value)?;
+        MediaKind::from_str(&str).map_err(|e| FromSqlError::Other(From::from(e)))
     }
 }
 
@@ -522,11 +1739,41 @@ impl TryFrom<&Row<'_>> for Subscription {
 
     fn try_from(row: &Row<'_>) -> Result<Self, Self::Error> {
         Ok(Self {
+            kind: row.get_unwrap("kind"),
             subreddit: row.get_unwrap("subreddit"),
             chat_id: row.get_unwrap("chat_id"),
             limit: row.get_unwrap("post_limit"),
             time: row.get_unwrap("time"),
-            filter: row.get_unwrap("filter"),
+            // Older subscriptions have a single type, which parses the same
+            filter: row.get_unwrap::<_, Option<String>>("filter").map(|f| {
+                f.split(',')
+                    .map(|t| PostType::from_str(t).expect("Filter should be valid post types"))
+                    .collect()
+            }),
+            domains: row
+                .get_unwrap::<_, Option<String>>("domains")
+                .map(|d| d.split(',').map(ToString::to_string).collect()),
+            flairs: row
+                .get_unwrap::<_, Option<String>>("flairs")
+                .map(|f| f.split(',').map(ToString::to_string).collect()),
+            template: row.get_unwrap("template"),
+            digest: row.get_unwrap("digest"),
+            selftext: row.get_unwrap("selftext"),
+            photo_as_document: row.get_unwrap("photo_as_document"),
+            caption: row.get_unwrap("caption"),
+            comments: row.get_unwrap("comments"),
+            comment_sort: row.get_unwrap("comment_sort"),
+            topic: row.get_unwrap("topic"),
+            skip_stickied: row.get_unwrap("skip_stickied"),
+            only_newer_than_sub: row.get_unwrap("only_newer_than_sub"),
+            min_comments: row.get_unwrap("min_comments"),
+            link_preview: row.get_unwrap("link_preview"),
+            attach_source: row.get_unwrap("attach_source"),
+            media_only: row.get_unwrap("media_only"),
+            extended_info: row.get_unwrap("extended_info"),
+            paused: row.get_unwrap("paused"),
+            primed: row.get_unwrap("primed"),
+            created_at: row.get_unwrap("created_at"),
         })
     }
 }
@@ -536,6 +1783,117 @@ mod tests {
     use super::*;
     use crate::reddit::PostType;
 
+    #[test]
+    fn test_db_migration_status() {
+        let config = Config::default();
+        let mut db = Database::open(&config).unwrap();
+        assert_eq!(db.migration_status().unwrap(), (0, MIGRATIONS.len()));
+        db.migrate().unwrap();
+        assert_eq!(
+            db.migration_status().unwrap(),
+            (MIGRATIONS.len(), MIGRATIONS.len())
+        );
+    }
+
+    #[test]
+    fn test_db_post_permalink() {
+        let config = Config::default();
+        let mut db = Database::open(&config).unwrap();
+        db.migrate().unwrap();
+        let post = Post {
+            id: "v6nu75".into(),
+            subreddit: "absoluteunit".into(),
+            title: "Tipping a cow to trim its hooves".into(),
+            permalink: "/r/absoluteunit/comments/v6nu75/tipping_a_cow_to_trim_its_hooves/".into(),
+            ..Default::default()
+        };
+        db.record_post_seen_with_current_time(1, &post).unwrap();
+        assert_eq!(
+            db.get_post_permalink(1, "v6nu75").unwrap().as_deref(),
+            Some("/r/absoluteunit/comments/v6nu75/tipping_a_cow_to_trim_its_hooves/")
+        );
+        assert_eq!(db.get_post_permalink(2, "v6nu75").unwrap(), None);
+    }
+
+    #[test]
+    fn test_concurrent_connections() {
+        let dir = tempfile::tempdir().unwrap();
+        let db_path = dir.path().join("data.db3");
+        let open = || {
+            let conn = Connection::open(&db_path).unwrap();
+            configure_connection(&conn).unwrap();
+            conn
+        };
+        let mut writer = open();
+        writer
+            .execute_batch("create table t(x integer); insert into t values (1);")
+            .unwrap();
+        let reader = open();
+
+        let tx = writer.transaction().unwrap();
+        tx.execute("insert into t values (2)", []).unwrap();
+        // Reading while another connection is writing sees the last committed state
+        let count: i64 = reader
+            .query_row("select count(*) from t", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(count, 1);
+
+        // A second writer waits for the first one instead of failing
+        let other_writer = std::thread::spawn({
+            let conn = open();
+            move || conn.execute("insert into t values (3)", []).unwrap()
+        });
+        std::thread::sleep(Duration::from_millis(200));
+        tx.commit().unwrap();
+        other_writer.join().unwrap();
+        let count: i64 = reader
+            .query_row("select count(*) from t", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(count, 3);
+    }
+
+    #[test]
+    fn test_connection_pool() {
+        let dir = tempfile::tempdir().unwrap();
+        let db_path = dir.path().join("data.db3");
+        assert!(take_idle_connection(&db_path).is_none());
+
+        let conn = Connection::open(&db_path).unwrap();
+        conn.execute_batch("create table t(x integer)").unwrap();
+        release_connection(&db_path, conn);
+        let conn = take_idle_connection(&db_path).unwrap();
+        conn.execute("insert into t values (1)", []).unwrap();
+        assert!(take_idle_connection(&db_path).is_none());
+
+        // In-memory databases are not shared
+        let path = Path::new(":memory:");
+        release_connection(path, Connection::open_in_memory().unwrap());
+        assert!(take_idle_connection(path).is_none());
+    }
+
+    #[test]
+    fn test_db_vacuum() {
+        let config = Config::default();
+        let mut db = Database::open(&config).unwrap();
+        db.migrate().unwrap();
+        let post = Post {
+            id: "123".to_string(),
+            subreddit: "test".to_string(),
+            title: "title".to_string(),
+            ..Default::default()
+        };
+        for chat_id in 0..500 {
+            db.record_post(chat_id, &post, None).unwrap();
+        }
+        db.conn
+            .lock()
+            .unwrap()
+            .execute("delete from post", [])
+            .unwrap();
+        let (before, after) = db.vacuum().unwrap();
+        assert!(after < before);
+    }
+
     #[test]
     fn test_db() {
         let config = Config::default();
@@ -551,6 +1909,7 @@ mod tests {
             permalink: "/r/absoluteunit/comments/v6nu75/tipping_a_cow_to_trim_its_hooves/".into(),
             url: "https://i.imgur.com/Zt6f5mB.gifv".into(),
             post_type: PostType::Video,
+            ..Default::default()
         };
 
         assert!(!db.existing_posts_for_subreddit(1, "absoluteunit").unwrap());
@@ -565,14 +1924,35 @@ mod tests {
         let mut db = Database::open(&config).unwrap();
         db.migrate().unwrap();
         let subscription_args = SubscriptionArgs {
+            kind: SubscriptionKind::Subreddit,
             subreddit: "test".to_string(),
             limit: Some(1),
             time: Some(TopPostsTimePeriod::Week),
-            filter: Some(PostType::Video),
+            filter: Some(vec![PostType::Video, PostType::Gallery]),
+            domains: Some(vec!["github.com".to_string(), "gitlab.com".to_string()]),
+            flairs: Some(vec!["news".to_string(), "breaking news".to_string()]),
+            template: Some("{title} ▲{score}".to_string()),
+            digest: true,
+            selftext: true,
+            photo_as_document: true,
+            caption: Some(CaptionMode::Title),
+            comments: Some(3),
+            comment_sort: Some(CommentSort::Best),
+            topic: Some(42),
+            skip_stickied: Some(false),
+            only_newer_than_sub: true,
+            min_comments: Some(10),
+            link_preview: Some(LinkPreview::Large),
+            attach_source: true,
+            media_only: true,
+            extended_info: true,
+            group_media: false,
         };
         db.subscribe(1, &subscription_args).unwrap();
 
         let subs = db.get_subscriptions_for_chat(1).unwrap();
+        let created_at = subs[0].created_at;
+        assert!(chrono::Utc::now() - created_at < chrono::Duration::minutes(1));
         assert_eq!(
             subs,
             vec![Subscription {
@@ -580,11 +1960,48 @@ mod tests {
                 subreddit: "test".to_string(),
                 limit: Some(1),
                 time: Some(TopPostsTimePeriod::Week),
-                filter: Some(PostType::Video),
+                filter: Some(vec![PostType::Video, PostType::Gallery]),
+                domains: Some(vec!["github.com".to_string(), "gitlab.com".to_string()]),
+                flairs: Some(vec!["news".to_string(), "breaking news".to_string()]),
+                template: Some("{title} ▲{score}".to_string()),
+                digest: true,
+                selftext: true,
+                photo_as_document: true,
+                caption: Some(CaptionMode::Title),
+                comments: Some(3),
+                comment_sort: Some(CommentSort::Best),
+                topic: Some(42),
+                skip_stickied: Some(false),
+                only_newer_than_sub: true,
+                min_comments: Some(10),
+                link_preview: Some(LinkPreview::Large),
+                attach_source: true,
+                media_only: true,
+                extended_info: true,
+                created_at,
+                ..Default::default()
             }]
         );
     }
 
+    #[test]
+    fn test_db_subscribe_user() {
+        let config = Config::default();
+        let mut db = Database::open(&config).unwrap();
+        db.migrate().unwrap();
+        let subscription_args = SubscriptionArgs {
+            kind: SubscriptionKind::User,
+            subreddit: "spez".to_string(),
+            ..Default::default()
+        };
+        db.subscribe(1, &subscription_args).unwrap();
+
+        let subs = db.get_subscriptions_for_chat(1).unwrap();
+        assert_eq!(subs.len(), 1);
+        assert_eq!(subs[0].kind, SubscriptionKind::User);
+        assert_eq!(subs[0].subreddit, "spez");
+    }
+
     #[test]
     fn test_db_unsubscribe() {
         let config = Config::default();
@@ -594,15 +2011,511 @@ mod tests {
             subreddit: "test".to_string(),
             limit: Some(1),
             time: Some(TopPostsTimePeriod::Week),
-            filter: Some(PostType::Video),
+            filter: Some(vec![PostType::Video]),
+            ..Default::default()
         };
         db.subscribe(1, &subscription_args).unwrap();
         let subs = db.get_subscriptions_for_chat(1).unwrap();
         assert_eq!(subs.len(), 1);
-        let deleted = db.unsubscribe(1, "test").unwrap();
+        assert!(db.unsubscribe(1, SubscriptionKind::User, "test").is_err());
+        // A user with the same name is a separate subscription
+        db.subscribe(
+            1,
+            &SubscriptionArgs {
+                kind: SubscriptionKind::User,
+                ..subscription_args.clone()
+            },
+        )
+        .unwrap();
+        assert_eq!(db.get_subscriptions_for_chat(1).unwrap().len(), 2);
+        let deleted = db
+            .unsubscribe(1, SubscriptionKind::Subreddit, "test")
+            .unwrap();
         assert_eq!(deleted, "test");
         let subs = db.get_subscriptions_for_chat(1).unwrap();
-        assert_eq!(subs, vec![]);
+        assert_eq!(subs.len(), 1);
+        assert_eq!(subs[0].kind, SubscriptionKind::User);
+    }
+
+    #[test]
+    fn test_db_subscribe_time_periods() {
+        let config = Config::default();
+        let mut db = Database::open(&config).unwrap();
+        db.migrate().unwrap();
+        let times = [
+            TopPostsTimePeriod::Hour,
+            TopPostsTimePeriod::Day,
+            TopPostsTimePeriod::Week,
+            TopPostsTimePeriod::Month,
+            TopPostsTimePeriod::Year,
+            TopPostsTimePeriod::All,
+        ];
+        for (i, time) in times.iter().enumerate() {
+            let subscription_args = SubscriptionArgs {
+                subreddit: format!("test{i}"),
+                time: Some(*time),
+                ..Default::default()
+            };
+            db.subscribe(1, &subscription_args).unwrap();
+        }
+
+        let subs = db.get_subscriptions_for_chat(1).unwrap();
+        let mut stored = subs.iter().map(|s| s.time.unwrap()).collect::<Vec<_>>();
+        stored.sort_by_key(|t| times.iter().position(|x| x == t));
+        assert_eq!(stored, times);
+    }
+
+    #[test]
+    fn test_db_telegram_files_from_any_chat() {
+        let config = Config::default();
+        let mut db = Database::open(&config).unwrap();
+        db.migrate().unwrap();
+        let post = Post {
+            id: "v6nu75".into(),
+            subreddit: "test".into(),
+            title: "Gallery".into(),
+            post_type: PostType::Gallery,
+            ..Default::default()
+        };
+        assert_eq!(
+            db.get_telegram_files_for_post_from_any_chat(&post.id)
+                .unwrap(),
+            vec![]
+        );
+
+        for chat_id in [1, 2] {
+            db.record_post_seen_with_current_time(chat_id, &post)
+                .unwrap();
+        }
+        let dimensions = Dimensions {
+            width: 720,
+            height: 1280,
+        };
+        for (chat_id, file, kind, dimensions) in [
+            (1, "a", MediaKind::Photo, None),
+            (1, "b", MediaKind::Video, Some(dimensions)),
+            (2, "c", MediaKind::Photo, None),
+        ] {
+            db.add_telegram_file(
+                &post.id,
+                chat_id,
+                &file.to_string().into(),
+                &FileUniqueId(file.to_string()),
+                kind,
+                dimensions,
+            )
+            .unwrap();
+        }
+
+        let files = db
+            .get_telegram_files_for_post_from_any_chat(&post.id)
+            .unwrap();
+        assert_eq!(
+            files,
+            vec![
+                TelegramFile {
+                    file_id: "a".to_string().into(),
+                    kind: MediaKind::Photo,
+                    dimensions: None,
+                },
+                TelegramFile {
+                    file_id: "b".to_string().into(),
+                    kind: MediaKind::Video,
+                    dimensions: Some(dimensions),
+                },
+            ]
+        );
+        assert_eq!(
+            db.get_telegram_files_for_post(&post.id, 2).unwrap(),
+            vec![TelegramFile {
+                file_id: "c".to_string().into(),
+                kind: MediaKind::Photo,
+                dimensions: None,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_db_repost_channel() {
+        let config = Config::default();
+        let mut db = Database::open(&config).unwrap();
+        db.migrate().unwrap();
+        assert_eq!(db.get_repost_channel(1).unwrap(), None);
+        db.set_repost_channel(1, -100123).unwrap();
+        assert_eq!(db.get_repost_channel(1).unwrap(), Some(-100123));
+        db.clear_repost_channel(1).unwrap();
+        assert_eq!(db.get_repost_channel(1).unwrap(), None);
+    }
+
+    #[test]
+    fn test_db_show_repost_buttons() {
+        let config = Config::default();
+        let mut db = Database::open(&config).unwrap();
+        db.migrate().unwrap();
+        assert_eq!(db.get_show_repost_buttons(1).unwrap(), None);
+        db.set_show_repost_buttons(1, Some(false)).unwrap();
+        assert_eq!(db.get_show_repost_buttons(1).unwrap(), Some(false));
+        db.set_show_repost_buttons(1, None).unwrap();
+        assert_eq!(db.get_show_repost_buttons(1).unwrap(), None);
+    }
+
+    #[test]
+    fn test_db_timezone() {
+        let config = Config::default();
+        let mut db = Database::open(&config).unwrap();
+        db.migrate().unwrap();
+        assert_eq!(db.get_timezone(1).unwrap(), None);
+        let timezone = Tz::America__St_Johns;
+        db.set_timezone(1, Some(timezone)).unwrap();
+        assert_eq!(db.get_timezone(1).unwrap(), Some(timezone));
+        db.set_timezone(1, None).unwrap();
+        assert_eq!(db.get_timezone(1).unwrap(), None);
+    }
+
+    #[test]
+    fn test_db_post_debug_info() {
+        let config = Config::default();
+        let mut db = Database::open(&config).unwrap();
+        db.migrate().unwrap();
+        assert_eq!(
+            db.get_post_debug_info(1, "a").unwrap(),
+            PostDebugInfo::default()
+        );
+
+        let post = Post {
+            id: "a".into(),
+            subreddit: "pics".into(),
+            title: "title".into(),
+            ..Default::default()
+        };
+        db.record_post(1, &post, None).unwrap();
+        let info = db.get_post_debug_info(1, "a").unwrap();
+        assert!(info.recorded);
+        assert_eq!(info.seen_at, None);
+
+        db.record_post_seen_with_current_time(1, &post).unwrap();
+        db.add_telegram_file(
+            "a",
+            1,
+            &"file".to_string().into(),
+            &FileUniqueId("unique".into()),
+            MediaKind::Photo,
+            None,
+        )
+        .unwrap();
+        db.mute_post(1, "a").unwrap();
+        let info = db.get_post_debug_info(1, "a").unwrap();
+        assert!(info.seen_at.is_some());
+        assert_eq!(info.subreddit.as_deref(), Some("pics"));
+        assert_eq!(info.title.as_deref(), Some("title"));
+        assert_eq!(info.telegram_files, 1);
+        assert!(info.muted);
+        assert!(!db.get_post_debug_info(2, "a").unwrap().recorded);
+    }
+
+    #[test]
+    fn test_db_subscription_primed() {
+        let config = Config::default();
+        let mut db = Database::open(&config).unwrap();
+        db.migrate().unwrap();
+        let args = SubscriptionArgs {
+            subreddit: "test".to_string(),
+            ..Default::default()
+        };
+        db.subscribe(1, &args).unwrap();
+        assert!(!db.get_subscriptions_for_chat(1).unwrap()[0].primed);
+
+        db.mark_subscription_primed(1, SubscriptionKind::Subreddit, "test")
+            .unwrap();
+        assert!(db.get_subscriptions_for_chat(1).unwrap()[0].primed);
+    }
+
+    #[test]
+    fn test_db_clear_seen_for_subreddit() {
+        let config = Config::default();
+        let mut db = Database::open(&config).unwrap();
+        db.migrate().unwrap();
+        let post = |id: &str, subreddit: &str| Post {
+            id: id.into(),
+            subreddit: subreddit.into(),
+            title: "title".into(),
+            ..Default::default()
+        };
+        let cleared = post("v6nu75", "pics");
+        let other_subreddit = post("abc123", "aww");
+        for (chat_id, post) in [(1, &cleared), (1, &other_subreddit), (2, &cleared)] {
+            db.record_post_seen_with_current_time(chat_id, post)
+                .unwrap();
+        }
+        db.add_telegram_file(
+            &cleared.id,
+            1,
+            &"a".to_string().into(),
+            &FileUniqueId("a".to_string()),
+            MediaKind::Photo,
+            None,
+        )
+        .unwrap();
+        db.record_media_seen(1, "https://imgur.com/a", &cleared.id)
+            .unwrap();
+
+        assert_eq!(db.clear_seen_for_subreddit(1, "Pics").unwrap(), 1);
+        assert!(!db.is_post_seen(1, &cleared).unwrap());
+        assert!(db
+            .get_telegram_files_for_post(&cleared.id, 1)
+            .unwrap()
+            .is_empty());
+        assert!(!db.is_media_seen(1, "https://imgur.com/a").unwrap());
+        assert!(db.is_post_seen(1, &other_subreddit).unwrap());
+        assert!(db.is_post_seen(2, &cleared).unwrap());
+        assert_eq!(db.clear_seen_for_subreddit(1, "pics").unwrap(), 0);
+    }
+
+    #[test]
+    fn test_db_move_chat() {
+        let config = Config::default();
+        let mut db = Database::open(&config).unwrap();
+        db.migrate().unwrap();
+        let args = |subreddit: &str, limit| SubscriptionArgs {
+            subreddit: subreddit.into(),
+            limit: Some(limit),
+            ..Default::default()
+        };
+        let post = |id: &str| Post {
+            id: id.into(),
+            subreddit: "pics".into(),
+            title: "title".into(),
+            ..Default::default()
+        };
+        db.subscribe(1, &args("pics", 1)).unwrap();
+        db.subscribe(1, &args("aww", 1)).unwrap();
+        db.subscribe(2, &args("Pics", 5)).unwrap();
+        db.set_repost_channel(1, -100123).unwrap();
+        for (chat_id, post) in [(1, post("a")), (1, post("b")), (2, post("b"))] {
+            db.record_post_seen_with_current_time(chat_id, &post)
+                .unwrap();
+            db.add_telegram_file(
+                &post.id,
+                chat_id,
+                &format!("{chat_id}{}", post.id).into(),
+                &FileUniqueId(post.id.clone()),
+                MediaKind::Photo,
+                None,
+            )
+            .unwrap();
+        }
+        db.mute_post(1, "c").unwrap();
+
+        assert!(db.move_chat(1, 1).is_err());
+        assert_eq!(
+            db.move_chat(1, 2).unwrap(),
+            MovedChat {
+                subscriptions: 1,
+                conflicting_subscriptions: vec!["r/pics".to_string()],
+            }
+        );
+
+        assert!(db.get_subscriptions_for_chat(1).unwrap().is_empty());
+        let subs = db.get_subscriptions_for_chat(2).unwrap();
+        let limits = subs
+            .iter()
+            .map(|sub| (sub.subreddit.as_str(), sub.limit))
+            .collect::<Vec<_>>();
+        assert_eq!(limits, vec![("aww", Some(1)), ("Pics", Some(5))]);
+        assert_eq!(db.get_repost_channel(2).unwrap(), Some(-100123));
+        assert!(db.is_post_seen(2, &post("a")).unwrap());
+        assert!(!db.is_post_seen(1, &post("a")).unwrap());
+        assert_eq!(db.get_telegram_files_for_post("a", 2).unwrap().len(), 1);
+        // The target chat's own file of a post both chats had is kept
+        assert_eq!(
+            db.get_telegram_files_for_post("b", 2).unwrap()[0].file_id,
+            "2b".to_string().into()
+        );
+        assert!(db.is_post_muted(2, &post("c")).unwrap());
+    }
+
+    #[test]
+    fn test_db_muted_posts() {
+        let config = Config::default();
+        let mut db = Database::open(&config).unwrap();
+        db.migrate().unwrap();
+        let post = Post {
+            id: "v6nu75".into(),
+            ..Default::default()
+        };
+        let crosspost = Post {
+            id: "abc123".into(),
+            crosspost_parent: Some("t3_v6nu75".into()),
+            ..Default::default()
+        };
+        assert!(!db.is_post_muted(1, &post).unwrap());
+
+        db.mute_post(1, "v6nu75").unwrap();
+        assert!(db.is_post_muted(1, &post).unwrap());
+        assert!(db.is_post_muted(1, &crosspost).unwrap());
+        assert!(!db.is_post_muted(2, &post).unwrap());
+
+        assert!(db.unmute_post(1, "v6nu75").unwrap());
+        assert!(!db.unmute_post(1, "v6nu75").unwrap());
+        assert!(!db.is_post_muted(1, &crosspost).unwrap());
+    }
+
+    #[test]
+    fn test_db_seen_media() {
+        let config = Config::default();
+        let mut db = Database::open(&config).unwrap();
+        db.migrate().unwrap();
+        let url = "https://imgur.com/abc123";
+        assert!(!db.is_media_seen(1, url).unwrap());
+
+        db.record_media_seen(1, url, "v6nu75").unwrap();
+        db.record_media_seen(1, url, "abc123").unwrap();
+        assert!(db.is_media_seen(1, url).unwrap());
+        assert!(!db.is_media_seen(2, url).unwrap());
+    }
+
+    #[test]
+    fn test_db_quiet_hours() {
+        let config = Config::default();
+        let mut db = Database::open(&config).unwrap();
+        db.migrate().unwrap();
+        assert_eq!(db.get_quiet_hours(1).unwrap(), None);
+
+        let quiet_hours = QuietHours {
+            start: chrono::NaiveTime::from_hms_opt(23, 0, 0).unwrap(),
+            end: chrono::NaiveTime::from_hms_opt(7, 30, 0).unwrap(),
+        };
+        db.set_quiet_hours(1, Some(quiet_hours)).unwrap();
+        assert_eq!(db.get_quiet_hours(1).unwrap(), Some(quiet_hours));
+
+        db.set_quiet_hours(1, None).unwrap();
+        assert_eq!(db.get_quiet_hours(1).unwrap(), None);
+    }
+
+    #[test]
+    fn test_db_pending_posts() {
+        let config = Config::default();
+        let mut db = Database::open(&config).unwrap();
+        db.migrate().unwrap();
+        let post = Post {
+            id: "v6nu75".into(),
+            subreddit: "absoluteunit".into(),
+            title: "title".into(),
+            ..Default::default()
+        };
+        db.hold_post(1, &post, SubscriptionKind::User, "spez")
+            .unwrap();
+        assert!(!db.is_post_seen(1, &post).unwrap());
+        assert_eq!(
+            db.get_pending_posts(1).unwrap(),
+            vec![PendingPost {
+                post_id: "v6nu75".into(),
+                subscription: Some((SubscriptionKind::User, "spez".into())),
+            }]
+        );
+
+        db.mark_pending_post_seen(1, "v6nu75").unwrap();
+        assert!(db.is_post_seen(1, &post).unwrap());
+        assert!(db.get_pending_posts(1).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_db_recent_post_ids() {
+        let config = Config::default();
+        let mut db = Database::open(&config).unwrap();
+        db.migrate().unwrap();
+        let now = chrono::Utc::now();
+        for (i, id) in ["aaa111", "bbb222", "ccc333"].into_iter().enumerate() {
+            let post = Post {
+                id: id.into(),
+                subreddit: "AbsoluteUnit".into(),
+                title: "title".into(),
+                ..Default::default()
+            };
+            let seen_at = now + chrono::Duration::seconds(i as i64);
+            db.record_post(1, &post, Some(seen_at)).unwrap();
+        }
+        let pending = Post {
+            id: "ddd444".into(),
+            subreddit: "AbsoluteUnit".into(),
+            title: "title".into(),
+            ..Default::default()
+        };
+        db.record_post(1, &pending, None).unwrap();
+
+        assert_eq!(
+            db.get_recent_post_ids(1, "absoluteunit", 2).unwrap(),
+            vec!["ccc333", "bbb222"]
+        );
+        assert!(db
+            .get_recent_post_ids(2, "absoluteunit", 2)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_db_recent_titles_for_subreddit() {
+        let config = Config::default();
+        let mut db = Database::open(&config).unwrap();
+        db.migrate().unwrap();
+        let now = chrono::Utc::now();
+        for (id, title, hours_ago) in [("aaa111", "old", 48), ("bbb222", "new", 1)] {
+            let post = Post {
+                id: id.into(),
+                subreddit: "AbsoluteUnit".into(),
+                title: title.into(),
+                ..Default::default()
+            };
+            let seen_at = now - chrono::Duration::hours(hours_ago);
+            db.record_post(1, &post, Some(seen_at)).unwrap();
+        }
+        let pending = Post {
+            id: "ccc333".into(),
+            subreddit: "AbsoluteUnit".into(),
+            title: "pending".into(),
+            ..Default::default()
+        };
+        db.record_post(1, &pending, None).unwrap();
+
+        let since = now - chrono::Duration::hours(24);
+        assert_eq!(
+            db.recent_titles_for_subreddit(1, "absoluteunit", since)
+                .unwrap()
+                .into_iter()
+                .sorted()
+                .collect::<Vec<_>>(),
+            vec!["new", "pending"]
+        );
+        assert!(db
+            .recent_titles_for_subreddit(2, "absoluteunit", since)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_db_subscription_failures() {
+        let config = Config::default();
+        let mut db = Database::open(&config).unwrap();
+        db.migrate().unwrap();
+        let subscription_args = SubscriptionArgs {
+            subreddit: "test".to_string(),
+            ..Default::default()
+        };
+        db.subscribe(1, &subscription_args).unwrap();
+        let kind = SubscriptionKind::Subreddit;
+        assert_eq!(db.record_subscription_failure(1, kind, "test").unwrap(), 1);
+        assert_eq!(db.record_subscription_failure(1, kind, "test").unwrap(), 2);
+        db.reset_subscription_failures(1, kind, "test").unwrap();
+        assert_eq!(db.record_subscription_failure(1, kind, "test").unwrap(), 1);
+
+        db.pause_subscription(1, kind, "test").unwrap();
+        let subs = db.get_subscriptions_for_chat(1).unwrap();
+        assert!(subs[0].paused);
+
+        // Subscribing again resumes the subscription
+        db.subscribe(1, &subscription_args).unwrap();
+        let subs = db.get_subscriptions_for_chat(1).unwrap();
+        assert!(!subs[0].paused);
     }
 
     #[test]
@@ -614,7 +2527,8 @@ mod tests {
             subreddit: "test".to_string(),
             limit: Some(1),
             time: Some(TopPostsTimePeriod::Week),
-            filter: Some(PostType::Video),
+            filter: Some(vec![PostType::Video]),
+            ..Default::default()
         };
         db.subscribe(1, &subscription_args).unwrap();
         let post = Post {
@@ -627,10 +2541,12 @@ mod tests {
             permalink: "/r/test/comments/v6nu75/tipping_a_cow_to_trim_its_hooves/".into(),
             url: "https://i.imgur.com/Zt6f5mB.gifv".into(),
             post_type: PostType::Video,
+            ..Default::default()
         };
         db.record_post_seen_with_current_time(1, &post).unwrap();
         assert!(db.is_post_seen(1, &post).unwrap());
-        db.unsubscribe(1, "test").unwrap();
+        db.unsubscribe(1, SubscriptionKind::Subreddit, "test")
+            .unwrap();
         assert!(db.is_post_seen(1, &post).unwrap());
     }
 }
@@ -0,0 +1,57 @@
+use log::*;
+use serde::Serialize;
+use std::time::Duration;
+use teloxide::types::MessageId;
+
+use crate::{config::Config, reddit};
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Body of the request made to `post_delivered_webhook_url` after a post has been sent.
+#[derive(Serialize, Debug)]
+struct PostDelivered<'a> {
+    post_id: &'a str,
+    subreddit: &'a str,
+    chat_id: i64,
+    post_type: String,
+    url: &'a str,
+    message_id: i32,
+}
+
+/// Lets `post_delivered_webhook_url` know that the post was sent to the chat, if it's set. The
+/// request is made in the background and failures are only logged, so that they don't affect
+/// sending posts.
+pub fn notify(config: &Config, chat_id: i64, post: &reddit::Post, message_id: MessageId) {
+    let Some(webhook_url) = config.post_delivered_webhook_url.clone() else {
+        return;
+    };
+    let body = serde_json::to_string(&PostDelivered {
+        post_id: &post.id,
+        subreddit: &post.subreddit,
+        chat_id,
+        post_type: post.post_type.to_string(),
+        url: &post.url,
+        message_id: message_id.0,
+    })
+    .expect("serializing can't fail");
+    let post_id = post.id.clone();
+
+    tokio::spawn(async move {
+        let result = async {
+            reqwest::Client::builder()
+                .timeout(TIMEOUT)
+                .build()?
+                .post(&webhook_url)
+                .header(reqwest::header::CONTENT_TYPE, "application/json")
+                .body(body)
+                .send()
+                .await?
+                .error_for_status()
+        }
+        .await;
+        match result {
+            Ok(_) => debug!("notified webhook of delivered post_id={post_id} chat_id={chat_id}"),
+            Err(err) => warn!("failed to notify webhook of delivered post_id={post_id}: {err}"),
+        }
+    });
+}
@@ -1,33 +1,462 @@
 use anyhow::{Context, Result};
 use log::*;
 
-use std::io::Write;
+use crate::{
+    config::{self, Config},
+    reddit,
+};
+
+use reqwest::StatusCode;
+use std::io::{Read, Write};
+use std::time::Duration;
 use std::{
     fs::File,
     path::{Path, PathBuf},
 };
 use tempfile::TempDir;
+use thiserror::Error;
 use url::Url;
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MediaType {
+    Jpeg,
+    Png,
+    Webp,
+    AnimatedWebp,
+    Gif,
+    Mp4,
+    Audio,
+    Unknown,
+}
+
+impl MediaType {
+    /// Whether the media should be sent to Telegram as video rather than photo.
+    pub fn is_animated(&self) -> bool {
+        matches!(
+            self,
+            MediaType::Gif | MediaType::Mp4 | MediaType::AnimatedWebp
+        )
+    }
+}
+
+/// Prefix of the temp dirs created for downloads.
+const TMP_DIR_PREFIX: &str = "tgreddit";
+
+/// Temp dirs older than this are left over from a crashed process, as downloads don't take this
+/// long. Younger ones may belong to another instance running at the same time.
+const STALE_TMP_DIR_AGE: Duration = Duration::from_secs(60 * 60);
+
+/// The media of a post has been deleted, as opposed to failing to download for a reason that may
+/// go away on retry.
+#[derive(Error, Debug)]
+#[error("media is no longer available: {0}")]
+pub struct MediaUnavailable(pub String);
+
+/// The media is larger than what is allowed to be downloaded.
+#[derive(Error, Debug)]
+#[error("media is larger than {0} bytes")]
+pub struct MediaTooLarge(pub u64);
+
+/// The host of the media is not allowed by `allowed_media_hosts` or `blocked_media_hosts`.
+#[derive(Error, Debug)]
+#[error("media host is not allowed: {0}")]
+pub struct MediaHostNotAllowed(pub String);
+
+/// Errors if media may not be downloaded from the url.
+pub fn check_media_host(url: &str, config: &Config) -> Result<()> {
+    if config.is_media_host_allowed(url) {
+        Ok(())
+    } else {
+        Err(MediaHostNotAllowed(url.to_string()).into())
+    }
+}
+
+const DOWNLOAD_ATTEMPTS: u32 = 3;
+/// Same as reqwest's default redirect policy
+const MAX_REDIRECTS: usize = 10;
+
+/// Follows redirects only to allowed media hosts, so that a disallowed host is never contacted.
+fn media_redirect_policy(config: &Config) -> reqwest::redirect::Policy {
+    let allowed = config.allowed_media_hosts.clone();
+    let blocked = config.blocked_media_hosts.clone();
+    reqwest::redirect::Policy::custom(move |attempt| {
+        let url = attempt.url().to_string();
+        if !config::is_media_host_allowed(allowed.as_deref(), &blocked, &url) {
+            attempt.error(MediaHostNotAllowed(url))
+        } else if attempt.previous().len() >= MAX_REDIRECTS {
+            attempt.error("too many redirects")
+        } else {
+            attempt.follow()
+        }
+    })
+}
+
+/// Gets the url, retrying with backoff on connection errors, timeouts and server errors.
+async fn get_with_retry(url: &str, config: &Config) -> Result<reqwest::Response> {
+    let client = reddit::create_client()
+        .redirect(media_redirect_policy(config))
+        .build()?;
+    let parsed_url = Url::parse(url).with_context(|| format!("invalid url {url}"))?;
+    let mut attempt = 1;
+    loop {
+        match reddit::get_request(&client, parsed_url.clone())
+            .send()
+            .await
+        {
+            Ok(res) if res.status().is_server_error() && attempt < DOWNLOAD_ATTEMPTS => {
+                warn!("got {} downloading {url}, retrying", res.status());
+            }
+            Err(err) if (err.is_connect() || err.is_timeout()) && attempt < DOWNLOAD_ATTEMPTS => {
+                warn!("failed to download {url}, retrying: {err}");
+            }
+            Err(err) if err.is_redirect() => {
+                let not_allowed = std::error::Error::source(&err)
+                    .and_then(|source| source.downcast_ref::<MediaHostNotAllowed>());
+                return match not_allowed {
+                    Some(MediaHostNotAllowed(url)) => Err(MediaHostNotAllowed(url.clone()).into()),
+                    None => Err(err.into()),
+                };
+            }
+            res => return Ok(res?),
+        }
+        tokio::time::sleep(Duration::from_secs(2u64.pow(attempt))).await;
+        attempt += 1;
+    }
+}
+
+/// Creates a temp dir for downloads in `download_dir`, or in the system temp dir if it's not set.
+/// The dir and its contents are deleted when the returned value is dropped.
+pub fn create_tmp_dir(config: &Config) -> Result<TempDir> {
+    match &config.download_dir {
+        Some(dir) => TempDir::with_prefix_in(TMP_DIR_PREFIX, dir)
+            .with_context(|| format!("failed to create temp dir in {dir:?}")),
+        None => TempDir::with_prefix(TMP_DIR_PREFIX).context("failed to create temp dir"),
+    }
+}
+
+/// Removes temp dirs left behind by a process that exited before it could clean them up. Returns
+/// the number of removed dirs.
+pub fn cleanup_stale_tmp_dirs(config: &Config) -> Result<usize> {
+    let base_dir = config
+        .download_dir
+        .clone()
+        .unwrap_or_else(std::env::temp_dir);
+    // The video cache could be configured in the temp dir with a name that has the same prefix
+    let keep = config.video_cache_dir.as_deref();
+    cleanup_stale_tmp_dirs_in(&base_dir, STALE_TMP_DIR_AGE, keep)
+}
+
+fn cleanup_stale_tmp_dirs_in(
+    base_dir: &Path,
+    max_age: Duration,
+    keep: Option<&Path>,
+) -> Result<usize> {
+    let mut removed = 0;
+    for entry in
+        std::fs::read_dir(base_dir).with_context(|| format!("failed to read {base_dir:?}"))?
+    {
+        let entry = entry?;
+        if !entry
+            .file_name()
+            .to_string_lossy()
+            .starts_with(TMP_DIR_PREFIX)
+        {
+            continue;
+        }
+        let metadata = entry.metadata()?;
+        let age = metadata.modified()?.elapsed().unwrap_or(Duration::ZERO);
+        if !metadata.is_dir() || age < max_age {
+            continue;
+        }
+
+        let path = entry.path();
+        if keep == Some(path.as_path()) {
+            continue;
+        }
+        match std::fs::remove_dir_all(&path) {
+            Ok(()) => {
+                info!("removed stale temp dir {path:?}");
+                removed += 1;
+            }
+            Err(err) => warn!("failed to remove stale temp dir {path:?}: {err}"),
+        }
+    }
+    Ok(removed)
+}
+
+/// Creates `download_dir` if it's missing and checks that temp dirs can be created in it.
+pub fn prepare_download_dir(config: &Config) -> Result<()> {
+    let Some(dir) = &config.download_dir else {
+        return Ok(());
+    };
+    std::fs::create_dir_all(dir).with_context(|| format!("failed to create {dir:?}"))?;
+    create_tmp_dir(config).with_context(|| format!("download_dir {dir:?} is not writable"))?;
+    Ok(())
+}
+
+#[derive(Debug)]
+pub struct DownloadedFile {
+    pub path: PathBuf,
+    pub media_type: MediaType,
+    pub _tmp_dir: TempDir,
+}
+
 /// Downloads url to a file and returns the path along with handle to temp dir in which the file is.
 /// Whe the temp dir value is dropped, the contents in file system are deleted.
-pub async fn download_url_to_tmp(url: &str) -> Result<(PathBuf, TempDir)> {
+///
+/// The type of media is detected from the file contents, falling back to the Content-Type header,
+/// as the extension in url is not reliable.
+pub async fn download_url_to_tmp(url: &str, config: &Config) -> Result<DownloadedFile> {
+    download_url_to_tmp_limited(url, config, None).await
+}
+
+/// Like `download_url_to_tmp`, but stops with `MediaTooLarge` if the media is larger than
+/// `max_bytes`.
+pub async fn download_url_to_tmp_limited(
+    url: &str,
+    config: &Config,
+    max_bytes: Option<u64>,
+) -> Result<DownloadedFile> {
+    check_media_host(url, config)?;
     info!("downloading {url}");
-    let mut res = reqwest::get(url).await?;
-    let tmp_dir = TempDir::with_prefix("tgreddit")?;
+    let res = get_with_retry(url, config).await?;
+    let status = res.status();
+    if matches!(status, StatusCode::NOT_FOUND | StatusCode::GONE) {
+        return Err(MediaUnavailable(status.to_string()).into());
+    }
+    // Imgur redirects removed images to a placeholder image instead of responding with 404
+    if res.url().path() == "/removed.png" {
+        return Err(MediaUnavailable("removed from imgur".to_string()).into());
+    }
+    let mut res = res.error_for_status()?;
+    let is_too_large = |len| max_bytes.is_some_and(|max_bytes| len > max_bytes);
+    if res.content_length().is_some_and(is_too_large) {
+        return Err(MediaTooLarge(max_bytes.unwrap_or_default()).into());
+    }
+    let tmp_dir = create_tmp_dir(config)?;
     let parsed_url = Url::parse(url)?;
     let tmp_filename = Path::new(parsed_url.path())
         .file_name()
-        .context("could not get basename from url")?;
+        .unwrap_or("media".as_ref());
     let tmp_path = tmp_dir.path().join(tmp_filename);
     let mut file = File::create(&tmp_path)
         .map_err(|_| anyhow::anyhow!("failed to create file {tmp_path:?}"))?;
+    let content_type_media_type = res
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map(media_type_from_content_type)
+        .unwrap_or(MediaType::Unknown);
 
+    let mut len = 0;
     while let Some(bytes) = res.chunk().await? {
+        len += bytes.len() as u64;
+        // Content-Length may be missing or wrong
+        if is_too_large(len) {
+            return Err(MediaTooLarge(max_bytes.unwrap_or_default()).into());
+        }
         file.write(&bytes)
             .map_err(|_| anyhow::anyhow!("error writing to file {tmp_path:?}"))?;
     }
 
-    info!("downloaded {url} to {}", tmp_path.to_string_lossy());
-    Ok((tmp_path, tmp_dir))
+    let media_type = match sniff_media_type(&tmp_path)? {
+        MediaType::Unknown => content_type_media_type,
+        media_type => media_type,
+    };
+
+    info!(
+        "downloaded {url} to {} as {media_type:?}",
+        tmp_path.to_string_lossy()
+    );
+    Ok(DownloadedFile {
+        path: tmp_path,
+        media_type,
+        _tmp_dir: tmp_dir,
+    })
+}
+
+/// Detects the type of a media file from its first bytes. For example, imgur may serve a gif url
+/// as mp4.
+pub fn sniff_media_type(path: &Path) -> Result<MediaType> {
+    let mut header = [0u8; 21];
+    let len = File::open(path)?.read(&mut header)?;
+    Ok(media_type_from_magic_bytes(&header[..len]))
+}
+
+fn media_type_from_magic_bytes(header: &[u8]) -> MediaType {
+    if header.starts_with(b"\xff\xd8\xff") {
+        MediaType::Jpeg
+    } else if header.starts_with(b"\x89PNG\r\n\x1a\n") {
+        MediaType::Png
+    } else if header.len() >= 12 && &header[..4] == b"RIFF" && &header[8..12] == b"WEBP" {
+        // Animated webp starts with an extended format chunk that has the animation flag set
+        let is_animated =
+            header.len() >= 21 && &header[12..16] == b"VP8X" && header[20] & 0x02 != 0;
+        if is_animated {
+            MediaType::AnimatedWebp
+        } else {
+            MediaType::Webp
+        }
+    } else if header.starts_with(b"GIF87a") || header.starts_with(b"GIF89a") {
+        MediaType::Gif
+    } else if header.len() >= 12 && &header[4..8] == b"ftyp" && &header[8..12] == b"M4A " {
+        MediaType::Audio
+    } else if header.len() >= 8 && &header[4..8] == b"ftyp" {
+        MediaType::Mp4
+    } else if header.starts_with(b"ID3")
+        || header.starts_with(b"OggS")
+        || header.starts_with(b"fLaC")
+        || (header.len() >= 12 && &header[..4] == b"RIFF" && &header[8..12] == b"WAVE")
+        // Frame sync of an mp3 without ID3 tag
+        || (header.len() >= 2 && header[0] == 0xff && header[1] & 0xe0 == 0xe0)
+    {
+        MediaType::Audio
+    } else {
+        MediaType::Unknown
+    }
+}
+
+fn media_type_from_content_type(content_type: &str) -> MediaType {
+    let mime = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    match mime.as_str() {
+        "image/jpeg" | "image/jpg" => MediaType::Jpeg,
+        "image/png" => MediaType::Png,
+        "image/webp" => MediaType::Webp,
+        "image/gif" => MediaType::Gif,
+        "video/mp4" => MediaType::Mp4,
+        _ if mime.starts_with("audio/") => MediaType::Audio,
+        _ => MediaType::Unknown,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const JPEG: &[u8] = b"\xff\xd8\xff\xe0\x00\x10JFIF\x00\x01";
+    const PNG: &[u8] = b"\x89PNG\r\n\x1a\n\x00\x00\x00\rIHDR";
+    const WEBP: &[u8] = b"RIFF\x24\x00\x00\x00WEBPVP8 ";
+    const ANIMATED_WEBP: &[u8] = b"RIFF\x24\x00\x00\x00WEBPVP8X\x0a\x00\x00\x00\x12";
+    const GIF: &[u8] = b"GIF89a\x01\x00\x01\x00\x80\x00";
+    const MP4: &[u8] = b"\x00\x00\x00\x20ftypisom\x00\x00";
+    const MP3: &[u8] = b"ID3\x04\x00\x00\x00\x00\x00\x00";
+    const M4A: &[u8] = b"\x00\x00\x00\x20ftypM4A \x00\x00";
+    const OGG: &[u8] = b"OggS\x00\x02\x00\x00";
+
+    #[test]
+    fn test_sniff_media_type() {
+        let dir = TempDir::new().unwrap();
+        for (name, bytes, media_type) in [
+            // Extensions are deliberately misleading or missing
+            ("image.png", JPEG, MediaType::Jpeg),
+            ("image", PNG, MediaType::Png),
+            ("image.jpg", WEBP, MediaType::Webp),
+            ("image.webp", ANIMATED_WEBP, MediaType::AnimatedWebp),
+            ("image.gifv", GIF, MediaType::Gif),
+            ("image.gif", MP4, MediaType::Mp4),
+            ("audio", MP3, MediaType::Audio),
+            ("audio.mp4", M4A, MediaType::Audio),
+            ("audio.mp3", OGG, MediaType::Audio),
+            ("empty.jpg", b"".as_slice(), MediaType::Unknown),
+        ] {
+            let path = dir.path().join(name);
+            std::fs::write(&path, bytes).unwrap();
+            assert_eq!(sniff_media_type(&path).unwrap(), media_type, "{name}");
+        }
+    }
+
+    #[test]
+    fn test_media_type_from_content_type() {
+        assert_eq!(media_type_from_content_type("image/jpeg"), MediaType::Jpeg);
+        assert_eq!(
+            media_type_from_content_type("image/PNG; charset=binary"),
+            MediaType::Png
+        );
+        assert_eq!(media_type_from_content_type("video/mp4"), MediaType::Mp4);
+        assert_eq!(media_type_from_content_type("audio/mpeg"), MediaType::Audio);
+        assert_eq!(
+            media_type_from_content_type("text/html"),
+            MediaType::Unknown
+        );
+    }
+
+    #[test]
+    fn test_download_dir() {
+        let dir = TempDir::new().unwrap();
+        let download_dir = dir.path().join("nested").join("downloads");
+        let config = Config {
+            download_dir: Some(download_dir.clone()),
+            ..Default::default()
+        };
+        prepare_download_dir(&config).unwrap();
+        assert!(download_dir.is_dir());
+
+        let tmp_dir = create_tmp_dir(&config).unwrap();
+        let tmp_path = tmp_dir.path().to_owned();
+        assert!(tmp_path.starts_with(&download_dir));
+        drop(tmp_dir);
+        assert!(!tmp_path.exists());
+    }
+
+    #[test]
+    fn test_cleanup_stale_tmp_dirs() {
+        let base_dir = TempDir::new().unwrap();
+        let stale_dir = base_dir.path().join("tgreddit1a2b3c");
+        let other_dir = base_dir.path().join("other");
+        let stale_file = base_dir.path().join("tgreddit.txt");
+        std::fs::create_dir(&stale_dir).unwrap();
+        std::fs::write(stale_dir.join("video.mp4"), b"").unwrap();
+        std::fs::create_dir(&other_dir).unwrap();
+        std::fs::write(&stale_file, b"").unwrap();
+
+        let removed =
+            cleanup_stale_tmp_dirs_in(base_dir.path(), Duration::from_secs(60 * 60), None).unwrap();
+        assert_eq!(removed, 0);
+        assert!(stale_dir.exists());
+
+        let removed =
+            cleanup_stale_tmp_dirs_in(base_dir.path(), Duration::ZERO, Some(&stale_dir)).unwrap();
+        assert_eq!(removed, 0);
+        assert!(stale_dir.exists());
+
+        let removed = cleanup_stale_tmp_dirs_in(base_dir.path(), Duration::ZERO, None).unwrap();
+        assert_eq!(removed, 1);
+        assert!(!stale_dir.exists());
+        assert!(other_dir.exists());
+        assert!(stale_file.exists());
+    }
+
+    #[tokio::test]
+    async fn test_redirect_to_blocked_host() {
+        use std::net::TcpListener;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = [0u8; 1024];
+            let _ = stream.read(&mut request).unwrap();
+            stream
+                .write_all(
+                    b"HTTP/1.1 302 Found\r\nLocation: http://blocked.invalid/a.jpg\r\n\
+                      Content-Length: 0\r\nConnection: close\r\n\r\n",
+                )
+                .unwrap();
+        });
+
+        let config = Config {
+            blocked_media_hosts: vec!["blocked.invalid".into()],
+            ..Default::default()
+        };
+        let err = download_url_to_tmp(&format!("http://{addr}/a.jpg"), &config)
+            .await
+            .err()
+            .unwrap();
+        assert!(err.is::<MediaHostNotAllowed>(), "{err:?}");
+    }
 }
@@ -0,0 +1,175 @@
+use log::{info, warn};
+use std::{
+    collections::BTreeMap,
+    sync::{Mutex, OnceLock},
+};
+use teloxide::prelude::*;
+use tokio::time::{Duration, Instant};
+
+use crate::{config::Config, messages, send_limiter};
+
+/// Distinct reasons kept per source, the rest are only counted
+const MAX_REASONS: usize = 3;
+/// How long a source that was reported is left out of reports, so that a subscription that keeps
+/// failing is reported about once a day. Its errors are still counted in the meantime.
+const REPEAT_AFTER: Duration = Duration::from_secs(24 * 60 * 60);
+
+static ERROR_REPORTS: OnceLock<Mutex<ErrorReports>> = OnceLock::new();
+
+/// Errors of a source, such as a subscription, since it was last reported.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ErrorSummary {
+    pub source: String,
+    pub count: usize,
+    pub reasons: Vec<String>,
+    pub first_at: Instant,
+}
+
+/// Collects errors to be sent to the operator as a summary at most once per interval.
+#[derive(Debug)]
+pub struct ErrorReports {
+    interval: Duration,
+    reported_at: Instant,
+    errors: BTreeMap<String, ErrorSummary>,
+    source_reported_at: BTreeMap<String, Instant>,
+}
+
+impl ErrorReports {
+    pub fn new(interval: Duration, now: Instant) -> Self {
+        Self {
+            interval,
+            reported_at: now,
+            errors: BTreeMap::new(),
+            source_reported_at: BTreeMap::new(),
+        }
+    }
+
+    pub fn record(&mut self, source: &str, reason: String, now: Instant) {
+        let summary = self
+            .errors
+            .entry(source.to_string())
+            .or_insert_with(|| ErrorSummary {
+                source: source.to_string(),
+                count: 0,
+                reasons: vec![],
+                first_at: now,
+            });
+        summary.count += 1;
+        if summary.reasons.len() < MAX_REASONS && !summary.reasons.contains(&reason) {
+            summary.reasons.push(reason);
+        }
+    }
+
+    /// Takes the errors to report if the interval has passed since the last report, leaving out
+    /// sources that were reported recently.
+    pub fn take_report(&mut self, now: Instant) -> Vec<ErrorSummary> {
+        if now.saturating_duration_since(self.reported_at) < self.interval {
+            return vec![];
+        }
+        self.reported_at = now;
+        self.source_reported_at
+            .retain(|_, reported_at| now.saturating_duration_since(*reported_at) < REPEAT_AFTER);
+        let sources = self
+            .errors
+            .keys()
+            .filter(|source| !self.source_reported_at.contains_key(*source))
+            .cloned()
+            .collect::<Vec<_>>();
+        sources
+            .into_iter()
+            .filter_map(|source| {
+                self.source_reported_at.insert(source.clone(), now);
+                self.errors.remove(&source)
+            })
+            .collect()
+    }
+}
+
+/// Starts collecting errors for [`flush`]. Errors recorded before this are dropped, so nothing is
+/// collected when there's no chat to report them to.
+pub fn enable(config: &Config) {
+    let interval = Duration::from_secs(config.error_report_interval_secs);
+    ERROR_REPORTS.get_or_init(|| Mutex::new(ErrorReports::new(interval, Instant::now())));
+}
+
+/// Records an error of the given source, e.g. "r/pics", to be reported in the next summary.
+pub fn record(source: &str, err: &anyhow::Error) {
+    let Some(reports) = ERROR_REPORTS.get() else {
+        return;
+    };
+    // The outermost context, the rest is in the logs
+    reports
+        .lock()
+        .expect("No poison")
+        .record(source, err.to_string(), Instant::now());
+}
+
+/// Sends the summary of the errors recorded since the last one to the error report chat, if the
+/// interval has passed.
+pub async fn flush(config: &Config, tg: &Bot) {
+    let (Some(chat_id), Some(reports)) = (config.error_report_chat_id, ERROR_REPORTS.get()) else {
+        return;
+    };
+    let now = Instant::now();
+    let summaries = reports.lock().expect("No poison").take_report(now);
+    if summaries.is_empty() {
+        return;
+    }
+    let message = messages::format_error_report(&summaries, now);
+    if config.dry_run {
+        info!("dry run, not sending error report to chat {chat_id}: {message}");
+        return;
+    }
+    let _permit = send_limiter::acquire(config).await;
+    if let Err(err) = tg.send_message(ChatId(chat_id), message).await {
+        warn!("failed to send error report to chat {chat_id}: {err:?}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_error_reports() {
+        let hour = Duration::from_secs(3600);
+        let start = Instant::now();
+        let mut reports = ErrorReports::new(hour, start);
+        for reason in ["a", "b", "a", "c", "d"] {
+            reports.record("r/foo", reason.to_string(), start);
+        }
+        reports.record("r/bar", "e".to_string(), start + hour / 2);
+        assert_eq!(reports.take_report(start + hour / 2), vec![]);
+
+        let summaries = reports.take_report(start + hour);
+        assert_eq!(
+            summaries,
+            vec![
+                ErrorSummary {
+                    source: "r/bar".to_string(),
+                    count: 1,
+                    reasons: vec!["e".to_string()],
+                    first_at: start + hour / 2,
+                },
+                ErrorSummary {
+                    source: "r/foo".to_string(),
+                    count: 5,
+                    reasons: vec!["a".to_string(), "b".to_string(), "c".to_string()],
+                    first_at: start,
+                },
+            ]
+        );
+
+        // r/foo keeps failing but was just reported, so it's held back for a day
+        reports.record("r/foo", "a".to_string(), start + hour * 2);
+        reports.record("r/baz", "f".to_string(), start + hour * 2);
+        let summaries = reports.take_report(start + hour * 3);
+        assert_eq!(summaries.len(), 1);
+        assert_eq!(summaries[0].source, "r/baz");
+        reports.record("r/foo", "a".to_string(), start + hour * 20);
+        let summaries = reports.take_report(start + hour * 25);
+        assert_eq!(summaries.len(), 1);
+        assert_eq!(summaries[0].source, "r/foo");
+        assert_eq!(summaries[0].count, 2);
+    }
+}
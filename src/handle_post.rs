@@ -1,39 +1,72 @@
 use crate::reddit::{self};
-use crate::{config, db, download::*, messages, ytdlp};
+use crate::{
+    config, db, delivery_webhook,
+    download::*,
+    messages, send_limiter, transcode,
+    types::{
+        CaptionMode, DeliveryOptions, Dimensions, LinkPreview, MediaKind, TelegramFile, Video,
+    },
+    ytdlp,
+};
 use anyhow::{Context, Result};
 use log::*;
 use url::Url;
 
+use std::collections::HashMap;
 use std::string::ToString;
+use std::time::Instant;
 use std::{borrow::Cow, path::PathBuf};
-use std::{collections::HashMap, path::Path};
-use teloxide::types::{InputFile, InputMediaVideo};
+use teloxide::types::{
+    FileMeta, InputFile, InputMediaDocument, InputMediaVideo, LinkPreviewOptions, MessageId,
+    ReplyMarkup, ReplyParameters, ThreadId,
+};
 use teloxide::{
     payloads::{SendMessageSetters, SendPhotoSetters, SendVideoSetters},
     types::InputMediaPhoto,
 };
-use teloxide::{prelude::*, types::InputMedia};
-use tempfile::TempDir;
+use teloxide::{prelude::*, requests::HasPayload, types::InputMedia};
+
+/// Telegram's limit for items in a media group
+const MAX_MEDIA_GROUP_SIZE: usize = 10;
+/// Telegram's limit for files uploaded by bots
+const MAX_UPLOAD_BYTES: u64 = 50 * 1024 * 1024;
+
+/// Appended to posts that are sent as links because their media has been deleted
+const MEDIA_UNAVAILABLE_NOTE: &str = "Media unavailable";
 
 pub async fn handle_video_link(
     db: &db::Database,
     tg: &Bot,
     chat_id: i64,
+    thread_id: Option<ThreadId>,
     link: &Url,
+    config: &config::Config,
 ) -> Result<()> {
-    let video = tokio::task::block_in_place(|| ytdlp::download(link.as_str()))
-        .context("Failed to download video from link")?;
+    if config.dry_run {
+        info!("dry run, not downloading video link {link} chat_id={chat_id}");
+        return Ok(());
+    }
+
+    let options = DeliveryOptions {
+        show_repost_buttons: db.get_show_repost_buttons(chat_id)?,
+        ..Default::default()
+    };
+    let video =
+        download_video(link.as_str(), config).context("Failed to download video from link")?;
+    let video = fit_upload_limit(config, video).context("Video is too large to upload")?;
 
     db.record_post_seen_with_current_time(chat_id, &video)?;
 
     info!("got a video: {video:?}");
     let caption = messages::format_link_video_caption_html(&video);
+    let _permit = send_limiter::acquire(config).await;
     tg.send_video(ChatId(chat_id), InputFile::file(&video.path))
+        .with_payload_mut(|payload| payload.message_thread_id = thread_id)
         .parse_mode(teloxide::types::ParseMode::Html)
         .caption(&caption)
         .height(video.height.into())
         .width(video.width.into())
-        .reply_markup(messages::format_repost_buttons(&video))
+        .with_payload_mut(|payload| payload.reply_markup = repost_buttons(config, &options, &video))
         .await?;
     info!(
         "video uploaded post_id={} chat_id={chat_id} video={video:?}",
@@ -47,55 +80,194 @@ async fn handle_new_video_post(
     tg: &Bot,
     chat_id: i64,
     post: &reddit::Post,
-) -> Result<()> {
-    let video = tokio::task::block_in_place(|| ytdlp::download(&post.url))
-        .context("Failed to download video from post")?;
+    options: &DeliveryOptions,
+) -> Result<MessageId> {
+    let caption = format_caption(config, options, post, options.caption.unwrap_or_default());
+    let thread_id = options.thread_id();
+    if let Some(message_id) =
+        send_uploaded_file(config, tg, chat_id, options, post, &caption, false).await?
+    {
+        return Ok(message_id);
+    }
+
+    let video = match download_video(&post.url, config) {
+        Ok(video) => video,
+        Err(err) if err.is::<MediaHostNotAllowed>() => {
+            info!("sending post {} as link: {err}", post.id);
+            return handle_new_link_post(config, tg, chat_id, post, options, None).await;
+        }
+        // Embedded videos are links to begin with, so they're sent as such if they can't be
+        // downloaded, e.g. because they're region locked
+        Err(err) if post.post_hint.as_deref() == Some("rich:video") => {
+            warn!(
+                "failed to download embedded video of post {}, sending as link: {err:?}",
+                post.id
+            );
+            return handle_new_link_post(config, tg, chat_id, post, options, None).await;
+        }
+        Err(err) => return Err(err.context("Failed to download video from post")),
+    };
+    let video = ensure_reddit_video_audio(&post.url, config, video);
+    let transcoded = video.size_bytes > MAX_UPLOAD_BYTES;
+    let Some(video) = fit_upload_limit(config, video) else {
+        info!("sending post {} as link, its video is too large", post.id);
+        return handle_new_link_post(config, tg, chat_id, post, options, None).await;
+    };
 
     info!("got a video: {video:?}");
-    let caption = messages::format_media_caption_html(post, config.links_base_url.as_deref());
-    tg.send_video(ChatId(chat_id), InputFile::file(&video.path))
+    let _permit = send_limiter::acquire(config).await;
+    let msg = tg
+        .send_video(ChatId(chat_id), InputFile::file(&video.path))
+        .with_payload_mut(|payload| payload.message_thread_id = thread_id)
         .parse_mode(teloxide::types::ParseMode::Html)
         .caption(&caption)
+        .has_spoiler(has_spoiler(config, post))
         .height(video.height.into())
         .width(video.width.into())
-        .reply_markup(messages::format_repost_buttons(post))
+        .with_payload_mut(|payload| payload.reply_markup = repost_buttons(config, options, post))
         .await?;
+    record_sent_file(config, chat_id, post, &msg)?;
     info!(
         "video uploaded post_id={} chat_id={chat_id} video={video:?}",
         post.id
     );
-    Ok(())
+    // The source of a re-encoded video is over the upload limit
+    if options.attach_source && !transcoded {
+        send_source_file(config, tg, chat_id, thread_id, &video, msg.id).await;
+    }
+    Ok(msg.id)
+}
+
+/// Sends the downloaded file of a video as a document replying to the video, so that the original
+/// can be saved. The video has already been delivered, so failing to send the file is only logged.
+async fn send_source_file(
+    config: &config::Config,
+    tg: &Bot,
+    chat_id: i64,
+    thread_id: Option<ThreadId>,
+    video: &Video,
+    video_message_id: MessageId,
+) {
+    if video.size_bytes > MAX_UPLOAD_BYTES {
+        info!(
+            "not attaching source file of {} bytes over upload limit video={video:?}",
+            video.size_bytes
+        );
+        return;
+    }
+    let _permit = send_limiter::acquire(config).await;
+    let res = tg
+        .send_document(ChatId(chat_id), InputFile::file(&video.path))
+        .with_payload_mut(|payload| payload.message_thread_id = thread_id)
+        .disable_content_type_detection(true)
+        .reply_parameters(ReplyParameters::new(video_message_id))
+        .await;
+    if let Err(err) = res {
+        warn!(
+            "failed to attach source file of video {}: {err:?}",
+            video.id
+        );
+    }
 }
 
+/// Sends an image post, using `image` instead of downloading the image if it was downloaded
+/// already.
 async fn handle_new_image_post(
     config: &config::Config,
     tg: &Bot,
     chat_id: i64,
     post: &reddit::Post,
-) -> Result<()> {
-    match download_url_to_tmp(&post.url).await {
-        Ok((path, _tmp_dir)) => {
-            // path will be deleted when _tmp_dir when goes out of scope
-            let caption =
-                messages::format_media_caption_html(post, config.links_base_url.as_deref());
-            if is_gif(&path) {
-                tg.send_video(ChatId(chat_id), InputFile::file(path))
+    options: &DeliveryOptions,
+    image: Option<DownloadedFile>,
+) -> Result<MessageId> {
+    let caption = format_caption(config, options, post, options.caption.unwrap_or_default());
+    let thread_id = options.thread_id();
+    let as_document = options.photo_as_document || config.photo_as_document;
+    if let Some(message_id) =
+        send_uploaded_file(config, tg, chat_id, options, post, &caption, as_document).await?
+    {
+        return Ok(message_id);
+    }
+
+    let downloaded = match image {
+        Some(file) => Ok(file),
+        None => download_url_to_tmp(&post.url, config).await,
+    };
+    match downloaded {
+        Ok(file) => {
+            // file will be deleted when it goes out of scope
+            if file.media_type.is_animated() {
+                let path = match prepare_animation(config, &file) {
+                    Ok(path) => path,
+                    Err(err) => {
+                        warn!("could not prepare animation, sending as link: {err:?}");
+                        return handle_new_link_post(config, tg, chat_id, post, options, None)
+                            .await;
+                    }
+                };
+                let _permit = send_limiter::acquire(config).await;
+                let msg = tg
+                    .send_video(ChatId(chat_id), InputFile::file(path))
+                    .with_payload_mut(|payload| payload.message_thread_id = thread_id)
                     .parse_mode(teloxide::types::ParseMode::Html)
                     .caption(&caption)
-                    .reply_markup(messages::format_repost_buttons(post))
+                    .has_spoiler(has_spoiler(config, post))
+                    .with_payload_mut(|payload| {
+                        payload.reply_markup = repost_buttons(config, options, post)
+                    })
                     .await?;
+                record_sent_file(config, chat_id, post, &msg)?;
 
                 info!("gif uploaded post_id={} chat_id={chat_id}", post.id);
+                Ok(msg.id)
+            } else if as_document {
+                // Documents are sent as is, while photos are recompressed by Telegram
+                let _permit = send_limiter::acquire(config).await;
+                let msg = tg
+                    .send_document(ChatId(chat_id), InputFile::file(&file.path))
+                    .with_payload_mut(|payload| payload.message_thread_id = thread_id)
+                    .parse_mode(teloxide::types::ParseMode::Html)
+                    .caption(&caption)
+                    .with_payload_mut(|payload| {
+                        payload.reply_markup = repost_buttons(config, options, post)
+                    })
+                    .await?;
+                record_sent_file(config, chat_id, post, &msg)?;
+
+                info!(
+                    "image uploaded as document post_id={} chat_id={chat_id}",
+                    post.id
+                );
+                Ok(msg.id)
             } else {
-                tg.send_photo(ChatId(chat_id), InputFile::file(path))
+                let _permit = send_limiter::acquire(config).await;
+                let msg = tg
+                    .send_photo(ChatId(chat_id), InputFile::file(&file.path))
+                    .with_payload_mut(|payload| payload.message_thread_id = thread_id)
                     .parse_mode(teloxide::types::ParseMode::Html)
                     .caption(&caption)
-                    .reply_markup(messages::format_repost_buttons(post))
+                    .has_spoiler(has_spoiler(config, post))
+                    .with_payload_mut(|payload| {
+                        payload.reply_markup = repost_buttons(config, options, post)
+                    })
                     .await?;
+                record_sent_file(config, chat_id, post, &msg)?;
 
                 info!("image uploaded post_id={} chat_id={chat_id}", post.id);
+                Ok(msg.id)
             }
-            Ok(())
+        }
+        Err(e) if e.is::<MediaUnavailable>() => {
+            warn!(
+                "image of post {} is unavailable, sending as link: {e}",
+                post.id
+            );
+            let note = Some(MEDIA_UNAVAILABLE_NOTE);
+            handle_new_link_post(config, tg, chat_id, post, options, note).await
+        }
+        Err(e) if e.is::<MediaHostNotAllowed>() => {
+            info!("sending post {} as link: {e}", post.id);
+            handle_new_link_post(config, tg, chat_id, post, options, None).await
         }
         Err(e) => {
             error!("failed to download image: {e:?}");
@@ -104,19 +276,115 @@ async fn handle_new_image_post(
     }
 }
 
+async fn handle_new_audio_post(
+    config: &config::Config,
+    tg: &Bot,
+    chat_id: i64,
+    post: &reddit::Post,
+    options: &DeliveryOptions,
+) -> Result<MessageId> {
+    let file = match download_url_to_tmp_limited(&post.url, config, Some(MAX_UPLOAD_BYTES)).await {
+        Ok(file) if file.media_type == MediaType::Audio => file,
+        Ok(file) => {
+            info!(
+                "post {} does not link to audio but {:?}, sending as link",
+                post.id, file.media_type
+            );
+            return handle_new_link_post(config, tg, chat_id, post, options, None).await;
+        }
+        Err(err) if err.is::<MediaUnavailable>() => {
+            warn!(
+                "audio of post {} is unavailable, sending as link: {err}",
+                post.id
+            );
+            let note = Some(MEDIA_UNAVAILABLE_NOTE);
+            return handle_new_link_post(config, tg, chat_id, post, options, note).await;
+        }
+        Err(err) if err.is::<MediaHostNotAllowed>() || err.is::<MediaTooLarge>() => {
+            info!("sending post {} as link: {err}", post.id);
+            return handle_new_link_post(config, tg, chat_id, post, options, None).await;
+        }
+        Err(err) => return Err(err),
+    };
+
+    let caption = format_caption(config, options, post, options.caption.unwrap_or_default());
+    let _permit = send_limiter::acquire(config).await;
+    let msg = tg
+        .send_audio(ChatId(chat_id), InputFile::file(&file.path))
+        .with_payload_mut(|payload| payload.message_thread_id = options.thread_id())
+        .parse_mode(teloxide::types::ParseMode::Html)
+        .caption(&caption)
+        .with_payload_mut(|payload| payload.reply_markup = repost_buttons(config, options, post))
+        .await?;
+    info!("audio uploaded post_id={} chat_id={chat_id}", post.id);
+    Ok(msg.id)
+}
+
 async fn handle_new_link_post(
     config: &config::Config,
     tg: &Bot,
     chat_id: i64,
     post: &reddit::Post,
-) -> Result<()> {
-    let message_html = messages::format_link_message_html(post, config.links_base_url.as_deref());
-    tg.send_message(ChatId(chat_id), message_html)
+    options: &DeliveryOptions,
+    note: Option<&str>,
+) -> Result<MessageId> {
+    let message_html = match template(config, options) {
+        Some(template) => {
+            messages::render_template(post, template, config.links_base_url.as_deref())
+        }
+        None => messages::format_link_message_html(post, config.links_base_url.as_deref()),
+    };
+    let message_html = with_sensitive_marker(config, post, message_html);
+    let message_html = match note {
+        Some(note) => format!("{message_html}\n\n<i>{note}</i>"),
+        None => message_html,
+    };
+    let thread_id = options.thread_id();
+    let link_preview = options.link_preview.unwrap_or(config.link_preview);
+
+    // Telegram's own link previews are unreliable for reddit hosted thumbnails, so prefer sending
+    // the preview image with the link as caption when there is one
+    let preview_url = post
+        .preview_image_url()
+        .filter(|_| link_preview != LinkPreview::Disabled)
+        .filter(|_| message_html.chars().count() <= messages::MAX_CAPTION_LENGTH)
+        .and_then(|url| Url::parse(&url).ok());
+    if let Some(preview_url) = preview_url {
+        let _permit = send_limiter::acquire(config).await;
+        match tg
+            .send_photo(ChatId(chat_id), InputFile::url(preview_url))
+            .with_payload_mut(|payload| payload.message_thread_id = thread_id)
+            .parse_mode(teloxide::types::ParseMode::Html)
+            .caption(&message_html)
+            .has_spoiler(has_spoiler(config, post))
+            .with_payload_mut(|payload| {
+                payload.reply_markup = repost_buttons(config, options, post)
+            })
+            .await
+        {
+            Ok(msg) => {
+                info!("link preview sent post_id={} chat_id={chat_id}", post.id);
+                return Ok(msg.id);
+            }
+            Err(err) => {
+                warn!("failed to send link preview, sending as message instead: {err:?}");
+            }
+        }
+    }
+
+    let _permit = send_limiter::acquire(config).await;
+    let msg = tg
+        .send_message(ChatId(chat_id), message_html)
+        .with_payload_mut(|payload| {
+            payload.message_thread_id = thread_id;
+            // Preview the linked page rather than whichever link comes first in the message
+            payload.link_preview_options = link_preview_options(link_preview, Some(&post.url));
+        })
         .parse_mode(teloxide::types::ParseMode::Html)
-        .reply_markup(messages::format_repost_buttons(post))
+        .with_payload_mut(|payload| payload.reply_markup = repost_buttons(config, options, post))
         .await?;
     info!("message sent post_id={} chat_id={chat_id}", post.id);
-    Ok(())
+    Ok(msg.id)
 }
 
 async fn handle_new_self_post(
@@ -124,31 +392,83 @@ async fn handle_new_self_post(
     tg: &Bot,
     chat_id: i64,
     post: &reddit::Post,
-) -> Result<()> {
-    let message_html = messages::format_media_caption_html(post, config.links_base_url.as_deref());
-    tg.send_message(ChatId(chat_id), message_html)
+    options: &DeliveryOptions,
+) -> Result<MessageId> {
+    // A self post is sent as a message, which can't be empty
+    let mode = match options.caption.unwrap_or_default() {
+        CaptionMode::None => CaptionMode::Title,
+        mode => mode,
+    };
+    let caption = format_caption(config, options, post, mode);
+    let selftext_html = if options.selftext || config.include_selftext {
+        let max_length = messages::MAX_MESSAGE_LENGTH.saturating_sub(caption.chars().count() + 2);
+        messages::format_selftext_html(post, max_length, config.links_base_url.as_deref())
+    } else {
+        None
+    };
+    let message_html = match selftext_html {
+        Some(selftext_html) => format!("{caption}\n\n{selftext_html}"),
+        None => caption,
+    };
+    let thread_id = options.thread_id();
+    let link_preview = options.link_preview.unwrap_or(config.link_preview);
+    let _permit = send_limiter::acquire(config).await;
+    let msg = tg
+        .send_message(ChatId(chat_id), message_html)
+        .with_payload_mut(|payload| {
+            payload.message_thread_id = thread_id;
+            payload.link_preview_options = link_preview_options(link_preview, None);
+        })
         .parse_mode(teloxide::types::ParseMode::Html)
-        .reply_markup(messages::format_repost_buttons(post))
+        .with_payload_mut(|payload| payload.reply_markup = repost_buttons(config, options, post))
         .await?;
     info!("message sent post_id={} chat_id={chat_id}", post.id);
-    Ok(())
+    Ok(msg.id)
 }
 
-async fn download_gallery(post: &reddit::Post) -> Result<HashMap<String, (PathBuf, TempDir)>> {
+async fn download_gallery(
+    config: &config::Config,
+    post: &reddit::Post,
+) -> Result<HashMap<String, DownloadedFile>> {
     let media_metadata_map = post
         .media_metadata
         .as_ref()
-        .expect("expected media_metadata to exist in gallery post");
+        .context("Gallery post has no media_metadata")?;
 
-    let mut map: HashMap<String, (PathBuf, TempDir)> = HashMap::new();
+    let mut map: HashMap<String, DownloadedFile> = HashMap::new();
     for (id, media_metadata) in media_metadata_map {
-        let s = media_metadata
-            .s
-            .as_ref()
-            .context("Media metadata not available")?;
-        let url = &s.url.replace("&amp;", "&");
-        info!("got media id={id} x={} y={} url={}", &s.x, &s.y, url);
-        map.insert(id.to_string(), download_url_to_tmp(url).await?);
+        // Items that failed processing on reddit's side have no source
+        let Some(source) = media_metadata.source() else {
+            warn!(
+                "no source for gallery item id={id} e={:?}",
+                media_metadata.e
+            );
+            continue;
+        };
+        let (x, y) = media_metadata.s.as_ref().map_or((0, 0), |s| (s.x, s.y));
+        info!(
+            "got media id={id} e={:?} x={x} y={y} source={source:?}",
+            media_metadata.e
+        );
+        let file = match source {
+            reddit::MediaSource::File(url) => download_url_to_tmp(&url, config).await,
+            reddit::MediaSource::Stream(url) => download_video(&url, config)
+                .map(|video| DownloadedFile {
+                    path: video.path,
+                    media_type: MediaType::Mp4,
+                    _tmp_dir: video._video_tempdir,
+                })
+                .context("Failed to download gallery video"),
+        };
+        let file = match file {
+            Ok(file) => file,
+            Err(err) if err.is::<MediaUnavailable>() || err.is::<MediaHostNotAllowed>() => {
+                warn!("skipping gallery item id={id}: {err}");
+                continue;
+            }
+            Err(err) => return Err(err),
+        };
+        map.insert(id.to_string(), file);
     }
 
     Ok(map)
@@ -159,80 +479,382 @@ async fn handle_new_gallery_post(
     tg: &Bot,
     chat_id: i64,
     post: &reddit::Post,
-) -> Result<()> {
+    options: &DeliveryOptions,
+) -> Result<MessageId> {
     // post.gallery_data is an array that describes the order of photos in the gallery, while
     // post.media_metadata is a map that contains the URL for each photo
     let gallery_data_items = &post
         .gallery_data
         .as_ref()
-        .expect("expected media_metadata to exist in gallery post")
+        .context("Gallery post has no gallery_data")?
         .items;
-    let gallery_files_map = download_gallery(post).await?;
-    let mut media_group = vec![];
-    let mut first = true;
-
-    for item in gallery_data_items {
-        let file = gallery_files_map.get(&item.media_id);
-        match file {
-            Some((image_path, _tempdir)) => {
-                if is_gif(image_path) {
-                    let mut input_media_video = InputMediaVideo::new(InputFile::file(image_path));
-                    if first {
-                        let caption = messages::format_media_caption_html(
-                            post,
-                            config.links_base_url.as_deref(),
-                        );
-                        input_media_video = input_media_video
-                            .caption(&caption)
-                            .parse_mode(teloxide::types::ParseMode::Html);
-                        first = false;
-                    }
-                    media_group.push(InputMedia::Video(input_media_video));
-                } else {
-                    let mut input_media_photo = InputMediaPhoto::new(InputFile::file(image_path));
-                    if first {
-                        let caption = messages::format_media_caption_html(
-                            post,
-                            config.links_base_url.as_deref(),
-                        );
-                        input_media_photo = input_media_photo
-                            .caption(&caption)
-                            .parse_mode(teloxide::types::ParseMode::Html);
-                        first = false;
+    let caption = format_caption(config, options, post, options.caption.unwrap_or_default());
+    let thread_id = options.thread_id();
+    let db = db::Database::open(config)?;
+    let uploaded_files = db.get_telegram_files_for_post_from_any_chat(&post.id)?;
+    // Keeps the downloaded files around until the gallery has been sent
+    let gallery_files_map;
+    let files = if uploaded_files.is_empty() {
+        gallery_files_map = download_gallery(config, post).await?;
+        let mut files = vec![];
+        for item in gallery_data_items {
+            match gallery_files_map.get(&item.media_id) {
+                Some(file) => {
+                    if file.media_type.is_animated() {
+                        match prepare_animation(config, file) {
+                            Ok(path) => files.push((InputFile::file(path), MediaKind::Video, None)),
+                            Err(err) => {
+                                warn!("skipping gallery item {item:?}: {err:?}");
+                            }
+                        }
+                    } else {
+                        files.push((InputFile::file(&file.path), MediaKind::Photo, None));
                     }
-                    media_group.push(InputMedia::Photo(input_media_photo));
+                }
+                None => {
+                    error!("could not find downloaded image for gallery data item: {item:?}");
                 }
             }
-            None => {
-                error!("could not find downloaded image for gallery data item: {item:?}");
-            }
         }
+        files
+    } else {
+        info!("reusing uploaded files for gallery post_id={}", post.id);
+        uploaded_files
+            .into_iter()
+            .map(|file| (InputFile::file_id(file.file_id), file.kind, file.dimensions))
+            .collect()
+    };
+    if files.is_empty() {
+        warn!(
+            "no media of gallery post {} is available, sending as link",
+            post.id
+        );
+        let note = Some(MEDIA_UNAVAILABLE_NOTE);
+        return handle_new_link_post(config, tg, chat_id, post, options, note).await;
+    }
+    let gallery_msg = match config.gallery_mode {
+        config::GalleryMode::MediaGroup => {
+            let media_group = build_media_group(files, Some(&caption), has_spoiler(config, post));
+            let _permit = send_limiter::acquire(config).await;
+            tg.send_media_group(ChatId(chat_id), media_group)
+                .with_payload_mut(|payload| payload.message_thread_id = thread_id)
+                .await?
+        }
+        config::GalleryMode::ReplyChain => {
+            send_reply_chain(
+                config,
+                tg,
+                ChatId(chat_id),
+                thread_id,
+                files,
+                Some(&caption),
+                has_spoiler(config, post),
+            )
+            .await?
+        }
+    };
+    let message_id = gallery_msg
+        .first()
+        .context("No messages sent for gallery")?
+        .id;
+    for msg in gallery_msg {
+        let (file_meta, kind, dimensions) =
+            sent_file(&msg).context("Neither photo nor video found in message")?;
+        db.add_telegram_file(
+            &post.id,
+            chat_id,
+            &file_meta.id,
+            &file_meta.unique_id,
+            kind,
+            dimensions,
+        )?;
+    }
+
+    if let Some(item_captions) = messages::format_gallery_item_captions(gallery_data_items)
+        .filter(|_| config.gallery_item_captions)
+    {
+        let _permit = send_limiter::acquire(config).await;
+        tg.send_message(ChatId(chat_id), item_captions)
+            .with_payload_mut(|payload| payload.message_thread_id = thread_id)
+            .parse_mode(teloxide::types::ParseMode::Html)
+            .link_preview_options(disabled_link_preview())
+            .await?;
+    }
+
+    if show_repost_buttons(config, options) {
+        let _permit = send_limiter::acquire(config).await;
+        tg.send_message(ChatId(chat_id), "To repost:")
+            .with_payload_mut(|payload| payload.message_thread_id = thread_id)
+            .reply_markup(messages::format_repost_buttons_gallery(post, true, config))
+            .send()
+            .await?;
+    }
+
+    info!("gallery uploaded post_id={} chat_id={chat_id}", post.id);
+
+    Ok(message_id)
+}
+
+/// File of a sent photo, video or document, so that it can be sent again by its file id. For
+/// photos, the largest size is used. Dimensions are only returned for videos.
+fn sent_file(msg: &Message) -> Option<(&FileMeta, MediaKind, Option<Dimensions>)> {
+    if let Some(video) = msg.video() {
+        let dimensions = Dimensions {
+            width: video.width,
+            height: video.height,
+        };
+        Some((&video.file, MediaKind::Video, Some(dimensions)))
+    } else if let Some(photo) = msg.photo() {
+        let photo = photo.iter().max_by_key(|x| x.file.size)?;
+        Some((&photo.file, MediaKind::Photo, None))
+    } else {
+        msg.document()
+            .map(|document| (&document.file, MediaKind::Document, None))
     }
+}
 
-    let gallery_msg = tg.send_media_group(ChatId(chat_id), media_group).await?;
+/// Records the file of a sent post so that later sends of the post, such as to other chats or
+/// with /resend, can reuse it instead of downloading it again.
+fn record_sent_file(
+    config: &config::Config,
+    chat_id: i64,
+    post: &reddit::Post,
+    msg: &Message,
+) -> Result<()> {
+    let Some((file_meta, kind, dimensions)) = sent_file(msg) else {
+        // e.g. gifs that Telegram turned into animations
+        debug!("no file to record in message post_id={}", post.id);
+        return Ok(());
+    };
     let db = db::Database::open(config)?;
-    for msg in gallery_msg {
-        let file_meta = if let Some(video) = msg.video() {
-            &video.file
-        } else if let Some(photo) = msg.photo() {
-            &photo
-                .iter()
-                .max_by_key(|x| x.file.size)
-                .expect("There must be at least one element")
-                .file
-        } else {
-            panic!("Neither photo nor video found in message");
+    db.add_telegram_file(
+        &post.id,
+        chat_id,
+        &file_meta.id,
+        &file_meta.unique_id,
+        kind,
+        dimensions,
+    )
+}
+
+/// Media group with the caption set on the first item, which is how Telegram shows the caption
+/// for the whole group. Documents can't be sent as spoilers, so `has_spoiler` only applies to
+/// photos and videos. Dimensions are only used for videos.
+pub fn build_media_group(
+    files: Vec<(InputFile, MediaKind, Option<Dimensions>)>,
+    caption: Option<&str>,
+    has_spoiler: bool,
+) -> Vec<InputMedia> {
+    files
+        .into_iter()
+        .enumerate()
+        .map(|(i, (file, kind, dimensions))| match kind {
+            MediaKind::Video => {
+                let mut input_media_video = InputMediaVideo::new(file);
+                input_media_video.has_spoiler = has_spoiler;
+                input_media_video.width = dimensions.and_then(|d| d.width.try_into().ok());
+                input_media_video.height = dimensions.and_then(|d| d.height.try_into().ok());
+                if i == 0 {
+                    if let Some(caption) = caption {
+                        input_media_video = input_media_video.caption(caption);
+                    }
+                    input_media_video =
+                        input_media_video.parse_mode(teloxide::types::ParseMode::Html);
+                }
+                InputMedia::Video(input_media_video)
+            }
+            MediaKind::Photo => {
+                let mut input_media_photo = InputMediaPhoto::new(file);
+                input_media_photo.has_spoiler = has_spoiler;
+                if i == 0 {
+                    if let Some(caption) = caption {
+                        input_media_photo = input_media_photo.caption(caption);
+                    }
+                    input_media_photo =
+                        input_media_photo.parse_mode(teloxide::types::ParseMode::Html);
+                }
+                InputMedia::Photo(input_media_photo)
+            }
+            MediaKind::Document => {
+                let mut input_media_document = InputMediaDocument::new(file);
+                if i == 0 {
+                    if let Some(caption) = caption {
+                        input_media_document = input_media_document.caption(caption);
+                    }
+                    input_media_document =
+                        input_media_document.parse_mode(teloxide::types::ParseMode::Html);
+                }
+                InputMedia::Document(input_media_document)
+            }
+        })
+        .collect()
+}
+
+/// Sends files as separate messages, each replying to the previous one, with the caption on the
+/// first. Unlike a media group, this keeps the files in order.
+pub async fn send_reply_chain(
+    config: &config::Config,
+    tg: &Bot,
+    chat_id: ChatId,
+    thread_id: Option<ThreadId>,
+    files: Vec<(InputFile, MediaKind, Option<Dimensions>)>,
+    caption: Option<&str>,
+    has_spoiler: bool,
+) -> Result<Vec<Message>> {
+    let mut messages: Vec<Message> = vec![];
+    for (i, (file, kind, dimensions)) in files.into_iter().enumerate() {
+        let caption = caption.filter(|_| i == 0).unwrap_or_default();
+        let reply_parameters = messages.last().map(|msg| ReplyParameters::new(msg.id));
+        let _permit = send_limiter::acquire(config).await;
+        let msg = match kind {
+            MediaKind::Video => {
+                let mut req = tg
+                    .send_video(chat_id, file)
+                    .with_payload_mut(|payload| {
+                        payload.message_thread_id = thread_id;
+                        payload.width = dimensions.map(|dimensions| dimensions.width);
+                        payload.height = dimensions.map(|dimensions| dimensions.height);
+                    })
+                    .caption(caption)
+                    .has_spoiler(has_spoiler)
+                    .parse_mode(teloxide::types::ParseMode::Html);
+                if let Some(reply_parameters) = reply_parameters {
+                    req = req.reply_parameters(reply_parameters);
+                }
+                req.await?
+            }
+            MediaKind::Photo => {
+                let mut req = tg
+                    .send_photo(chat_id, file)
+                    .with_payload_mut(|payload| payload.message_thread_id = thread_id)
+                    .caption(caption)
+                    .has_spoiler(has_spoiler)
+                    .parse_mode(teloxide::types::ParseMode::Html);
+                if let Some(reply_parameters) = reply_parameters {
+                    req = req.reply_parameters(reply_parameters);
+                }
+                req.await?
+            }
+            MediaKind::Document => {
+                let mut req = tg
+                    .send_document(chat_id, file)
+                    .with_payload_mut(|payload| payload.message_thread_id = thread_id)
+                    .caption(caption)
+                    .parse_mode(teloxide::types::ParseMode::Html);
+                if let Some(reply_parameters) = reply_parameters {
+                    req = req.reply_parameters(reply_parameters);
+                }
+                req.await?
+            }
         };
-        db.add_telegram_file(&post.id, chat_id, &file_meta.id, &file_meta.unique_id)?;
+        messages.push(msg);
     }
+    Ok(messages)
+}
 
-    tg.send_message(ChatId(chat_id), "To repost:")
-        .reply_markup(messages::format_repost_buttons_gallery(post, true))
-        .send()
-        .await?;
+/// Sends the post's media by file id if it has already been uploaded to some chat, saving the
+/// download and upload. Returns false if there was nothing to reuse.
+async fn send_uploaded_file(
+    config: &config::Config,
+    tg: &Bot,
+    chat_id: i64,
+    options: &DeliveryOptions,
+    post: &reddit::Post,
+    caption: &str,
+    as_document: bool,
+) -> Result<Option<MessageId>> {
+    let thread_id = options.thread_id();
+    let db = db::Database::open(config)?;
+    // A file uploaded as document is only reused where documents are wanted, and vice versa
+    let Some(TelegramFile {
+        file_id,
+        kind,
+        dimensions,
+    }) = db
+        .get_telegram_files_for_post_from_any_chat(&post.id)?
+        .into_iter()
+        .find(|file| (file.kind == MediaKind::Document) == as_document)
+    else {
+        return Ok(None);
+    };
 
-    info!("gallery uploaded post_id={} chat_id={chat_id}", post.id);
+    let _permit = send_limiter::acquire(config).await;
+    let msg = match kind {
+        MediaKind::Video => {
+            tg.send_video(ChatId(chat_id), InputFile::file_id(file_id))
+                .with_payload_mut(|payload| {
+                    payload.message_thread_id = thread_id;
+                    payload.width = dimensions.map(|dimensions| dimensions.width);
+                    payload.height = dimensions.map(|dimensions| dimensions.height);
+                })
+                .parse_mode(teloxide::types::ParseMode::Html)
+                .caption(caption)
+                .has_spoiler(has_spoiler(config, post))
+                .with_payload_mut(|payload| {
+                    payload.reply_markup = repost_buttons(config, options, post)
+                })
+                .await?
+        }
+        MediaKind::Photo => {
+            tg.send_photo(ChatId(chat_id), InputFile::file_id(file_id))
+                .with_payload_mut(|payload| payload.message_thread_id = thread_id)
+                .parse_mode(teloxide::types::ParseMode::Html)
+                .caption(caption)
+                .has_spoiler(has_spoiler(config, post))
+                .with_payload_mut(|payload| {
+                    payload.reply_markup = repost_buttons(config, options, post)
+                })
+                .await?
+        }
+        MediaKind::Document => {
+            tg.send_document(ChatId(chat_id), InputFile::file_id(file_id))
+                .with_payload_mut(|payload| payload.message_thread_id = thread_id)
+                .parse_mode(teloxide::types::ParseMode::Html)
+                .caption(caption)
+                .with_payload_mut(|payload| {
+                    payload.reply_markup = repost_buttons(config, options, post)
+                })
+                .await?
+        }
+    };
+    info!(
+        "reused uploaded {kind} post_id={} chat_id={chat_id}",
+        post.id
+    );
+    Ok(Some(msg.id))
+}
 
+/// Sends new posts of a subscription as a single message instead of one message per post. Media
+/// isn't downloaded.
+pub async fn send_post_digest(
+    config: &config::Config,
+    db: &db::Database,
+    tg: &Bot,
+    chat_id: i64,
+    thread_id: Option<ThreadId>,
+    name: &str,
+    posts: &[reddit::Post],
+) -> Result<()> {
+    if config.dry_run {
+        info!(
+            "dry run, would send digest of {} post(s) from {name} chat_id={chat_id}",
+            posts.len()
+        );
+        return Ok(());
+    }
+
+    // Dated in the chat's time zone so that the day matches the one the chat is having
+    let timezone = db.get_chat_timezone(chat_id, config)?;
+    let date = chrono::Utc::now().with_timezone(&timezone).date_naive();
+    let message_html =
+        messages::format_post_digest(name, date, posts, config.links_base_url.as_deref());
+    let _permit = send_limiter::acquire(config).await;
+    tg.send_message(ChatId(chat_id), message_html)
+        .with_payload_mut(|payload| payload.message_thread_id = thread_id)
+        .parse_mode(teloxide::types::ParseMode::Html)
+        .link_preview_options(disabled_link_preview())
+        .await?;
+    info!("digest of {} post(s) sent chat_id={chat_id}", posts.len());
     Ok(())
 }
 
@@ -242,21 +864,170 @@ pub async fn process_post(
     post: &reddit::Post,
     config: &config::Config,
     tg: &Bot,
+    options: &DeliveryOptions,
 ) -> Result<()> {
+    process_post_with_image(db, chat_id, post, None, config, tg, options).await
+}
+
+/// Like `process_post`, but sends the already downloaded image of an image post.
+async fn process_post_with_image(
+    db: &db::Database,
+    chat_id: i64,
+    post: &reddit::Post,
+    image: Option<DownloadedFile>,
+    config: &config::Config,
+    tg: &Bot,
+    options: &DeliveryOptions,
+) -> Result<()> {
+    if config.dry_run {
+        info!(
+            "dry run, would send {} post_id={} chat_id={chat_id} title={:?}",
+            post.post_type, post.id, post.title
+        );
+        return Ok(());
+    }
+
     db.record_post_seen_with_current_time(chat_id, post)?;
-    if let Err(e) = handle_new_post(config, tg, chat_id, post).await {
+    let options = DeliveryOptions {
+        show_repost_buttons: db.get_show_repost_buttons(chat_id)?,
+        ..options.clone()
+    };
+    if let Err(e) = handle_new_post(config, tg, chat_id, post, &options, image).await {
         error!("failed to handle new post: {e:?}");
     };
     Ok(())
 }
 
+/// Like `process_post` for each post, except that consecutive image posts are sent together as
+/// albums of up to 10 images. Posts are sent in the given order.
+pub async fn process_posts_grouped(
+    db: &db::Database,
+    chat_id: i64,
+    posts: &[reddit::Post],
+    config: &config::Config,
+    tg: &Bot,
+    options: &DeliveryOptions,
+) -> Result<()> {
+    let mut album = vec![];
+    let download_images = config.download_types.contains(&reddit::PostType::Image);
+    for post in posts {
+        // Animated images and images that fail to download end the album and are sent on their
+        // own, so that the order of posts is kept
+        let mut image = None;
+        if post.post_type == reddit::PostType::Image && download_images && !config.dry_run {
+            match download_url_to_tmp(&post.url, config).await {
+                Ok(file) if !file.media_type.is_animated() => {
+                    album.push((post, file));
+                    if album.len() == MAX_MEDIA_GROUP_SIZE {
+                        process_album(db, chat_id, &mut album, config, tg, options).await?;
+                    }
+                    continue;
+                }
+                Ok(file) => image = Some(file),
+                Err(err) => warn!("could not download image of post {}: {err}", post.id),
+            }
+        }
+        process_album(db, chat_id, &mut album, config, tg, options).await?;
+        process_post_with_image(db, chat_id, post, image, config, tg, options).await?;
+    }
+    process_album(db, chat_id, &mut album, config, tg, options).await
+}
+
+/// Sends the images of posts as a media group, each image captioned with its post, and empties
+/// the album.
+async fn process_album(
+    db: &db::Database,
+    chat_id: i64,
+    album: &mut Vec<(&reddit::Post, DownloadedFile)>,
+    config: &config::Config,
+    tg: &Bot,
+    options: &DeliveryOptions,
+) -> Result<()> {
+    match album.as_slice() {
+        [] => return Ok(()),
+        // A media group needs at least two items
+        [_] => {
+            let (post, image) = album.pop().expect("album has an item");
+            process_post_with_image(db, chat_id, post, Some(image), config, tg, options).await?;
+        }
+        items => {
+            for (post, _) in items {
+                db.record_post_seen_with_current_time(chat_id, *post)?;
+            }
+            let as_document = options.photo_as_document || config.photo_as_document;
+            let media_group = items
+                .iter()
+                .map(|(post, file)| {
+                    let caption =
+                        format_caption(config, options, post, options.caption.unwrap_or_default());
+                    let file = InputFile::file(&file.path);
+                    if as_document {
+                        InputMedia::Document(
+                            InputMediaDocument::new(file)
+                                .caption(caption)
+                                .parse_mode(teloxide::types::ParseMode::Html),
+                        )
+                    } else {
+                        let mut input_media_photo = InputMediaPhoto::new(file)
+                            .caption(caption)
+                            .parse_mode(teloxide::types::ParseMode::Html);
+                        input_media_photo.has_spoiler = has_spoiler(config, post);
+                        InputMedia::Photo(input_media_photo)
+                    }
+                })
+                .collect::<Vec<_>>();
+            let sent = {
+                let _permit = send_limiter::acquire(config).await;
+                tg.send_media_group(ChatId(chat_id), media_group)
+                    .with_payload_mut(|payload| payload.message_thread_id = options.thread_id())
+                    .await
+            };
+            match sent {
+                Ok(msgs) => {
+                    for ((post, _), msg) in items.iter().zip(&msgs) {
+                        record_sent_file(config, chat_id, post, msg)?;
+                        delivery_webhook::notify(config, chat_id, post, msg.id);
+                    }
+                    info!("album of {} images uploaded chat_id={chat_id}", items.len());
+                    // The extended info of each post follows the whole album, as messages can't
+                    // be placed between the items of a media group
+                    if options.extended_info {
+                        for (post, _) in items {
+                            let thread_id = options.thread_id();
+                            if let Err(err) =
+                                send_extended_info(config, tg, chat_id, thread_id, post).await
+                            {
+                                error!("failed to send extended info of post {}: {err:?}", post.id);
+                            }
+                        }
+                    }
+                }
+                Err(err) => error!("failed to send album: {err:?}"),
+            }
+        }
+    }
+    album.clear();
+    Ok(())
+}
+
+/// Sends a new post. `image` is the image of an image post if it was downloaded already.
 pub async fn handle_new_post(
     config: &config::Config,
     tg: &Bot,
     chat_id: i64,
     post: &reddit::Post,
+    options: &DeliveryOptions,
+    image: Option<DownloadedFile>,
 ) -> Result<()> {
     info!("got new {post:#?}");
+    if config.dry_run {
+        info!(
+            "dry run, not sending {} post_id={} chat_id={chat_id}",
+            post.post_type, post.id
+        );
+        return Ok(());
+    }
+
     let mut post = Cow::Borrowed(post);
 
     // Sometimes post_hint is not in top list response but exists when getting the link directly,
@@ -264,37 +1035,348 @@ pub async fn handle_new_post(
     // TODO: It appears that post with is_gallery=true will never have post_hint set
     if post.post_hint.is_none() {
         info!("post missing post_hint, getting like directly");
-        post = Cow::Owned(reddit::get_link(&post.id).await.unwrap());
+        post = Cow::Owned(
+            reddit::get_link(&post.id)
+                .await
+                .context("Failed getting post directly")?,
+        );
     }
 
-    match post.post_type {
-        reddit::PostType::Image => handle_new_image_post(config, tg, chat_id, &post)
+    let message_id = send_post(config, tg, chat_id, &post, options, image).await?;
+    delivery_webhook::notify(config, chat_id, &post, message_id);
+    Ok(())
+}
+
+/// Sends the post with the handler for its type, followed by its comments if enabled. Returns the
+/// id of the message of the post, or the first message of a gallery. `image` is the image of an
+/// image post if it was downloaded already.
+pub async fn send_post(
+    config: &config::Config,
+    tg: &Bot,
+    chat_id: i64,
+    post: &reddit::Post,
+    options: &DeliveryOptions,
+    image: Option<DownloadedFile>,
+) -> Result<MessageId> {
+    info!(
+        "sending post_id={} as {} chat_id={chat_id}",
+        post.id, post.post_type
+    );
+    let is_media = matches!(
+        post.post_type,
+        reddit::PostType::Image
+            | reddit::PostType::Video
+            | reddit::PostType::Gallery
+            | reddit::PostType::Audio
+    );
+    let result = match post.post_type {
+        _ if is_media && !config.download_types.contains(&post.post_type) => {
+            info!(
+                "{} posts are not downloaded, sending as link",
+                post.post_type
+            );
+            handle_new_link_post(config, tg, chat_id, post, options, None)
+                .await
+                .context("Failed handling new link post")
+        }
+        reddit::PostType::Image => handle_new_image_post(config, tg, chat_id, post, options, image)
             .await
             .context("Failed handling new image"),
-        reddit::PostType::Video => handle_new_video_post(config, tg, chat_id, &post)
+        reddit::PostType::Video => handle_new_video_post(config, tg, chat_id, post, options)
             .await
             .context("Failed handling new video"),
-        reddit::PostType::Link => handle_new_link_post(config, tg, chat_id, &post)
+        reddit::PostType::Link => handle_new_link_post(config, tg, chat_id, post, options, None)
             .await
             .context("Failed handling new link post"),
-        reddit::PostType::SelfText => handle_new_self_post(config, tg, chat_id, &post)
+        reddit::PostType::SelfText => handle_new_self_post(config, tg, chat_id, post, options)
             .await
             .context("Failed handling new self"),
-        reddit::PostType::Gallery => handle_new_gallery_post(config, tg, chat_id, &post)
+        reddit::PostType::Gallery => handle_new_gallery_post(config, tg, chat_id, post, options)
             .await
             .context("Failed handling new gallery"),
-        // /r/bestof posts have no characteristics like post_hint that could be used to
-        // determine them as a type of Link; as a workaround, post Unknown post types the same way
-        // as a link
+        reddit::PostType::Audio => handle_new_audio_post(config, tg, chat_id, post, options)
+            .await
+            .context("Failed handling new audio"),
+        // Posts that couldn't be classified, e.g. ones with neither post_hint nor anything else
+        // to go by, are sent the same way as a link
         reddit::PostType::Unknown => {
             warn!("unknown post type, post={post:?}");
-            handle_new_link_post(config, tg, chat_id, &post).await
+            handle_new_link_post(config, tg, chat_id, post, options, None).await
+        }
+    };
+
+    if let Some(limit) = options.comments.filter(|limit| *limit > 0) {
+        if result.is_ok() {
+            let sort = options.comment_sort.unwrap_or_default();
+            send_post_comments(config, tg, chat_id, options.thread_id(), post, limit, sort)
+                .await
+                .context("Failed sending comments")?;
+        }
+    }
+
+    if options.extended_info && result.is_ok() {
+        // The post was sent already, so failing to follow it up isn't a failure of the post
+        if let Err(err) = send_extended_info(config, tg, chat_id, options.thread_id(), post).await {
+            error!("failed to send extended info of post {}: {err:?}", post.id);
         }
     }
+
+    result
 }
 
-fn is_gif(path: &Path) -> bool {
-    path.extension()
-        .and_then(|x| x.to_str().map(|x| x == "gif"))
-        .unwrap_or(false)
+/// Sends a message with more about the post, such as its author and top comment, following the
+/// post.
+async fn send_extended_info(
+    config: &config::Config,
+    tg: &Bot,
+    chat_id: i64,
+    thread_id: Option<ThreadId>,
+    post: &reddit::Post,
+) -> Result<()> {
+    // The rest of the message is still worth sending without the comment
+    let top_comment = match reddit::get_post_comments(&post.id, reddit::CommentSort::Top, 1).await {
+        Ok(comments) => comments.into_iter().next(),
+        Err(err) => {
+            warn!("failed to get top comment of post {}: {err:?}", post.id);
+            None
+        }
+    };
+
+    let message_html = messages::format_extended_info_html(
+        post,
+        top_comment.as_ref(),
+        config.links_base_url.as_deref(),
+    );
+    let _permit = send_limiter::acquire(config).await;
+    tg.send_message(ChatId(chat_id), message_html)
+        .with_payload_mut(|payload| payload.message_thread_id = thread_id)
+        .parse_mode(teloxide::types::ParseMode::Html)
+        .link_preview_options(disabled_link_preview())
+        .await?;
+    info!("extended info sent post_id={} chat_id={chat_id}", post.id);
+    Ok(())
+}
+
+/// Sends top level comments of a post as a message following the post.
+async fn send_post_comments(
+    config: &config::Config,
+    tg: &Bot,
+    chat_id: i64,
+    thread_id: Option<ThreadId>,
+    post: &reddit::Post,
+    limit: u32,
+    sort: reddit::CommentSort,
+) -> Result<()> {
+    let comments = reddit::get_post_comments(&post.id, sort, limit).await?;
+    if comments.is_empty() {
+        return Ok(());
+    }
+
+    let message_html = messages::format_comments_html(&comments);
+    let _permit = send_limiter::acquire(config).await;
+    tg.send_message(ChatId(chat_id), message_html)
+        .with_payload_mut(|payload| payload.message_thread_id = thread_id)
+        .parse_mode(teloxide::types::ParseMode::Html)
+        .link_preview_options(disabled_link_preview())
+        .await?;
+    info!(
+        "{} comment(s) sent post_id={} chat_id={chat_id}",
+        comments.len(),
+        post.id
+    );
+    Ok(())
+}
+
+fn disabled_link_preview() -> LinkPreviewOptions {
+    LinkPreviewOptions {
+        is_disabled: true,
+        url: None,
+        prefer_small_media: false,
+        prefer_large_media: false,
+        show_above_text: false,
+    }
+}
+
+/// Link preview options of a text message, `None` leaving the preview up to Telegram. Without
+/// `url` the first link in the message is previewed.
+fn link_preview_options(mode: LinkPreview, url: Option<&str>) -> Option<LinkPreviewOptions> {
+    match mode {
+        LinkPreview::Auto => None,
+        LinkPreview::Disabled => Some(disabled_link_preview()),
+        LinkPreview::Small | LinkPreview::Large => Some(LinkPreviewOptions {
+            is_disabled: false,
+            url: url.map(Into::into),
+            prefer_small_media: mode == LinkPreview::Small,
+            prefer_large_media: mode == LinkPreview::Large,
+            show_above_text: false,
+        }),
+    }
+}
+
+/// Whether the post's media should be hidden behind Telegram's spoiler blur.
+fn has_spoiler(config: &config::Config, post: &reddit::Post) -> bool {
+    config.respect_spoilers && post.spoiler
+}
+
+fn template<'a>(config: &'a config::Config, options: &'a DeliveryOptions) -> Option<&'a str> {
+    options
+        .template
+        .as_deref()
+        .or(config.default_template.as_deref())
+}
+
+fn format_caption(
+    config: &config::Config,
+    options: &DeliveryOptions,
+    post: &reddit::Post,
+    mode: CaptionMode,
+) -> String {
+    let links_base_url = config.links_base_url.as_deref();
+    if mode == CaptionMode::None {
+        return String::new();
+    }
+    let caption = match template(config, options) {
+        Some(template) => messages::render_template(post, template, links_base_url),
+        None => {
+            let badge = config
+                .score_badges
+                .then(|| messages::score_badge(post.score, &config.score_badge_tiers))
+                .flatten();
+            messages::format_media_caption_html(post, links_base_url, badge, mode)
+        }
+    };
+    with_sensitive_marker(config, post, caption)
+}
+
+/// Prefixes the caption or message of posts from sensitive subreddits with `sensitive_marker`.
+/// Independent of spoilers, which only blur the media.
+fn with_sensitive_marker(config: &config::Config, post: &reddit::Post, html: String) -> String {
+    match &config.sensitive_marker {
+        Some(marker) if post.is_sensitive(&config.sensitive_subreddits) => {
+            messages::prefix_marker(marker, &html)
+        }
+        _ => html,
+    }
+}
+
+/// Downloads a video with yt-dlp, logging how large it was and how long it took.
+fn download_video(url: &str, config: &config::Config) -> Result<Video> {
+    let started = Instant::now();
+    let video = tokio::task::block_in_place(|| ytdlp::download(url, config))?;
+    info!(
+        "downloaded {:.1} MB in {:.1}s url={url}",
+        video.size_bytes as f64 / 1_000_000.0,
+        started.elapsed().as_secs_f64()
+    );
+    Ok(video)
+}
+
+/// v.redd.it serves video and audio separately, and yt-dlp sometimes ends up with only the video.
+/// If the downloaded video has no audio, it's downloaded again preferring formats with audio.
+/// Videos that really have no sound are common too, so the original is kept if retrying doesn't
+/// help.
+fn ensure_reddit_video_audio(url: &str, config: &config::Config, video: Video) -> Video {
+    let is_reddit_video = Url::parse(url)
+        .ok()
+        .is_some_and(|url| url.host_str() == Some("v.redd.it"));
+    if !is_reddit_video || !transcode::is_ffprobe_available() {
+        return video;
+    }
+    match transcode::has_audio_stream(&video.path) {
+        Ok(true) => return video,
+        Ok(false) => info!("video {url} has no audio, downloading it again"),
+        Err(err) => {
+            warn!("could not check if video {url} has audio: {err:?}");
+            return video;
+        }
+    }
+    let retried =
+        tokio::task::block_in_place(|| ytdlp::redownload(url, config, ytdlp::WITH_AUDIO_FORMAT));
+    match retried {
+        Ok(retried) if transcode::has_audio_stream(&retried.path).unwrap_or(false) => retried,
+        Ok(_) => {
+            warn!("video {url} has no audio even after downloading it again");
+            video
+        }
+        Err(err) => {
+            warn!("failed to download video {url} again: {err:?}");
+            video
+        }
+    }
+}
+
+fn show_repost_buttons(config: &config::Config, options: &DeliveryOptions) -> bool {
+    options
+        .show_repost_buttons
+        .unwrap_or(config.show_repost_buttons)
+}
+
+/// Repost buttons of the post, unless they're turned off for the chat or in the config.
+fn repost_buttons<T: db::Recordable>(
+    config: &config::Config,
+    options: &DeliveryOptions,
+    post: &T,
+) -> Option<ReplyMarkup> {
+    show_repost_buttons(config, options)
+        .then(|| messages::format_repost_buttons(post, config).into())
+}
+
+/// Videos over the upload limit can't be sent, so they're re-encoded to fit if enabled. Returns
+/// `None` if the video is over the limit and couldn't be made to fit.
+fn fit_upload_limit(config: &config::Config, mut video: Video) -> Option<Video> {
+    if video.size_bytes <= MAX_UPLOAD_BYTES {
+        return Some(video);
+    }
+    if !config.transcode_oversize {
+        info!(
+            "video of {} bytes is over the upload limit video={video:?}",
+            video.size_bytes
+        );
+        return None;
+    }
+    if !transcode::is_ffmpeg_available() || !transcode::is_ffprobe_available() {
+        warn!("ffmpeg and ffprobe are required to re-encode oversize videos");
+        return None;
+    }
+    match tokio::task::block_in_place(|| transcode::shrink_to_fit(&video.path, MAX_UPLOAD_BYTES)) {
+        Ok(shrunk) => {
+            info!(
+                "re-encoded video of {} bytes to {shrunk:?}",
+                video.size_bytes
+            );
+            video.path = shrunk.path;
+            video.width = shrunk.width;
+            video.height = shrunk.height;
+            video.size_bytes = shrunk.size_bytes;
+            Some(video)
+        }
+        Err(err) => {
+            warn!("failed to re-encode oversize video {}: {err:?}", video.id);
+            None
+        }
+    }
+}
+
+/// Converts animations to a format Telegram handles well. Gifs are converted to mp4 only if
+/// enabled, falling back to the original file if conversion fails, as Telegram can convert gifs
+/// itself. Animated webp is not supported by Telegram, so it must be converted.
+fn prepare_animation(config: &config::Config, file: &DownloadedFile) -> Result<PathBuf> {
+    match file.media_type {
+        MediaType::Gif if config.convert_gifs => {
+            Ok(
+                tokio::task::block_in_place(|| transcode::animation_to_mp4(&file.path, config))
+                    .unwrap_or_else(|err| {
+                        warn!("failed to convert gif to mp4, sending as is: {err:?}");
+                        file.path.clone()
+                    }),
+            )
+        }
+        MediaType::AnimatedWebp => {
+            if !transcode::is_ffmpeg_available() {
+                anyhow::bail!("ffmpeg is required to convert animated webp");
+            }
+            tokio::task::block_in_place(|| transcode::animation_to_mp4(&file.path, config))
+        }
+        _ => Ok(file.path.clone()),
+    }
 }
@@ -1,8 +1,9 @@
 use crate::{handle_post::process_post, types::*};
 use anyhow::{Context, Result};
-use handle_post::handle_new_post;
+use itertools::Itertools;
 use log::*;
 use reddit::{PostType, TopPostsTimePeriod};
+use secrecy::ExposeSecret;
 use signal_hook::{
     consts::signal::{SIGINT, SIGTERM},
     iterator::Signals,
@@ -16,9 +17,8 @@ use std::{
     },
     time::Duration,
 };
-use teloxide::types::InputMediaPhoto;
 use teloxide::types::{InlineKeyboardButton, InlineKeyboardMarkup, InputFile};
-use teloxide::{prelude::*, types::InputMedia};
+use teloxide::{prelude::*, requests::HasPayload};
 
 use tokio::sync::broadcast;
 
@@ -26,44 +26,130 @@ mod args;
 mod bot;
 mod config;
 mod db;
+mod delivery_webhook;
 mod download;
+mod error_report;
 mod handle_post;
 mod messages;
 mod reddit;
+mod send_limiter;
+mod transcode;
 mod types;
+mod video_cache;
 mod ytdlp;
 
 const PKG_NAME: &str = env!("CARGO_PKG_NAME");
+const PKG_VERSION: &str = env!("CARGO_PKG_VERSION");
+// Set GIT_COMMIT environment variable at build time to include it in /version output
+const GIT_COMMIT: Option<&str> = option_env!("GIT_COMMIT");
+
+/// Checks that yt-dlp can be run, so that a missing yt-dlp is noticed at startup rather than when
+/// the first video fails to download.
+fn check_ytdlp(config: &config::Config) -> Result<()> {
+    let path = ytdlp::binary_path();
+    let path_str = path
+        .as_deref()
+        .map_or("<not found in PATH>".into(), |path| path.to_string_lossy());
+    match ytdlp::version() {
+        Ok(version) => {
+            info!("using yt-dlp {} at {path_str}", version.trim());
+            Ok(())
+        }
+        Err(err) if config.require_ytdlp => Err(err.context("yt-dlp is required but can't be run")),
+        Err(err) => {
+            warn!("yt-dlp at {path_str} can't be run, videos will fail to download: {err:?}");
+            Ok(())
+        }
+    }
+}
 
 #[tokio::main]
 async fn main() -> Result<()> {
     env_logger::init();
-    let config = Arc::new(config::read_config());
+    let opts = args::parse_args();
+    let mut config = config::read_config();
+    if opts.opt_present("dry-run") {
+        config.dry_run = true;
+    }
+    let config = Arc::new(config);
     info!("starting with config: {config:#?}");
+    if let Some(proxy_url) = &config.proxy_url {
+        reddit::set_proxy(proxy_url).context("failed to set up proxy")?;
+    }
+    if let Some(user_agent) = &config.reddit_user_agent {
+        reddit::set_user_agent(user_agent).context("failed to set up user agent")?;
+    }
+    if let Some(cookie) = &config.reddit_cookie {
+        reddit::set_cookie(cookie.expose_secret()).context("failed to set up reddit cookie")?;
+    }
+    if let Some(geo_filter) = &config.geo_filter {
+        reddit::set_geo_filter(geo_filter).context("failed to set up geo filter")?;
+    }
     let mut db = db::Database::open(&config)?;
+    // Usage: tgreddit --migration-status => Print the schema version of the database and exit
+    //        tgreddit --migrate          => Run pending migrations and exit
+    //        tgreddit --vacuum           => Reclaim unused space in the database and exit
+    //        tgreddit --selftest --chat-id <chatid> => Send a test message to the chat and exit
+    if opts.opt_present("migration-status") {
+        let (current, latest) = db.migration_status()?;
+        println!("database version: {current}, latest version: {latest}");
+        if current < latest {
+            println!("{} pending migration(s)", latest - current);
+        }
+        return Ok(());
+    }
     db.migrate()?;
+    if opts.opt_present("migrate") {
+        let (current, _) = db.migration_status()?;
+        println!("database migrated to version {current}");
+        return Ok(());
+    }
+    if opts.opt_present("vacuum") {
+        // Runs before anything else has the database open, VACUUM needs it to itself
+        let (before, after) = db.vacuum().context("failed to vacuum database")?;
+        println!("database vacuumed, size {before} -> {after} bytes");
+        return Ok(());
+    }
+    if opts.opt_present("selftest") {
+        return self_test(&config, &opts).await;
+    }
+    let subs = db.get_all_subscriptions()?;
+    let chat_count = subs.iter().map(|sub| sub.chat_id).unique().count();
+    let subscription_count = subs.len();
     drop(db);
+    download::prepare_download_dir(&config)?;
+    match download::cleanup_stale_tmp_dirs(&config) {
+        Ok(0) => {}
+        Ok(removed) => info!("removed {removed} stale temp dir(s)"),
+        Err(err) => warn!("failed to clean up stale temp dirs: {err:?}"),
+    }
+
+    check_ytdlp(&config)?;
+    if config.error_report_chat_id.is_some() {
+        error_report::enable(&config);
+    }
 
     let (shutdown_tx, mut shutdown_rx) = broadcast::channel::<()>(1);
     let shutdown = Arc::new(AtomicBool::new(false));
-    let bot = bot::MyBot::new(config.clone()).await?;
+    let maintenance = Arc::new(Maintenance::default());
+    let bot = bot::MyBot::new(config.clone(), maintenance.clone()).await?;
+
+    if config.dry_run {
+        warn!("dry run enabled, posts will not be sent or marked as seen");
+    }
 
     // Any arguments are for things that help with debugging and development
     // Not optimized for usability.
     //
     // Usage: tgreddit --debug-post <linkid>                    => Fetch post and print deserialized post
     //        tgreddit --debug-post <linkid> --chat-id <chatid> => Also send to telegram
-    let opts = args::parse_args();
+    //        tgreddit --debug-post <linkid> --debug-post-type <type> => Handle post as given type
+    //        tgreddit --dry-run                                  => Log posts instead of sending
     if let Some(post_id) = opts.opt_str("debug-post") {
-        let post = reddit::get_link(&post_id).await.unwrap();
-        info!("{post:#?}");
-        if let Some(chat_id) = opts.opt_str("chat-id") {
-            let db = db::Database::open(&config)?;
-            let chat_id = chat_id.parse().unwrap();
-            db.record_post(chat_id, &post, None)?;
-            return handle_new_post(&config, &bot.tg, chat_id, &post).await;
-        }
-        return Ok(());
+        return debug_post(&config, &bot.tg, &post_id, &opts).await;
+    }
+    if let Some(chat_id) = config.startup_notify_chat_id {
+        notify_startup(&config, &bot.tg, chat_id, subscription_count, chat_count).await;
     }
 
     let sub_check_loop_handle = {
@@ -71,9 +157,12 @@ async fn main() -> Result<()> {
         let tg = bot.tg.clone();
         tokio::task::spawn(async move {
             while !shutdown.load(Ordering::Acquire) {
-                check_new_posts(&config, &tg).await.unwrap_or_else(|err| {
-                    error!("failed to check for new posts: {err}");
-                });
+                check_new_posts(&config, &tg, &maintenance)
+                    .await
+                    .unwrap_or_else(|err| {
+                        error!("failed to check for new posts: {err}");
+                    });
+                error_report::flush(&config, &tg).await;
 
                 tokio::select! {
                    _ = tokio::time::sleep(Duration::from_secs(config.check_interval_secs)) => {}
@@ -84,7 +173,7 @@ async fn main() -> Result<()> {
             }
         })
     };
-    let (bot_handle, bot_shutdown_token) = bot.spawn();
+    let (bot_handle, bot_shutdown_token) = bot.spawn().await?;
 
     {
         let shutdown = shutdown.clone();
@@ -111,18 +200,21 @@ async fn main() -> Result<()> {
     Ok(())
 }
 
+/// Marks the post seen and sends it unless `only_mark_seen` is set. Returns whether the post was
+/// new and matched the filter.
 async fn check_post_newness(
     config: &config::Config,
+    db: &db::Database,
     tg: &Bot,
-    chat_id: i64,
-    filter: Option<reddit::PostType>,
+    sub: &Subscription,
+    filter: Option<&[reddit::PostType]>,
     post: &reddit::Post,
     only_mark_seen: bool,
-) -> Result<()> {
-    let db = db::Database::open(config)?;
-    if filter.is_some() && filter.as_ref() != Some(&post.post_type) {
+) -> Result<bool> {
+    let chat_id = sub.chat_id;
+    if !post.matches_filter(filter) {
         debug!("filter set and post does not match filter, skipping");
-        return Ok(());
+        return Ok(false);
     }
 
     if db
@@ -130,36 +222,321 @@ async fn check_post_newness(
         .expect("failed to query if post is seen")
     {
         debug!("post already seen, skipping...");
-        return Ok(());
+        return Ok(false);
     }
 
-    if !only_mark_seen {
+    if db.is_post_muted(chat_id, post)? {
+        debug!("post is muted, skipping...");
+        return Ok(false);
+    }
+
+    let media_url = post
+        .canonical_media_url()
+        .filter(|_| config.dedup_media_urls);
+    if let Some(media_url) = &media_url {
+        if db.is_media_seen(chat_id, media_url)? {
+            info!(
+                "media of post {} already seen in chat, skipping: {media_url}",
+                post.id
+            );
+            if !config.dry_run {
+                db.record_post_seen_with_current_time(chat_id, post)?;
+            }
+            return Ok(false);
+        }
+    }
+
+    if config.dedup_titles && is_title_repost(config, db, chat_id, post)? {
+        info!(
+            "post {} has the title of a recent post in r/{}, skipping",
+            post.id, post.subreddit
+        );
+        if !config.dry_run {
+            db.record_post_seen_with_current_time(chat_id, post)?;
+        }
+        return Ok(false);
+    }
+
+    if !only_mark_seen && is_quiet_time(config, db, chat_id)? {
+        if config.dry_run {
+            info!("dry run, quiet hours, not holding post: {}", post.id);
+        } else {
+            // Posts without seen_at are delivered when quiet hours end
+            db.hold_post(chat_id, post, sub.kind, &sub.subreddit)?;
+            if let Some(media_url) = &media_url {
+                db.record_media_seen(chat_id, media_url, &post.id)?;
+            }
+            info!("quiet hours, holding post: {}", post.id);
+        }
+        return Ok(false);
+    }
+
+    // Posts of digest subscriptions are sent together by the caller
+    if !only_mark_seen && !sub.digest {
         // Intentionally marking post as seen if handling it fails. It's preferable to not have it
         // fail continuously.
-        process_post(&db, chat_id, post, config, tg).await?;
+        process_post(db, chat_id, post, config, tg, &sub.into()).await?;
+    }
+
+    if config.dry_run {
+        info!("dry run, not marking post seen: {}", post.id);
+        return Ok(true);
     }
 
     db.record_post_seen_with_current_time(chat_id, post)?;
+    if let Some(media_url) = &media_url {
+        db.record_media_seen(chat_id, media_url, &post.id)?;
+    }
     info!("marked post seen: {}", post.id);
 
+    Ok(true)
+}
+
+/// Lets the operator know the bot restarted and what it's running with. Failing to send is only
+/// logged, the bot works without it.
+async fn notify_startup(
+    config: &config::Config,
+    tg: &Bot,
+    chat_id: i64,
+    subscription_count: usize,
+    chat_count: usize,
+) {
+    let ytdlp_version = tokio::task::block_in_place(ytdlp::version)
+        .map_err(|err| warn!("could not get yt-dlp version: {err:?}"))
+        .ok();
+    let message = messages::format_startup_summary(
+        ytdlp_version.as_deref(),
+        subscription_count,
+        chat_count,
+        config.check_interval_secs,
+    );
+    if config.dry_run {
+        info!("dry run, not sending startup summary to chat {chat_id}: {message}");
+        return;
+    }
+    let _permit = send_limiter::acquire(config).await;
+    if let Err(err) = tg.send_message(ChatId(chat_id), message).await {
+        warn!("failed to send startup summary to chat {chat_id}: {err:?}");
+    }
+}
+
+/// Sends a message to the chat given with --chat-id, to check that the bot token is valid and the
+/// bot can message the chat.
+async fn self_test(config: &config::Config, opts: &getopts::Matches) -> Result<()> {
+    let chat_id = opts
+        .opt_str("chat-id")
+        .context("--selftest requires --chat-id")?;
+    let chat_id: i64 = chat_id
+        .parse()
+        .with_context(|| format!("invalid chat id {chat_id}"))?;
+    let tg = bot::create_bot(config);
+    let me = tg
+        .get_me()
+        .await
+        .context("self-test failed, is the bot token valid?")?;
+    tg.send_message(ChatId(chat_id), format!("{PKG_NAME} self-test OK"))
+        .await
+        .with_context(|| {
+            format!(
+                "self-test failed, could not send a message to chat {chat_id} as @{}",
+                me.username()
+            )
+        })?;
+    println!(
+        "self-test OK, sent a message to chat {chat_id} as @{}",
+        me.username()
+    );
     Ok(())
 }
 
-async fn check_new_posts(config: &config::Config, tg: &Bot) -> Result<()> {
+async fn debug_post(
+    config: &config::Config,
+    tg: &Bot,
+    post_id: &str,
+    opts: &getopts::Matches,
+) -> Result<()> {
+    let post_id = &if reddit::is_share_link(post_id) {
+        reddit::resolve_share_link(post_id).await?
+    } else {
+        post_id.to_string()
+    };
+    let mut post = reddit::get_link(post_id)
+        .await
+        .with_context(|| format!("failed to get post {post_id}"))?;
+    info!("{post:#?}");
+    if let Some(post_type) = opts.opt_str("debug-post-type") {
+        post.post_type = post_type
+            .parse()
+            .with_context(|| format!("invalid post type {post_type}"))?;
+    }
+    info!("post {post_id} is handled as {}", post.post_type);
+
+    let Some(chat_id) = opts.opt_str("chat-id") else {
+        return Ok(());
+    };
+    let chat_id = chat_id
+        .parse()
+        .with_context(|| format!("invalid chat id {chat_id}"))?;
+    if config.dry_run {
+        info!("dry run, not sending post {post_id}");
+        return Ok(());
+    }
+
+    let db = db::Database::open(config)?;
+    db.record_post_seen_with_current_time(chat_id, &post)?;
+    // The post was just fetched directly, so handle_new_post's refetch isn't needed and would
+    // undo a forced post type
+    let result =
+        handle_post::send_post(config, tg, chat_id, &post, &Default::default(), None).await;
+    match &result {
+        Ok(_) => info!("handled {} post {post_id}", post.post_type),
+        Err(err) => error!(
+            "failed to handle {} post {post_id}: {err:?}",
+            post.post_type
+        ),
+    }
+    result.map(|_| ())
+}
+
+async fn check_new_posts(
+    config: &config::Config,
+    tg: &Bot,
+    maintenance: &Maintenance,
+) -> Result<()> {
+    if maintenance.is_on() {
+        info!("maintenance mode on, not checking subscriptions for new posts");
+        return Ok(());
+    }
     info!("checking subscriptions for new posts");
     let db = db::Database::open(config)?;
     let subs = db.get_all_subscriptions()?;
+    let chat_ids = subs
+        .iter()
+        .map(|sub| sub.chat_id)
+        .unique()
+        .collect::<Vec<_>>();
+    for chat_id in chat_ids {
+        deliver_pending_posts(config, tg, &subs, chat_id)
+            .await
+            .unwrap_or_else(|err| {
+                error!("failed to deliver pending posts: {err:?}");
+            });
+    }
+
     for sub in subs {
+        if sub.paused {
+            debug!("subscription to {} is paused, skipping", sub.subreddit);
+            continue;
+        }
         check_new_posts_for_subscription(config, tg, &sub)
             .await
             .unwrap_or_else(|err| {
                 error!("failed to check subscription for new posts: {err:?}");
+                error_report::record(&format!("{}{}", sub.kind.prefix(), sub.subreddit), &err);
             });
     }
 
     Ok(())
 }
 
+/// Whether a post with a similar title was seen or held in the subreddit within the window.
+fn is_title_repost(
+    config: &config::Config,
+    db: &db::Database,
+    chat_id: i64,
+    post: &reddit::Post,
+) -> Result<bool> {
+    let window = chrono::Duration::hours(config.dedup_titles_window_hours as i64);
+    Ok(db
+        .recent_titles_for_subreddit(chat_id, &post.subreddit, chrono::Utc::now() - window)?
+        .iter()
+        .any(|recent| {
+            reddit::title_similarity(&post.title, recent) >= config.dedup_titles_similarity
+        }))
+}
+
+fn is_quiet_time(config: &config::Config, db: &db::Database, chat_id: i64) -> Result<bool> {
+    let timezone = db.get_chat_timezone(chat_id, config)?;
+    let now = chrono::Utc::now().with_timezone(&timezone).time();
+    Ok(db
+        .get_quiet_hours(chat_id)?
+        .is_some_and(|quiet_hours| quiet_hours.contains(now)))
+}
+
+/// Delivers posts that were held during quiet hours, once quiet hours are over.
+async fn deliver_pending_posts(
+    config: &config::Config,
+    tg: &Bot,
+    subs: &[Subscription],
+    chat_id: i64,
+) -> Result<()> {
+    let db = db::Database::open(config)?;
+    if is_quiet_time(config, &db, chat_id)? {
+        return Ok(());
+    }
+
+    let pending_posts = db.get_pending_posts(chat_id)?;
+    if pending_posts.is_empty() {
+        return Ok(());
+    }
+
+    info!(
+        "delivering {} post(s) held during quiet hours to chat {chat_id}",
+        pending_posts.len()
+    );
+    // Posts held for digest subscriptions are sent as one digest per subscription
+    let mut digests: Vec<(&Subscription, Vec<reddit::Post>)> = vec![];
+    for db::PendingPost {
+        post_id,
+        subscription,
+    } in pending_posts
+    {
+        match reddit::get_link(&post_id).await {
+            Ok(post) => {
+                // Posts held before the subscription was recorded with them fall back to the
+                // subscription to their subreddit
+                let (kind, name) = subscription
+                    .unwrap_or_else(|| (SubscriptionKind::Subreddit, post.subreddit.clone()));
+                let sub = subs.iter().find(|sub| {
+                    sub.chat_id == chat_id
+                        && sub.kind == kind
+                        && sub.subreddit.eq_ignore_ascii_case(&name)
+                });
+                match sub {
+                    Some(sub) if sub.digest => {
+                        match digests
+                            .iter_mut()
+                            .find(|(other, _)| std::ptr::eq(*other, sub))
+                        {
+                            Some((_, posts)) => posts.push(post),
+                            None => digests.push((sub, vec![post])),
+                        }
+                    }
+                    _ => {
+                        let options = sub.map(DeliveryOptions::from).unwrap_or_default();
+                        process_post(&db, chat_id, &post, config, tg, &options).await?;
+                    }
+                }
+            }
+            Err(err) => {
+                warn!("could not get held post {post_id}, skipping it: {err:?}");
+                db.mark_pending_post_seen(chat_id, &post_id)?;
+            }
+        }
+    }
+
+    for (sub, posts) in digests {
+        for post in &posts {
+            db.mark_pending_post_seen(chat_id, &post.id)?;
+        }
+        let name = format!("{}{}", sub.kind.prefix(), sub.subreddit);
+        handle_post::send_post_digest(config, &db, tg, chat_id, sub.thread_id(), &name, &posts)
+            .await?;
+    }
+
+    Ok(())
+}
+
 async fn check_new_posts_for_subscription(
     config: &config::Config,
     tg: &Bot,
@@ -175,33 +552,281 @@ async fn check_new_posts_for_subscription(
         .time
         .or(config.default_time)
         .unwrap_or(config::DEFAULT_TIME_PERIOD);
-    let filter = sub.filter.or(config.default_filter);
+    let filter = sub
+        .filter
+        .clone()
+        .or_else(|| config.default_filter.map(|filter| vec![filter]));
     let chat_id = sub.chat_id;
 
-    match reddit::get_subreddit_top_posts(subreddit, limit, &time).await {
+    let prefix = sub.kind.prefix();
+    match reddit::get_top_posts(sub.kind, subreddit, limit, &time).await {
         Ok(posts) => {
-            debug!("got {} post(s) for subreddit /r/{}", posts.len(), subreddit);
+            debug!("got {} post(s) for {prefix}{subreddit}", posts.len());
+            db.reset_subscription_failures(chat_id, sub.kind, subreddit)?;
 
             // First run should not send anything to telegram but the post should be marked
             // as seen, unless skip_initial_send is enabled
-            let is_new_subreddit = !db
-                .existing_posts_for_subreddit(chat_id, subreddit)
-                .context("failed to query if subreddit has existing posts")?;
+            let is_new_subreddit = is_first_check(&db, sub)?;
             let only_mark_seen = is_new_subreddit && config.skip_initial_send;
 
+            // In digest mode posts are only marked seen or held here and sent together after the
+            // loop
+            let mut digest_posts = vec![];
+            let mut primed_count = 0;
             for post in posts {
                 debug!("got {post:?}");
-                check_post_newness(config, tg, chat_id, filter, &post, only_mark_seen)
-                    .await
-                    .unwrap_or_else(|err| {
-                        error!("failed to check post newness: {err:?}");
-                    });
+                // Posts from other domains, with other flairs, too few comments, stickied posts,
+                // posts that aren't media in media_only subscriptions or posts older than the
+                // subscription are marked seen so that they're not considered again
+                let is_excluded_domain = match &sub.domains {
+                    Some(domains) => !post.is_from_domain(domains),
+                    None => false,
+                };
+                if is_excluded_domain {
+                    debug!("post {} is not from subscribed domains", post.id);
+                }
+                let is_excluded_flair = match &sub.flairs {
+                    Some(flairs) => !post.has_flair(flairs, config.flair_match),
+                    None => false,
+                };
+                if is_excluded_flair {
+                    debug!("post {} does not have a subscribed flair", post.id);
+                }
+                let is_excluded_comments = sub
+                    .min_comments
+                    .is_some_and(|min_comments| post.num_comments < min_comments.into());
+                if is_excluded_comments {
+                    debug!("post {} has too few comments", post.id);
+                }
+                let is_excluded_stickied =
+                    post.stickied && sub.skip_stickied.unwrap_or(config.skip_stickied);
+                if is_excluded_stickied {
+                    debug!("post {} is stickied", post.id);
+                }
+                let is_excluded_media = sub.media_only && !post.is_media();
+                if is_excluded_media {
+                    debug!("post {} is not media", post.id);
+                }
+                let is_excluded_old =
+                    sub.only_newer_than_sub && post.created < sub.created_at.timestamp() as f64;
+                if is_excluded_old {
+                    debug!("post {} was created before the subscription", post.id);
+                }
+                let is_excluded = is_excluded_domain
+                    || is_excluded_flair
+                    || is_excluded_comments
+                    || is_excluded_stickied
+                    || is_excluded_media
+                    || is_excluded_old;
+                let only_mark_seen = only_mark_seen || is_excluded;
+                let is_new = check_post_newness(
+                    config,
+                    &db,
+                    tg,
+                    sub,
+                    filter.as_deref(),
+                    &post,
+                    only_mark_seen,
+                )
+                .await
+                .unwrap_or_else(|err| {
+                    error!("failed to check post newness: {err:?}");
+                    error_report::record(&format!("{prefix}{subreddit}"), &err);
+                    false
+                });
+                if sub.digest && is_new && !only_mark_seen {
+                    digest_posts.push(post);
+                }
+                if is_new && !is_excluded && is_new_subreddit && config.skip_initial_send {
+                    primed_count += 1;
+                }
+            }
+
+            if is_new_subreddit && sub.kind == SubscriptionKind::User && !config.dry_run {
+                db.mark_subscription_primed(chat_id, sub.kind, subreddit)?;
+            }
+            if primed_count > 0 && !sub.primed {
+                notify_subscription_primed(config, tg, &db, sub, primed_count).await?;
+            }
+
+            if !digest_posts.is_empty() {
+                let name = format!("{prefix}{subreddit}");
+                handle_post::send_post_digest(
+                    config,
+                    &db,
+                    tg,
+                    chat_id,
+                    sub.thread_id(),
+                    &name,
+                    &digest_posts,
+                )
+                .await?;
+            }
+        }
+        Err(e @ (reddit::TopPostsError::Forbidden | reddit::TopPostsError::NotFound)) => {
+            warn!("could not get posts for {prefix}{subreddit}: {e}");
+            let failures = db.record_subscription_failure(chat_id, sub.kind, subreddit)?;
+            let threshold = config.max_subscription_failures;
+            if threshold > 0 && failures >= threshold {
+                pause_failing_subscription(config, tg, &db, sub, failures, &e).await?;
             }
         }
+        Err(e @ reddit::TopPostsError::RateLimited) => {
+            warn!("could not get posts for {prefix}{subreddit}: {e}")
+        }
         Err(e) => {
-            error!("failed to get posts for {subreddit}: {e:?}")
+            error!("failed to get posts for {prefix}{subreddit}: {e:?}");
+            error_report::record(&format!("{prefix}{subreddit}"), &e.into());
         }
     };
 
     Ok(())
 }
+
+/// Whether the subscription hasn't been checked before, in which case its posts are only marked
+/// seen if skip_initial_send is enabled.
+fn is_first_check(db: &db::Database, sub: &Subscription) -> Result<bool> {
+    match sub.kind {
+        SubscriptionKind::Subreddit => Ok(!db
+            .existing_posts_for_subreddit(sub.chat_id, &sub.subreddit)
+            .context("failed to query if subreddit has existing posts")?),
+        // Posts of a user are recorded under the subreddits they were posted to, so the
+        // subscription is marked primed after its first check instead
+        SubscriptionKind::User => Ok(!sub.primed),
+    }
+}
+
+/// Lets the chat know that the first posts of a subscription were only marked seen, so that it
+/// doesn't look like the subscription isn't working.
+async fn notify_subscription_primed(
+    config: &config::Config,
+    tg: &Bot,
+    db: &db::Database,
+    sub: &Subscription,
+    post_count: usize,
+) -> Result<()> {
+    let message = messages::format_subscription_primed(sub, post_count);
+    if config.dry_run {
+        info!(
+            "dry run, not sending message to chat {}: {message}",
+            sub.chat_id
+        );
+        return Ok(());
+    }
+
+    db.mark_subscription_primed(sub.chat_id, sub.kind, &sub.subreddit)?;
+    tg.send_message(ChatId(sub.chat_id), message)
+        .with_payload_mut(|payload| payload.message_thread_id = sub.thread_id())
+        .await?;
+    Ok(())
+}
+
+async fn pause_failing_subscription(
+    config: &config::Config,
+    tg: &Bot,
+    db: &db::Database,
+    sub: &Subscription,
+    failures: u32,
+    err: &reddit::TopPostsError,
+) -> Result<()> {
+    let subreddit = &sub.subreddit;
+    let prefix = sub.kind.prefix();
+    db.pause_subscription(sub.chat_id, sub.kind, subreddit)?;
+    warn!(
+        "paused subscription to {prefix}{subreddit} in chat {} after {failures} failures",
+        sub.chat_id
+    );
+
+    let message = format!(
+        "{prefix}{subreddit} has failed {failures} times in a row ({err}), pausing the \
+         subscription. Use /sub {prefix}{subreddit} to resume it."
+    );
+    if config.dry_run {
+        info!(
+            "dry run, not sending message to chat {}: {message}",
+            sub.chat_id
+        );
+        return Ok(());
+    }
+    tg.send_message(ChatId(sub.chat_id), message)
+        .with_payload_mut(|payload| payload.message_thread_id = sub.thread_id())
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_first_check_user() {
+        let config = config::Config::default();
+        let mut db = db::Database::open(&config).unwrap();
+        db.migrate().unwrap();
+        let args = SubscriptionArgs {
+            kind: SubscriptionKind::User,
+            subreddit: "spez".into(),
+            ..Default::default()
+        };
+        db.subscribe(1, &args).unwrap();
+        let post = |id: &str| reddit::Post {
+            id: id.into(),
+            subreddit: "pics".into(),
+            title: "title".into(),
+            ..Default::default()
+        };
+        let get_sub = || db.get_subscriptions_for_chat(1).unwrap().remove(0);
+
+        // The first check only marks the top post seen
+        assert!(is_first_check(&db, &get_sub()).unwrap());
+        db.record_post_seen_with_current_time(1, &post("aaa111"))
+            .unwrap();
+        db.mark_subscription_primed(1, SubscriptionKind::User, "spez")
+            .unwrap();
+
+        // A new top post on the next check is unseen, but is delivered rather than only marked
+        // seen
+        assert!(!db.is_post_seen(1, &post("bbb222")).unwrap());
+        assert!(!is_first_check(&db, &get_sub()).unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_check_post_newness_holds_digest_posts() {
+        let config = config::Config::default();
+        let mut db = db::Database::open(&config).unwrap();
+        db.migrate().unwrap();
+        let args = SubscriptionArgs {
+            subreddit: "pics".into(),
+            digest: true,
+            ..Default::default()
+        };
+        db.subscribe(1, &args).unwrap();
+        let sub = db.get_subscriptions_for_chat(1).unwrap().remove(0);
+        let now = chrono::Utc::now().time();
+        let quiet_hours = QuietHours {
+            start: now - chrono::Duration::hours(1),
+            end: now + chrono::Duration::hours(1),
+        };
+        db.set_quiet_hours(1, Some(quiet_hours)).unwrap();
+        let post = reddit::Post {
+            id: "aaa111".into(),
+            subreddit: "pics".into(),
+            title: "title".into(),
+            ..Default::default()
+        };
+
+        // Nothing is sent during quiet hours, so the bot is never used
+        let tg = Bot::new("0:token");
+        let is_new = check_post_newness(&config, &db, &tg, &sub, None, &post, false)
+            .await
+            .unwrap();
+        assert!(!is_new);
+        assert_eq!(
+            db.get_pending_posts(1).unwrap(),
+            vec![db::PendingPost {
+                post_id: "aaa111".into(),
+                subscription: Some((SubscriptionKind::Subreddit, "pics".into())),
+            }]
+        );
+    }
+}
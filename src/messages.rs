@@ -3,7 +3,32 @@ use crate::{
     db::Recordable,
     reddit::{self},
 };
+use chrono::NaiveDate;
 use itertools::Itertools;
+use lazy_static::lazy_static;
+use regex::{Captures, Regex};
+
+const TEMPLATE_PLACEHOLDERS: &[&str] = &[
+    "title",
+    "subreddit",
+    "comments",
+    "old_comments",
+    "url",
+    "permalink",
+    "score",
+    "num_comments",
+    "type",
+    "flair",
+];
+
+/// Telegram's limit for text messages
+pub const MAX_MESSAGE_LENGTH: usize = 4096;
+/// Telegram's limit for media captions
+pub const MAX_CAPTION_LENGTH: usize = 1024;
+
+lazy_static! {
+    static ref TEMPLATE_PLACEHOLDER_RE: Regex = Regex::new(r"\{(\w+)\}").unwrap();
+}
 
 fn escape(html: &str) -> String {
     html.replace('<', "&lt;").replace('>', "&gt;")
@@ -34,10 +59,91 @@ fn format_meta_html(post: &reddit::Post, links_base_url: Option<&str>) -> String
     }
 }
 
-pub fn format_media_caption_html(post: &reddit::Post, links_base_url: Option<&str>) -> String {
-    let title = &post.title;
-    let meta = format_meta_html(post, links_base_url);
-    format!("{title}\n{meta}")
+pub fn format_media_caption_html(
+    post: &reddit::Post,
+    links_base_url: Option<&str>,
+    badge: Option<&str>,
+    mode: CaptionMode,
+) -> String {
+    let title = match badge {
+        Some(badge) => format!("{badge} {}", post.title),
+        None => post.title.clone(),
+    };
+    match mode {
+        CaptionMode::Full => {
+            let meta = format_meta_html(post, links_base_url);
+            format!("{title}\n{meta}")
+        }
+        CaptionMode::Title => title,
+        CaptionMode::None => String::new(),
+    }
+}
+
+/// Badge of the highest tier the score is above, if any.
+pub fn score_badge(score: i64, tiers: &[config::ScoreBadgeTier]) -> Option<&str> {
+    tiers
+        .iter()
+        .filter(|tier| score > tier.above)
+        .max_by_key(|tier| tier.above)
+        .map(|tier| tier.badge.as_str())
+}
+
+/// Checks that a template only uses known placeholders, returning the first unknown one otherwise.
+pub fn validate_template(template: &str) -> Result<(), String> {
+    match TEMPLATE_PLACEHOLDER_RE
+        .captures_iter(template)
+        .find(|caps| !TEMPLATE_PLACEHOLDERS.contains(&&caps[1]))
+    {
+        Some(caps) => Err(format!(
+            "Unknown placeholder {} in template, supported are: {}",
+            &caps[0],
+            TEMPLATE_PLACEHOLDERS
+                .iter()
+                .map(|p| format!("{{{p}}}"))
+                .join(", ")
+        )),
+        None => Ok(()),
+    }
+}
+
+/// Renders a template such as `{title}\n{subreddit} [{comments}]` as HTML. Unknown placeholders
+/// are left as is.
+pub fn render_template(
+    post: &reddit::Post,
+    template: &str,
+    links_base_url: Option<&str>,
+) -> String {
+    TEMPLATE_PLACEHOLDER_RE
+        .replace_all(template, |caps: &Captures| match &caps[1] {
+            "title" => escape(&post.title),
+            "subreddit" => format_subreddit_link(&post.subreddit, links_base_url),
+            "comments" => {
+                format_html_anchor(&post.format_permalink_url(links_base_url), "comments")
+            }
+            "old_comments" => format_html_anchor(&post.format_old_permalink_url(), "old"),
+            "url" => escape(&post.url),
+            "permalink" => escape(&post.format_permalink_url(links_base_url)),
+            "score" => post.score.to_string(),
+            "num_comments" => post.num_comments.to_string(),
+            "type" => post.post_type.to_string(),
+            "flair" => post
+                .link_flair_text
+                .as_deref()
+                .map(escape)
+                .unwrap_or_default(),
+            _ => caps[0].to_string(),
+        })
+        .into_owned()
+}
+
+/// Prefixes a caption or message with a marker such as an emoji. The marker is shown on its own if
+/// there's no caption.
+pub fn prefix_marker(marker: &str, html: &str) -> String {
+    if html.is_empty() {
+        escape(marker)
+    } else {
+        format!("{} {html}", escape(marker))
+    }
 }
 
 pub fn format_link_video_caption_html(video: &Video) -> String {
@@ -46,30 +152,158 @@ pub fn format_link_video_caption_html(video: &Video) -> String {
     format!("{title}\n{meta}")
 }
 
+/// Repost buttons, followed by a button that opens the post on reddit if `post_link_button` is
+/// enabled and the post has a permalink.
 pub fn format_repost_buttons_gallery<T: Recordable>(
     post: &T,
     is_gallery: bool,
+    config: &config::Config,
+) -> InlineKeyboardMarkup {
+    let buttons = format_repost_buttons_for_id(post.id(), is_gallery);
+    let post_url = post
+        .permalink()
+        .filter(|_| config.post_link_button)
+        .and_then(|permalink| {
+            let url = reddit::format_url_from_path(permalink, config.links_base_url.as_deref());
+            url::Url::parse(&url).ok()
+        });
+    match post_url {
+        Some(url) => buttons.append_row([InlineKeyboardButton::url("Open post", url)]),
+        None => buttons,
+    }
+}
+
+pub fn format_repost_buttons<T: Recordable>(
+    post: &T,
+    config: &config::Config,
+) -> InlineKeyboardMarkup {
+    format_repost_buttons_gallery(post, false, config)
+}
+
+/// Buttons of the sample message sent by /testrepost.
+pub fn format_test_repost_buttons() -> InlineKeyboardMarkup {
+    format_repost_buttons_for_id(TEST_REPOST_POST_ID, false)
+}
+
+/// No buttons if the post id doesn't fit in the callback data, as Telegram would reject the
+/// message.
+fn format_repost_buttons_for_id(post_id: &str, is_gallery: bool) -> InlineKeyboardMarkup {
+    let encode = |copy_caption| {
+        ButtonCallbackData {
+            post_id: post_id.to_owned(),
+            copy_caption,
+            is_gallery,
+        }
+        .encode()
+    };
+    match (encode(true), encode(false)) {
+        (Ok(callback_data), Ok(callback_data_no_title)) => InlineKeyboardMarkup::default()
+            .append_row([
+                InlineKeyboardButton::callback("Post", callback_data),
+                InlineKeyboardButton::callback("Post (no title)", callback_data_no_title),
+            ]),
+        (Err(err), _) | (_, Err(err)) => {
+            log::warn!("not adding repost buttons to post {post_id}: {err}");
+            InlineKeyboardMarkup::default()
+        }
+    }
+}
+
+/// Buttons subscribing to the subreddits found when the one given to /sub doesn't exist, one per
+/// row.
+pub fn format_subreddit_suggestion_buttons(
+    results: &[reddit::SubredditSearchResult],
 ) -> InlineKeyboardMarkup {
-    let callback_data = serde_json::to_string(&ButtonCallbackData {
-        post_id: post.id().to_owned(),
-        copy_caption: true,
-        is_gallery,
-    })
-    .expect("This can't fail i promise");
-    let callback_data_no_title = serde_json::to_string(&ButtonCallbackData {
-        post_id: post.id().to_owned(),
-        copy_caption: false,
-        is_gallery,
-    })
-    .expect("Can't fail");
-    InlineKeyboardMarkup::default().append_row([
-        InlineKeyboardButton::callback("Post", callback_data),
-        InlineKeyboardButton::callback("Post (no title)", callback_data_no_title),
-    ])
-}
-
-pub fn format_repost_buttons<T: Recordable>(post: &T) -> InlineKeyboardMarkup {
-    format_repost_buttons_gallery(post, false)
+    InlineKeyboardMarkup::new(results.iter().map(|result| {
+        let text = match result.subscribers {
+            Some(subscribers) => format!(
+                "r/{} ({} subscribers)",
+                result.display_name,
+                format_compact_count(subscribers)
+            ),
+            None => format!("r/{}", result.display_name),
+        };
+        let callback_data = serde_json::to_string(&SubscribeCallbackData {
+            subreddit: result.display_name.clone(),
+        })
+        .expect("Can't fail");
+        [InlineKeyboardButton::callback(text, callback_data)]
+    }))
+}
+
+/// Formats a count with a k or M suffix when it's large, e.g. 1.2k.
+fn format_compact_count(count: u64) -> String {
+    // Rounded before picking the suffix, so that e.g. 999,950 is 1.0M rather than 1000.0k
+    let thousands = (count as f64 / 100.0).round() / 10.0;
+    if count < 1_000 {
+        count.to_string()
+    } else if thousands < 1_000.0 {
+        format!("{thousands:.1}k")
+    } else {
+        format!("{:.1}M", count as f64 / 1e6)
+    }
+}
+
+/// Caption of a post reposted to the repost channel, with the title if it's copied and the
+/// configured suffix. The title is shortened if both don't fit in a caption.
+pub fn format_repost_caption(title: Option<&str>, suffix: Option<&str>) -> Option<String> {
+    let suffix = suffix.map(str::trim).filter(|suffix| !suffix.is_empty());
+    let budget =
+        MAX_CAPTION_LENGTH.saturating_sub(suffix.map_or(0, |suffix| suffix.chars().count() + 2));
+    let title = title.map(|title| {
+        if title.chars().count() <= budget {
+            title.to_string()
+        } else {
+            let mut title = title
+                .chars()
+                .take(budget.saturating_sub(1))
+                .collect::<String>();
+            title.push('…');
+            title
+        }
+    });
+    let caption = [title.as_deref(), suffix]
+        .into_iter()
+        .flatten()
+        .map(escape)
+        .join("\n\n");
+    (!caption.is_empty()).then_some(caption)
+}
+
+pub fn format_moved_chat(from: i64, to: i64, moved: &db::MovedChat) -> String {
+    let mut message = format!(
+        "Moved {} subscription(s) and the seen posts of chat {from} to chat {to}",
+        moved.subscriptions
+    );
+    if !moved.conflicting_subscriptions.is_empty() {
+        message.push_str(&format!(
+            "\nChat {to} was already subscribed to {}, its subscriptions were kept as they were",
+            moved.conflicting_subscriptions.join(", ")
+        ));
+    }
+    message
+}
+
+pub fn format_post_debug_info(post_id: &str, info: &db::PostDebugInfo) -> String {
+    let seen = match (info.recorded, info.seen_at) {
+        (false, _) => "no".to_string(),
+        (true, None) => "held during quiet hours, not sent yet".to_string(),
+        (true, Some(seen_at)) => format!("yes, at {}", seen_at.format("%Y-%m-%d %H:%M:%S UTC")),
+    };
+    let yes_no = |b| if b { "yes" } else { "no" };
+    let mut lines = vec![
+        format!("Post {post_id} in this chat"),
+        format!("Seen: {seen}"),
+    ];
+    if let Some(subreddit) = &info.subreddit {
+        lines.push(format!("Subreddit: r/{subreddit}"));
+    }
+    if let Some(title) = &info.title {
+        lines.push(format!("Title: {title}"));
+    }
+    lines.push(format!("Uploaded files: {}", info.telegram_files));
+    lines.push(format!("Muted: {}", yes_no(info.muted)));
+    lines.join("\n")
 }
 
 pub fn format_link_message_html(post: &reddit::Post, links_base_url: Option<&str>) -> String {
@@ -78,8 +312,258 @@ pub fn format_link_message_html(post: &reddit::Post, links_base_url: Option<&str
     format!("{title}\n{meta}")
 }
 
-pub fn format_subscription_list(post: &[Subscription]) -> String {
-    fn format_subscription(sub: &Subscription) -> String {
+/// Lists the captions and links of gallery items, numbered by their position in the gallery.
+/// Returns None if no item has either.
+pub fn format_gallery_item_captions(items: &[reddit::GalleryDataItem]) -> Option<String> {
+    let lines = items
+        .iter()
+        .enumerate()
+        .filter(|(_, item)| item.caption.is_some() || item.outbound_url.is_some())
+        .map(|(i, item)| {
+            let caption = item.caption.as_deref().map(escape);
+            let link = item
+                .outbound_url
+                .as_deref()
+                .map(|url| format_html_anchor(url, "link"));
+            let text = [caption, link].into_iter().flatten().join(" ");
+            format!("{}. {text}", i + 1)
+        })
+        .collect::<Vec<_>>();
+    (!lines.is_empty()).then(|| lines.join("\n"))
+}
+
+/// Formats posts as a single message listing their scores and titles linking to the comments.
+/// Posts that don't fit in one Telegram message are summarized as a count. `date` is the day the
+/// digest is for in the chat's time zone.
+pub fn format_post_digest(
+    name: &str,
+    date: NaiveDate,
+    posts: &[reddit::Post],
+    links_base_url: Option<&str>,
+) -> String {
+    let mut message = format!(
+        "<b>New posts in {} on {}</b>",
+        escape(name),
+        date.format("%-d %b %Y")
+    );
+    for (i, post) in posts.iter().enumerate() {
+        let line = format!(
+            "\n▲{} {}",
+            post.score,
+            format_html_anchor(&post.format_permalink_url(links_base_url), &post.title)
+        );
+        let more = format!("\n…and {} more", posts.len() - i);
+        if message.len() + line.len() + more.len() > MAX_MESSAGE_LENGTH {
+            message.push_str(&more);
+            break;
+        }
+        message.push_str(&line);
+    }
+    message
+}
+
+/// Formats the body of a self post as HTML. Bodies longer than `max_length` are truncated with a
+/// link to the post.
+pub fn format_selftext_html(
+    post: &reddit::Post,
+    max_length: usize,
+    links_base_url: Option<&str>,
+) -> Option<String> {
+    let selftext = post.selftext.as_deref()?.trim();
+    if selftext.is_empty() {
+        return None;
+    }
+
+    let selftext_html = escape(selftext);
+    if selftext_html.chars().count() <= max_length {
+        return Some(selftext_html);
+    }
+
+    let read_more = format!(
+        "… {}",
+        format_html_anchor(&post.format_permalink_url(links_base_url), "read more")
+    );
+    let budget = max_length.saturating_sub(read_more.chars().count());
+    let mut excerpt = String::new();
+    let mut excerpt_length = 0;
+    // Truncating the escaped text could cut an entity in half, so the text is escaped a
+    // character at a time
+    for c in selftext.chars() {
+        let c_html = escape(&c.to_string());
+        let c_length = c_html.chars().count();
+        if excerpt_length + c_length > budget {
+            break;
+        }
+        excerpt.push_str(&c_html);
+        excerpt_length += c_length;
+    }
+
+    Some(format!("{}{read_more}", excerpt.trim_end()))
+}
+
+/// Formats a comment with its score and author, truncating long comments.
+fn format_comment_html(comment: &reddit::Comment) -> String {
+    const MAX_COMMENT_LENGTH: usize = 500;
+    let body = comment.body.trim();
+    let body = if body.chars().count() > MAX_COMMENT_LENGTH {
+        format!(
+            "{}…",
+            body.chars().take(MAX_COMMENT_LENGTH).collect::<String>()
+        )
+    } else {
+        body.to_string()
+    };
+    format!(
+        "▲{} <b>{}</b>\n{}",
+        comment.score,
+        escape(&comment.author),
+        escape(&body)
+    )
+}
+
+/// Formats comments of a post as a single message. Long comments are truncated and comments that
+/// don't fit in the message are left out.
+pub fn format_comments_html(comments: &[reddit::Comment]) -> String {
+    let mut message = String::new();
+    for comment in comments {
+        let comment_html = format_comment_html(comment);
+        let separator = if message.is_empty() { "" } else { "\n\n" };
+        if message.chars().count() + separator.len() + comment_html.chars().count()
+            > MAX_MESSAGE_LENGTH
+        {
+            break;
+        }
+        message.push_str(separator);
+        message.push_str(&comment_html);
+    }
+    message
+}
+
+/// Message following a post in `extended_info` subscriptions, with the author, score, comment and
+/// award counts and the top comment.
+pub fn format_extended_info_html(
+    post: &reddit::Post,
+    top_comment: Option<&reddit::Comment>,
+    links_base_url: Option<&str>,
+) -> String {
+    let mut lines = vec![format!("<b>{}</b>", escape(&post.title))];
+    if !post.author.is_empty() {
+        lines.push(format!("by u/{}", escape(&post.author)));
+    }
+    let comments = format_html_anchor(
+        &post.format_permalink_url(links_base_url),
+        &format!("{} comments", post.num_comments),
+    );
+    let mut stats = format!("▲{} · {comments}", post.score);
+    if post.total_awards_received > 0 {
+        stats.push_str(&format!(" · 🏆{}", post.total_awards_received));
+    }
+    lines.push(stats);
+    if let Some(comment) = top_comment {
+        lines.push(format!("\nTop comment:\n{}", format_comment_html(comment)));
+    }
+    lines.join("\n")
+}
+
+pub fn format_subscription_primed(sub: &Subscription, post_count: usize) -> String {
+    format!(
+        "Primed {}{} with {post_count} recent post(s), you'll get new ones from here.",
+        sub.kind.prefix(),
+        sub.subreddit
+    )
+}
+
+pub fn format_quiet_hours(quiet_hours: &QuietHours) -> String {
+    format!(
+        "Quiet hours are from {} to {}, posts are held until they end",
+        quiet_hours.start.format("%H:%M"),
+        quiet_hours.end.format("%H:%M")
+    )
+}
+
+pub fn format_version(ytdlp_version: Option<&str>) -> String {
+    let commit = GIT_COMMIT
+        .filter(|c| !c.is_empty())
+        .map(|c| format!(" ({c})"))
+        .unwrap_or_default();
+    let ytdlp_version = ytdlp_version.unwrap_or("not available");
+    format!("{PKG_NAME} {PKG_VERSION}{commit}\nyt-dlp {ytdlp_version}")
+}
+
+pub fn format_startup_summary(
+    ytdlp_version: Option<&str>,
+    subscription_count: usize,
+    chat_count: usize,
+    check_interval_secs: u64,
+) -> String {
+    let mut message = format!(
+        "Started {}\n{subscription_count} subscription(s) in {chat_count} chat(s), checked every {check_interval_secs}s",
+        format_version(ytdlp_version)
+    );
+    if subscription_count == 0 {
+        message.push_str("\nThere are no subscriptions, subscribe with /sub");
+    }
+    message
+}
+
+pub fn format_effective_config(name: &str, settings: &[config::EffectiveSetting]) -> String {
+    let lines = settings
+        .iter()
+        .map(|setting| format!("{}: {} ({})", setting.name, setting.value, setting.source))
+        .join("\n");
+    format!("Settings of {name}:\n{lines}")
+}
+
+pub fn format_error_report(
+    summaries: &[error_report::ErrorSummary],
+    now: tokio::time::Instant,
+) -> String {
+    let mut message = format!("{PKG_NAME} errors:");
+    for (i, summary) in summaries.iter().enumerate() {
+        let age = chrono::TimeDelta::from_std(now.saturating_duration_since(summary.first_at))
+            .unwrap_or_default();
+        let line = format!(
+            "\n{} error(s) in {}, the first {}: {}",
+            summary.count,
+            summary.source,
+            format_relative_age(age),
+            summary.reasons.join("; ")
+        );
+        let more = format!("\n…and {} more", summaries.len() - i);
+        if message.len() + line.len() + more.len() > MAX_MESSAGE_LENGTH {
+            message.push_str(&more);
+            break;
+        }
+        message.push_str(&line);
+    }
+    message
+}
+
+/// Age such as "3 days ago", in the largest unit that fits.
+fn format_relative_age(age: chrono::TimeDelta) -> String {
+    let days = age.num_days();
+    let (count, unit) = if days >= 365 {
+        (days / 365, "year")
+    } else if days >= 30 {
+        (days / 30, "month")
+    } else if days >= 1 {
+        (days, "day")
+    } else if age.num_hours() >= 1 {
+        (age.num_hours(), "hour")
+    } else if age.num_minutes() >= 1 {
+        (age.num_minutes(), "minute")
+    } else {
+        return "just now".to_string();
+    };
+    let plural = if count == 1 { "" } else { "s" };
+    format!("{count} {unit}{plural} ago")
+}
+
+pub fn format_subscription_list(
+    post: &[Subscription],
+    now: chrono::DateTime<chrono::Utc>,
+) -> String {
+    let format_subscription = |sub: &Subscription| {
         let mut args = vec![];
         if let Some(time) = sub.time {
             args.push(format!("time={time}"));
@@ -87,8 +571,62 @@ pub fn format_subscription_list(post: &[Subscription]) -> String {
         if let Some(limit) = sub.limit {
             args.push(format!("limit={limit}"));
         }
-        if let Some(filter) = sub.filter {
-            args.push(format!("filter={filter}"));
+        if let Some(filter) = &sub.filter {
+            args.push(format!("filter={}", filter.iter().join(",")));
+        }
+        if let Some(domains) = &sub.domains {
+            args.push(format!("domain={}", domains.join(",")));
+        }
+        if let Some(flairs) = &sub.flairs {
+            args.push(format!("flair={:?}", flairs.join(",")));
+        }
+        if let Some(template) = &sub.template {
+            args.push(format!("template={template:?}"));
+        }
+        if sub.digest {
+            args.push("digest".to_string());
+        }
+        if sub.selftext {
+            args.push("selftext".to_string());
+        }
+        if sub.photo_as_document {
+            args.push("photo_as_document".to_string());
+        }
+        if let Some(caption) = sub.caption {
+            args.push(format!("caption={caption}"));
+        }
+        if let Some(comments) = sub.comments {
+            args.push(format!("comments={comments}"));
+        }
+        if let Some(comment_sort) = sub.comment_sort {
+            args.push(format!("comment_sort={comment_sort}"));
+        }
+        if let Some(topic) = sub.topic {
+            args.push(format!("topic={topic}"));
+        }
+        if let Some(skip_stickied) = sub.skip_stickied {
+            args.push(format!("skip_stickied={skip_stickied}"));
+        }
+        if sub.only_newer_than_sub {
+            args.push("only_newer_than_sub".to_string());
+        }
+        if let Some(min_comments) = sub.min_comments {
+            args.push(format!("min_comments={min_comments}"));
+        }
+        if let Some(link_preview) = sub.link_preview {
+            args.push(format!("link_preview={link_preview}"));
+        }
+        if sub.attach_source {
+            args.push("attach_source".to_string());
+        }
+        if sub.media_only {
+            args.push("media_only".to_string());
+        }
+        if sub.extended_info {
+            args.push("extended_info".to_string());
+        }
+        if sub.paused {
+            args.push("paused".to_string());
         }
 
         let args_str = if !args.is_empty() {
@@ -97,11 +635,13 @@ pub fn format_subscription_list(post: &[Subscription]) -> String {
             "".to_string()
         };
 
-        [sub.subreddit.to_owned(), args_str]
-            .join(" ")
-            .trim_end()
-            .to_string()
-    }
+        let name = match sub.kind {
+            SubscriptionKind::Subreddit => sub.subreddit.to_owned(),
+            SubscriptionKind::User => format!("{}{}", sub.kind.prefix(), sub.subreddit),
+        };
+        let added = format_relative_age(now - sub.created_at);
+        format!("{}, added {added}", [name, args_str].join(" ").trim_end())
+    };
 
     if post.is_empty() {
         "No subscriptions".to_owned()
@@ -123,25 +663,378 @@ mod tests {
     }
 
     #[test]
-    fn test_format_subscription_list() {
+    fn test_format_media_caption_html_modes() {
+        let post = reddit::Post {
+            title: "Cat".into(),
+            subreddit: "cats".into(),
+            permalink: "/r/cats/comments/abc123/cat/".into(),
+            ..Default::default()
+        };
+        let caption = |badge, mode| format_media_caption_html(&post, None, badge, mode);
+        assert!(caption(None, CaptionMode::Full).starts_with("Cat\n"));
+        assert!(caption(None, CaptionMode::Full).contains("comments"));
+        assert_eq!(caption(None, CaptionMode::Title), "Cat");
+        assert_eq!(caption(Some("⭐"), CaptionMode::Title), "⭐ Cat");
+        assert_eq!(caption(Some("⭐"), CaptionMode::None), "");
+    }
+
+    #[test]
+    fn test_format_gallery_item_captions() {
+        let item = |caption: Option<&str>, outbound_url: Option<&str>| reddit::GalleryDataItem {
+            media_id: "id".into(),
+            caption: caption.map(Into::into),
+            outbound_url: outbound_url.map(Into::into),
+        };
         assert_eq!(
-            format_subscription_list(&[
-                Subscription {
-                    chat_id: 1,
-                    subreddit: "foo".to_owned(),
-                    limit: None,
-                    time: None,
-                    filter: None,
-                },
-                Subscription {
-                    chat_id: 1,
-                    subreddit: "bar".to_owned(),
-                    limit: Some(1),
-                    time: Some(TopPostsTimePeriod::Week),
-                    filter: None,
-                },
+            format_gallery_item_captions(&[
+                item(Some("a <b>"), None),
+                item(None, None),
+                item(Some("c"), Some("https://example.com")),
+                item(None, Some("https://example.org")),
             ]),
-            "foo\nbar (time=week, limit=1)"
+            Some(
+                "1. a &lt;b&gt;\n\
+                 3. c <a href=\"https://example.com\">link</a>\n\
+                 4. <a href=\"https://example.org\">link</a>"
+                    .to_string()
+            )
+        );
+        assert_eq!(format_gallery_item_captions(&[item(None, None)]), None);
+    }
+
+    #[test]
+    fn test_format_repost_buttons_post_link() {
+        let post = reddit::Post {
+            id: "abc".into(),
+            permalink: "/r/pics/comments/abc/title/".into(),
+            ..Default::default()
+        };
+        let mut config = config::Config::default();
+        assert_eq!(
+            format_repost_buttons(&post, &config).inline_keyboard.len(),
+            1
+        );
+
+        config.post_link_button = true;
+        let buttons = format_repost_buttons(&post, &config);
+        assert_eq!(buttons.inline_keyboard.len(), 2);
+        assert_eq!(
+            buttons.inline_keyboard[1][0].kind,
+            teloxide::types::InlineKeyboardButtonKind::Url(
+                "https://www.reddit.com/r/pics/comments/abc/title/"
+                    .parse()
+                    .unwrap()
+            )
+        );
+    }
+
+    #[test]
+    fn test_score_badge() {
+        let tiers = vec![
+            config::ScoreBadgeTier {
+                above: 1000,
+                badge: "⭐".to_string(),
+            },
+            config::ScoreBadgeTier {
+                above: 5000,
+                badge: "🔥".to_string(),
+            },
+        ];
+        assert_eq!(score_badge(500, &tiers), None);
+        assert_eq!(score_badge(1000, &tiers), None);
+        assert_eq!(score_badge(1001, &tiers), Some("⭐"));
+        assert_eq!(score_badge(12000, &tiers), Some("🔥"));
+        assert_eq!(score_badge(12000, &[]), None);
+    }
+
+    #[test]
+    fn test_format_selftext_html() {
+        let post = reddit::Post {
+            permalink: "/r/rust/comments/v6nu75/title/".into(),
+            selftext: Some("a <b> c".into()),
+            ..Default::default()
+        };
+        assert_eq!(
+            format_selftext_html(&post, 100, None).as_deref(),
+            Some("a &lt;b&gt; c")
+        );
+
+        let read_more =
+            r#"… <a href="https://www.reddit.com/r/rust/comments/v6nu75/title/">read more</a>"#;
+        let post = reddit::Post {
+            selftext: Some(format!("a <b> {}", "c".repeat(100))),
+            ..post
+        };
+        let max_length = read_more.chars().count() + 5;
+        assert_eq!(
+            format_selftext_html(&post, max_length, None),
+            Some(format!("a{read_more}"))
+        );
+
+        let post = reddit::Post {
+            selftext: None,
+            ..post
+        };
+        assert_eq!(format_selftext_html(&post, 100, None), None);
+    }
+
+    #[test]
+    fn test_format_comments_html() {
+        let comments = vec![
+            reddit::Comment {
+                author: "spez".into(),
+                body: "first <3".into(),
+                score: 10,
+            },
+            reddit::Comment {
+                author: "kn0thing".into(),
+                body: "second".into(),
+                score: -1,
+            },
+        ];
+        assert_eq!(
+            format_comments_html(&comments),
+            "▲10 <b>spez</b>\nfirst &lt;3\n\n▲-1 <b>kn0thing</b>\nsecond"
+        );
+    }
+
+    #[test]
+    fn test_format_extended_info_html() {
+        let post = reddit::Post {
+            id: "v6nu75".into(),
+            subreddit: "rust".into(),
+            title: "<Rust> 1.0".into(),
+            permalink: "/r/rust/comments/v6nu75/rust_10/".into(),
+            score: 1234,
+            num_comments: 56,
+            author: "steveklabnik1".into(),
+            total_awards_received: 3,
+            ..Default::default()
+        };
+        let comment = reddit::Comment {
+            author: "spez".into(),
+            body: "nice".into(),
+            score: 10,
+        };
+        assert_eq!(
+            format_extended_info_html(&post, Some(&comment), None),
+            "<b>&lt;Rust&gt; 1.0</b>\nby u/steveklabnik1\n▲1234 · <a href=\"https://www.reddit.com/r/rust/comments/v6nu75/rust_10/\">56 comments</a> · 🏆3\n\nTop comment:\n▲10 <b>spez</b>\nnice"
+        );
+
+        let post = reddit::Post {
+            author: String::new(),
+            total_awards_received: 0,
+            ..post
+        };
+        assert_eq!(
+            format_extended_info_html(&post, None, None),
+            "<b>&lt;Rust&gt; 1.0</b>\n▲1234 · <a href=\"https://www.reddit.com/r/rust/comments/v6nu75/rust_10/\">56 comments</a>"
+        );
+    }
+
+    #[test]
+    fn test_format_post_digest() {
+        let date = NaiveDate::from_ymd_opt(2024, 3, 5).unwrap();
+        let post = reddit::Post {
+            id: "v6nu75".into(),
+            subreddit: "rust".into(),
+            title: "<Rust> 1.0".into(),
+            permalink: "/r/rust/comments/v6nu75/rust_10/".into(),
+            score: 42,
+            ..Default::default()
+        };
+        assert_eq!(
+            format_post_digest("r/rust", date, std::slice::from_ref(&post), None),
+            "<b>New posts in r/rust on 5 Mar 2024</b>\n▲42 <a href=\"https://www.reddit.com/r/rust/comments/v6nu75/rust_10/\">&lt;Rust&gt; 1.0</a>"
+        );
+
+        let posts = vec![post; 100];
+        let digest = format_post_digest("r/rust", date, &posts, None);
+        assert!(digest.len() <= MAX_MESSAGE_LENGTH);
+        assert!(digest.ends_with("more"));
+    }
+
+    #[test]
+    fn test_format_subscription_list() {
+        let now = chrono::Utc::now();
+        assert_eq!(
+            format_subscription_list(
+                &[
+                    Subscription {
+                        chat_id: 1,
+                        subreddit: "foo".to_owned(),
+                        limit: None,
+                        time: None,
+                        filter: None,
+                        created_at: now - chrono::Duration::days(3),
+                        ..Default::default()
+                    },
+                    Subscription {
+                        chat_id: 1,
+                        subreddit: "bar".to_owned(),
+                        limit: Some(1),
+                        time: Some(TopPostsTimePeriod::Week),
+                        filter: None,
+                        created_at: now,
+                        ..Default::default()
+                    },
+                ],
+                now
+            ),
+            "foo, added 3 days ago\nbar (time=week, limit=1), added just now"
         )
     }
+
+    #[test]
+    fn test_format_repost_caption() {
+        assert_eq!(format_repost_caption(None, None), None);
+        assert_eq!(
+            format_repost_caption(Some("Cats <3"), None).as_deref(),
+            Some("Cats &lt;3")
+        );
+        assert_eq!(
+            format_repost_caption(Some("Cats"), Some("via <@mychannel>")).as_deref(),
+            Some("Cats\n\nvia &lt;@mychannel&gt;")
+        );
+        assert_eq!(
+            format_repost_caption(None, Some("via @mychannel")).as_deref(),
+            Some("via @mychannel")
+        );
+
+        let title = "a".repeat(MAX_CAPTION_LENGTH);
+        let caption = format_repost_caption(Some(&title), Some("via @mychannel")).unwrap();
+        assert_eq!(caption.chars().count(), MAX_CAPTION_LENGTH);
+        assert!(caption.ends_with("…\n\nvia @mychannel"));
+    }
+
+    #[test]
+    fn test_format_compact_count() {
+        assert_eq!(format_compact_count(999), "999");
+        assert_eq!(format_compact_count(1234), "1.2k");
+        assert_eq!(format_compact_count(2_500_000), "2.5M");
+        assert_eq!(format_compact_count(999_949), "999.9k");
+        assert_eq!(format_compact_count(999_950), "1.0M");
+    }
+
+    #[test]
+    fn test_prefix_marker() {
+        assert_eq!(prefix_marker("🔞", "<b>title</b>"), "🔞 <b>title</b>");
+        assert_eq!(prefix_marker("<18>", ""), "&lt;18&gt;");
+    }
+
+    #[test]
+    fn test_format_post_debug_info() {
+        let info = db::PostDebugInfo {
+            recorded: true,
+            seen_at: Some(
+                chrono::DateTime::parse_from_rfc3339("2024-05-01T12:30:00Z")
+                    .unwrap()
+                    .into(),
+            ),
+            subreddit: Some("pics".to_string()),
+            title: Some("A cat".to_string()),
+            telegram_files: 2,
+            muted: false,
+        };
+        assert_eq!(
+            format_post_debug_info("abc123", &info),
+            "Post abc123 in this chat\nSeen: yes, at 2024-05-01 12:30:00 UTC\n\
+             Subreddit: r/pics\nTitle: A cat\nUploaded files: 2\nMuted: no"
+        );
+        assert_eq!(
+            format_post_debug_info("abc123", &Default::default()),
+            "Post abc123 in this chat\nSeen: no\nUploaded files: 0\nMuted: no"
+        );
+    }
+
+    #[test]
+    fn test_format_startup_summary() {
+        let summary = format_startup_summary(Some("2025.01.01"), 3, 2, 600);
+        assert!(summary.starts_with(&format!("Started {PKG_NAME} {PKG_VERSION}")));
+        assert!(summary
+            .ends_with("yt-dlp 2025.01.01\n3 subscription(s) in 2 chat(s), checked every 600s"));
+        let summary = format_startup_summary(None, 0, 0, 600);
+        assert!(summary.ends_with("\nThere are no subscriptions, subscribe with /sub"));
+    }
+
+    #[test]
+    fn test_format_error_report() {
+        let now = tokio::time::Instant::now();
+        let summaries = [error_report::ErrorSummary {
+            source: "r/foo".to_string(),
+            count: 3,
+            reasons: vec![
+                "Failed to download video".to_string(),
+                "timed out".to_string(),
+            ],
+            first_at: now - std::time::Duration::from_secs(2 * 3600),
+        }];
+        assert_eq!(
+            format_error_report(&summaries, now),
+            format!(
+                "{PKG_NAME} errors:\n3 error(s) in r/foo, the first 2 hours ago: \
+                 Failed to download video; timed out"
+            )
+        );
+    }
+
+    #[test]
+    fn test_format_relative_age() {
+        let age = |seconds| format_relative_age(chrono::Duration::seconds(seconds));
+        assert_eq!(age(30), "just now");
+        assert_eq!(age(60), "1 minute ago");
+        assert_eq!(age(2 * 3600 + 59), "2 hours ago");
+        assert_eq!(age(86400), "1 day ago");
+        assert_eq!(age(45 * 86400), "1 month ago");
+        assert_eq!(age(800 * 86400), "2 years ago");
+    }
+
+    #[test]
+    fn test_format_subscription_list_time_periods() {
+        let now = chrono::Utc::now();
+        let subs = [TopPostsTimePeriod::Hour, TopPostsTimePeriod::All].map(|time| Subscription {
+            chat_id: 1,
+            subreddit: "foo".to_owned(),
+            time: Some(time),
+            created_at: now,
+            ..Default::default()
+        });
+        assert_eq!(
+            format_subscription_list(&subs, now),
+            "foo (time=hour), added just now\nfoo (time=all), added just now"
+        )
+    }
+
+    #[test]
+    fn test_render_template() {
+        let post = reddit::Post {
+            id: "v6nu75".into(),
+            subreddit: "absoluteunit".into(),
+            title: "Tipping a <cow>".into(),
+            permalink: "/r/absoluteunit/comments/v6nu75/tipping_a_cow/".into(),
+            url: "https://i.imgur.com/Zt6f5mB.gifv".into(),
+            score: 1234,
+            num_comments: 56,
+            ..Default::default()
+        };
+        assert_eq!(
+            render_template(&post, "▲{score} 💬{num_comments}", None),
+            "▲1234 💬56"
+        );
+        assert_eq!(
+            render_template(&post, "{title}\n{subreddit} ▲{score} [{comments}] {unknown}", None),
+            "Tipping a &lt;cow&gt;\n\
+             <a href=\"https://www.reddit.com/r/absoluteunit\">/r/absoluteunit</a> ▲1234 \
+             [<a href=\"https://www.reddit.com/r/absoluteunit/comments/v6nu75/tipping_a_cow/\">comments</a>] \
+             {unknown}"
+        );
+    }
+
+    #[test]
+    fn test_validate_template() {
+        assert!(validate_template("{title} ▲{score}").is_ok());
+        assert!(validate_template("{title} {foo}")
+            .unwrap_err()
+            .starts_with("Unknown placeholder {foo}"));
+    }
 }
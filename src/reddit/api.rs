@@ -1,12 +1,23 @@
 use super::*;
+use crate::types::SubscriptionKind;
 use anyhow::{Context, Result};
 use log::info;
+use std::sync::OnceLock;
 use thiserror::Error;
 use url::Url;
 
 static REDDIT_BASE_URL: &str = "https://www.reddit.com";
+/// Reddit ignores limits larger than this for a single page of a listing
+const MAX_LISTING_PAGE_SIZE: u32 = 100;
+/// Upper bound for pages fetched for one listing, regardless of the limit
+const MAX_LISTING_PAGES: u32 = 10;
 const USER_AGENT: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
 
+static PROXY: OnceLock<reqwest::Proxy> = OnceLock::new();
+static CUSTOM_USER_AGENT: OnceLock<String> = OnceLock::new();
+static COOKIE: OnceLock<reqwest::header::HeaderValue> = OnceLock::new();
+static GEO_FILTER: OnceLock<String> = OnceLock::new();
+
 fn get_base_url() -> Url {
     Url::parse(REDDIT_BASE_URL).unwrap()
 }
@@ -30,46 +41,241 @@ pub fn format_subreddit_url(subreddit: &str, base_url: Option<&str>) -> String {
     format_url_from_path(&format!("/r/{subreddit}"), base_url)
 }
 
+#[derive(Error, Debug)]
+pub enum TopPostsError {
+    #[error("subreddit or user is private, quarantined or suspended")]
+    Forbidden,
+    #[error("subreddit or user does not exist or is banned")]
+    NotFound,
+    #[error("rate limited by reddit")]
+    RateLimited,
+    #[error(transparent)]
+    Reqwest(#[from] reqwest::Error),
+    #[error(transparent)]
+    UrlParseError(#[from] url::ParseError),
+}
+
 pub async fn get_subreddit_top_posts(
     subreddit: &str,
     limit: u32,
     time: &TopPostsTimePeriod,
-) -> Result<Vec<Post>> {
+) -> Result<Vec<Post>, TopPostsError> {
     info!("getting top posts for /r/{subreddit} limit={limit} time={time:?}");
-    let url = get_base_url()
-        .join(&format!("/r/{subreddit}/top.json"))
-        .unwrap();
+    let url = get_base_url().join(&format!("/r/{subreddit}/top.json"))?;
+    let time = time.to_string();
+    let mut query = vec![("t", time.as_str())];
+    if let Some(geo_filter) = GEO_FILTER.get() {
+        query.push(("geo_filter", geo_filter));
+    }
+    get_top_posts_listing(url, &query, limit).await
+}
+
+/// Gets the top submissions of a user, similarly to how top posts of a subreddit are fetched.
+pub async fn get_user_top_posts(
+    username: &str,
+    limit: u32,
+    time: &TopPostsTimePeriod,
+) -> Result<Vec<Post>, TopPostsError> {
+    info!("getting top posts for /u/{username} limit={limit} time={time:?}");
+    let url = get_base_url().join(&format!("/user/{username}/submitted.json"))?;
+    get_top_posts_listing(url, &[("sort", "top"), ("t", &time.to_string())], limit).await
+}
+
+/// Gets top posts of either a subreddit or a user, depending on the kind of subscription.
+pub async fn get_top_posts(
+    kind: SubscriptionKind,
+    name: &str,
+    limit: u32,
+    time: &TopPostsTimePeriod,
+) -> Result<Vec<Post>, TopPostsError> {
+    match kind {
+        SubscriptionKind::Subreddit => get_subreddit_top_posts(name, limit, time).await,
+        SubscriptionKind::User => get_user_top_posts(name, limit, time).await,
+    }
+}
+
+/// Gets up to `limit` posts of a listing. Listings are paginated with the `after` token when the
+/// limit is larger than what Reddit returns in one page.
+async fn get_top_posts_listing(
+    url: Url,
+    query: &[(&str, &str)],
+    limit: u32,
+) -> Result<Vec<Post>, TopPostsError> {
     let client = create_client().build()?;
-    let res = client
-        .get(url)
-        .query(&[
-            ("limit", &limit.to_string()),
-            ("t", &format!("{time:?}").to_lowercase()),
-        ])
-        .send()
+    let limit = limit.min(MAX_LISTING_PAGE_SIZE * MAX_LISTING_PAGES);
+    let mut posts = vec![];
+    let mut after: Option<String> = None;
+    loop {
+        let page_size = (limit - posts.len() as u32).min(MAX_LISTING_PAGE_SIZE);
+        let mut req = get_request(&client, url.clone())
+            .query(query)
+            .query(&[("limit", page_size.to_string())]);
+        if let Some(after) = &after {
+            req = req.query(&[("after", after)]);
+        }
+        let res = send(req).await?;
+
+        let res = match res.status() {
+            reqwest::StatusCode::FORBIDDEN => return Err(TopPostsError::Forbidden),
+            reqwest::StatusCode::NOT_FOUND => return Err(TopPostsError::NotFound),
+            reqwest::StatusCode::TOO_MANY_REQUESTS => return Err(TopPostsError::RateLimited),
+            _ => res.error_for_status()?.json::<ListingResponse>().await?,
+        };
+        let page_len = res.data.children.len();
+        posts.extend(res.data.children.into_iter().map(|e| e.data));
+        after = res.data.after;
+        if posts.len() as u32 >= limit || page_len == 0 || after.is_none() {
+            break;
+        }
+        info!("got {} of {limit} posts, getting next page", posts.len());
+    }
+    posts.truncate(limit as usize);
+
+    Ok(posts)
+}
+
+/// Gets the top level comments of a post.
+pub async fn get_post_comments(
+    post_id: &str,
+    sort: CommentSort,
+    limit: u32,
+) -> Result<Vec<Comment>> {
+    info!("getting comments for post id {post_id} sort={sort} limit={limit}");
+    let url = get_base_url().join(&format!("/comments/{post_id}.json"))?;
+    let client = create_client().build()?;
+    let req = get_request(&client, url).query(&[
+        ("sort", sort.as_query_value()),
+        ("limit", &limit.to_string()),
+        ("depth", "1"),
+    ]);
+    let res = send(req)
         .await?
         .error_for_status()?
-        .json::<ListingResponse>()
+        .json::<CommentsResponse>()
         .await?;
-    let posts = res.data.children.into_iter().map(|e| e.data).collect();
-    Ok(posts)
+
+    let comments = res
+        .1
+        .data
+        .children
+        .into_iter()
+        .filter_map(|item| match item {
+            CommentListingItem::Comment(comment) => Some(comment),
+            CommentListingItem::More(_) => None,
+        })
+        .take(limit as usize)
+        .collect();
+    Ok(comments)
+}
+
+/// Routes requests of all clients created with `create_client` through the proxy. Supports http,
+/// https and socks5 proxies. Should be called once at startup, so that an invalid proxy fails
+/// early rather than on every request.
+pub fn set_proxy(proxy_url: &str) -> Result<()> {
+    let proxy = reqwest::Proxy::all(proxy_url).context("invalid proxy url")?;
+    // Check that a client can be built with the proxy, as some errors are only found then
+    reqwest::Client::builder()
+        .proxy(proxy.clone())
+        .build()
+        .context("invalid proxy")?;
+    PROXY
+        .set(proxy)
+        .map_err(|_| anyhow::anyhow!("proxy already set"))
 }
 
-fn create_client() -> reqwest::ClientBuilder {
-    reqwest::Client::builder().user_agent(USER_AGENT)
+/// Overrides the default user agent sent with requests to reddit and media hosts.
+pub fn set_user_agent(user_agent: &str) -> Result<()> {
+    reqwest::header::HeaderValue::from_str(user_agent).context("invalid user agent")?;
+    CUSTOM_USER_AGENT
+        .set(user_agent.to_string())
+        .map_err(|_| anyhow::anyhow!("user agent already set"))
+}
+
+fn user_agent() -> &'static str {
+    CUSTOM_USER_AGENT
+        .get()
+        .map(String::as_str)
+        .unwrap_or(USER_AGENT)
+}
+
+/// Sets the cookie of a logged in reddit session, which gives access to content that reddit only
+/// shows to logged in users, such as some NSFW subreddits.
+pub fn set_cookie(cookie: &str) -> Result<()> {
+    let mut cookie =
+        reqwest::header::HeaderValue::from_str(cookie).context("invalid reddit cookie")?;
+    cookie.set_sensitive(true);
+    COOKIE
+        .set(cookie)
+        .map_err(|_| anyhow::anyhow!("reddit cookie already set"))
+}
+
+/// Sets the region whose view of subreddit listings is fetched, given as a two letter country
+/// code such as "US".
+pub fn set_geo_filter(geo_filter: &str) -> Result<()> {
+    GEO_FILTER
+        .set(parse_geo_filter(geo_filter)?)
+        .map_err(|_| anyhow::anyhow!("geo filter already set"))
+}
+
+fn parse_geo_filter(geo_filter: &str) -> Result<String> {
+    let geo_filter = geo_filter.trim();
+    if geo_filter.len() == 2 && geo_filter.chars().all(|c| c.is_ascii_alphabetic()) {
+        Ok(geo_filter.to_uppercase())
+    } else {
+        Err(anyhow::anyhow!(
+            "invalid geo filter {geo_filter:?}, expected a two letter country code"
+        ))
+    }
+}
+
+/// Whether the host belongs to reddit, so that the session cookie can be sent to it.
+fn is_reddit_host(host: &str) -> bool {
+    ["reddit.com", "redd.it"].iter().any(|domain| {
+        host == *domain
+            || host
+                .strip_suffix(domain)
+                .is_some_and(|subdomain| subdomain.ends_with('.'))
+    })
+}
+
+/// GET request that carries the session cookie if the url is on a reddit host. The cookie is
+/// never sent to other hosts, and reqwest drops it on redirects to other hosts.
+pub fn get_request(client: &reqwest::Client, url: Url) -> reqwest::RequestBuilder {
+    let cookie = COOKIE
+        .get()
+        .filter(|_| url.host_str().is_some_and(is_reddit_host))
+        .cloned();
+    let req = client.get(url);
+    match cookie {
+        Some(cookie) => req.header(reqwest::header::COOKIE, cookie),
+        None => req,
+    }
+}
+
+/// Sends a request to Reddit, first waiting for the rate limit window to reset if the last
+/// response said the limit is almost used up.
+async fn send(req: reqwest::RequestBuilder) -> reqwest::Result<reqwest::Response> {
+    ratelimit::wait().await;
+    let res = req.send().await?;
+    ratelimit::record(res.headers());
+    Ok(res)
+}
+
+/// Client builder for requests to reddit and media hosts.
+pub fn create_client() -> reqwest::ClientBuilder {
+    let builder = reqwest::Client::builder().user_agent(user_agent());
+    match PROXY.get() {
+        Some(proxy) => builder.proxy(proxy.clone()),
+        None => builder,
+    }
 }
 
 pub async fn get_link(link_id: &str) -> Result<Post> {
     info!("getting link id {link_id}");
     let url = get_base_url().join("/api/info.json")?;
     let client = create_client().build()?;
-    let res = client
-        .get(url)
-        .query(&[("id", &format!("t3_{link_id}"))])
-        .send()
-        .await?
-        .json::<ListingResponse>()
-        .await?;
+    let req = get_request(&client, url).query(&[("id", &format!("t3_{link_id}"))]);
+    let res = send(req).await?.json::<ListingResponse>().await?;
 
     res.data
         .children
@@ -79,6 +285,54 @@ pub async fn get_link(link_id: &str) -> Result<Post> {
         .context("no post in response")
 }
 
+/// Whether the url is a short link to a post, such as reddit.com/r/pics/s/<token> made by the
+/// share button, or redd.it/<id>.
+pub fn is_share_link(url: &str) -> bool {
+    let Ok(url) = Url::parse(url.trim()) else {
+        return false;
+    };
+    let host = url.host_str().unwrap_or_default();
+    let segments = url.path_segments().map(Iterator::collect::<Vec<_>>);
+    match segments.as_deref() {
+        Some([id]) => host == "redd.it" && !id.is_empty(),
+        Some(["r", _, "s", token]) => is_reddit_host(host) && !token.is_empty(),
+        _ => false,
+    }
+}
+
+/// Resolves a share link to the id of the post it points to. redd.it links contain the id, while
+/// share button links are followed to the post.
+pub async fn resolve_share_link(url: &str) -> Result<String> {
+    let url = Url::parse(url.trim()).context("invalid share link")?;
+    if let Some(id) = post_id_from_url(&url) {
+        return Ok(id);
+    }
+
+    info!("resolving share link {url}");
+    let client = create_client().build()?;
+    let res = send(get_request(&client, url.clone()))
+        .await?
+        .error_for_status()?;
+    post_id_from_url(res.url())
+        .with_context(|| format!("share link {url} does not point to a post"))
+}
+
+/// Id of the post a url points to, e.g. reddit.com/r/pics/comments/<id>/... or redd.it/<id>.
+fn post_id_from_url(url: &Url) -> Option<String> {
+    let host = url.host_str()?;
+    let segments = url.path_segments()?.collect::<Vec<_>>();
+    match segments.as_slice() {
+        [id] if host == "redd.it" && !id.is_empty() => Some(id.to_string()),
+        _ if is_reddit_host(host) => segments
+            .iter()
+            .position(|segment| *segment == "comments")
+            .and_then(|i| segments.get(i + 1))
+            .filter(|id| !id.is_empty())
+            .map(|id| id.to_string()),
+        _ => None,
+    }
+}
+
 #[allow(clippy::large_enum_variant)]
 #[derive(Error, Debug)]
 pub enum SubredditAboutError {
@@ -98,7 +352,7 @@ pub async fn get_subreddit_about(subreddit: &str) -> Result<SubredditAbout, Subr
         .redirect(reqwest::redirect::Policy::none())
         .build()?;
     let url = get_base_url().join(&format!("/r/{subreddit}/about.json"))?;
-    let res = client.get(url).send().await?.error_for_status()?;
+    let res = send(get_request(&client, url)).await?.error_for_status()?;
 
     match res.status() {
         reqwest::StatusCode::FOUND => Err(SubredditAboutError::NoSuchSubreddit),
@@ -108,3 +362,73 @@ pub async fn get_subreddit_about(subreddit: &str) -> Result<SubredditAbout, Subr
         }
     }
 }
+
+/// Searches subreddits by name and description, most relevant first.
+pub async fn search_subreddits(query: &str, limit: u32) -> Result<Vec<SubredditSearchResult>> {
+    info!("searching subreddits for {query:?} limit={limit}");
+    let url = get_base_url().join("/subreddits/search.json")?;
+    let client = create_client().build()?;
+    let req = get_request(&client, url).query(&[("q", query), ("limit", &limit.to_string())]);
+    let res = send(req)
+        .await?
+        .error_for_status()?
+        .json::<SubredditSearchResponse>()
+        .await?;
+    Ok(res
+        .data
+        .children
+        .into_iter()
+        .map(|item| item.data)
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_geo_filter() {
+        assert_eq!(parse_geo_filter("us").unwrap(), "US");
+        assert_eq!(parse_geo_filter(" GB ").unwrap(), "GB");
+        assert!(parse_geo_filter("USA").is_err());
+        assert!(parse_geo_filter("U1").is_err());
+        assert!(parse_geo_filter("").is_err());
+    }
+
+    #[test]
+    fn test_is_reddit_host() {
+        assert!(is_reddit_host("www.reddit.com"));
+        assert!(is_reddit_host("reddit.com"));
+        assert!(is_reddit_host("i.redd.it"));
+        assert!(is_reddit_host("v.redd.it"));
+        assert!(!is_reddit_host("notreddit.com"));
+        assert!(!is_reddit_host("i.imgur.com"));
+        assert!(!is_reddit_host("reddit.com.example.com"));
+    }
+
+    #[test]
+    fn test_is_share_link() {
+        assert!(is_share_link("https://www.reddit.com/r/pics/s/AbCdEf123"));
+        assert!(is_share_link("https://redd.it/v6nu75"));
+        assert!(!is_share_link(
+            "https://www.reddit.com/r/pics/comments/v6nu75/title/"
+        ));
+        assert!(!is_share_link("https://i.redd.it/abc.jpg"));
+        assert!(!is_share_link("https://notreddit.com/r/pics/s/AbCdEf123"));
+        assert!(!is_share_link("not a link"));
+    }
+
+    #[test]
+    fn test_post_id_from_url() {
+        let id = |url: &str| post_id_from_url(&Url::parse(url).unwrap());
+        assert_eq!(id("https://redd.it/v6nu75"), Some("v6nu75".to_string()));
+        assert_eq!(
+            id("https://www.reddit.com/r/pics/comments/v6nu75/title/?share_id=x"),
+            Some("v6nu75".to_string())
+        );
+        assert_eq!(id("https://www.reddit.com/r/pics/s/AbCdEf123"), None);
+        assert_eq!(id("https://www.reddit.com/r/pics/"), None);
+        assert_eq!(id("https://example.com/comments/v6nu75"), None);
+        assert_eq!(id("https://notreddit.com/r/pics/comments/v6nu75/"), None);
+    }
+}
@@ -1,4 +1,5 @@
 mod api;
+mod ratelimit;
 mod types;
 pub use api::*;
 pub use types::*;
@@ -0,0 +1,103 @@
+use log::{debug, info};
+use reqwest::header::HeaderMap;
+use std::sync::Mutex;
+use tokio::time::{Duration, Instant};
+
+/// Requests left in the window below which requests wait for the window to reset, leaving some
+/// room for requests already in flight
+const MIN_REMAINING: f64 = 5.0;
+
+static RATE_LIMIT: Mutex<Option<RateLimit>> = Mutex::new(None);
+
+/// Reddit's rate limit as of the last response, from its X-Ratelimit headers.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct RateLimit {
+    remaining: f64,
+    reset_at: Instant,
+}
+
+impl RateLimit {
+    /// Reads the rate limit from response headers. `X-Ratelimit-Remaining` is the number of
+    /// requests left in the window, which Reddit sends as a float, and `X-Ratelimit-Reset` the
+    /// seconds until the window resets.
+    fn from_headers(headers: &HeaderMap, now: Instant) -> Option<Self> {
+        let header = |name| {
+            let value = headers
+                .get(name)?
+                .to_str()
+                .ok()?
+                .trim()
+                .parse::<f64>()
+                .ok()?;
+            value.is_finite().then_some(value)
+        };
+        let remaining = header("x-ratelimit-remaining")?;
+        let reset_secs = header("x-ratelimit-reset")?;
+        Some(Self {
+            remaining,
+            reset_at: now + Duration::from_secs_f64(reset_secs.max(0.0)),
+        })
+    }
+
+    /// How long to wait before the next request, if the limit is almost used up.
+    fn delay(&self, now: Instant) -> Option<Duration> {
+        Some(self.reset_at.saturating_duration_since(now))
+            .filter(|delay| self.remaining < MIN_REMAINING && !delay.is_zero())
+    }
+}
+
+/// Remembers the rate limit of a response, if it has one.
+pub fn record(headers: &HeaderMap) {
+    let Some(rate_limit) = RateLimit::from_headers(headers, Instant::now()) else {
+        return;
+    };
+    debug!(
+        "reddit rate limit remaining={} reset_in={:?}",
+        rate_limit.remaining,
+        rate_limit
+            .reset_at
+            .saturating_duration_since(Instant::now())
+    );
+    *RATE_LIMIT.lock().expect("No poison") = Some(rate_limit);
+}
+
+/// Waits until the rate limit window resets if the last response said it's almost used up.
+pub async fn wait() {
+    let delay = RATE_LIMIT
+        .lock()
+        .expect("No poison")
+        .and_then(|rate_limit| rate_limit.delay(Instant::now()));
+    if let Some(delay) = delay {
+        info!("reddit rate limit almost used up, waiting {delay:?} for it to reset");
+        tokio::time::sleep(delay).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rate_limit_from_headers() {
+        let now = Instant::now();
+        let mut headers = HeaderMap::new();
+        assert_eq!(RateLimit::from_headers(&headers, now), None);
+
+        headers.insert("x-ratelimit-remaining", "3.0".parse().unwrap());
+        headers.insert("x-ratelimit-reset", "120".parse().unwrap());
+        let rate_limit = RateLimit::from_headers(&headers, now).unwrap();
+        assert_eq!(
+            rate_limit,
+            RateLimit {
+                remaining: 3.0,
+                reset_at: now + Duration::from_secs(120),
+            }
+        );
+        assert_eq!(rate_limit.delay(now), Some(Duration::from_secs(120)));
+        assert_eq!(rate_limit.delay(now + Duration::from_secs(120)), None);
+
+        headers.insert("x-ratelimit-remaining", "598.0".parse().unwrap());
+        let rate_limit = RateLimit::from_headers(&headers, now).unwrap();
+        assert_eq!(rate_limit.delay(now), None);
+    }
+}
@@ -1,14 +1,15 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
 use crate::db::Recordable;
 
 use super::*;
+use crate::config::FlairMatch;
 use anyhow::{Context, Result};
 use serde::{Deserialize, Deserializer};
 use strum_macros::{Display, EnumString};
 use url::Url;
 
-#[derive(Display, Debug, Clone, PartialEq, Hash, Eq, Deserialize, Copy, EnumString)]
+#[derive(Display, Debug, Clone, PartialEq, Hash, Eq, Deserialize, Copy, EnumString, Default)]
 #[serde(rename_all = "snake_case")]
 #[strum(serialize_all = "snake_case")]
 pub enum PostType {
@@ -17,6 +18,9 @@ pub enum PostType {
     Link,
     SelfText,
     Gallery,
+    /// Link to an audio file
+    Audio,
+    #[default]
     Unknown,
 }
 
@@ -32,6 +36,30 @@ pub enum TopPostsTimePeriod {
     All,
 }
 
+/// Sort order of comments of a post.
+#[derive(Display, Debug, Clone, PartialEq, Hash, Eq, Deserialize, Copy, EnumString, Default)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum CommentSort {
+    #[default]
+    Top,
+    Best,
+    New,
+    Controversial,
+}
+
+impl CommentSort {
+    /// Value of the sort query parameter, which for "best" is "confidence" in the API.
+    pub fn as_query_value(&self) -> &'static str {
+        match self {
+            CommentSort::Top => "top",
+            CommentSort::Best => "confidence",
+            CommentSort::New => "new",
+            CommentSort::Controversial => "controversial",
+        }
+    }
+}
+
 #[derive(Deserialize, Debug)]
 pub struct ListingResponse {
     pub data: ListingResponseData,
@@ -40,6 +68,8 @@ pub struct ListingResponse {
 #[derive(Deserialize, Debug)]
 pub struct ListingResponseData {
     pub children: Vec<ListingItem>,
+    /// Token for getting the next page of the listing, if there is one
+    pub after: Option<String>,
 }
 
 #[derive(Deserialize, Debug)]
@@ -50,6 +80,8 @@ pub struct ListingItem {
 #[derive(Deserialize, Debug, Clone)]
 pub struct GalleryDataItem {
     pub media_id: String,
+    pub caption: Option<String>,
+    pub outbound_url: Option<String>,
 }
 
 #[derive(Deserialize, Debug, Clone)]
@@ -62,25 +94,192 @@ pub struct Media {
     pub x: u16,
     pub y: u16,
     #[serde(rename = "u", alias = "gif")]
-    pub url: String,
+    pub url: Option<String>,
+    /// Set for animated images, in addition to the gif
+    pub mp4: Option<String>,
 }
 
 #[derive(Deserialize, Debug, Clone)]
 pub struct MediaMetadata {
+    /// Type of the item, e.g. "Image", "AnimatedImage" or "RedditVideo"
+    pub e: Option<String>,
     pub s: Option<Media>,
+    #[serde(rename = "hlsUrl")]
+    pub hls_url: Option<String>,
+    #[serde(rename = "dashUrl")]
+    pub dash_url: Option<String>,
+}
+
+/// Where a gallery item can be downloaded from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MediaSource {
+    /// Direct link to an image, gif or mp4
+    File(String),
+    /// Streaming playlist of a video, which has to be downloaded with yt-dlp
+    Stream(String),
+}
+
+impl MediaMetadata {
+    /// Source of the item, preferring mp4 over gif for animated images as it's smaller.
+    pub fn source(&self) -> Option<MediaSource> {
+        if let Some(url) = self
+            .s
+            .as_ref()
+            .and_then(|s| s.mp4.as_ref().or(s.url.as_ref()))
+        {
+            return Some(MediaSource::File(url.replace("&amp;", "&")));
+        }
+
+        self.hls_url
+            .as_ref()
+            .or(self.dash_url.as_ref())
+            .map(|url| MediaSource::Stream(url.replace("&amp;", "&")))
+    }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Deserialize, Debug, Clone)]
+pub struct PreviewImageSource {
+    pub url: String,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct PreviewImage {
+    pub source: PreviewImageSource,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Preview {
+    pub images: Vec<PreviewImage>,
+}
+
+#[derive(Debug, Clone, Default)]
 pub struct Post {
     pub id: String,
     pub subreddit: String,
     pub title: String,
     pub permalink: String,
     pub url: String,
+    pub score: i64,
     pub post_hint: Option<String>,
     pub post_type: PostType,
     pub gallery_data: Option<GalleryData>,
     pub media_metadata: Option<HashMap<String, MediaMetadata>>,
+    pub thumbnail: Option<String>,
+    pub preview: Option<Preview>,
+    pub link_flair_text: Option<String>,
+    pub selftext: Option<String>,
+    pub crosspost_parent: Option<String>,
+    /// Pinned by moderators, usually an announcement
+    pub stickied: bool,
+    /// Marked as a spoiler, Telegram can blur the media the same way
+    pub spoiler: bool,
+    /// Marked NSFW, which posts of NSFW subreddits always are
+    pub over_18: bool,
+    /// Unix timestamp of when the post was created
+    pub created: f64,
+    pub num_comments: u64,
+    pub author: String,
+    pub total_awards_received: u64,
+}
+
+/// A post as Reddit returns it, before its type is classified.
+#[derive(Deserialize, Default)]
+pub struct RawPost {
+    pub id: String,
+    pub subreddit: String,
+    pub title: String,
+    pub is_video: bool,
+    pub permalink: String,
+    pub url: String,
+    #[serde(default)]
+    pub score: i64,
+    pub post_hint: Option<String>,
+    pub is_self: bool,
+    pub is_gallery: Option<bool>,
+    pub crosspost_parent_list: Option<Vec<Post>>,
+    pub gallery_data: Option<GalleryData>,
+    pub media_metadata: Option<HashMap<String, MediaMetadata>>,
+    pub thumbnail: Option<String>,
+    pub preview: Option<Preview>,
+    pub link_flair_text: Option<String>,
+    pub selftext: Option<String>,
+    pub crosspost_parent: Option<String>,
+    #[serde(default)]
+    pub stickied: bool,
+    #[serde(default)]
+    pub spoiler: bool,
+    #[serde(default)]
+    pub over_18: bool,
+    #[serde(default)]
+    pub created_utc: f64,
+    #[serde(default)]
+    pub num_comments: u64,
+    #[serde(default)]
+    pub author: String,
+    #[serde(default)]
+    pub total_awards_received: u64,
+}
+
+impl RawPost {
+    fn is_downloadable_video(&self) -> bool {
+        let is_downloadable_3rd_party = || -> Result<bool> {
+            let url = Url::parse(&self.url)?;
+            let host = url.host_str().context("no host in url")?;
+            let path = url.path();
+            let is_imgur_gif = host == "i.imgur.com" && path.ends_with(".gifv");
+            let is_gfycat_gif = host == "gfycat.com";
+            Ok(is_imgur_gif || is_gfycat_gif)
+        };
+
+        // If the post is a crosspost with a video, it can be downloaded with post.url as
+        // url as yt-dlp follows redirects
+        let is_downloadable_crosspost = || -> bool {
+            self.crosspost_parent_list
+                .as_ref()
+                .map(|list| list.iter().any(|post| post.post_type == PostType::Video))
+                .unwrap_or(false)
+        };
+
+        self.is_video || is_downloadable_crosspost() || is_downloadable_3rd_party().unwrap_or(false)
+    }
+
+    /// Whether the post links to a post or comment on Reddit, e.g. /r/bestof posts. These have no
+    /// post_hint.
+    fn is_reddit_post_link(&self) -> bool {
+        !self.is_self
+            && Url::parse(&self.url).is_ok_and(|url| {
+                url.host_str()
+                    .is_some_and(|host| host.ends_with("reddit.com"))
+                    && url.path().contains("/comments/")
+            })
+    }
+}
+
+/// Decides how a post is sent from what Reddit says about it. The checks are in order of
+/// precedence, e.g. a crosspost of a video is a video even though it has post_hint link.
+pub fn classify_post_type(post: &RawPost) -> PostType {
+    let post_hint = post.post_hint.as_deref();
+    if post.is_downloadable_video() {
+        PostType::Video
+    } else if post_hint == Some("image") {
+        PostType::Image
+    } else if !post.is_self && is_audio_url(&post.url) {
+        PostType::Audio
+    } else if post_hint == Some("rich:video") && is_downloadable_embed(&post.url) {
+        PostType::Video
+    // Other post_hint => rich:video posts are links to e.g. youtube videos, which are not
+    // worthwhile to download due to their length
+    } else if post_hint == Some("link") || post_hint == Some("rich:video") {
+        PostType::Link
+    } else if post.is_self {
+        PostType::SelfText
+    } else if post.is_gallery.unwrap_or(false) {
+        PostType::Gallery
+    } else if post_hint.is_none() && post.is_reddit_post_link() {
+        PostType::Link
+    } else {
+        PostType::Unknown
+    }
 }
 
 impl<'de> Deserialize<'de> for Post {
@@ -88,76 +287,32 @@ impl<'de> Deserialize<'de> for Post {
     where
         D: Deserializer<'de>,
     {
-        #[derive(Deserialize)]
-        pub struct PostHelper {
-            pub id: String,
-            pub subreddit: String,
-            pub title: String,
-            pub is_video: bool,
-            pub permalink: String,
-            pub url: String,
-            pub post_hint: Option<String>,
-            pub is_self: bool,
-            pub is_gallery: Option<bool>,
-            pub crosspost_parent_list: Option<Vec<Post>>,
-            pub gallery_data: Option<GalleryData>,
-            pub media_metadata: Option<HashMap<String, MediaMetadata>>,
-        }
-
-        impl PostHelper {
-            pub fn is_downloadable_video(&self) -> bool {
-                let is_downloadable_3rd_party = || -> Result<bool> {
-                    let url = Url::parse(&self.url)?;
-                    let host = url.host_str().context("no host in url")?;
-                    let path = url.path();
-                    let is_imgur_gif = host == "i.imgur.com" && path.ends_with(".gifv");
-                    let is_gfycat_gif = host == "gfycat.com";
-                    Ok(is_imgur_gif || is_gfycat_gif)
-                };
-
-                // If the post is a crosspost with a video, it can be downloaded with post.url as
-                // url as yt-dlp follows redirects
-                let is_downloadable_crosspost = || -> bool {
-                    self.crosspost_parent_list
-                        .as_ref()
-                        .map(|list| list.iter().any(|post| post.post_type == PostType::Video))
-                        .unwrap_or(false)
-                };
-
-                self.is_video
-                    || is_downloadable_crosspost()
-                    || is_downloadable_3rd_party().unwrap_or(false)
-            }
-        }
-
-        let helper = PostHelper::deserialize(deserializer)?;
-        let post_hint = helper.post_hint.as_deref();
-        let post_type = if helper.is_downloadable_video() {
-            PostType::Video
-        } else if post_hint == Some("image") {
-            PostType::Image
-        // post_hint => rich:video can be a link to a youtube video, which are not worthwhile to
-        // download due to their length, though exceptions could be made for short (< 1min) videos
-        } else if post_hint == Some("link") || post_hint == Some("rich:video") {
-            PostType::Link
-        } else if helper.is_self {
-            PostType::SelfText
-        } else if helper.is_gallery.unwrap_or(false) {
-            PostType::Gallery
-        } else {
-            PostType::Unknown
-        };
+        let raw = RawPost::deserialize(deserializer)?;
+        let post_type = classify_post_type(&raw);
 
         Ok(Post {
-            id: helper.id,
-            subreddit: helper.subreddit,
-            title: helper.title,
-            permalink: helper.permalink,
-            url: helper.url,
-            post_hint: helper.post_hint,
+            id: raw.id,
+            subreddit: raw.subreddit,
+            title: raw.title,
+            permalink: raw.permalink,
+            url: raw.url,
+            score: raw.score,
+            post_hint: raw.post_hint,
             post_type,
-            gallery_data: helper.gallery_data,
-            media_metadata: helper.media_metadata,
+            gallery_data: raw.gallery_data,
+            media_metadata: raw.media_metadata,
+            thumbnail: raw.thumbnail,
+            preview: raw.preview,
+            link_flair_text: raw.link_flair_text.filter(|flair| !flair.is_empty()),
+            selftext: raw.selftext.filter(|text| !text.is_empty()),
+            crosspost_parent: raw.crosspost_parent,
+            stickied: raw.stickied,
+            spoiler: raw.spoiler,
+            over_18: raw.over_18,
+            created: raw.created_utc,
+            num_comments: raw.num_comments,
+            author: raw.author,
+            total_awards_received: raw.total_awards_received,
         })
     }
 }
@@ -170,6 +325,170 @@ impl Post {
     pub(crate) fn format_old_permalink_url(&self) -> String {
         to_old_reddit_url(&format_url_from_path(&self.permalink, None))
     }
+
+    /// URL of the best available preview image of the post. Thumbnail is used as a fallback, but
+    /// it can also be a placeholder like "self" or "default", which are ignored.
+    pub fn preview_image_url(&self) -> Option<String> {
+        self.preview
+            .as_ref()
+            .and_then(|preview| preview.images.first())
+            .map(|image| image.source.url.replace("&amp;", "&"))
+            .or_else(|| {
+                self.thumbnail
+                    .as_ref()
+                    .filter(|thumbnail| thumbnail.starts_with("http"))
+                    .cloned()
+            })
+    }
+
+    /// Id of the post this post is a crosspost of, without the type prefix.
+    pub fn crosspost_parent_id(&self) -> Option<&str> {
+        self.crosspost_parent
+            .as_deref()
+            .map(|parent| parent.trim_start_matches("t3_"))
+    }
+
+    /// Whether the post is of one of the filtered types. Galleries and single images are different
+    /// types, so `image` doesn't match galleries and vice versa.
+    pub fn matches_filter(&self, filter: Option<&[PostType]>) -> bool {
+        filter.is_none_or(|filter| filter.contains(&self.post_type))
+    }
+
+    /// Whether the post is marked NSFW or is from one of the given subreddits.
+    pub fn is_sensitive(&self, sensitive_subreddits: &[String]) -> bool {
+        self.over_18
+            || sensitive_subreddits
+                .iter()
+                .any(|subreddit| subreddit.eq_ignore_ascii_case(&self.subreddit))
+    }
+
+    /// Whether the post is an image, a video or a gallery.
+    pub fn is_media(&self) -> bool {
+        matches!(
+            self.post_type,
+            PostType::Image | PostType::Video | PostType::Gallery
+        )
+    }
+
+    /// Whether the post links to one of the given domains or their subdomains.
+    pub fn is_from_domain(&self, domains: &[String]) -> bool {
+        let Some(host) = Url::parse(&self.url)
+            .ok()
+            .and_then(|url| url.host_str().map(str::to_owned))
+        else {
+            return false;
+        };
+
+        domains
+            .iter()
+            .any(|domain| host == *domain || host.ends_with(&format!(".{domain}")))
+    }
+
+    /// Whether the flair of the post matches one of the given lowercase flairs.
+    pub fn has_flair(&self, flairs: &[String], flair_match: FlairMatch) -> bool {
+        let Some(flair) = self.link_flair_text.as_ref().map(|f| f.to_lowercase()) else {
+            return false;
+        };
+
+        flairs.iter().any(|f| match flair_match {
+            FlairMatch::Exact => flair == *f,
+            FlairMatch::Substring => flair.contains(f.as_str()),
+        })
+    }
+
+    /// Canonical form of the url of the linked media, so that the same media posted to different
+    /// subreddits can be recognized. Self posts and galleries link to reddit itself and have none.
+    pub fn canonical_media_url(&self) -> Option<String> {
+        match self.post_type {
+            PostType::SelfText | PostType::Gallery => None,
+            _ => canonicalize_url(&self.url),
+        }
+    }
+}
+
+/// Normalizes a post title so that reposts with different casing, punctuation or spacing compare
+/// equal.
+pub fn normalize_title(title: &str) -> String {
+    title
+        .replace("&amp;", "&")
+        .to_lowercase()
+        .chars()
+        .map(|c| if c.is_alphanumeric() { c } else { ' ' })
+        .collect::<String>()
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// How similar two titles are, from 0 to 1: the share of the words of their normalized forms that
+/// they have in common. Titles without any words aren't similar to anything.
+pub fn title_similarity(a: &str, b: &str) -> f64 {
+    let (a, b) = (normalize_title(a), normalize_title(b));
+    let a = a.split_whitespace().collect::<HashSet<_>>();
+    let b = b.split_whitespace().collect::<HashSet<_>>();
+    if a.is_empty() || b.is_empty() {
+        return 0.0;
+    }
+    let common = a.intersection(&b).count();
+    common as f64 / (a.len() + b.len() - common) as f64
+}
+
+/// Extensions of audio files that Telegram can play.
+const AUDIO_EXTENSIONS: &[&str] = &["mp3", "m4a", "ogg", "oga", "opus", "flac", "wav"];
+
+/// Whether the url links directly to an audio file.
+fn is_audio_url(url: &str) -> bool {
+    Url::parse(url).is_ok_and(|url| {
+        url.path()
+            .rsplit_once('.')
+            .is_some_and(|(_, ext)| AUDIO_EXTENSIONS.contains(&ext.to_lowercase().as_str()))
+    })
+}
+
+/// Hosts of short videos that Reddit embeds and yt-dlp can download.
+const EMBEDDED_VIDEO_HOSTS: &[&str] = &["streamable.com", "tiktok.com", "clips.twitch.tv"];
+
+/// Whether the url of an embedded video is worth downloading. Of YouTube only shorts are, since
+/// regular videos are often too long.
+fn is_downloadable_embed(url: &str) -> bool {
+    let Ok(url) = Url::parse(url) else {
+        return false;
+    };
+    let host = url.host_str().unwrap_or_default().to_lowercase();
+    let is_host = |domain: &str| host == domain || host.ends_with(&format!(".{domain}"));
+    EMBEDDED_VIDEO_HOSTS.iter().any(|domain| is_host(domain))
+        || (is_host("youtube.com") && url.path().starts_with("/shorts/"))
+}
+
+/// Query parameters that don't affect what a url points to.
+const IGNORED_QUERY_PARAMS: &[&str] = &[
+    "fbclid", "gclid", "ref", "ref_src", "ref_url", "si", "feature", "share_id", "context",
+];
+
+/// Normalizes the scheme and host of a url and strips tracking query parameters and the fragment.
+fn canonicalize_url(url: &str) -> Option<String> {
+    let url = Url::parse(&url.replace("&amp;", "&")).ok()?;
+    let host = url.host_str()?.to_lowercase();
+    let host = ["www.", "m.", "old."]
+        .iter()
+        .find_map(|prefix| host.strip_prefix(prefix))
+        .unwrap_or(&host);
+
+    let mut query = url
+        .query_pairs()
+        .filter(|(key, _)| {
+            !key.starts_with("utm_") && !IGNORED_QUERY_PARAMS.contains(&key.as_ref())
+        })
+        .map(|(key, value)| format!("{key}={value}"))
+        .collect::<Vec<_>>();
+    query.sort();
+
+    let path = url.path().trim_end_matches('/');
+    if query.is_empty() {
+        Some(format!("https://{host}{path}"))
+    } else {
+        Some(format!("https://{host}{path}?{}", query.join("&")))
+    }
 }
 
 impl Recordable for Post {
@@ -184,6 +503,42 @@ impl Recordable for Post {
     fn subreddit(&self) -> &str {
         &self.subreddit
     }
+
+    fn permalink(&self) -> Option<&str> {
+        Some(&self.permalink)
+    }
+}
+
+/// The comments endpoint responds with a listing of the post followed by a listing of comments.
+#[derive(Deserialize, Debug)]
+pub struct CommentsResponse(pub serde::de::IgnoredAny, pub CommentListingResponse);
+
+#[derive(Deserialize, Debug)]
+pub struct CommentListingResponse {
+    pub data: CommentListingResponseData,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct CommentListingResponseData {
+    pub children: Vec<CommentListingItem>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(tag = "kind", content = "data")]
+pub enum CommentListingItem {
+    #[serde(rename = "t1")]
+    Comment(Comment),
+    /// Placeholder for comments that have to be loaded separately
+    #[serde(rename = "more")]
+    More(serde::de::IgnoredAny),
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct Comment {
+    pub author: String,
+    pub body: String,
+    #[serde(default)]
+    pub score: i64,
 }
 
 #[derive(Deserialize, Debug)]
@@ -195,3 +550,537 @@ pub struct SubredditAboutResponse {
 pub struct SubredditAbout {
     pub display_name: String,
 }
+
+#[derive(Deserialize, Debug)]
+pub struct SubredditSearchResponse {
+    pub data: SubredditSearchResponseData,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct SubredditSearchResponseData {
+    pub children: Vec<SubredditSearchItem>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct SubredditSearchItem {
+    pub data: SubredditSearchResult,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct SubredditSearchResult {
+    pub display_name: String,
+    /// Missing for some subreddits, e.g. quarantined ones
+    pub subscribers: Option<u64>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn post_with_url(url: &str) -> Post {
+        Post {
+            id: "v6nu75".into(),
+            subreddit: "programming".into(),
+            title: "title".into(),
+            permalink: "/r/programming/comments/v6nu75/title/".into(),
+            url: url.into(),
+            post_hint: Some("link".into()),
+            post_type: PostType::Link,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_is_from_domain() {
+        let domains = vec!["github.com".to_string(), "gitlab.com".to_string()];
+        assert!(post_with_url("https://github.com/raine/tgreddit").is_from_domain(&domains));
+        assert!(post_with_url("https://gist.github.com/foo").is_from_domain(&domains));
+        assert!(post_with_url("https://GitLab.com/foo").is_from_domain(&domains));
+        assert!(!post_with_url("https://notgithub.com/foo").is_from_domain(&domains));
+        assert!(!post_with_url("https://github.com.evil.com/").is_from_domain(&domains));
+        assert!(!post_with_url("not a url").is_from_domain(&domains));
+    }
+
+    #[test]
+    fn test_has_flair() {
+        let flairs = vec!["news".to_string(), "discussion".to_string()];
+        let post = Post {
+            link_flair_text: Some("News".into()),
+            ..post_with_url("https://github.com/")
+        };
+        assert!(post.has_flair(&flairs, FlairMatch::Exact));
+
+        let post = Post {
+            link_flair_text: Some("Breaking News".into()),
+            ..post
+        };
+        assert!(!post.has_flair(&flairs, FlairMatch::Exact));
+        assert!(post.has_flair(&flairs, FlairMatch::Substring));
+
+        let post = Post {
+            link_flair_text: None,
+            ..post
+        };
+        assert!(!post.has_flair(&flairs, FlairMatch::Substring));
+    }
+
+    #[test]
+    fn test_deserialize_comments_response() {
+        let res: CommentsResponse = serde_json::from_str(
+            r#"[
+                {"kind": "Listing", "data": {"children": []}},
+                {"kind": "Listing", "data": {"children": [
+                    {"kind": "t1", "data": {"author": "spez", "body": "hello", "score": 5}},
+                    {"kind": "more", "data": {"count": 10, "children": ["abc"]}}
+                ]}}
+            ]"#,
+        )
+        .unwrap();
+        let children = res.1.data.children;
+        assert_eq!(children.len(), 2);
+        assert!(matches!(
+            &children[0],
+            CommentListingItem::Comment(Comment { author, score: 5, .. }) if author == "spez"
+        ));
+        assert!(matches!(children[1], CommentListingItem::More(_)));
+    }
+
+    #[test]
+    fn test_deserialize_subreddit_search_response() {
+        let res: SubredditSearchResponse = serde_json::from_str(
+            r#"{"kind": "Listing", "data": {"after": null, "children": [
+                {"kind": "t5", "data": {"display_name": "rust", "subscribers": 300000}},
+                {"kind": "t5", "data": {"display_name": "playrust", "subscribers": null}}
+            ]}}"#,
+        )
+        .unwrap();
+        let results = res
+            .data
+            .children
+            .into_iter()
+            .map(|item| item.data)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            results,
+            vec![
+                SubredditSearchResult {
+                    display_name: "rust".to_string(),
+                    subscribers: Some(300000),
+                },
+                SubredditSearchResult {
+                    display_name: "playrust".to_string(),
+                    subscribers: None,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_canonicalize_url() {
+        assert_eq!(
+            canonicalize_url("http://WWW.Imgur.com/abc123/?utm_source=reddit&b=2&a=1#top"),
+            Some("https://imgur.com/abc123?a=1&b=2".to_string())
+        );
+        assert_eq!(
+            canonicalize_url("https://m.youtube.com/watch?v=dQw4w9WgXcQ&si=xyz&feature=share"),
+            Some("https://youtube.com/watch?v=dQw4w9WgXcQ".to_string())
+        );
+        assert_eq!(
+            canonicalize_url("https://i.redd.it/abc.jpg"),
+            Some("https://i.redd.it/abc.jpg".to_string())
+        );
+        assert_eq!(canonicalize_url("not a url"), None);
+    }
+
+    #[test]
+    fn test_gallery_media_sources() {
+        let post: Post = serde_json::from_str(
+            r#"{
+                "id": "1abcde",
+                "subreddit": "pics",
+                "title": "mixed gallery",
+                "is_video": false,
+                "permalink": "/r/pics/comments/1abcde/mixed_gallery/",
+                "url": "https://www.reddit.com/gallery/1abcde",
+                "is_self": false,
+                "is_gallery": true,
+                "gallery_data": {
+                    "items": [
+                        {
+                            "media_id": "img1",
+                            "caption": "The first one",
+                            "outbound_url": "https://example.com/source"
+                        },
+                        {"media_id": "gif1"},
+                        {"media_id": "vid1"}
+                    ]
+                },
+                "media_metadata": {
+                    "img1": {
+                        "status": "valid",
+                        "e": "Image",
+                        "m": "image/jpg",
+                        "s": {"y": 1080, "x": 1920, "u": "https://preview.redd.it/img1.jpg?width=1920&amp;s=abc"}
+                    },
+                    "gif1": {
+                        "status": "valid",
+                        "e": "AnimatedImage",
+                        "m": "image/gif",
+                        "s": {
+                            "y": 480,
+                            "x": 640,
+                            "gif": "https://i.redd.it/gif1.gif",
+                            "mp4": "https://preview.redd.it/gif1.gif?format=mp4&amp;s=def"
+                        }
+                    },
+                    "vid1": {
+                        "status": "valid",
+                        "e": "RedditVideo",
+                        "x": 1280,
+                        "y": 720,
+                        "isGif": false,
+                        "dashUrl": "https://v.redd.it/link/1abcde/asset/vid1/DASHPlaylist.mpd?a=1&amp;v=1",
+                        "hlsUrl": "https://v.redd.it/link/1abcde/asset/vid1/HLSPlaylist.m3u8?a=1&amp;v=1",
+                        "id": "vid1"
+                    }
+                }
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(post.post_type, PostType::Gallery);
+        let items = &post.gallery_data.as_ref().unwrap().items;
+        assert_eq!(items[0].caption.as_deref(), Some("The first one"));
+        assert_eq!(
+            items[0].outbound_url.as_deref(),
+            Some("https://example.com/source")
+        );
+        assert_eq!(items[1].caption, None);
+
+        let media_metadata = post.media_metadata.unwrap();
+        let source = |id: &str| media_metadata[id].source();
+        assert_eq!(
+            source("img1"),
+            Some(MediaSource::File(
+                "https://preview.redd.it/img1.jpg?width=1920&s=abc".into()
+            ))
+        );
+        assert_eq!(
+            source("gif1"),
+            Some(MediaSource::File(
+                "https://preview.redd.it/gif1.gif?format=mp4&s=def".into()
+            ))
+        );
+        assert_eq!(
+            source("vid1"),
+            Some(MediaSource::Stream(
+                "https://v.redd.it/link/1abcde/asset/vid1/HLSPlaylist.m3u8?a=1&v=1".into()
+            ))
+        );
+    }
+
+    #[test]
+    fn test_is_audio_url() {
+        assert!(is_audio_url("https://example.com/episode-1.mp3"));
+        assert!(is_audio_url("https://example.com/a/b.M4A?token=abc"));
+        assert!(!is_audio_url("https://example.com/mp3"));
+        assert!(!is_audio_url("https://example.com/a.mp3.html"));
+        assert!(!is_audio_url("https://i.redd.it/abc.jpg"));
+        assert!(!is_audio_url("not a url"));
+    }
+
+    #[test]
+    fn test_classify_post_type() {
+        let raw = |url: &str, post_hint: Option<&str>| RawPost {
+            url: url.into(),
+            post_hint: post_hint.map(Into::into),
+            ..Default::default()
+        };
+        let classify = |post: RawPost| classify_post_type(&post);
+
+        assert_eq!(
+            classify(raw("https://i.redd.it/abc.jpg", Some("image"))),
+            PostType::Image
+        );
+        assert_eq!(
+            classify(RawPost {
+                is_video: true,
+                ..raw("https://v.redd.it/abc", Some("hosted:video"))
+            }),
+            PostType::Video
+        );
+        assert_eq!(
+            classify(raw("https://i.imgur.com/abc.gifv", Some("link"))),
+            PostType::Video
+        );
+        assert_eq!(
+            classify(raw("https://example.com/article", Some("link"))),
+            PostType::Link
+        );
+        assert_eq!(
+            classify(RawPost {
+                is_self: true,
+                ..raw(
+                    "https://www.reddit.com/r/rust/comments/abc/title/",
+                    Some("self")
+                )
+            }),
+            PostType::SelfText
+        );
+        assert_eq!(
+            classify(RawPost {
+                is_gallery: Some(true),
+                ..raw("https://www.reddit.com/gallery/abc", None)
+            }),
+            PostType::Gallery
+        );
+        assert_eq!(
+            classify(raw("https://example.com/article", None)),
+            PostType::Unknown
+        );
+    }
+
+    #[test]
+    fn test_classify_post_type_crosspost() {
+        let parent = |post_type| Post {
+            post_type,
+            ..Default::default()
+        };
+        let crosspost = |parent: Post| RawPost {
+            url: "/r/videos/comments/abc/title/".into(),
+            post_hint: Some("link".into()),
+            crosspost_parent_list: Some(vec![parent]),
+            ..Default::default()
+        };
+        assert_eq!(
+            classify_post_type(&crosspost(parent(PostType::Video))),
+            PostType::Video
+        );
+        assert_eq!(
+            classify_post_type(&crosspost(parent(PostType::Image))),
+            PostType::Link
+        );
+    }
+
+    #[test]
+    fn test_classify_post_type_bestof() {
+        let post = RawPost {
+            url: "https://www.reddit.com/r/AskReddit/comments/abc/title/def/".into(),
+            ..Default::default()
+        };
+        assert_eq!(classify_post_type(&post), PostType::Link);
+    }
+
+    #[test]
+    fn test_is_downloadable_embed() {
+        assert!(is_downloadable_embed("https://streamable.com/abc123"));
+        assert!(is_downloadable_embed(
+            "https://www.tiktok.com/@user/video/123"
+        ));
+        assert!(is_downloadable_embed("https://vm.tiktok.com/ZMabc/"));
+        assert!(is_downloadable_embed("https://youtube.com/shorts/abc123"));
+        assert!(!is_downloadable_embed(
+            "https://www.youtube.com/watch?v=abc123"
+        ));
+        assert!(!is_downloadable_embed("https://notstreamable.com/abc123"));
+        assert!(!is_downloadable_embed("not a url"));
+    }
+
+    #[test]
+    fn test_deserialize_rich_video() {
+        let post = |url: &str| -> Post {
+            serde_json::from_str(&format!(
+                r#"{{
+                    "id": "xyz123",
+                    "subreddit": "videos",
+                    "title": "title",
+                    "is_video": false,
+                    "permalink": "/r/videos/comments/xyz123/title/",
+                    "url": "{url}",
+                    "is_self": false,
+                    "post_hint": "rich:video"
+                }}"#
+            ))
+            .unwrap()
+        };
+        assert_eq!(
+            post("https://streamable.com/abc123").post_type,
+            PostType::Video
+        );
+        assert_eq!(
+            post("https://www.youtube.com/watch?v=abc123").post_type,
+            PostType::Link
+        );
+    }
+
+    #[test]
+    fn test_deserialize_stickied() {
+        let post: Post = serde_json::from_str(
+            r#"{
+                "id": "xyz123",
+                "subreddit": "pics",
+                "title": "Rules of the subreddit, read before posting",
+                "is_video": false,
+                "permalink": "/r/pics/comments/xyz123/rules/",
+                "url": "https://www.reddit.com/r/pics/comments/xyz123/rules/",
+                "is_self": true,
+                "stickied": true
+            }"#,
+        )
+        .unwrap();
+        assert!(post.stickied);
+
+        let post: Post = serde_json::from_str(
+            r#"{
+                "id": "xyz124",
+                "subreddit": "pics",
+                "title": "title",
+                "is_video": false,
+                "permalink": "/r/pics/comments/xyz124/title/",
+                "url": "https://i.redd.it/abc.jpg",
+                "is_self": false
+            }"#,
+        )
+        .unwrap();
+        assert!(!post.stickied);
+    }
+
+    #[test]
+    fn test_matches_filter() {
+        let post = |post_type| Post {
+            post_type,
+            ..Default::default()
+        };
+        let image = post(PostType::Image);
+        let gallery = post(PostType::Gallery);
+        assert!(image.matches_filter(None));
+        assert!(image.matches_filter(Some(&[PostType::Image])));
+        assert!(!image.matches_filter(Some(&[PostType::Gallery])));
+        assert!(gallery.matches_filter(Some(&[PostType::Gallery])));
+        assert!(!gallery.matches_filter(Some(&[PostType::Image])));
+        assert!(gallery.matches_filter(Some(&[PostType::Video, PostType::Gallery])));
+        assert!(!image.matches_filter(Some(&[PostType::Video, PostType::Gallery])));
+        assert!(image.is_media() && gallery.is_media());
+        assert!(!post(PostType::Link).is_media());
+        assert!(!post(PostType::SelfText).is_media());
+        assert!(!post(PostType::Unknown).is_media());
+    }
+
+    #[test]
+    fn test_deserialize_spoiler() {
+        let post: Post = serde_json::from_str(
+            r#"{
+                "id": "xyz125",
+                "subreddit": "movies",
+                "title": "The ending scene",
+                "is_video": false,
+                "permalink": "/r/movies/comments/xyz125/the_ending_scene/",
+                "url": "https://i.redd.it/abc.jpg",
+                "is_self": false,
+                "spoiler": true,
+                "created_utc": 1654612800.0
+            }"#,
+        )
+        .unwrap();
+        assert!(post.spoiler);
+        assert_eq!(post.created, 1654612800.0);
+    }
+
+    #[test]
+    fn test_is_sensitive() {
+        let post: Post = serde_json::from_str(
+            r#"{
+                "id": "xyz126",
+                "subreddit": "pics",
+                "title": "title",
+                "is_video": false,
+                "permalink": "/r/pics/comments/xyz126/title/",
+                "url": "https://i.redd.it/abc.jpg",
+                "is_self": false,
+                "over_18": true
+            }"#,
+        )
+        .unwrap();
+        assert!(post.over_18);
+        assert!(post.is_sensitive(&[]));
+
+        let post = Post {
+            subreddit: "Pics".to_string(),
+            ..Default::default()
+        };
+        assert!(!post.is_sensitive(&[]));
+        assert!(post.is_sensitive(&["pics".to_string()]));
+    }
+
+    #[test]
+    fn test_preview_image_url() {
+        let post: Post = serde_json::from_str(
+            r#"{
+                "id": "v6nu75",
+                "subreddit": "programming",
+                "title": "title",
+                "is_video": false,
+                "permalink": "/r/programming/comments/v6nu75/title/",
+                "url": "https://github.com/raine/tgreddit",
+                "post_hint": "link",
+                "is_self": false,
+                "thumbnail": "https://b.thumbs.redditmedia.com/thumb.jpg",
+                "preview": {
+                    "images": [
+                        {
+                            "source": {
+                                "url": "https://external-preview.redd.it/img.png?width=1200&amp;s=abc"
+                            }
+                        }
+                    ]
+                }
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(
+            post.preview_image_url().as_deref(),
+            Some("https://external-preview.redd.it/img.png?width=1200&s=abc")
+        );
+
+        let post = Post {
+            preview: None,
+            ..post
+        };
+        assert_eq!(
+            post.preview_image_url().as_deref(),
+            Some("https://b.thumbs.redditmedia.com/thumb.jpg")
+        );
+
+        let post = Post {
+            thumbnail: Some("default".into()),
+            ..post
+        };
+        assert_eq!(post.preview_image_url(), None);
+    }
+
+    #[test]
+    fn test_normalize_title() {
+        assert_eq!(
+            normalize_title("  This cat is HUGE!!  (OC) "),
+            "this cat is huge oc"
+        );
+        assert_eq!(
+            normalize_title("Tom &amp; Jerry"),
+            normalize_title("tom & jerry")
+        );
+        assert_eq!(normalize_title("?!"), "");
+    }
+
+    #[test]
+    fn test_title_similarity() {
+        assert_eq!(
+            title_similarity("This cat is HUGE!!", "this cat is huge"),
+            1.0
+        );
+        assert_eq!(
+            title_similarity("this cat is huge", "this cat is huge oc"),
+            0.8
+        );
+        assert_eq!(title_similarity("this cat is huge", "a small dog"), 0.0);
+        assert_eq!(title_similarity("?!", "?!"), 0.0);
+    }
+}
@@ -0,0 +1,68 @@
+use std::sync::OnceLock;
+use tokio::{
+    sync::{Mutex, Semaphore, SemaphorePermit},
+    time::{Duration, Instant},
+};
+
+use crate::config::Config;
+
+static SEND_LIMITER: OnceLock<SendLimiter> = OnceLock::new();
+
+/// Limits how many Telegram sends can be in flight at once and how often a new one may start, to
+/// avoid hitting Telegram's flood limits when many posts are sent in a burst.
+pub struct SendLimiter {
+    semaphore: Semaphore,
+    min_interval: Duration,
+    next_send_at: Mutex<Instant>,
+}
+
+impl SendLimiter {
+    pub fn new(max_concurrent: usize, min_interval: Duration) -> Self {
+        Self {
+            semaphore: Semaphore::new(max_concurrent.max(1)),
+            min_interval,
+            next_send_at: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Waits until a send is allowed. The send should be done while holding the returned permit.
+    pub async fn acquire(&self) -> SemaphorePermit<'_> {
+        let permit = self
+            .semaphore
+            .acquire()
+            .await
+            .expect("semaphore is never closed");
+        let mut next_send_at = self.next_send_at.lock().await;
+        tokio::time::sleep_until(*next_send_at).await;
+        *next_send_at = Instant::now() + self.min_interval;
+        permit
+    }
+}
+
+/// Waits on the limiter shared by all sends of the process.
+pub async fn acquire(config: &Config) -> SemaphorePermit<'static> {
+    SEND_LIMITER
+        .get_or_init(|| {
+            SendLimiter::new(
+                config.telegram_max_concurrent_sends,
+                Duration::from_millis(config.telegram_min_send_interval_ms),
+            )
+        })
+        .acquire()
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_send_limiter_spaces_sends() {
+        let limiter = SendLimiter::new(2, Duration::from_millis(50));
+        let start = Instant::now();
+        for _ in 0..3 {
+            let _permit = limiter.acquire().await;
+        }
+        assert!(start.elapsed() >= Duration::from_millis(100));
+    }
+}
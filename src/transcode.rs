@@ -0,0 +1,261 @@
+use anyhow::{Context, Result};
+use duct::cmd;
+use log::{info, warn};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::OnceLock,
+};
+
+use crate::config::Config;
+
+/// Heights videos are scaled down to when re-encoding them to fit a size limit, one per attempt.
+/// The video is given up on if it doesn't fit after the last one.
+const SHRINK_MAX_HEIGHTS: &[u32] = &[720, 480, 360];
+/// Bitrate of the audio of re-encoded videos in kbit/s
+const SHRINK_AUDIO_KBPS: f64 = 96.0;
+/// Video bitrate in kbit/s below which re-encoding isn't worth it
+const SHRINK_MIN_VIDEO_KBPS: f64 = 150.0;
+
+/// A video re-encoded by [`shrink_to_fit`].
+#[derive(Debug)]
+pub struct ShrunkVideo {
+    pub path: PathBuf,
+    pub width: u16,
+    pub height: u16,
+    pub size_bytes: u64,
+}
+
+/// Whether ffmpeg can be run. Checked only once.
+pub fn is_ffmpeg_available() -> bool {
+    static AVAILABLE: OnceLock<bool> = OnceLock::new();
+    *AVAILABLE.get_or_init(|| {
+        cmd!("ffmpeg", "-version")
+            .stdout_null()
+            .stderr_null()
+            .run()
+            .map_err(|err| warn!("ffmpeg is not available: {err}"))
+            .is_ok()
+    })
+}
+
+/// Whether ffprobe can be run. Checked only once.
+pub fn is_ffprobe_available() -> bool {
+    static AVAILABLE: OnceLock<bool> = OnceLock::new();
+    *AVAILABLE.get_or_init(|| {
+        cmd!("ffprobe", "-version")
+            .stdout_null()
+            .stderr_null()
+            .run()
+            .map_err(|err| warn!("ffprobe is not available: {err}"))
+            .is_ok()
+    })
+}
+
+/// Whether the media file has at least one audio stream.
+pub fn has_audio_stream(path: &Path) -> Result<bool> {
+    let streams = cmd!(
+        "ffprobe",
+        "-loglevel",
+        "error",
+        "-select_streams",
+        "a",
+        "-show_entries",
+        "stream=index",
+        "-of",
+        "csv=p=0",
+        path
+    )
+    .read()
+    .context("Failed to run ffprobe")?;
+    Ok(!streams.trim().is_empty())
+}
+
+/// Converts an animated image such as gif or animated webp to mp4 with ffmpeg, limiting the
+/// bitrate and width as configured. Telegram converts gifs to mp4 anyway, but does a poor job with
+/// large ones, and doesn't support animated webp at all. The mp4 is written next to the original,
+/// so it's cleaned up along with the original's temp dir.
+pub fn animation_to_mp4(path: &Path, config: &Config) -> Result<PathBuf> {
+    let output = path.with_extension("converted.mp4");
+    let bitrate = format!("{}k", config.gif_max_bitrate_kbps);
+    let bufsize = format!("{}k", config.gif_max_bitrate_kbps * 2);
+    // Width and height must be even for yuv420p
+    let scale = format!("scale='trunc(min({},iw)/2)*2':-2", config.gif_max_width);
+
+    info!("converting {path:?} to mp4");
+    cmd!(
+        "ffmpeg",
+        "-y",
+        "-loglevel",
+        "error",
+        "-i",
+        path,
+        "-movflags",
+        "+faststart",
+        "-pix_fmt",
+        "yuv420p",
+        "-vf",
+        scale,
+        "-b:v",
+        &bitrate,
+        "-maxrate",
+        &bitrate,
+        "-bufsize",
+        bufsize,
+        &output
+    )
+    .stderr_to_stdout()
+    .run()
+    .context("Failed to run ffmpeg")?;
+
+    Ok(output)
+}
+
+/// Re-encodes a video with ffmpeg so that it's at most `max_bytes`, lowering the bitrate and
+/// resolution on each attempt, as the encoder doesn't hit the target bitrate exactly. The
+/// re-encoded video is written next to the original, so it's cleaned up along with the original's
+/// temp dir. Fails if the video doesn't fit after the last attempt. Requires ffmpeg and ffprobe.
+pub fn shrink_to_fit(path: &Path, max_bytes: u64) -> Result<ShrunkVideo> {
+    let duration_secs = duration_secs(path)?;
+    let output = path.with_extension("shrunk.mp4");
+
+    for (attempt, max_height) in SHRINK_MAX_HEIGHTS.iter().enumerate() {
+        let Some(video_kbps) = shrink_video_kbps(duration_secs, max_bytes, attempt) else {
+            anyhow::bail!("Video of {duration_secs:.0}s is too long to fit in {max_bytes} bytes");
+        };
+        let bitrate = format!("{video_kbps}k");
+        let bufsize = format!("{}k", video_kbps * 2);
+        // Width and height must be even for yuv420p
+        let scale = format!("scale=-2:'trunc(min({max_height},ih)/2)*2'");
+
+        info!("re-encoding {path:?} at {bitrate}bit/s and at most {max_height}p");
+        cmd!(
+            "ffmpeg",
+            "-y",
+            "-loglevel",
+            "error",
+            "-i",
+            path,
+            "-movflags",
+            "+faststart",
+            "-pix_fmt",
+            "yuv420p",
+            "-vf",
+            scale,
+            "-c:v",
+            "libx264",
+            "-preset",
+            "veryfast",
+            "-b:v",
+            &bitrate,
+            "-maxrate",
+            &bitrate,
+            "-bufsize",
+            bufsize,
+            "-c:a",
+            "aac",
+            "-b:a",
+            format!("{SHRINK_AUDIO_KBPS}k"),
+            &output
+        )
+        .stderr_to_stdout()
+        .run()
+        .context("Failed to run ffmpeg")?;
+
+        let size_bytes = fs::metadata(&output)
+            .context("Could not read re-encoded video metadata")?
+            .len();
+        if size_bytes <= max_bytes {
+            let (width, height) = dimensions(&output)?;
+            return Ok(ShrunkVideo {
+                path: output,
+                width,
+                height,
+                size_bytes,
+            });
+        }
+        info!("re-encoded video is still {size_bytes} bytes, over {max_bytes}");
+    }
+
+    anyhow::bail!(
+        "Video didn't fit in {max_bytes} bytes after {} attempts",
+        SHRINK_MAX_HEIGHTS.len()
+    )
+}
+
+/// Video bitrate in kbit/s for the given re-encoding attempt, aiming further below the limit on
+/// each attempt. `None` if the video is too long for a bitrate worth encoding at.
+fn shrink_video_kbps(duration_secs: f64, max_bytes: u64, attempt: usize) -> Option<u64> {
+    let total_kbps = max_bytes as f64 * 8.0 / 1000.0 / duration_secs;
+    let headroom = 0.9 - 0.15 * attempt as f64;
+    let video_kbps = total_kbps * headroom - SHRINK_AUDIO_KBPS;
+    (video_kbps >= SHRINK_MIN_VIDEO_KBPS).then_some(video_kbps as u64)
+}
+
+fn duration_secs(path: &Path) -> Result<f64> {
+    let duration = cmd!(
+        "ffprobe",
+        "-loglevel",
+        "error",
+        "-show_entries",
+        "format=duration",
+        "-of",
+        "csv=p=0",
+        path
+    )
+    .read()
+    .context("Failed to run ffprobe")?;
+    duration
+        .trim()
+        .parse::<f64>()
+        .ok()
+        .filter(|duration| duration.is_finite() && *duration > 0.0)
+        .with_context(|| format!("Unexpected video duration {duration:?}"))
+}
+
+fn dimensions(path: &Path) -> Result<(u16, u16)> {
+    let dimensions = cmd!(
+        "ffprobe",
+        "-loglevel",
+        "error",
+        "-select_streams",
+        "v:0",
+        "-show_entries",
+        "stream=width,height",
+        "-of",
+        "csv=p=0",
+        path
+    )
+    .read()
+    .context("Failed to run ffprobe")?;
+    parse_dimensions(&dimensions)
+        .with_context(|| format!("Unexpected video dimensions {dimensions:?}"))
+}
+
+/// Parses ffprobe's `width,height` output.
+fn parse_dimensions(output: &str) -> Option<(u16, u16)> {
+    let (width, height) = output.trim().split_once(',')?;
+    Some((width.parse().ok()?, height.parse().ok()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shrink_video_kbps() {
+        // 50 MB over 5 minutes is about 1333 kbit/s in total
+        let max_bytes = 50_000_000;
+        assert_eq!(shrink_video_kbps(300.0, max_bytes, 0), Some(1104));
+        assert_eq!(shrink_video_kbps(300.0, max_bytes, 1), Some(904));
+        assert_eq!(shrink_video_kbps(300.0, max_bytes, 2), Some(704));
+        assert_eq!(shrink_video_kbps(3600.0, max_bytes, 0), None);
+    }
+
+    #[test]
+    fn test_parse_dimensions() {
+        assert_eq!(parse_dimensions("1280,720\n"), Some((1280, 720)));
+        assert_eq!(parse_dimensions("N/A,720"), None);
+        assert_eq!(parse_dimensions(""), None);
+    }
+}
@@ -1,11 +1,17 @@
+use chrono::NaiveTime;
 use serde_derive::{Deserialize, Serialize};
+use strum_macros::{Display, EnumString};
+use teloxide::types::{FileId, MessageId, ThreadId};
 use tempfile::TempDir;
 
 use crate::{
     db::Recordable,
-    reddit::{PostType, TopPostsTimePeriod},
+    reddit::{CommentSort, PostType, TopPostsTimePeriod},
+};
+use std::{
+    path::PathBuf,
+    sync::atomic::{AtomicBool, Ordering},
 };
-use std::path::PathBuf;
 
 #[derive(Debug)]
 pub struct Video {
@@ -15,6 +21,7 @@ pub struct Video {
     pub title: String,
     pub width: u16,
     pub height: u16,
+    pub size_bytes: u64,
     pub _video_tempdir: TempDir,
 }
 
@@ -32,24 +39,218 @@ impl Recordable for Video {
     }
 }
 
-#[derive(Debug, PartialEq, Eq)]
+/// What a subscription follows. For user subscriptions the `subreddit` field holds the username.
+#[derive(Display, Debug, Default, Clone, Copy, PartialEq, Eq, EnumString)]
+#[strum(serialize_all = "snake_case")]
+pub enum SubscriptionKind {
+    #[default]
+    Subreddit,
+    User,
+}
+
+impl SubscriptionKind {
+    pub fn prefix(&self) -> &'static str {
+        match self {
+            SubscriptionKind::Subreddit => "r/",
+            SubscriptionKind::User => "u/",
+        }
+    }
+}
+
+/// What the caption of media posts contains.
+#[derive(Display, Debug, Default, Clone, Copy, PartialEq, Eq, EnumString)]
+#[strum(serialize_all = "snake_case")]
+pub enum CaptionMode {
+    /// Title with links to the subreddit and comments
+    #[default]
+    Full,
+    Title,
+    None,
+}
+
+/// How Telegram previews the link of link and self posts sent as text messages.
+#[derive(Display, Debug, Default, Clone, Copy, PartialEq, Eq, EnumString, Deserialize)]
+#[strum(serialize_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum LinkPreview {
+    /// Telegram decides whether and how to show a preview
+    #[default]
+    Auto,
+    Disabled,
+    Small,
+    Large,
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
 pub struct Subscription {
     pub chat_id: i64,
+    pub kind: SubscriptionKind,
     pub subreddit: String,
     pub limit: Option<u32>,
     pub time: Option<TopPostsTimePeriod>,
-    pub filter: Option<PostType>,
+    pub filter: Option<Vec<PostType>>,
+    pub domains: Option<Vec<String>>,
+    pub flairs: Option<Vec<String>>,
+    pub template: Option<String>,
+    pub digest: bool,
+    pub selftext: bool,
+    pub photo_as_document: bool,
+    pub caption: Option<CaptionMode>,
+    pub comments: Option<u32>,
+    pub comment_sort: Option<CommentSort>,
+    pub topic: Option<i32>,
+    pub skip_stickied: Option<bool>,
+    pub only_newer_than_sub: bool,
+    pub min_comments: Option<u32>,
+    pub link_preview: Option<LinkPreview>,
+    pub attach_source: bool,
+    pub media_only: bool,
+    pub extended_info: bool,
+    pub paused: bool,
+    pub primed: bool,
+    pub created_at: chrono::DateTime<chrono::Utc>,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+impl Subscription {
+    pub fn thread_id(&self) -> Option<ThreadId> {
+        self.topic.map(|topic| ThreadId(MessageId(topic)))
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
 pub struct SubscriptionArgs {
+    pub kind: SubscriptionKind,
     pub subreddit: String,
     pub limit: Option<u32>,
     pub time: Option<TopPostsTimePeriod>,
-    pub filter: Option<PostType>,
+    pub filter: Option<Vec<PostType>>,
+    pub domains: Option<Vec<String>>,
+    pub flairs: Option<Vec<String>>,
+    pub template: Option<String>,
+    pub digest: bool,
+    pub selftext: bool,
+    pub photo_as_document: bool,
+    pub caption: Option<CaptionMode>,
+    pub comments: Option<u32>,
+    pub comment_sort: Option<CommentSort>,
+    pub topic: Option<i32>,
+    pub skip_stickied: Option<bool>,
+    pub only_newer_than_sub: bool,
+    pub min_comments: Option<u32>,
+    pub link_preview: Option<LinkPreview>,
+    pub attach_source: bool,
+    pub media_only: bool,
+    pub extended_info: bool,
+    /// Only used by /get, subscriptions send each post separately
+    pub group_media: bool,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+/// Per-subscription settings that affect how a post is delivered, as opposed to which posts are
+/// delivered. Unset values fall back to config.
+#[derive(Debug, Default, Clone)]
+pub struct DeliveryOptions {
+    pub template: Option<String>,
+    pub selftext: bool,
+    pub photo_as_document: bool,
+    pub caption: Option<CaptionMode>,
+    pub comments: Option<u32>,
+    pub comment_sort: Option<CommentSort>,
+    pub link_preview: Option<LinkPreview>,
+    pub attach_source: bool,
+    pub extended_info: bool,
+    /// Forum topic of the chat to send the post to
+    pub topic: Option<i32>,
+    /// Set per chat rather than per subscription, see `Database::get_show_repost_buttons`
+    pub show_repost_buttons: Option<bool>,
+}
+
+impl DeliveryOptions {
+    pub fn thread_id(&self) -> Option<ThreadId> {
+        self.topic.map(|topic| ThreadId(MessageId(topic)))
+    }
+}
+
+impl From<&Subscription> for DeliveryOptions {
+    fn from(sub: &Subscription) -> Self {
+        Self {
+            template: sub.template.clone(),
+            selftext: sub.selftext,
+            photo_as_document: sub.photo_as_document,
+            caption: sub.caption,
+            comments: sub.comments,
+            comment_sort: sub.comment_sort,
+            link_preview: sub.link_preview,
+            attach_source: sub.attach_source,
+            extended_info: sub.extended_info,
+            topic: sub.topic,
+            show_repost_buttons: None,
+        }
+    }
+}
+
+impl From<&SubscriptionArgs> for DeliveryOptions {
+    fn from(args: &SubscriptionArgs) -> Self {
+        Self {
+            template: args.template.clone(),
+            selftext: args.selftext,
+            photo_as_document: args.photo_as_document,
+            caption: args.caption,
+            comments: args.comments,
+            comment_sort: args.comment_sort,
+            link_preview: args.link_preview,
+            attach_source: args.attach_source,
+            extended_info: args.extended_info,
+            topic: args.topic,
+            show_repost_buttons: None,
+        }
+    }
+}
+
+/// Daily time window during which posts are not delivered to a chat. The window may extend past
+/// midnight, e.g. from 23:00 to 07:00.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QuietHours {
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+}
+
+impl QuietHours {
+    pub fn contains(&self, time: NaiveTime) -> bool {
+        if self.start <= self.end {
+            self.start <= time && time < self.end
+        } else {
+            time >= self.start || time < self.end
+        }
+    }
+}
+
+/// Whether the bot is in maintenance mode, toggled with /maintenance. In maintenance mode
+/// subscriptions are not checked for new posts, but commands keep working.
+#[derive(Debug, Default)]
+pub struct Maintenance(AtomicBool);
+
+impl Maintenance {
+    pub fn is_on(&self) -> bool {
+        self.0.load(Ordering::Acquire)
+    }
+
+    /// Returns whether maintenance mode was on before.
+    pub fn set(&self, on: bool) -> bool {
+        self.0.swap(on, Ordering::AcqRel)
+    }
+}
+
+/// Post id of the sample message sent by /testrepost. Reddit ids are alphanumeric, so this can't
+/// clash with a real post.
+pub const TEST_REPOST_POST_ID: &str = "test_repost";
+
+/// Telegram's limit for the data of inline keyboard buttons
+pub const MAX_CALLBACK_DATA_BYTES: usize = 64;
+
+/// Data of the repost buttons, encoded as `v1:<post id>:<flags>` where the flags are `c` if the
+/// caption is copied and `g` if the post is a gallery. Buttons of older messages hold the same
+/// data as JSON, which is still decoded.
+#[derive(Debug, PartialEq, Eq, Deserialize)]
 #[serde(rename = "BtnDt")]
 pub struct ButtonCallbackData {
     #[serde(rename = "n")]
@@ -59,3 +260,155 @@ pub struct ButtonCallbackData {
     #[serde(rename = "d")]
     pub is_gallery: bool,
 }
+
+impl ButtonCallbackData {
+    const V1_PREFIX: &'static str = "v1:";
+
+    /// Fails if the encoded data is over Telegram's limit, as Telegram would reject the message.
+    pub fn encode(&self) -> anyhow::Result<String> {
+        let mut flags = String::new();
+        if self.copy_caption {
+            flags.push('c');
+        }
+        if self.is_gallery {
+            flags.push('g');
+        }
+        let data = format!("{}{}:{flags}", Self::V1_PREFIX, self.post_id);
+        anyhow::ensure!(
+            data.len() <= MAX_CALLBACK_DATA_BYTES,
+            "callback data {data:?} is over {MAX_CALLBACK_DATA_BYTES} bytes"
+        );
+        Ok(data)
+    }
+
+    pub fn decode(data: &str) -> anyhow::Result<Self> {
+        let Some(data) = data.strip_prefix(Self::V1_PREFIX) else {
+            return Ok(serde_json::from_str(data)?);
+        };
+        let (post_id, flags) = data
+            .rsplit_once(':')
+            .ok_or_else(|| anyhow::anyhow!("callback data has no flags"))?;
+        anyhow::ensure!(
+            flags.chars().all(|flag| matches!(flag, 'c' | 'g')),
+            "unknown callback data flags {flags:?}"
+        );
+        Ok(Self {
+            post_id: post_id.to_string(),
+            copy_caption: flags.contains('c'),
+            is_gallery: flags.contains('g'),
+        })
+    }
+}
+
+/// Data of the buttons suggesting subreddits when /sub is given one that doesn't exist. Subreddit
+/// names are at most 21 characters, which keeps this well under Telegram's 64 byte limit.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SubscribeCallbackData {
+    #[serde(rename = "s")]
+    pub subreddit: String,
+}
+
+/// How a file was sent to Telegram, which determines how it can be sent again by its file id.
+#[derive(Display, Debug, Clone, Copy, PartialEq, Eq, EnumString)]
+#[strum(serialize_all = "snake_case")]
+pub enum MediaKind {
+    Photo,
+    Video,
+    Document,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TelegramFile {
+    pub file_id: FileId,
+    pub kind: MediaKind,
+    /// Only known for videos, which Telegram doesn't get the aspect ratio of right when sent by
+    /// file id without them
+    pub dimensions: Option<Dimensions>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Dimensions {
+    pub width: u32,
+    pub height: u32,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn time(hour: u32, min: u32) -> NaiveTime {
+        NaiveTime::from_hms_opt(hour, min, 0).unwrap()
+    }
+
+    #[test]
+    fn test_quiet_hours_contains() {
+        let quiet_hours = QuietHours {
+            start: time(9, 0),
+            end: time(17, 0),
+        };
+        assert!(quiet_hours.contains(time(9, 0)));
+        assert!(quiet_hours.contains(time(12, 30)));
+        assert!(!quiet_hours.contains(time(17, 0)));
+        assert!(!quiet_hours.contains(time(8, 59)));
+
+        let quiet_hours = QuietHours {
+            start: time(23, 0),
+            end: time(7, 0),
+        };
+        assert!(quiet_hours.contains(time(23, 30)));
+        assert!(quiet_hours.contains(time(0, 0)));
+        assert!(quiet_hours.contains(time(6, 59)));
+        assert!(!quiet_hours.contains(time(7, 0)));
+        assert!(!quiet_hours.contains(time(12, 0)));
+    }
+
+    #[test]
+    fn test_button_callback_data_encode_decode() {
+        let data = ButtonCallbackData {
+            post_id: "v6nu75".to_string(),
+            copy_caption: true,
+            is_gallery: true,
+        };
+        let encoded = data.encode().unwrap();
+        assert_eq!(encoded, "v1:v6nu75:cg");
+        assert_eq!(ButtonCallbackData::decode(&encoded).unwrap(), data);
+
+        let data = ButtonCallbackData {
+            post_id: TEST_REPOST_POST_ID.to_string(),
+            copy_caption: false,
+            is_gallery: false,
+        };
+        let encoded = data.encode().unwrap();
+        assert_eq!(encoded, "v1:test_repost:");
+        assert_eq!(ButtonCallbackData::decode(&encoded).unwrap(), data);
+
+        assert!(ButtonCallbackData::decode("v1:v6nu75").is_err());
+        assert!(ButtonCallbackData::decode("v1:v6nu75:x").is_err());
+    }
+
+    #[test]
+    fn test_button_callback_data_decode_json() {
+        // Buttons of messages sent before the compact encoding
+        assert_eq!(
+            ButtonCallbackData::decode(r#"{"n":"v6nu75","c":true,"d":false}"#).unwrap(),
+            ButtonCallbackData {
+                post_id: "v6nu75".to_string(),
+                copy_caption: true,
+                is_gallery: false,
+            }
+        );
+    }
+
+    #[test]
+    fn test_button_callback_data_size_limit() {
+        // Reddit post ids are base 36 and currently 7 characters, leaving plenty of room
+        let data = |post_id: &str| ButtonCallbackData {
+            post_id: post_id.to_string(),
+            copy_caption: true,
+            is_gallery: true,
+        };
+        let encoded = data("zzzzzzzzzzzz").encode().unwrap();
+        assert!(encoded.len() <= MAX_CALLBACK_DATA_BYTES);
+        assert!(data(&"z".repeat(MAX_CALLBACK_DATA_BYTES)).encode().is_err());
+    }
+}
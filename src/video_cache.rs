@@ -0,0 +1,169 @@
+use anyhow::{Context, Result};
+use log::*;
+use std::{
+    fs::{self, File},
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+/// Bumped when the files of an entry change, so that older entries are never hit and are evicted
+/// like any other unused entry
+const ENTRY_VERSION: u32 = 2;
+
+/// On-disk cache of downloaded videos keyed by source url and yt-dlp format selector, so that the
+/// same video isn't downloaded multiple times in a short period. Each entry is a directory
+/// containing the video file with its original filename, along with the files describing it, such
+/// as its metadata. Least recently used entries are evicted when the cache grows over max size.
+pub struct VideoCache {
+    dir: PathBuf,
+    max_bytes: u64,
+}
+
+impl VideoCache {
+    pub fn new(dir: &Path, max_bytes: u64) -> Self {
+        Self {
+            dir: dir.to_owned(),
+            max_bytes,
+        }
+    }
+
+    fn entry_dir(&self, url: &str, format: &str) -> PathBuf {
+        let key = format!("{ENTRY_VERSION}\n{format}\n{url}");
+        self.dir.join(format!("{:016x}", fnv1a(key.as_bytes())))
+    }
+
+    /// Copies the cached files for url downloaded with format to dest_dir. Returns whether there
+    /// were any.
+    pub fn get(&self, url: &str, format: &str, dest_dir: &Path) -> Result<bool> {
+        let cached_paths = files_in_dir(&self.entry_dir(url, format))?;
+        if cached_paths.is_empty() {
+            return Ok(false);
+        }
+
+        let now = SystemTime::now();
+        for cached_path in cached_paths {
+            let dest_path = dest_dir.join(cached_path.file_name().expect("file has a name"));
+            fs::copy(&cached_path, &dest_path).context("Could not copy video from cache")?;
+            // Mark entry as recently used
+            File::open(&cached_path)?.set_modified(now)?;
+        }
+        info!("got {url} from video cache");
+        Ok(true)
+    }
+
+    /// Caches the video and the files describing it for url downloaded with format.
+    pub fn put(&self, url: &str, format: &str, paths: &[&Path]) -> Result<()> {
+        let entry_dir = self.entry_dir(url, format);
+        fs::create_dir_all(&entry_dir).context("Could not create video cache dir")?;
+        for path in paths {
+            fs::copy(
+                path,
+                entry_dir.join(path.file_name().expect("file has a name")),
+            )
+            .context("Could not copy video to cache")?;
+        }
+        self.evict()
+    }
+
+    fn evict(&self) -> Result<()> {
+        let mut entries = vec![];
+        for entry in fs::read_dir(&self.dir)? {
+            let entry_dir = entry?.path();
+            let mut size = 0;
+            let mut modified = None;
+            for path in files_in_dir(&entry_dir)? {
+                let metadata = fs::metadata(&path)?;
+                size += metadata.len();
+                modified = modified.max(Some(metadata.modified()?));
+            }
+            if let Some(modified) = modified {
+                entries.push((entry_dir, size, modified));
+            }
+        }
+
+        let mut total_bytes: u64 = entries.iter().map(|(_, size, _)| size).sum();
+        entries.sort_by_key(|(_, _, modified)| *modified);
+        for (entry_dir, size, _) in entries {
+            if total_bytes <= self.max_bytes {
+                break;
+            }
+            info!("evicting {entry_dir:?} from video cache");
+            fs::remove_dir_all(&entry_dir)?;
+            total_bytes -= size;
+        }
+
+        Ok(())
+    }
+}
+
+/// 64-bit FNV-1a hash. Unlike `DefaultHasher` its output never changes between Rust releases, so
+/// entries stay valid across toolchain upgrades.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    const OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+    const PRIME: u64 = 0x100000001b3;
+    bytes.iter().fold(OFFSET_BASIS, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(PRIME)
+    })
+}
+
+fn files_in_dir(dir: &Path) -> Result<Vec<PathBuf>> {
+    if !dir.is_dir() {
+        return Ok(vec![]);
+    }
+    fs::read_dir(dir)?.map(|entry| Ok(entry?.path())).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    const FORMAT: &str = "bv[height<=1080]+ba/best";
+
+    fn write_video(dir: &Path, name: &str, size: usize) -> PathBuf {
+        let path = dir.join(name);
+        fs::write(&path, vec![0u8; size]).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_fnv1a() {
+        assert_eq!(fnv1a(b""), 0xcbf29ce484222325);
+        assert_eq!(fnv1a(b"a"), 0xaf63dc4c8601ec8c);
+        assert_eq!(fnv1a(b"foobar"), 0x85944171f73967e8);
+    }
+
+    #[test]
+    fn test_video_cache() {
+        let cache_dir = TempDir::new().unwrap();
+        let src_dir = TempDir::new().unwrap();
+        let dest_dir = TempDir::new().unwrap();
+        let cache = VideoCache::new(cache_dir.path(), 15);
+
+        assert!(!cache.get("https://a", FORMAT, dest_dir.path()).unwrap());
+
+        let a = write_video(src_dir.path(), "a [a].mp4", 8);
+        let a_metadata = write_video(src_dir.path(), "metadata.json", 2);
+        cache.put("https://a", FORMAT, &[&a, &a_metadata]).unwrap();
+        assert!(cache.get("https://a", FORMAT, dest_dir.path()).unwrap());
+        assert_eq!(
+            fs::read(dest_dir.path().join("a [a].mp4")).unwrap().len(),
+            8
+        );
+        assert!(dest_dir.path().join("metadata.json").is_file());
+        // Videos downloaded with another format are not the same
+        assert!(!cache.get("https://a", "best", dest_dir.path()).unwrap());
+        for cached in files_in_dir(&cache.entry_dir("https://a", FORMAT)).unwrap() {
+            File::open(cached)
+                .unwrap()
+                .set_modified(SystemTime::UNIX_EPOCH)
+                .unwrap();
+        }
+
+        // Adding another video goes over max size, so the older one is evicted
+        let b = write_video(src_dir.path(), "b [b].mp4", 10);
+        cache.put("https://b", FORMAT, &[&b]).unwrap();
+        assert!(!cache.get("https://a", FORMAT, dest_dir.path()).unwrap());
+        assert!(cache.get("https://b", FORMAT, dest_dir.path()).unwrap());
+    }
+}
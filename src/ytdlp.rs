@@ -1,7 +1,7 @@
 use anyhow::{Context, Result};
 use duct::cmd;
-use lazy_static::lazy_static;
-use log::info;
+use log::{info, warn};
+use serde_derive::Deserialize;
 use std::{
     ffi::OsString,
     fs,
@@ -9,57 +9,149 @@ use std::{
     path::{Path, PathBuf},
 };
 
-use crate::types::*;
+use crate::{config::Config, download, types::*, video_cache::VideoCache};
 
-use regex::Regex;
-use tempfile::TempDir;
+/// Format selector that prefers formats which already include audio, for when the configured one
+/// ends up with a video without sound.
+pub const WITH_AUDIO_FORMAT: &str = "b[acodec!=none]/bv*+ba/b";
 
-fn make_ytdlp_args(output: &Path, url: &str) -> Vec<OsString> {
-    vec![
+/// Extensions of the subtitle files yt-dlp may leave next to the video
+const SUBTITLE_EXTENSIONS: &[&str] = &["vtt", "srt", "ass", "ssa", "ttml", "srv3", "json3"];
+
+/// File yt-dlp writes the metadata of the video to, next to the video
+const METADATA_FILE_NAME: &str = "metadata.json";
+
+/// Metadata of a downloaded video as written by yt-dlp. Dimensions are missing for some sites.
+#[derive(Debug, PartialEq, Deserialize)]
+struct VideoMetadata {
+    id: String,
+    title: String,
+    width: Option<u16>,
+    height: Option<u16>,
+}
+
+/// `subtitle_langs` is `None` when subtitles are not wanted. An empty list leaves the languages
+/// to yt-dlp.
+fn make_ytdlp_args(
+    output: &Path,
+    url: &str,
+    format: &str,
+    subtitle_langs: Option<&[String]>,
+    proxy_url: Option<&str>,
+) -> Vec<OsString> {
+    let mut args: Vec<OsString> = vec![
         "--impersonate".into(),
         "Firefox-135".into(),
         "--paths".into(),
         output.into(),
         "--output".into(),
-        // To get telegram show correct aspect ratio for video, we need the dimensions and simplest
-        // way to make that happens is have yt-dlp write them in the filename.
-        "%(title).200B_[%(id)s]_%(width)sx%(height)s.%(ext)s".into(),
+        "%(title).200B [%(id)s].%(ext)s".into(),
+        // To get telegram show correct aspect ratio for video, we need the dimensions, which
+        // yt-dlp writes to a file along with the rest of the metadata once the video is done
+        "--print-to-file".into(),
+        "after_move:%(.{id,title,width,height})j".into(),
+        output.join(METADATA_FILE_NAME).into(),
         "-f".into(),
-        "bv[height<=1080]+ba/best".into(),
+        format.into(),
         "-S".into(),
         "res,ext:mp4:m4a".into(),
         "--recode".into(),
         "mp4".into(),
         "--no-playlist".into(),
         url.into(),
-    ]
+    ];
+    if let Some(subtitle_langs) = subtitle_langs {
+        args.extend(["--write-subs".into(), "--embed-subs".into()]);
+        if !subtitle_langs.is_empty() {
+            args.extend(["--sub-langs".into(), subtitle_langs.join(",").into()]);
+        }
+    }
+    if let Some(proxy_url) = proxy_url {
+        args.extend(["--proxy".into(), proxy_url.into()]);
+    }
+    args
 }
 
 /// Downloads given url with yt-dlp and returns path to video
-pub fn download(url: &str) -> Result<Video> {
-    let tmp_dir = TempDir::with_prefix("tgreddit")?;
+pub fn download(url: &str, config: &Config) -> Result<Video> {
+    let cache = config
+        .video_cache_dir
+        .as_deref()
+        .map(|dir| VideoCache::new(dir, config.video_cache_max_bytes));
+    download_with_format(url, config, &config.video_format(), cache)
+}
+
+/// Downloads given url again with the given format selector, bypassing the video cache.
+pub fn redownload(url: &str, config: &Config, format: &str) -> Result<Video> {
+    download_with_format(url, config, format, None)
+}
+
+fn download_with_format(
+    url: &str,
+    config: &Config,
+    format: &str,
+    cache: Option<VideoCache>,
+) -> Result<Video> {
+    download::check_media_host(url, config)?;
+    let tmp_dir = download::create_tmp_dir(config)?;
     let tmp_path = tmp_dir.path();
-    let ytdlp_args = make_ytdlp_args(tmp_path, url);
 
-    info!("running yt-dlp with arguments {ytdlp_args:?}");
-    let duct_exp = cmd("yt-dlp", ytdlp_args).stderr_to_stdout();
-    let reader = duct_exp.reader().context("Failed to run yt-dlp")?;
+    let is_cached = cache.as_ref().is_some_and(|cache| {
+        cache
+            .get(url, format, tmp_path)
+            .map_err(|err| warn!("failed to get video from cache: {err:?}"))
+            .unwrap_or(false)
+    });
 
-    log_output(BufReader::new(reader))?;
+    if !is_cached {
+        let ytdlp_args = make_ytdlp_args(
+            tmp_path,
+            url,
+            format,
+            config
+                .embed_subtitles
+                .then_some(config.subtitle_langs.as_slice()),
+            config.proxy_url.as_deref(),
+        );
+
+        info!("running yt-dlp with arguments {ytdlp_args:?}");
+        let duct_exp = cmd("yt-dlp", ytdlp_args).stderr_to_stdout();
+        let reader = duct_exp.reader().context("Failed to run yt-dlp")?;
+
+        log_output(BufReader::new(reader))?;
+    }
 
-    // yt-dlp is expected to write a single file, which is the video, to tmp_path
+    // yt-dlp is expected to write a single video file to tmp_path, possibly next to subtitles it
+    // failed to embed, and the metadata file
     let video_path = get_video_path(tmp_path)?;
+    let metadata_path = tmp_path.join(METADATA_FILE_NAME);
+    let metadata = fs::read_to_string(&metadata_path)
+        .context("Could not read video metadata written by yt-dlp")?;
+    let metadata = parse_metadata(&metadata)?;
+    let (width, height) = metadata
+        .width
+        .zip(metadata.height)
+        .context("Video metadata should have dimensions")?;
+    if !is_cached {
+        if let Some(cache) = &cache {
+            cache
+                .put(url, format, &[&video_path, &metadata_path])
+                .unwrap_or_else(|err| warn!("failed to add video to cache: {err:?}"));
+        }
+    }
 
-    let (title, id, width, height) =
-        parse_metadata_from_path(&video_path).context("Video filename should have dimensions")?;
+    let size_bytes = fs::metadata(&video_path)
+        .context("Could not read video file metadata")?
+        .len();
 
     let video = Video {
         path: video_path,
         url: url.to_owned(),
-        title,
-        id,
+        title: metadata.title,
+        id: metadata.id,
         width,
         height,
+        size_bytes,
         // return temp dir with the video so that when Video goes out of scope tempdir is deleted
         // but not at the end of this scope
         _video_tempdir: tmp_dir,
@@ -68,6 +160,25 @@ pub fn download(url: &str) -> Result<Video> {
     Ok(video)
 }
 
+/// Returns the version of yt-dlp found in PATH
+pub fn version() -> Result<String> {
+    cmd!("yt-dlp", "--version")
+        .stderr_to_stdout()
+        .read()
+        .context("Failed to run yt-dlp")
+}
+
+/// Path of the yt-dlp binary that would be run, if it's found in PATH.
+pub fn binary_path() -> Option<PathBuf> {
+    find_in_path("yt-dlp", &std::env::var_os("PATH")?)
+}
+
+fn find_in_path(name: &str, path_var: &std::ffi::OsStr) -> Option<PathBuf> {
+    std::env::split_paths(path_var)
+        .map(|dir| dir.join(name))
+        .find(|path| path.is_file())
+}
+
 /// Log each line of output from a reader.
 fn log_output<R: BufRead>(reader: R) -> Result<()> {
     for line_result in reader.lines() {
@@ -77,80 +188,141 @@ fn log_output<R: BufRead>(reader: R) -> Result<()> {
     Ok(())
 }
 
-/// Get the path to the video file in a directory.
+/// Get the path to the video file in a directory, ignoring subtitle and metadata files.
 fn get_video_path(dir: &Path) -> Result<PathBuf> {
-    let mut entries = fs::read_dir(dir).context("Could not read files in temp dir")?;
-    let video_entry = entries.next().context("No video file in temp dir")?;
-    Ok(video_entry?.path())
-}
-
-fn parse_metadata_from_path(path: &Path) -> Option<(String, String, u16, u16)> {
-    lazy_static! {
-        static ref RE: Regex =
-            Regex::new(r"(?P<title>.*)_\[(?P<id>.*)\]_(?P<width>\d+)x(?P<height>\d+)\.").unwrap();
+    let entries = fs::read_dir(dir).context("Could not read files in temp dir")?;
+    for entry in entries {
+        let path = entry?.path();
+        let is_subtitle = path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .is_some_and(|ext| SUBTITLE_EXTENSIONS.contains(&ext.to_lowercase().as_str()));
+        let is_metadata = path
+            .file_name()
+            .is_some_and(|name| name == METADATA_FILE_NAME);
+        if !is_subtitle && !is_metadata {
+            return Ok(path);
+        }
     }
+    anyhow::bail!("No video file in temp dir")
+}
 
-    let filename_str = path
-        .file_name()
-        .expect("file should have a stem")
-        .to_string_lossy();
-
-    let caps = RE.captures(&filename_str)?;
-
-    let id = caps.name("id")?.as_str().to_string();
-    let title = caps.name("title")?.as_str().to_string();
-    let width = caps.name("width")?.as_str().parse::<u16>().ok()?;
-    let height = caps.name("height")?.as_str().parse::<u16>().ok()?;
-
-    Some((title, id, width, height))
+/// Parses the metadata yt-dlp printed to the metadata file. It's appended to, so the last line is
+/// the latest.
+fn parse_metadata(metadata: &str) -> Result<VideoMetadata> {
+    let line = metadata
+        .lines()
+        .rfind(|line| !line.trim().is_empty())
+        .context("Video metadata is empty")?;
+    serde_json::from_str(line).context("Could not parse video metadata")
 }
 
 #[cfg(test)]
 mod tests {
-    use super::parse_metadata_from_path;
-    use std::path::Path;
+    use super::{
+        find_in_path, get_video_path, make_ytdlp_args, parse_metadata, VideoMetadata,
+        METADATA_FILE_NAME,
+    };
+    use std::{ffi::OsString, fs, path::Path};
 
     #[test]
-    fn test_parse_metadata_from_path() {
-        assert_eq!(
-            parse_metadata_from_path(Path::new("/foo/bar/video_[dummyid]_1920x1080.mp4")),
-            Some(("video".into(), "dummyid".into(), 1920, 1080))
+    fn test_make_ytdlp_args_proxy() {
+        let args = make_ytdlp_args(
+            Path::new("/tmp"),
+            "https://example.com/",
+            "best",
+            None,
+            None,
         );
+        assert!(!args.contains(&OsString::from("--proxy")));
 
-        // This test should fail now because the filename format is incorrect
-        assert_eq!(
-            parse_metadata_from_path(Path::new("/foo/bar/someothervideo_asdfax1080.mp4")),
+        let args = make_ytdlp_args(
+            Path::new("/tmp"),
+            "https://example.com/",
+            "best",
             None,
+            Some("socks5://localhost:1080"),
         );
+        assert!(args.ends_with(&["--proxy".into(), "socks5://localhost:1080".into()]));
+    }
 
-        // Testing a case where title includes underscores
-        assert_eq!(
-            parse_metadata_from_path(Path::new("/foo/bar/cool_video_[dummyid]_1280x720.mp4")),
-            Some(("cool_video".into(), "dummyid".into(), 1280, 720))
-        );
+    #[test]
+    fn test_make_ytdlp_args_subtitles() {
+        let url = "https://example.com/";
+        let args = make_ytdlp_args(Path::new("/tmp"), url, "best", None, None);
+        assert!(!args.contains(&OsString::from("--embed-subs")));
+
+        let args = make_ytdlp_args(Path::new("/tmp"), url, "best", Some(&[]), None);
+        assert!(args.ends_with(&["--write-subs".into(), "--embed-subs".into()]));
 
-        // Testing a case where title includes special characters
+        let langs = ["en.*".to_string(), "de".to_string()];
+        let args = make_ytdlp_args(Path::new("/tmp"), url, "best", Some(&langs), None);
+        assert!(args.ends_with(&["--sub-langs".into(), "en.*,de".into()]));
+    }
+
+    #[test]
+    fn test_find_in_path() {
+        let empty_dir = tempfile::tempdir().unwrap();
+        let bin_dir = tempfile::tempdir().unwrap();
+        fs::write(bin_dir.path().join("yt-dlp"), "").unwrap();
+        let path_var = std::env::join_paths([empty_dir.path(), bin_dir.path()]).unwrap();
         assert_eq!(
-            parse_metadata_from_path(Path::new("/foo/bar/awesome#video!_[dummyid]_640x480.mp4")),
-            Some(("awesome#video!".into(), "dummyid".into(), 640, 480))
+            find_in_path("yt-dlp", &path_var),
+            Some(bin_dir.path().join("yt-dlp"))
         );
+        assert_eq!(find_in_path("ffmpeg", &path_var), None);
+    }
 
-        // Testing a case where dimensions are not in the standard format
-        assert_eq!(
-            parse_metadata_from_path(Path::new("/foo/bar/video_1920_1080.mp4")),
+    #[test]
+    fn test_make_ytdlp_args_metadata_file() {
+        let args = make_ytdlp_args(
+            Path::new("/tmp"),
+            "https://example.com/",
+            "best",
+            None,
             None,
         );
+        let i = args
+            .iter()
+            .position(|arg| arg == "--print-to-file")
+            .unwrap();
+        assert_eq!(args[i + 2], OsString::from("/tmp/metadata.json"));
+    }
 
-        // Testing a case where there is no title
-        assert_eq!(
-            parse_metadata_from_path(Path::new("/foo/bar/_[dummyid]_1920x1080.mp4")),
-            Some(("".into(), "dummyid".into(), 1920, 1080))
-        );
+    #[test]
+    fn test_get_video_path_ignores_subtitles() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("video [id].en.vtt"), "WEBVTT").unwrap();
+        fs::write(dir.path().join(METADATA_FILE_NAME), "{}").unwrap();
+        fs::write(dir.path().join("video [id].mp4"), "").unwrap();
+        let path = get_video_path(dir.path()).unwrap();
+        assert_eq!(path, dir.path().join("video [id].mp4"));
+
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("video [id].en.srt"), "").unwrap();
+        assert!(get_video_path(dir.path()).is_err());
+    }
 
-        // Testing a case where ID is an empty string
+    #[test]
+    fn test_parse_metadata() {
+        // Titles can look like anything, including the filename format
+        let metadata =
+            r#"{"id": "dummyid", "title": "cool_video_[x]_1x1", "width": 1920, "height": 1080}"#;
         assert_eq!(
-            parse_metadata_from_path(Path::new("/foo/bar/video_[]_1920x1080.mp4")),
-            Some(("video".into(), "".into(), 1920, 1080))
+            parse_metadata(&format!("{metadata}\n")).unwrap(),
+            VideoMetadata {
+                id: "dummyid".into(),
+                title: "cool_video_[x]_1x1".into(),
+                width: Some(1920),
+                height: Some(1080),
+            }
         );
+
+        let metadata = r#"{"id": "dummyid", "title": "audio", "width": null, "height": null}"#;
+        let metadata = parse_metadata(metadata).unwrap();
+        assert_eq!((metadata.width, metadata.height), (None, None));
+
+        assert!(parse_metadata("").is_err());
+        assert!(parse_metadata("dummyid 1920 1080").is_err());
     }
 }